@@ -0,0 +1,254 @@
+// Copyright © 2025-26 l5yth & contributors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Preflight for the `check` subcommand: exercises every connection and
+//! permission the bridge relies on and reports each as a checklist line.
+
+use std::fmt;
+
+use crate::matrix::MatrixAppserviceClient;
+use crate::potatomesh::PotatoClient;
+
+/// Name of the throwaway puppet the test message is sent as, after the
+/// puppet prefix.
+pub const CHECK_PUPPET_NAME: &str = "bridge_check";
+
+/// Body of the test message.
+const CHECK_MESSAGE: &str = "PotatoMesh bridge check: this room is reachable.";
+
+/// One line of the checklist.
+pub struct CheckStep {
+    /// What was checked.
+    pub name: String,
+    /// Why the check failed, if it did.
+    pub error: Option<String>,
+}
+
+impl CheckStep {
+    fn new(name: impl Into<String>, result: anyhow::Result<()>) -> Self {
+        Self {
+            name: name.into(),
+            error: result.err().map(|e| format!("{:#}", e)),
+        }
+    }
+}
+
+impl fmt::Display for CheckStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.error {
+            None => write!(f, "✅ {}", self.name),
+            Some(error) => write!(f, "❌ {}: {}", self.name, error),
+        }
+    }
+}
+
+/// Run every check in order. Later checks run even when earlier ones fail,
+/// so one run shows everything that needs fixing.
+pub async fn run_checks(potato: &PotatoClient, matrix: &MatrixAppserviceClient) -> Vec<CheckStep> {
+    let mut steps = vec![
+        CheckStep::new("PotatoMesh API reachable", potato.health_check().await),
+        CheckStep::new("Matrix homeserver reachable", matrix.health_check().await),
+    ];
+
+    let whoami = matrix.whoami().await;
+    let name = match &whoami {
+        Ok(user_id) => format!("as_token accepted (bot is {})", user_id),
+        Err(_) => "as_token accepted".to_string(),
+    };
+    steps.push(CheckStep::new(name, whoami.map(drop)));
+
+    for room_id in matrix.bridged_rooms() {
+        let joined = matrix.bot_joined_to(&room_id).await.and_then(|joined| {
+            if joined {
+                Ok(())
+            } else {
+                Err(anyhow::anyhow!("the bot is not joined to the room"))
+            }
+        });
+        steps.push(CheckStep::new(format!("Bot can see {}", room_id), joined));
+    }
+
+    steps.push(CheckStep::new(
+        "Test puppet registered",
+        matrix
+            .ensure_user_registered(&matrix.puppet_localpart(CHECK_PUPPET_NAME))
+            .await,
+    ));
+    steps.push(CheckStep::new(
+        "Test message sent",
+        send_test_message(matrix).await,
+    ));
+    steps
+}
+
+/// Join the test puppet to the room of mesh channel 0 and post a message.
+async fn send_test_message(matrix: &MatrixAppserviceClient) -> anyhow::Result<()> {
+    let room_id = matrix.room_for_channel(0)?;
+    let user_id = matrix.user_id(&matrix.puppet_localpart(CHECK_PUPPET_NAME));
+    matrix.ensure_user_joined_room(&user_id, &room_id).await?;
+    matrix
+        .send_formatted_message_as(&user_id, &room_id, CHECK_MESSAGE, CHECK_MESSAGE, None)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{MatrixConfig, PotatomeshConfig};
+
+    fn clients(server: &mockito::ServerGuard) -> (PotatoClient, MatrixAppserviceClient) {
+        let potato = PotatoClient::new(
+            reqwest::Client::new(),
+            PotatomeshConfig::for_test(&server.url()),
+        );
+        let matrix = MatrixAppserviceClient::new(
+            reqwest::Client::new(),
+            MatrixConfig {
+                homeserver: server.url(),
+                as_token: "AS_TOKEN".to_string(),
+                hs_token: "HS_TOKEN".to_string(),
+                server_name: "example.org".to_string(),
+                room_id: Some("!roomid:example.org".to_string()),
+                log_room: None,
+                max_retry_after_secs: 60,
+                auto_create_room: None,
+                membership_check: Default::default(),
+                channel_rooms: Default::default(),
+                direct_room: None,
+                rich_notices: false,
+                max_rate_limit_retries: 3,
+                sends_per_sec: 0.0,
+                puppet_prefix: "potato_".to_string(),
+            },
+        );
+        (potato, matrix)
+    }
+
+    fn mock_healthy(server: &mut mockito::ServerGuard) {
+        server.mock("GET", "/version").with_status(200).create();
+        server
+            .mock("GET", "/_matrix/client/versions")
+            .with_status(200)
+            .create();
+        server
+            .mock("GET", "/_matrix/client/v3/account/whoami")
+            .with_status(200)
+            .with_body(r#"{"user_id":"@potatobridge:example.org"}"#)
+            .create();
+        server
+            .mock("GET", "/_matrix/client/v3/joined_rooms")
+            .with_status(200)
+            .with_body(r#"{"joined_rooms":["!roomid:example.org"]}"#)
+            .create();
+        server
+            .mock("POST", "/_matrix/client/v3/register")
+            .match_query(mockito::Matcher::Any)
+            .with_status(200)
+            .create();
+        server
+            .mock(
+                "POST",
+                mockito::Matcher::Regex(r"/_matrix/client/v3/rooms/.+/join".to_string()),
+            )
+            .match_query(mockito::Matcher::Any)
+            .with_status(200)
+            .create();
+    }
+
+    #[tokio::test]
+    async fn run_checks_passes_against_a_working_setup() {
+        let mut server = mockito::Server::new_async().await;
+        mock_healthy(&mut server);
+        let send = server
+            .mock(
+                "PUT",
+                mockito::Matcher::Regex(r"/_matrix/client/v3/rooms/.+/send/.+".to_string()),
+            )
+            .match_query(mockito::Matcher::UrlEncoded(
+                "user_id".into(),
+                "@potato_bridge_check:example.org".into(),
+            ))
+            .with_status(200)
+            .with_body(r#"{"event_id":"$check"}"#)
+            .create();
+        let (potato, matrix) = clients(&server);
+
+        let steps = run_checks(&potato, &matrix).await;
+
+        send.assert();
+        assert!(steps.iter().all(|step| step.error.is_none()));
+        assert_eq!(
+            steps[2].to_string(),
+            "✅ as_token accepted (bot is @potatobridge:example.org)"
+        );
+    }
+
+    #[tokio::test]
+    async fn run_checks_reports_each_failure_with_its_status() {
+        let mut server = mockito::Server::new_async().await;
+        server.mock("GET", "/version").with_status(200).create();
+        server
+            .mock("GET", "/_matrix/client/versions")
+            .with_status(200)
+            .create();
+        server
+            .mock("GET", "/_matrix/client/v3/account/whoami")
+            .with_status(401)
+            .with_body(r#"{"errcode":"M_UNKNOWN_TOKEN"}"#)
+            .create();
+        server
+            .mock("GET", "/_matrix/client/v3/joined_rooms")
+            .with_status(200)
+            .with_body(r#"{"joined_rooms":[]}"#)
+            .create();
+        server
+            .mock("POST", "/_matrix/client/v3/register")
+            .match_query(mockito::Matcher::Any)
+            .with_status(401)
+            .with_body(r#"{"errcode":"M_UNKNOWN_TOKEN"}"#)
+            .create();
+        server
+            .mock(
+                "POST",
+                mockito::Matcher::Regex(r"/_matrix/client/v3/rooms/.+/join".to_string()),
+            )
+            .match_query(mockito::Matcher::Any)
+            .with_status(403)
+            .create();
+        server
+            .mock(
+                "POST",
+                mockito::Matcher::Regex(r"/_matrix/client/v3/rooms/.+/invite".to_string()),
+            )
+            .with_status(403)
+            .create();
+        let (potato, matrix) = clients(&server);
+
+        let steps = run_checks(&potato, &matrix).await;
+        let lines: Vec<String> = steps.iter().map(ToString::to_string).collect();
+
+        assert_eq!(lines[0], "✅ PotatoMesh API reachable");
+        assert_eq!(lines[1], "✅ Matrix homeserver reachable");
+        assert!(lines[2].starts_with("❌ as_token accepted: whoami failed with status 401"));
+        assert_eq!(
+            lines[3],
+            "❌ Bot can see !roomid:example.org: the bot is not joined to the room"
+        );
+        assert!(lines[4].starts_with("❌ Test puppet registered:"));
+        assert!(lines[4].contains("401"));
+        assert!(lines[5].starts_with("❌ Test message sent:"));
+        assert_eq!(steps.len(), 6);
+    }
+}
@@ -12,13 +12,16 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use clap::{ArgAction, Parser};
+use clap::{ArgAction, Parser, Subcommand, ValueEnum};
 
 #[cfg(not(test))]
-use crate::config::{ConfigInputs, ConfigOverrides};
+use crate::config::{parse_portnum_list, ConfigInputs, ConfigOverrides};
 
 /// CLI arguments for the Matrix bridge.
-#[derive(Debug, Parser)]
+///
+/// Not `Debug`: the raw token flags would be printed in the clear; log the
+/// redacting [`ConfigInputs`] from [`Cli::to_inputs`] instead.
+#[derive(Parser)]
 #[command(
     name = "potatomesh-matrix-bridge",
     version,
@@ -26,47 +29,131 @@ use crate::config::{ConfigInputs, ConfigOverrides};
 )]
 pub struct Cli {
     /// Path to the configuration TOML file.
-    #[arg(long, value_name = "PATH")]
+    #[arg(long, global = true, value_name = "PATH")]
     pub config: Option<String>,
     /// Path to the bridge state file.
-    #[arg(long, value_name = "PATH")]
+    #[arg(long, global = true, value_name = "PATH")]
     pub state_file: Option<String>,
     /// PotatoMesh base URL.
-    #[arg(long, value_name = "URL")]
+    #[arg(long, global = true, value_name = "URL")]
     pub potatomesh_base_url: Option<String>,
     /// Poll interval in seconds.
-    #[arg(long, value_name = "SECS")]
+    #[arg(long, global = true, value_name = "SECS")]
     pub potatomesh_poll_interval_secs: Option<u64>,
+    /// Comma-separated mesh ports to forward; empty forwards every port.
+    #[arg(long, global = true, value_name = "PORTS")]
+    pub forward_portnums: Option<String>,
     /// Matrix homeserver base URL.
-    #[arg(long, value_name = "URL")]
+    #[arg(long, global = true, value_name = "URL")]
     pub matrix_homeserver: Option<String>,
     /// Matrix appservice access token.
-    #[arg(long, value_name = "TOKEN")]
+    #[arg(long, global = true, value_name = "TOKEN")]
     pub matrix_as_token: Option<String>,
     /// Path to a secret file containing the Matrix appservice access token.
-    #[arg(long, value_name = "PATH")]
+    #[arg(long, global = true, value_name = "PATH")]
     pub matrix_as_token_file: Option<String>,
     /// Matrix homeserver token for inbound appservice requests.
-    #[arg(long, value_name = "TOKEN")]
+    #[arg(long, global = true, value_name = "TOKEN")]
     pub matrix_hs_token: Option<String>,
     /// Path to a secret file containing the Matrix homeserver token.
-    #[arg(long, value_name = "PATH")]
+    #[arg(long, global = true, value_name = "PATH")]
     pub matrix_hs_token_file: Option<String>,
     /// Matrix server name (domain).
-    #[arg(long, value_name = "NAME")]
+    #[arg(long, global = true, value_name = "NAME")]
     pub matrix_server_name: Option<String>,
     /// Matrix room id to forward into.
-    #[arg(long, value_name = "ROOM")]
+    #[arg(long, global = true, value_name = "ROOM")]
     pub matrix_room_id: Option<String>,
     /// Force container defaults (overrides detection).
-    #[arg(long, action = ArgAction::SetTrue)]
+    #[arg(long, global = true, action = ArgAction::SetTrue)]
     pub container: bool,
     /// Disable container defaults (overrides detection).
-    #[arg(long, action = ArgAction::SetTrue)]
+    #[arg(long, global = true, action = ArgAction::SetTrue)]
     pub no_container: bool,
     /// Directory to search for default secret files.
-    #[arg(long, value_name = "PATH")]
+    #[arg(long, global = true, value_name = "PATH")]
     pub secrets_dir: Option<String>,
+    /// Log what would be sent to Matrix instead of sending it.
+    #[arg(long, global = true, action = ArgAction::SetTrue)]
+    pub dry_run: bool,
+    /// With --dry-run, leave the state file untouched so the next run
+    /// processes the same messages again.
+    #[arg(long, global = true, action = ArgAction::SetTrue, requires = "dry_run")]
+    pub dry_run_hold_checkpoint: bool,
+    /// On a state file without a checkpoint, start at the newest message
+    /// instead of bridging the history PotatoMesh already holds.
+    #[arg(long, global = true, action = ArgAction::SetTrue)]
+    pub since_now: bool,
+    /// Log output format [env: RUST_LOG_FORMAT].
+    #[arg(long, global = true, value_enum, value_name = "FORMAT")]
+    pub log_format: Option<LogFormat>,
+    /// What to do; bridges continuously (`run`) when omitted.
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Bridge subcommands.
+#[derive(Debug, Clone, PartialEq, Eq, Subcommand)]
+pub enum Command {
+    /// Bridge new messages until stopped (the default).
+    Run,
+    /// Forward the most recent messages once, in id order, and exit.
+    Backfill {
+        /// How many of the most recent messages to forward.
+        #[arg(long, value_name = "N")]
+        count: usize,
+        /// Save the state afterwards, moving the checkpoint past the
+        /// backfilled messages. Without it the state file is left untouched.
+        #[arg(long, action = ArgAction::SetTrue)]
+        commit: bool,
+    },
+    /// Verify connectivity and permissions, print a checklist and exit
+    /// non-zero if anything fails.
+    Check,
+    /// Compare recent mesh messages with the delivery journal.
+    Audit {
+        /// Print the ids of messages that should have been bridged but are
+        /// not in the journal, exiting non-zero if there are any.
+        #[arg(long, action = ArgAction::SetTrue, required = true)]
+        missing: bool,
+        /// How many of the most recent messages to check.
+        #[arg(long, value_name = "N", default_value_t = 1000)]
+        count: usize,
+    },
+    /// Print an appservice registration file for the homeserver, built from
+    /// the config. Tokens the config does not set yet are generated.
+    GenRegistration {
+        /// Where the homeserver reaches the bridge's listener.
+        #[arg(long, value_name = "URL", default_value = "http://localhost:41448")]
+        url: String,
+        /// Localpart of the bridge's bot user.
+        #[arg(long, value_name = "NAME", default_value = "potatomesh-bridge")]
+        sender_localpart: String,
+    },
+}
+
+/// How the bridge writes its own log lines.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum LogFormat {
+    /// Human-readable lines.
+    #[default]
+    Text,
+    /// One JSON object per line, with event fields as top-level keys.
+    Json,
+}
+
+impl LogFormat {
+    /// `--log-format`, else `RUST_LOG_FORMAT` (`env_value`), else text. An
+    /// unknown environment value is reported and ignored.
+    pub fn resolve(flag: Option<LogFormat>, env_value: Option<&str>) -> LogFormat {
+        flag.or_else(|| {
+            let value = env_value?;
+            LogFormat::from_str(value.trim(), true)
+                .map_err(|_| eprintln!("Ignoring unknown RUST_LOG_FORMAT {:?}", value))
+                .ok()
+        })
+        .unwrap_or_default()
+    }
 }
 
 impl Cli {
@@ -81,14 +168,21 @@ impl Cli {
             overrides: ConfigOverrides {
                 potatomesh_base_url: self.potatomesh_base_url.clone(),
                 potatomesh_poll_interval_secs: self.potatomesh_poll_interval_secs,
+                potatomesh_forward_portnums: self
+                    .forward_portnums
+                    .as_deref()
+                    .map(parse_portnum_list),
                 matrix_homeserver: self.matrix_homeserver.clone(),
                 matrix_as_token: self.matrix_as_token.clone(),
                 matrix_as_token_file: self.matrix_as_token_file.clone(),
+                matrix_as_token_fallback: None,
                 matrix_hs_token: self.matrix_hs_token.clone(),
                 matrix_hs_token_file: self.matrix_hs_token_file.clone(),
                 matrix_server_name: self.matrix_server_name.clone(),
                 matrix_room_id: self.matrix_room_id.clone(),
                 state_file: self.state_file.clone(),
+                bridge_dry_run: self.dry_run.then_some(true),
+                bridge_dry_run_hold_checkpoint: self.dry_run_hold_checkpoint.then_some(true),
             },
         }
     }
@@ -103,3 +197,94 @@ fn resolve_container_override(container: bool, no_container: bool) -> Option<boo
         _ => None,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn log_format_flag_beats_environment() {
+        assert_eq!(
+            LogFormat::resolve(Some(LogFormat::Text), Some("json")),
+            LogFormat::Text
+        );
+        assert_eq!(LogFormat::resolve(None, Some("JSON")), LogFormat::Json);
+        assert_eq!(LogFormat::resolve(None, Some("yaml")), LogFormat::Text);
+        assert_eq!(LogFormat::resolve(None, None), LogFormat::Text);
+    }
+
+    #[test]
+    fn log_format_flag_parses() {
+        let cli = Cli::try_parse_from(["bridge", "--log-format", "json"]).unwrap();
+        assert_eq!(cli.log_format, Some(LogFormat::Json));
+        assert!(Cli::try_parse_from(["bridge", "--log-format", "yaml"]).is_err());
+    }
+
+    #[test]
+    fn subcommand_defaults_to_run() {
+        let cli = Cli::try_parse_from(["bridge", "--dry-run"]).unwrap();
+        assert!(cli.dry_run);
+        assert_eq!(cli.command, None);
+
+        let cli = Cli::try_parse_from(["bridge", "run", "--since-now"]).unwrap();
+        assert_eq!(cli.command, Some(Command::Run));
+        assert!(cli.since_now);
+
+        let cli = Cli::try_parse_from(["bridge", "check", "--config", "bridge.toml"]).unwrap();
+        assert_eq!(cli.command, Some(Command::Check));
+
+        let cli =
+            Cli::try_parse_from(["bridge", "gen-registration", "--url", "http://b:41448"]).unwrap();
+        assert_eq!(
+            cli.command,
+            Some(Command::GenRegistration {
+                url: "http://b:41448".to_string(),
+                sender_localpart: "potatomesh-bridge".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn backfill_takes_count_commit_and_global_flags() {
+        let cli = Cli::try_parse_from([
+            "bridge",
+            "backfill",
+            "--count",
+            "100",
+            "--config",
+            "bridge.toml",
+        ])
+        .unwrap();
+        assert_eq!(
+            cli.command,
+            Some(Command::Backfill {
+                count: 100,
+                commit: false
+            })
+        );
+        assert_eq!(cli.config.as_deref(), Some("bridge.toml"));
+
+        let cli = Cli::try_parse_from(["bridge", "backfill", "--count", "5", "--commit"]).unwrap();
+        assert_eq!(
+            cli.command,
+            Some(Command::Backfill {
+                count: 5,
+                commit: true
+            })
+        );
+        assert!(Cli::try_parse_from(["bridge", "backfill"]).is_err());
+    }
+
+    #[test]
+    fn audit_requires_missing_and_defaults_count() {
+        let cli = Cli::try_parse_from(["bridge", "audit", "--missing"]).unwrap();
+        assert_eq!(
+            cli.command,
+            Some(Command::Audit {
+                missing: true,
+                count: 1000
+            })
+        );
+        assert!(Cli::try_parse_from(["bridge", "audit"]).is_err());
+    }
+}
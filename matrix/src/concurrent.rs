@@ -0,0 +1,277 @@
+// Copyright © 2025-26 l5yth & contributors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Overlapping sends of one poll batch for `bridge.concurrency`, settled in
+//! batch order.
+
+use std::collections::VecDeque;
+
+use anyhow::Result;
+use futures::future::{BoxFuture, FutureExt};
+use futures::stream::{FuturesUnordered, StreamExt};
+use tracing::{Instrument, Span};
+
+use crate::config::{BridgeConfig, MessageOrdering};
+use crate::matrix::MatrixAppserviceClient;
+use crate::potatomesh::{PotatoClient, PotatoMessage};
+use crate::state::{log_state_update, persist_state, BridgeState};
+use crate::{
+    message_span, prepare_with, record_sent, screen_message, settle_message, Flow, Outcome,
+    PollRun, Prepared, PreparedMessage, StateView,
+};
+
+/// A message [`ConcurrentSends`] has in flight finished: its position in
+/// the batch, what preparing it came to, and the result of sending it, if
+/// it was sent.
+type SendResult = (u64, PreparedMessage, Option<Result<String>>);
+
+/// [`forward_batch`](crate::forward_batch) with up to `concurrency` messages
+/// being prepared and sent at once.
+///
+/// Messages are screened one by one, in batch order; looking up the node,
+/// setting up its puppet and the send overlap. Under `ordering = "strict"`
+/// a room has at most one message in flight, so its messages arrive in
+/// order; under `"relaxed"` they may arrive in any order, unless
+/// `collapse_duplicates_secs` is set: a repeat is only recognized once the
+/// message before it in the room was sent, so rooms then take turns as
+/// under strict ordering. A reply waits
+/// for every earlier message so its parent's event is known. Outcomes are
+/// settled in batch order, so the checkpoint never passes a message that
+/// is still in flight or failed. When a failure stops the batch, messages
+/// sent after it are noted in `delivered_ahead` instead of the checkpoint.
+pub struct ConcurrentSends<'a> {
+    limit: usize,
+    /// A room has at most one message in flight.
+    one_per_room: bool,
+    in_flight: FuturesUnordered<BoxFuture<'a, SendResult>>,
+    /// Messages not settled yet, in batch order, starting at position
+    /// `first`.
+    pending: VecDeque<PendingMessage>,
+    first: u64,
+    /// A settled message stopped the batch.
+    stopped: bool,
+}
+
+/// A message of a [`ConcurrentSends`] batch waiting to be settled.
+struct PendingMessage {
+    msg: PotatoMessage,
+    span: Span,
+    /// Room the message goes to, when it has one.
+    room_id: Option<String>,
+    stage: Stage,
+}
+
+/// How far a [`PendingMessage`] has got.
+enum Stage {
+    /// It is being prepared and sent.
+    InFlight,
+    /// Preparing it, and sending it if it came to that, finished.
+    Finished(Box<PreparedMessage>, Option<Result<String>>),
+    /// It was not sent.
+    Done(Outcome),
+}
+
+impl<'a> ConcurrentSends<'a> {
+    pub fn new(bridge_cfg: &BridgeConfig) -> Self {
+        Self {
+            limit: bridge_cfg.concurrency,
+            one_per_room: bridge_cfg.ordering == MessageOrdering::Strict
+                || bridge_cfg.collapse_duplicates_secs.is_some(),
+            in_flight: FuturesUnordered::new(),
+            pending: VecDeque::new(),
+            first: 0,
+            stopped: false,
+        }
+    }
+
+    /// Screen `msg`, then start preparing and sending it once a slot (and,
+    /// when rooms take turns, its room) is free. Returns [`Flow::Stop`] once
+    /// nothing more may be started.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn forward(
+        &mut self,
+        potato: &'a PotatoClient,
+        matrix: &'a MatrixAppserviceClient,
+        bridge_cfg: &'a BridgeConfig,
+        state: &mut BridgeState,
+        state_path: &str,
+        msg: &PotatoMessage,
+        run: &mut PollRun,
+    ) -> Flow {
+        let span = message_span(msg);
+        let screened = screen_message(potato, matrix, bridge_cfg, state, msg, run)
+            .instrument(span.clone())
+            .await;
+        // Without a room, preparing the message reports the error.
+        let room_id = matrix
+            .room_for_message(msg.channel, msg.is_broadcast())
+            .ok();
+        if let Some(outcome) = screened {
+            let deferred = matches!(outcome, Outcome::Deferred);
+            self.push(msg, span, room_id, Stage::Done(outcome));
+            if deferred {
+                return Flow::Stop;
+            }
+            return self.settle_ready(matrix, bridge_cfg, state, state_path, run);
+        }
+
+        while self.in_flight.len() >= self.limit
+            || (self.one_per_room
+                && room_id
+                    .as_deref()
+                    .is_some_and(|room_id| self.room_busy(room_id)))
+            || (msg.reply_id.is_some() && !self.in_flight.is_empty())
+        {
+            self.complete_one().await;
+            if let Flow::Stop = self.settle_ready(matrix, bridge_cfg, state, state_path, run) {
+                return Flow::Stop;
+            }
+        }
+
+        let view = StateView::new(matrix, bridge_cfg, state, msg);
+        let position = self.push(msg, span.clone(), room_id, Stage::InFlight);
+        let msg = msg.clone();
+        let send = async move {
+            let prepared = prepare_with(potato, matrix, bridge_cfg, view, &msg).await;
+            let sent = match &prepared.result {
+                Ok(Prepared::Send(out)) => Some(out.send(matrix).await),
+                _ => None,
+            };
+            (position, prepared, sent)
+        };
+        self.in_flight.push(send.instrument(span).boxed());
+        Flow::Next
+    }
+
+    /// Wait for the messages still in flight and settle the batch. Returns
+    /// whether every message was handled.
+    pub async fn finish(
+        mut self,
+        matrix: &MatrixAppserviceClient,
+        bridge_cfg: &BridgeConfig,
+        state: &mut BridgeState,
+        state_path: &str,
+        run: &PollRun,
+    ) -> bool {
+        while !self.in_flight.is_empty() {
+            self.complete_one().await;
+        }
+        self.settle_ready(matrix, bridge_cfg, state, state_path, run);
+        if !self.stopped {
+            return true;
+        }
+        // Left unsettled behind the message that stopped the batch.
+        for pending in self.pending.drain(..) {
+            let _entered = pending.span.enter();
+            let Stage::Finished(prepared, sent) = pending.stage else {
+                continue;
+            };
+            match (prepared.apply(state, &pending.msg), sent) {
+                (Ok(Prepared::Send(out)), Some(Ok(event_id))) => {
+                    record_sent(matrix, bridge_cfg, state, &pending.msg, out, event_id);
+                }
+                (Ok(Prepared::Collapsed), _) => {}
+                _ => continue,
+            }
+            state.delivered_ahead.insert(pending.msg.id);
+        }
+        persist_state(state, state_path);
+        false
+    }
+
+    /// Queue `msg` for settling, returning its position in the batch.
+    fn push(
+        &mut self,
+        msg: &PotatoMessage,
+        span: Span,
+        room_id: Option<String>,
+        stage: Stage,
+    ) -> u64 {
+        self.pending.push_back(PendingMessage {
+            msg: msg.clone(),
+            span,
+            room_id,
+            stage,
+        });
+        self.first + self.pending.len() as u64 - 1
+    }
+
+    /// Whether a message to `room_id` is in flight, or finished but not
+    /// yet recorded for replies and repeats.
+    fn room_busy(&self, room_id: &str) -> bool {
+        self.pending.iter().any(|pending| {
+            !matches!(pending.stage, Stage::Done(_)) && pending.room_id.as_deref() == Some(room_id)
+        })
+    }
+
+    /// Wait for the next message in flight to finish.
+    async fn complete_one(&mut self) {
+        if let Some((position, prepared, sent)) = self.in_flight.next().await {
+            let pending = &mut self.pending[(position - self.first) as usize];
+            pending.stage = Stage::Finished(Box::new(prepared), sent);
+        }
+    }
+
+    /// Settle the messages at the front of the batch whose outcome is
+    /// known, in order.
+    fn settle_ready(
+        &mut self,
+        matrix: &MatrixAppserviceClient,
+        bridge_cfg: &BridgeConfig,
+        state: &mut BridgeState,
+        state_path: &str,
+        run: &PollRun,
+    ) -> Flow {
+        while !self.stopped
+            && self
+                .pending
+                .front()
+                .is_some_and(|pending| !matches!(pending.stage, Stage::InFlight))
+        {
+            let Some(pending) = self.pending.pop_front() else {
+                break;
+            };
+            self.first += 1;
+            let _entered = pending.span.enter();
+            let msg = &pending.msg;
+            let outcome = match pending.stage {
+                Stage::Finished(prepared, sent) => match (prepared.apply(state, msg), sent) {
+                    (Ok(Prepared::Send(out)), Some(Ok(event_id))) => {
+                        record_sent(matrix, bridge_cfg, state, msg, out, event_id);
+                        state.update_with(msg);
+                        log_state_update(state);
+                        Outcome::Handled(Ok(()))
+                    }
+                    (Ok(Prepared::Send(_)), Some(Err(e))) | (Err(e), _) => Outcome::Handled(Err(e)),
+                    (Ok(Prepared::Collapsed), _) => {
+                        state.update_with(msg);
+                        log_state_update(state);
+                        Outcome::Handled(Ok(()))
+                    }
+                    (Ok(Prepared::Send(_)), None) => unreachable!("prepared messages are sent"),
+                },
+                Stage::Done(outcome) => outcome,
+                Stage::InFlight => unreachable!("in-flight messages are not settled"),
+            };
+            if let Flow::Stop = settle_message(bridge_cfg, state, state_path, msg, run, outcome) {
+                self.stopped = true;
+            }
+        }
+        if self.stopped {
+            Flow::Stop
+        } else {
+            Flow::Next
+        }
+    }
+}
@@ -0,0 +1,360 @@
+// Copyright © 2025-26 l5yth & contributors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Bounded record of the content of recently processed messages, so a
+//! message whose id PotatoMesh reused is still recognized as new.
+
+use std::collections::VecDeque;
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::potatomesh::PotatoMessage;
+use crate::text::fnv1a;
+
+/// Content hashes remembered unless `content_dedup_size` says otherwise.
+pub const DEFAULT_CONTENT_DEDUP_SIZE: usize = 1000;
+
+/// Stable hash of a message's sender, text and receive time.
+pub fn content_hash(msg: &PotatoMessage) -> u64 {
+    let sender = msg.sender_id();
+    let mut bytes = Vec::with_capacity(sender.len() + msg.text.len() + 10);
+    bytes.extend_from_slice(sender.as_bytes());
+    bytes.push(0);
+    bytes.extend_from_slice(msg.text.as_bytes());
+    bytes.push(0);
+    bytes.extend_from_slice(&msg.rx_time.to_le_bytes());
+    fnv1a(&bytes)
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(from = "SavedSeenContent")]
+pub struct SeenContent {
+    /// Receive times and content hashes of recorded messages, oldest first.
+    entries: VecDeque<(u64, u64)>,
+    /// Latest receive time whose content may be missing here: evicted, or
+    /// processed before recording started. Only messages received later
+    /// are judged by content.
+    floor: Option<u64>,
+    /// Most hashes kept; configured at startup, not persisted.
+    #[serde(skip)]
+    capacity: usize,
+    /// Sequence number the next recorded hash gets; the entries hold the
+    /// ones just before it. Lets a SQLite store write only new entries.
+    #[serde(skip)]
+    next_seq: u64,
+    /// Kept in a table of its own by a SQLite store, so left out of the
+    /// state's serialized fields.
+    #[serde(skip)]
+    in_table: bool,
+}
+
+fn default_capacity() -> usize {
+    DEFAULT_CONTENT_DEDUP_SIZE
+}
+
+/// [`SeenContent`] as saved, or the bare hashes saved before receive times
+/// were kept. Those cannot tell which messages they cover, so they are
+/// dropped.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SavedSeenContent {
+    Seen {
+        #[serde(default)]
+        entries: VecDeque<(u64, u64)>,
+        #[serde(default)]
+        floor: Option<u64>,
+    },
+    HashesOnly(#[allow(dead_code)] Vec<u64>),
+}
+
+impl From<SavedSeenContent> for SeenContent {
+    fn from(saved: SavedSeenContent) -> Self {
+        match saved {
+            SavedSeenContent::Seen { entries, floor } => Self {
+                next_seq: entries.len() as u64,
+                entries,
+                floor,
+                ..Self::default()
+            },
+            SavedSeenContent::HashesOnly(_) => Self::default(),
+        }
+    }
+}
+
+impl Default for SeenContent {
+    fn default() -> Self {
+        Self {
+            entries: VecDeque::new(),
+            floor: None,
+            capacity: default_capacity(),
+            next_seq: 0,
+            in_table: false,
+        }
+    }
+}
+
+impl fmt::Debug for SeenContent {
+    // The hashes are meaningless in logs; the size is enough.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SeenContent({} hashes)", self.entries.len())
+    }
+}
+
+impl SeenContent {
+    /// Keep at most `capacity` hashes, evicting the oldest if needed.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.evict();
+    }
+
+    /// Remember the content of `msg`.
+    pub fn record(&mut self, msg: &PotatoMessage) {
+        let hash = content_hash(msg);
+        if !self.contains(hash) {
+            self.entries.push_back((msg.rx_time, hash));
+            self.next_seq += 1;
+            self.evict();
+        }
+    }
+
+    /// Whether `msg` carries content not seen before. Only messages
+    /// received after the floor are judged: an earlier one's content may
+    /// have been evicted, or processed before recording started, so its id
+    /// alone decides. Ids play no part, as a reused id can be any number.
+    /// Always `false` while nothing has been recorded yet.
+    pub fn is_new(&self, msg: &PotatoMessage) -> bool {
+        !self.entries.is_empty()
+            && self.floor.is_none_or(|floor| msg.rx_time > floor)
+            && !self.contains(content_hash(msg))
+    }
+
+    /// Start recording on top of a state that processed messages received
+    /// up to `rx_time` without it, e.g. one saved before content was
+    /// recorded.
+    pub fn start_after(&mut self, rx_time: u64) {
+        if self.entries.is_empty() && self.floor.is_none() {
+            self.floor = Some(rx_time);
+        }
+    }
+
+    /// Rebuild a record kept in a SQLite store from its floor and its
+    /// `(seq, rx_time, hash)` entries in order.
+    pub fn restore(floor: Option<u64>, entries: Vec<(u64, u64, u64)>) -> Self {
+        Self {
+            next_seq: entries.last().map_or(0, |&(seq, _, _)| seq + 1),
+            entries: entries
+                .into_iter()
+                .map(|(_, rx_time, hash)| (rx_time, hash))
+                .collect(),
+            floor,
+            in_table: true,
+            ..Self::default()
+        }
+    }
+
+    pub fn floor(&self) -> Option<u64> {
+        self.floor
+    }
+
+    /// Sequence number of the oldest entry kept.
+    pub fn first_seq(&self) -> u64 {
+        self.next_seq - self.entries.len() as u64
+    }
+
+    pub fn next_seq(&self) -> u64 {
+        self.next_seq
+    }
+
+    /// `(seq, rx_time, hash)` of the entries recorded from `seq` on.
+    pub fn recorded_since(&self, seq: u64) -> impl Iterator<Item = (u64, u64, u64)> + '_ {
+        let first = self.first_seq();
+        let skip = seq.saturating_sub(first) as usize;
+        self.entries
+            .iter()
+            .enumerate()
+            .skip(skip)
+            .map(move |(i, &(rx_time, hash))| (first + i as u64, rx_time, hash))
+    }
+
+    /// Whether a SQLite store keeps the entries in a table of their own.
+    pub fn in_table(&self) -> bool {
+        self.in_table
+    }
+
+    pub fn set_in_table(&mut self, in_table: bool) {
+        self.in_table = in_table;
+    }
+
+    fn contains(&self, hash: u64) -> bool {
+        self.entries.iter().any(|&(_, seen)| seen == hash)
+    }
+
+    fn evict(&mut self) {
+        let excess = self.entries.len().saturating_sub(self.capacity);
+        for (rx_time, _) in self.entries.drain(..excess) {
+            self.floor = self.floor.max(Some(rx_time));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: u64, text: &str, rx_time: u64) -> PotatoMessage {
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "rx_time": rx_time,
+            "rx_iso": "2025-11-27T00:00:00Z",
+            "from_id": "!abcd1234",
+            "to_id": "^all",
+            "channel": 0,
+            "text": text,
+            "lora_freq": 868,
+            "modem_preset": "MediumFast",
+            "channel_name": "TEST",
+            "node_id": "!abcd1234",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn content_hash_ignores_id() {
+        assert_eq!(
+            content_hash(&message(1, "Ping", 100)),
+            content_hash(&message(2, "Ping", 100))
+        );
+        assert_ne!(
+            content_hash(&message(1, "Ping", 100)),
+            content_hash(&message(1, "Pong", 100))
+        );
+        assert_ne!(
+            content_hash(&message(1, "Ping", 100)),
+            content_hash(&message(1, "Ping", 101))
+        );
+    }
+
+    #[test]
+    fn nothing_is_new_until_something_was_recorded() {
+        let mut seen = SeenContent::default();
+        assert!(!seen.is_new(&message(1, "Ping", 100)));
+
+        seen.record(&message(1, "Ping", 100));
+        assert!(!seen.is_new(&message(1, "Ping", 100)));
+        assert!(seen.is_new(&message(1, "Pong", 100)));
+    }
+
+    #[test]
+    fn oldest_hashes_are_evicted_past_capacity() {
+        let mut seen = SeenContent::default();
+        seen.set_capacity(2);
+        for (id, rx_time) in [(1, 100), (2, 101), (3, 102)] {
+            seen.record(&message(id, "Ping", rx_time));
+        }
+
+        assert_eq!(seen.entries.len(), 2);
+        assert!(!seen.is_new(&message(3, "Ping", 102)));
+        assert!(seen.is_new(&message(4, "Pong", 102)));
+    }
+
+    #[test]
+    fn messages_received_up_to_an_evicted_one_are_not_judged() {
+        let mut seen = SeenContent::default();
+        seen.set_capacity(2);
+        // Out of receive order, as a batch sorted by id can be.
+        for (id, rx_time) in [(10, 101), (11, 100), (12, 102)] {
+            seen.record(&message(id, "Ping", rx_time));
+        }
+
+        // Message 10's content was evicted; that does not make it or
+        // anything received before it new.
+        assert!(!seen.is_new(&message(10, "Ping", 101)));
+        assert!(!seen.is_new(&message(9, "Pong", 100)));
+        assert!(seen.is_new(&message(13, "Pong", 102)));
+    }
+
+    #[test]
+    fn reused_low_ids_are_judged_after_evictions() {
+        let mut seen = SeenContent::default();
+        seen.set_capacity(2);
+        // Random packet ids near the top of the range.
+        for (id, rx_time) in [(4_000_000_001, 100), (4_000_000_002, 101), (3, 102)] {
+            seen.record(&message(id, "Ping", rx_time));
+        }
+
+        assert!(seen.is_new(&message(1, "Pong", 200)));
+    }
+
+    #[test]
+    fn recording_started_after_a_time_does_not_judge_up_to_it() {
+        let mut seen = SeenContent::default();
+        seen.start_after(100);
+        seen.record(&message(43, "Ping", 101));
+
+        assert!(!seen.is_new(&message(42, "Pong", 100)));
+        assert!(seen.is_new(&message(42, "Pong", 101)));
+
+        // Once recording, the floor only moves with evictions.
+        seen.start_after(150);
+        assert!(seen.is_new(&message(42, "Pong", 101)));
+    }
+
+    #[test]
+    fn entries_are_numbered_across_evictions_and_restores() {
+        let mut seen = SeenContent::default();
+        seen.set_capacity(2);
+        for (id, rx_time) in [(1, 100), (2, 101), (3, 102)] {
+            seen.record(&message(id, "Ping", rx_time));
+        }
+
+        assert_eq!(seen.first_seq(), 1);
+        assert_eq!(seen.next_seq(), 3);
+        let since: Vec<_> = seen.recorded_since(2).collect();
+        assert_eq!(
+            since,
+            vec![(2, 102, content_hash(&message(3, "Ping", 102)))]
+        );
+
+        let mut restored = SeenContent::restore(seen.floor(), seen.recorded_since(0).collect());
+        restored.set_capacity(2);
+        assert_eq!(restored.entries, seen.entries);
+        assert_eq!(restored.first_seq(), 1);
+        assert!(restored.in_table());
+        restored.record(&message(4, "Ping", 103));
+        assert_eq!(restored.first_seq(), 2);
+        assert_eq!(restored.floor(), Some(101));
+    }
+
+    #[test]
+    fn hashes_saved_without_receive_times_are_dropped() {
+        let seen: SeenContent = serde_json::from_str("[123, 456]").unwrap();
+
+        assert!(seen.entries.is_empty());
+        assert_eq!(seen.floor, None);
+    }
+
+    #[test]
+    fn entries_and_floor_survive_a_round_trip() {
+        let mut seen = SeenContent::default();
+        seen.set_capacity(1);
+        seen.record(&message(1, "Ping", 100));
+        seen.record(&message(2, "Ping", 101));
+
+        let json = serde_json::to_string(&seen).unwrap();
+        let loaded: SeenContent = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded.entries, seen.entries);
+        assert_eq!(loaded.floor, Some(100));
+    }
+}
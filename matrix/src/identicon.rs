@@ -0,0 +1,179 @@
+// Copyright © 2025-26 l5yth & contributors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Deterministic identicon avatars for puppet users.
+//!
+//! The cell pattern is seeded by the node id so every node keeps a stable,
+//! recognizable avatar; the colour comes from the node's hardware model when
+//! it is known, so nodes built on the same board share a hue.
+
+use crate::text::fnv1a;
+
+/// Cells per side of the mirrored pattern.
+const GRID: usize = 5;
+/// Pixels per cell.
+const CELL: usize = 12;
+/// Blank border around the pattern, in pixels.
+const PADDING: usize = 6;
+/// Width and height of the generated image, in pixels.
+pub const SIZE: usize = GRID * CELL + 2 * PADDING;
+
+const BACKGROUND: [u8; 3] = [0xf0, 0xf0, 0xf0];
+
+/// Render the identicon for `node_id` as a PNG image.
+pub fn identicon_png(node_id: &str, hw_model: Option<&str>) -> Vec<u8> {
+    let pattern = fnv1a(node_id.as_bytes());
+    let color = hue_to_rgb(fnv1a(hw_model.unwrap_or(node_id).as_bytes()) % 360);
+    let filled = |row: usize, col: usize| {
+        // Mirror the left half so the pattern is horizontally symmetric.
+        let col = col.min(GRID - 1 - col);
+        pattern >> (row * GRID.div_ceil(2) + col) & 1 == 1
+    };
+
+    let mut pixels = Vec::with_capacity(SIZE * (1 + SIZE * 3));
+    for y in 0..SIZE {
+        // PNG filter type "none" for every scanline.
+        pixels.push(0);
+        for x in 0..SIZE {
+            let inside =
+                (PADDING..SIZE - PADDING).contains(&x) && (PADDING..SIZE - PADDING).contains(&y);
+            let rgb = if inside && filled((y - PADDING) / CELL, (x - PADDING) / CELL) {
+                color
+            } else {
+                BACKGROUND
+            };
+            pixels.extend_from_slice(&rgb);
+        }
+    }
+    encode_png(SIZE as u32, SIZE as u32, &pixels)
+}
+
+/// Fully saturated, medium-light colour for `hue` degrees.
+fn hue_to_rgb(hue: u64) -> [u8; 3] {
+    const HIGH: u8 = 0xd0;
+    const LOW: u8 = 0x40;
+    let ramp = |t: u64| LOW + ((u64::from(HIGH - LOW) * t) / 60) as u8;
+    let t = hue % 60;
+    match hue / 60 {
+        0 => [HIGH, ramp(t), LOW],
+        1 => [ramp(60 - t), HIGH, LOW],
+        2 => [LOW, HIGH, ramp(t)],
+        3 => [LOW, ramp(60 - t), HIGH],
+        4 => [ramp(t), LOW, HIGH],
+        _ => [HIGH, LOW, ramp(60 - t)],
+    }
+}
+
+/// Encode filtered 8-bit RGB scanlines as a PNG, using uncompressed deflate
+/// blocks so no compression library is needed for these tiny images.
+fn encode_png(width: u32, height: u32, scanlines: &[u8]) -> Vec<u8> {
+    let mut ihdr = Vec::with_capacity(13);
+    ihdr.extend_from_slice(&width.to_be_bytes());
+    ihdr.extend_from_slice(&height.to_be_bytes());
+    // Bit depth 8, colour type 2 (RGB), default compression/filter, no interlace.
+    ihdr.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+    let mut zlib = vec![0x78, 0x01];
+    let mut blocks = scanlines.chunks(u16::MAX as usize).peekable();
+    while let Some(block) = blocks.next() {
+        zlib.push(u8::from(blocks.peek().is_none()));
+        let len = block.len() as u16;
+        zlib.extend_from_slice(&len.to_le_bytes());
+        zlib.extend_from_slice(&(!len).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+    zlib.extend_from_slice(&adler32(scanlines).to_be_bytes());
+
+    let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+    write_chunk(&mut png, b"IHDR", &ihdr);
+    write_chunk(&mut png, b"IDAT", &zlib);
+    write_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0u32, |crc, &b| {
+        (0..8).fold(crc ^ u32::from(b), |crc, _| {
+            if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
+
+fn adler32(bytes: &[u8]) -> u32 {
+    let (a, b) = bytes.iter().fold((1u32, 0u32), |(a, b), &byte| {
+        let a = (a + u32::from(byte)) % 65521;
+        (a, (b + a) % 65521)
+    });
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identicon_is_deterministic_per_node() {
+        assert_eq!(
+            identicon_png("abcd1234", Some("TBEAM")),
+            identicon_png("abcd1234", Some("TBEAM"))
+        );
+        assert_ne!(
+            identicon_png("abcd1234", Some("TBEAM")),
+            identicon_png("0badc0de", Some("TBEAM"))
+        );
+    }
+
+    #[test]
+    fn identicon_color_follows_hardware_model() {
+        assert_ne!(
+            identicon_png("abcd1234", Some("TBEAM")),
+            identicon_png("abcd1234", Some("HELTEC_V3"))
+        );
+    }
+
+    #[test]
+    fn identicon_is_a_png_of_the_expected_size() {
+        let png = identicon_png("abcd1234", None);
+        assert!(png.starts_with(b"\x89PNG\r\n\x1a\n"));
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(
+            u32::from_be_bytes(png[16..20].try_into().unwrap()),
+            SIZE as u32
+        );
+        assert_eq!(
+            u32::from_be_bytes(png[20..24].try_into().unwrap()),
+            SIZE as u32
+        );
+        assert!(png.ends_with(&[0xae, 0x42, 0x60, 0x82]));
+    }
+
+    #[test]
+    fn checksums_match_reference_values() {
+        assert_eq!(crc32(b"IEND"), 0xae42_6082);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+    }
+}
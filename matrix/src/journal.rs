@@ -0,0 +1,204 @@
+// Copyright © 2025-26 l5yth & contributors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Append-only journal of delivered messages, one JSON object per line, so
+//! `audit --missing` can tell which mesh messages never reached Matrix.
+
+use std::collections::HashSet;
+use std::fs;
+use std::io::{self, BufRead, Read, Seek, SeekFrom, Write};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::DeliveryJournal;
+
+/// A mesh message that reached Matrix.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Delivery {
+    pub mesh_id: u64,
+    pub node_id: String,
+    pub room_id: String,
+    /// The event it was sent as, or folded into as a repeat.
+    pub event_id: String,
+    /// Unix time of the delivery.
+    pub delivered_at: u64,
+}
+
+/// Append `delivery` to the journal, first rotating it when the line would
+/// take it past `max_bytes`. A last line torn by a crash is ended first, so
+/// the new one is not glued onto it.
+pub fn append(journal: &DeliveryJournal, delivery: &Delivery) -> io::Result<()> {
+    let mut line = serde_json::to_string(delivery)?;
+    line.push('\n');
+    let size = match fs::metadata(&journal.path) {
+        Ok(meta) => meta.len(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+        Err(e) => return Err(e),
+    };
+    if size > 0 && size + line.len() as u64 > journal.max_bytes {
+        rotate(journal)?;
+    } else if size > 0 && !ends_with_newline(&journal.path)? {
+        line.insert(0, '\n');
+    }
+    fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&journal.path)?
+        .write_all(line.as_bytes())
+}
+
+fn ends_with_newline(path: &str) -> io::Result<bool> {
+    let mut file = fs::File::open(path)?;
+    file.seek(SeekFrom::End(-1))?;
+    let mut last = [0u8];
+    file.read_exact(&mut last)?;
+    Ok(last[0] == b'\n')
+}
+
+/// Mesh ids of every delivery in the journal and its rotated files.
+pub fn delivered_ids(journal: &DeliveryJournal) -> io::Result<HashSet<u64>> {
+    let mut ids = HashSet::new();
+    let rotated = (1..=journal.max_files).map(|n| rotated_path(journal, n));
+    for path in std::iter::once(journal.path.clone()).chain(rotated) {
+        let file = match fs::File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        for line in io::BufReader::new(file).lines() {
+            let line = line?;
+            match serde_json::from_str::<Delivery>(&line) {
+                Ok(delivery) => {
+                    ids.insert(delivery.mesh_id);
+                }
+                // A crash mid-write can leave a torn last line.
+                Err(e) if !line.trim().is_empty() => {
+                    tracing::warn!("Ignoring unreadable line in {}: {}", path, e);
+                }
+                Err(_) => {}
+            }
+        }
+    }
+    Ok(ids)
+}
+
+/// Shift `<path>.1` … to `<path>.2` …, dropping the oldest past
+/// `max_files`, and move the journal to `<path>.1`.
+fn rotate(journal: &DeliveryJournal) -> io::Result<()> {
+    if journal.max_files == 0 {
+        return fs::remove_file(&journal.path);
+    }
+    ignore_missing(fs::remove_file(rotated_path(journal, journal.max_files)))?;
+    for n in (1..journal.max_files).rev() {
+        ignore_missing(fs::rename(
+            rotated_path(journal, n),
+            rotated_path(journal, n + 1),
+        ))?;
+    }
+    fs::rename(&journal.path, rotated_path(journal, 1))
+}
+
+fn rotated_path(journal: &DeliveryJournal, n: usize) -> String {
+    format!("{}.{}", journal.path, n)
+}
+
+fn ignore_missing(result: io::Result<()>) -> io::Result<()> {
+    match result {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn delivery(mesh_id: u64) -> Delivery {
+        Delivery {
+            mesh_id,
+            node_id: "!abcd1234".to_string(),
+            room_id: "!room:example.org".to_string(),
+            event_id: format!("$event{mesh_id}"),
+            delivered_at: 1_700_000_000,
+        }
+    }
+
+    fn journal_in(dir: &tempfile::TempDir, max_bytes: u64, max_files: usize) -> DeliveryJournal {
+        DeliveryJournal {
+            path: dir
+                .path()
+                .join("deliveries.jsonl")
+                .to_string_lossy()
+                .into_owned(),
+            max_bytes,
+            max_files,
+        }
+    }
+
+    #[test]
+    fn appended_deliveries_are_read_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal = journal_in(&dir, 1 << 20, 2);
+
+        assert!(delivered_ids(&journal).unwrap().is_empty());
+        append(&journal, &delivery(1)).unwrap();
+        append(&journal, &delivery(2)).unwrap();
+
+        let line = fs::read_to_string(&journal.path).unwrap();
+        let first: Delivery = serde_json::from_str(line.lines().next().unwrap()).unwrap();
+        assert_eq!(first, delivery(1));
+        assert_eq!(delivered_ids(&journal).unwrap(), HashSet::from([1, 2]));
+    }
+
+    #[test]
+    fn journal_rotates_and_keeps_max_files() {
+        let dir = tempfile::tempdir().unwrap();
+        // Room for one line per file.
+        let journal = journal_in(&dir, 100, 2);
+
+        for id in 1..=4 {
+            append(&journal, &delivery(id)).unwrap();
+        }
+
+        assert!(fs::metadata(rotated_path(&journal, 2)).is_ok());
+        assert!(fs::metadata(rotated_path(&journal, 3)).is_err());
+        assert_eq!(delivered_ids(&journal).unwrap(), HashSet::from([2, 3, 4]));
+    }
+
+    #[test]
+    fn torn_lines_are_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal = journal_in(&dir, 1 << 20, 1);
+        append(&journal, &delivery(1)).unwrap();
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&journal.path)
+            .unwrap()
+            .write_all(b"{\"mesh_id\":2,\"node")
+            .unwrap();
+
+        assert_eq!(delivered_ids(&journal).unwrap(), HashSet::from([1]));
+    }
+
+    #[test]
+    fn delivery_after_a_torn_line_starts_a_line_of_its_own() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal = journal_in(&dir, 1 << 20, 1);
+        fs::write(&journal.path, b"{\"mesh_id\":1,\"node").unwrap();
+
+        append(&journal, &delivery(2)).unwrap();
+
+        assert_eq!(delivered_ids(&journal).unwrap(), HashSet::from([2]));
+    }
+}
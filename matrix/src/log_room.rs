@@ -0,0 +1,286 @@
+// Copyright © 2025-26 l5yth & contributors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Mirror the bridge's own WARN/ERROR logs into a Matrix room.
+//!
+//! [`LogRoomLayer`] is installed into the tracing subscriber at startup and
+//! queues rate-limited notices on a bounded channel; [`spawn_log_room_forwarder`]
+//! drains that channel into the configured `log_room` once the Matrix client
+//! exists. Events emitted by this module are never forwarded, so a failing
+//! notice send cannot feed back into itself.
+
+use std::fmt::Write as _;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use tokio::sync::mpsc;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+
+use crate::matrix::{MatrixAppserviceClient, NoticeLevel};
+
+/// Notices allowed per [`LOG_ROOM_WINDOW`] before further events are dropped.
+pub const LOG_ROOM_MAX_NOTICES: u32 = 10;
+/// Length of the rate-limit window.
+pub const LOG_ROOM_WINDOW: Duration = Duration::from_secs(60);
+/// Notices buffered while the forwarder is busy or not yet started.
+const LOG_ROOM_QUEUE: usize = 64;
+/// Target of this module's own events, which are never forwarded.
+const LOG_ROOM_TARGET: &str = module_path!();
+
+/// Fixed-window limiter shared by all events passing through the layer.
+struct RateLimiter {
+    max: u32,
+    window: Duration,
+    window_start: Instant,
+    sent: u32,
+}
+
+impl RateLimiter {
+    fn allow(&mut self, now: Instant) -> bool {
+        if now.duration_since(self.window_start) >= self.window {
+            self.window_start = now;
+            self.sent = 0;
+        }
+        if self.sent >= self.max {
+            return false;
+        }
+        self.sent += 1;
+        true
+    }
+}
+
+/// Tracing layer that queues WARN and ERROR events as Matrix notices.
+pub struct LogRoomLayer {
+    tx: mpsc::Sender<(NoticeLevel, String)>,
+    limiter: Mutex<RateLimiter>,
+}
+
+impl LogRoomLayer {
+    /// Create a layer allowing `max` notices per `window`, plus the receiver
+    /// to hand to [`spawn_log_room_forwarder`].
+    pub fn new(max: u32, window: Duration) -> (Self, mpsc::Receiver<(NoticeLevel, String)>) {
+        let (tx, rx) = mpsc::channel(LOG_ROOM_QUEUE);
+        let layer = Self {
+            tx,
+            limiter: Mutex::new(RateLimiter {
+                max,
+                window,
+                window_start: Instant::now(),
+                sent: 0,
+            }),
+        };
+        (layer, rx)
+    }
+}
+
+impl<S: Subscriber> Layer<S> for LogRoomLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let meta = event.metadata();
+        if *meta.level() > Level::WARN || meta.target() == LOG_ROOM_TARGET {
+            return;
+        }
+        // Nobody is listening when no log room is configured.
+        if self.tx.is_closed() {
+            return;
+        }
+        let allowed = self
+            .limiter
+            .lock()
+            .map(|mut limiter| limiter.allow(Instant::now()))
+            .unwrap_or(false);
+        if !allowed {
+            return;
+        }
+        let mut visitor = NoticeVisitor::default();
+        event.record(&mut visitor);
+        let level = if *meta.level() == Level::ERROR {
+            NoticeLevel::Error
+        } else {
+            NoticeLevel::Warn
+        };
+        let notice = format!("{}{}", visitor.message, visitor.fields);
+        // A full queue means the room is already flooded; drop the notice.
+        let _ = self.tx.try_send((level, notice));
+    }
+}
+
+/// Collects the event message and any structured fields into notice text.
+#[derive(Default)]
+struct NoticeVisitor {
+    message: String,
+    fields: String,
+}
+
+impl Visit for NoticeVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.message, "{:?}", value);
+        } else {
+            let _ = write!(self.fields, " {}={:?}", field.name(), value);
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message.push_str(value);
+        } else {
+            let _ = write!(self.fields, " {}={}", field.name(), value);
+        }
+    }
+}
+
+/// Forward queued notices into `room_id` as the appservice bot user.
+pub fn spawn_log_room_forwarder(
+    matrix: MatrixAppserviceClient,
+    room_id: String,
+    mut rx: mpsc::Receiver<(NoticeLevel, String)>,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        while let Some((level, notice)) = rx.recv().await {
+            if let Err(e) = matrix.send_notice(&room_id, level, &notice).await {
+                // Ignored by `LogRoomLayer` because of this module's target.
+                tracing::warn!(
+                    target: LOG_ROOM_TARGET,
+                    "Failed to mirror log into {}: {:?}",
+                    room_id,
+                    e
+                );
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::MatrixConfig;
+    use tracing_subscriber::prelude::*;
+
+    fn collect_notices(layer: LogRoomLayer, emit: impl FnOnce()) {
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, emit);
+    }
+
+    #[test]
+    fn warn_and_error_events_are_queued_as_notices() {
+        let (layer, mut rx) = LogRoomLayer::new(10, LOG_ROOM_WINDOW);
+        collect_notices(layer, || {
+            tracing::info!("not forwarded");
+            tracing::warn!(node = "!abcd1234", "node lookup failed");
+            tracing::error!("send failed");
+        });
+
+        assert_eq!(
+            rx.try_recv().unwrap(),
+            (
+                NoticeLevel::Warn,
+                "node lookup failed node=!abcd1234".to_string()
+            )
+        );
+        assert_eq!(
+            rx.try_recv().unwrap(),
+            (NoticeLevel::Error, "send failed".to_string())
+        );
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn notices_are_rate_limited() {
+        let (layer, mut rx) = LogRoomLayer::new(2, LOG_ROOM_WINDOW);
+        collect_notices(layer, || {
+            for idx in 0..5 {
+                tracing::warn!("warning {}", idx);
+            }
+        });
+
+        assert_eq!(rx.try_recv().unwrap().1, "warning 0");
+        assert_eq!(rx.try_recv().unwrap().1, "warning 1");
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn rate_limiter_resets_after_window() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter {
+            max: 1,
+            window: Duration::from_secs(60),
+            window_start: start,
+            sent: 0,
+        };
+        assert!(limiter.allow(start));
+        assert!(!limiter.allow(start + Duration::from_secs(59)));
+        assert!(limiter.allow(start + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn own_events_are_not_forwarded() {
+        let (layer, mut rx) = LogRoomLayer::new(10, LOG_ROOM_WINDOW);
+        collect_notices(layer, || {
+            tracing::warn!(target: LOG_ROOM_TARGET, "notice send failed");
+        });
+
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn forwarder_sends_warn_event_as_notice() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock(
+                "PUT",
+                mockito::Matcher::Regex(
+                    r"/_matrix/client/v3/rooms/%21logs%3Aexample.org/send/m.room.message/.+"
+                        .to_string(),
+                ),
+            )
+            .match_body(mockito::Matcher::Json(serde_json::json!({
+                "msgtype": "m.notice",
+                "body": "[WARN] homeserver slow",
+            })))
+            .with_status(200)
+            .create();
+        let matrix = MatrixAppserviceClient::new(
+            reqwest::Client::new(),
+            MatrixConfig {
+                homeserver: server.url(),
+                as_token: "AS_TOKEN".to_string(),
+                hs_token: "HS_TOKEN".to_string(),
+                server_name: "example.org".to_string(),
+                room_id: Some("!roomid:example.org".to_string()),
+                log_room: Some("!logs:example.org".to_string()),
+                max_retry_after_secs: 60,
+                auto_create_room: None,
+                membership_check: Default::default(),
+                channel_rooms: Default::default(),
+                direct_room: None,
+                rich_notices: false,
+                max_rate_limit_retries: 3,
+                sends_per_sec: 0.0,
+                puppet_prefix: "potato_".to_string(),
+            },
+        );
+
+        let (layer, rx) = LogRoomLayer::new(LOG_ROOM_MAX_NOTICES, LOG_ROOM_WINDOW);
+        collect_notices(layer, || tracing::warn!("homeserver slow"));
+        // The layer dropped its sender with the subscriber, so the forwarder
+        // exits once the queued notice is sent.
+        spawn_log_room_forwarder(matrix, "!logs:example.org".to_string(), rx)
+            .await
+            .unwrap();
+
+        mock.assert();
+    }
+}
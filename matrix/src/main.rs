@@ -12,30 +12,69 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+mod check;
 mod cli;
+mod concurrent;
 mod config;
+mod dedup;
+mod identicon;
+mod journal;
+mod log_room;
 mod matrix;
 mod matrix_server;
+mod metrics;
 mod potatomesh;
 mod preset;
+mod recent;
+mod registration;
+mod render;
+mod state;
+mod state_db;
+mod subcommands;
+mod text;
+mod txns;
 
-use std::{fs, net::SocketAddr, path::Path};
+#[cfg(not(test))]
+use std::sync::Arc;
+use std::{borrow::Cow, fs, io::Write, net::SocketAddr, path::Path};
 
 use anyhow::Result;
 #[cfg(not(test))]
 use clap::Parser;
-use tokio::time::Duration;
-use tracing::{error, info, warn};
+use tokio::sync::mpsc;
+use tokio::time::{sleep, Duration};
+use tracing::{debug, error, info, info_span, warn, Instrument, Span};
+#[cfg(not(test))]
+use tracing_subscriber::prelude::*;
 
 #[cfg(not(test))]
-use crate::cli::Cli;
+use crate::cli::{Cli, Command, LogFormat};
+use crate::concurrent::ConcurrentSends;
+use crate::config::{
+    BridgeConfig, CheckpointTimeSource, Config, CooldownAction, HtmlLayout, MessageOrdering,
+    NodeCooldown, NodePresence, ReplyColdStart, SenderMode,
+};
+use crate::matrix::{MatrixAppserviceClient, NoticeLevel};
+use crate::matrix_server::{run_synapse_listener, BridgedRooms, RedactCommand};
+use crate::metrics::Metrics;
+use crate::potatomesh::{PotatoClient, PotatoMessage, PotatoNode};
+use crate::recent::RecentMessage;
+use crate::render::{
+    display_name_for_node, drop_unknown_fields, format_altitude, format_message_bodies,
+    format_signal_html, mesh_topic, protocol_tag, render_message_template, render_position_beacon,
+    reply_fallback, short_name, short_or_long_name, unknown_node_name,
+};
+use crate::state::{
+    build_fetch_params, log_state_update, persist_state, BridgeState, CreatedRoom, LastSent,
+    NodeSeen, PuppetAvatar, RetryMessage,
+};
 #[cfg(not(test))]
-use crate::config::Config;
-use crate::matrix::MatrixAppserviceClient;
-use crate::matrix_server::run_synapse_listener;
-use crate::potatomesh::{FetchParams, PotatoClient, PotatoMessage, PotatoNode};
+use crate::subcommands::{run_audit, run_backfill, run_check, run_gen_registration};
 #[cfg(not(test))]
-use tokio::time::sleep;
+use tokio::time::Instant;
+
+/// Reaction added to a message instead of re-posting an identical one.
+const DUPLICATE_REACTION_KEY: &str = "🔁";
 
 /// Consecutive poll attempts a single message may fail before it is skipped
 /// (advanced past, with a warning) so it cannot block every message queued
@@ -45,112 +84,152 @@ use tokio::time::sleep;
 /// losing everything queued behind it.
 const MAX_FORWARD_ATTEMPTS: u32 = 5;
 
-#[derive(Debug, serde::Serialize, serde::Deserialize, Default)]
-pub struct BridgeState {
-    /// Highest message id processed by the bridge.
-    last_message_id: Option<u64>,
-    /// Highest rx_time observed; used to build incremental fetch queries.
-    #[serde(default)]
-    last_rx_time: Option<u64>,
-    /// Message ids seen at the current last_rx_time for de-duplication.
-    #[serde(default)]
-    last_rx_time_ids: Vec<u64>,
-    /// Legacy checkpoint timestamp used before last_rx_time was added.
-    #[serde(default, skip_serializing)]
-    last_checked_at: Option<u64>,
-    /// Id of the message currently blocking the batch, and how many consecutive
-    /// polls it has failed to forward. In-memory only (never persisted — a
-    /// restart is itself a fresh attempt); used to skip a poison message after
-    /// [`MAX_FORWARD_ATTEMPTS`] instead of retrying it forever.
-    #[serde(skip)]
-    failing_msg_id: Option<u64>,
-    #[serde(skip)]
-    failing_msg_attempts: u32,
-}
-
-impl BridgeState {
-    fn load(path: &str) -> Result<Self> {
-        if !Path::new(path).exists() {
-            return Ok(Self::default());
-        }
-        let data = fs::read_to_string(path)?;
-        // Treat empty/whitespace-only files as a fresh state.
-        if data.trim().is_empty() {
-            return Ok(Self::default());
-        }
-        let mut s: Self = serde_json::from_str(&data)?;
-        if s.last_rx_time.is_none() {
-            s.last_rx_time = s.last_checked_at;
-        }
-        s.last_checked_at = None;
-        Ok(s)
-    }
-
-    fn save(&self, path: &str) -> Result<()> {
-        let data = serde_json::to_string_pretty(self)?;
-        fs::write(path, data)?;
-        Ok(())
-    }
-
-    fn should_forward(&self, msg: &PotatoMessage) -> bool {
-        match self.last_rx_time {
-            None => match self.last_message_id {
-                None => true,
-                Some(last_id) => msg.id > last_id,
-            },
-            Some(last_ts) => {
-                if msg.rx_time > last_ts {
-                    true
-                } else if msg.rx_time < last_ts {
-                    false
-                } else {
-                    !self.last_rx_time_ids.contains(&msg.id)
-                }
-            }
-        }
-    }
+/// `!redact` commands that may wait for the next poll; more are dropped
+/// with a warning.
+#[cfg(not(test))]
+const REDACT_QUEUE: usize = 64;
 
-    fn update_with(&mut self, msg: &PotatoMessage) {
-        self.last_message_id = Some(msg.id);
-        if self.last_rx_time.is_none() || Some(msg.rx_time) > self.last_rx_time {
-            self.last_rx_time = Some(msg.rx_time);
-            self.last_rx_time_ids = vec![msg.id];
-        } else if Some(msg.rx_time) == self.last_rx_time && !self.last_rx_time_ids.contains(&msg.id)
-        {
-            self.last_rx_time_ids.push(msg.id);
-        }
+/// Longest wait between polls during `startup_grace_secs`, so messages that
+/// failed while the homeserver settles are retried soon.
+#[cfg(not(test))]
+const STARTUP_GRACE_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Persist the node cache and log any write errors.
+async fn flush_nodes_cache(potato: &PotatoClient, cache_path: &str) {
+    match potato.save_nodes_cache(cache_path).await {
+        Ok(count) => debug!("Flushed {} cached nodes to {}", count, cache_path),
+        Err(e) => error!("Error saving node cache: {:?}", e),
     }
 }
 
-fn build_fetch_params(state: &BridgeState) -> FetchParams {
-    if state.last_message_id.is_none() {
-        FetchParams {
-            limit: None,
-            since: None,
+/// Poll the node list and post a notice for every node that came online or
+/// went offline, into the room of mesh channel 0.
+async fn check_node_presence(
+    potato: &PotatoClient,
+    matrix: &MatrixAppserviceClient,
+    presence: &NodePresence,
+    state: &mut BridgeState,
+    state_path: &str,
+) {
+    let nodes = match potato.list_nodes().await {
+        Ok(nodes) => nodes,
+        Err(e) => {
+            warn!("Failed to list nodes for presence: {:?}", e);
+            return;
         }
-    } else if let Some(ts) = state.last_rx_time {
-        FetchParams {
-            limit: None,
-            since: Some(ts),
+    };
+    let changes = presence_changes(state, presence, &nodes, potatomesh::now_secs());
+    if changes.is_empty() {
+        return;
+    }
+    persist_state(state, state_path);
+    let room_id = match matrix.room_for_channel(0) {
+        Ok(room_id) => room_id,
+        Err(e) => {
+            warn!("Cannot post presence notices: {:?}", e);
+            return;
         }
-    } else {
-        FetchParams {
-            limit: Some(10),
-            since: None,
+    };
+    for (node, online) in changes {
+        let body = if online {
+            format!("🟢 {} is back online", node.long_name)
+        } else {
+            format!("🔴 {} went offline", node.long_name)
+        };
+        if let Err(e) = matrix.send_notice(&room_id, NoticeLevel::Info, &body).await {
+            warn!("Failed to post presence of {}: {:?}", node.node_id, e);
         }
     }
 }
 
-/// Persist the bridge state and log any write errors.
-fn persist_state(state: &BridgeState, state_path: &str) {
-    if let Err(e) = state.save(state_path) {
-        error!("Error saving state: {:?}", e);
+/// Set the topic of the room of mesh channel 0 to the current
+/// [`mesh_topic`] of the nodes of every source in `potatoes`, unless that is
+/// `room_topic`, the topic last set.
+async fn update_room_topic(
+    potatoes: &[&PotatoClient],
+    matrix: &MatrixAppserviceClient,
+    last_rx_time: Option<u64>,
+    room_topic: &mut Option<String>,
+) {
+    let mut nodes = Vec::new();
+    for potato in potatoes {
+        match potato.list_nodes().await {
+            Ok(source_nodes) => nodes.extend(source_nodes),
+            Err(e) => {
+                warn!("Failed to list nodes for the room topic: {:?}", e);
+                return;
+            }
+        }
+    }
+    let topic = mesh_topic(&nodes, last_rx_time, potatomesh::now_secs());
+    if room_topic.as_deref() == Some(topic.as_str()) {
+        debug!("Room topic unchanged");
+        return;
+    }
+    let result = match matrix.room_for_channel(0) {
+        Ok(room_id) => matrix.set_room_topic(&room_id, &topic).await,
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(()) => *room_topic = Some(topic),
+        Err(e) => warn!("Failed to update the room topic: {:?}", e),
     }
 }
 
-/// Emit an info log for the latest bridge state snapshot.
-fn log_state_update(state: &BridgeState) {
-    info!("Updated state: {:?}", state);
+/// Update `state.last_seen` from the node list and return the nodes whose
+/// presence changed, with whether each is now online.
+///
+/// Nodes between the online and offline thresholds keep their last presence,
+/// and nodes seen for the first time are only recorded. A change within
+/// `min_notice_interval_secs` of the node's last notice is left for a later
+/// poll, so a flapping node that settles back is never announced.
+fn presence_changes<'a>(
+    state: &mut BridgeState,
+    presence: &NodePresence,
+    nodes: &'a [PotatoNode],
+    now: u64,
+) -> Vec<(&'a PotatoNode, bool)> {
+    let mut changes = Vec::new();
+    for node in nodes {
+        let Some(last_heard) = node.last_heard else {
+            continue;
+        };
+        let silent_for = now.saturating_sub(last_heard);
+        let online = if silent_for <= presence.online_within_secs {
+            true
+        } else if silent_for > presence.offline_after_secs {
+            false
+        } else {
+            continue;
+        };
+        let key = potatomesh::normalize_node_hex(&node.node_id);
+        let Some(seen) = state.last_seen.get_mut(&key) else {
+            state.last_seen.insert(
+                key,
+                NodeSeen {
+                    online,
+                    notified_at: None,
+                },
+            );
+            continue;
+        };
+        if seen.online == online {
+            continue;
+        }
+        if seen
+            .notified_at
+            .is_some_and(|at| now.saturating_sub(at) < presence.min_notice_interval_secs)
+        {
+            debug!("Holding back presence notice for {}", node.node_id);
+            continue;
+        }
+        *seen = NodeSeen {
+            online,
+            notified_at: Some(now),
+        };
+        changes.push((node, online));
+    }
+    changes
 }
 
 /// Emit a sanitized config log without sensitive tokens.
@@ -160,957 +239,6589 @@ fn log_config(cfg: &Config) {
         potatomesh_base_url = cfg.potatomesh.base_url.as_str(),
         matrix_homeserver = cfg.matrix.homeserver.as_str(),
         matrix_server_name = cfg.matrix.server_name.as_str(),
-        matrix_room_id = cfg.matrix.room_id.as_str(),
+        matrix_room_id = cfg.matrix.room_id.as_deref().unwrap_or_default(),
         state_file = cfg.state.state_file.as_str(),
+        txn_file = cfg.state.txn_file.as_str(),
+        http_timeout_secs = cfg.http.timeout_secs,
+        http_connect_timeout_secs = cfg.http.connect_timeout_secs,
         "Loaded config"
     );
+    // Tokens are redacted by the config types' `Debug` impls.
+    debug!("Full config: {:?}", cfg);
 }
 
 async fn poll_once(
     potato: &PotatoClient,
     matrix: &MatrixAppserviceClient,
+    bridge_cfg: &BridgeConfig,
     state: &mut BridgeState,
     state_path: &str,
 ) {
-    let params = build_fetch_params(state);
+    let now = potatomesh::now_secs();
+    poll_once_at(potato, matrix, bridge_cfg, state, state_path, now).await;
+}
 
-    match potato.fetch_messages(params).await {
-        Ok(mut msgs) => {
-            // sort by rx_time so we process by actual receipt time
-            msgs.sort_by_key(|m| m.rx_time);
+/// [`poll_once`] at an explicit Unix time `now`, which decides whether a
+/// maintenance window is active.
+async fn poll_once_at(
+    potato: &PotatoClient,
+    matrix: &MatrixAppserviceClient,
+    bridge_cfg: &BridgeConfig,
+    state: &mut BridgeState,
+    state_path: &str,
+    now: u64,
+) {
+    let in_maintenance = bridge_cfg
+        .maintenance_window
+        .as_ref()
+        .is_some_and(|window| window.contains(now));
+    let mut run = PollRun {
+        now,
+        registrations: 0,
+    };
+    state.metrics.record_poll(now);
 
-            for msg in &msgs {
-                if !state.should_forward(msg) {
-                    continue;
-                }
+    if !in_maintenance
+        && !state.held_messages.is_empty()
+        && !flush_held_messages(potato, matrix, bridge_cfg, state, state_path, &mut run).await
+    {
+        // Held messages go first; newer ones wait until they are through.
+        return;
+    }
 
-                // Filter to the ports you care about
-                if let Some(port) = &msg.portnum {
-                    if port != "TEXT_MESSAGE_APP" {
-                        state.update_with(msg);
-                        log_state_update(state);
-                        persist_state(state, state_path);
-                        continue;
-                    }
-                }
+    if !in_maintenance && !state.cooldown_messages.is_empty() {
+        retry_cooldown_messages(potato, matrix, bridge_cfg, state, state_path, &mut run).await;
+    }
 
-                if let Err(e) = handle_message(potato, matrix, state, msg).await {
-                    error!("Error handling message {}: {:?}", msg.id, e);
-                    // Track consecutive failures of THIS specific message across
-                    // polls (the batch is refetched each poll while the
-                    // watermark is stuck, so the same id reappears at the head).
-                    if state.failing_msg_id == Some(msg.id) {
-                        state.failing_msg_attempts += 1;
-                    } else {
-                        state.failing_msg_id = Some(msg.id);
-                        state.failing_msg_attempts = 1;
-                    }
+    if !in_maintenance && !state.retry_messages.is_empty() {
+        retry_out_of_order(potato, matrix, bridge_cfg, state, state_path, now).await;
+    }
+
+    let time_source = potato.checkpoint_time_source();
+    let params = build_fetch_params(state, potato.since_unit(), time_source);
+    let fetched = match (params.after_id, params.since) {
+        (Some(after_id), _) => potato.fetch_all_after(after_id).await,
+        (None, Some(since)) => potato.fetch_all_since(since).await,
+        (None, None) => {
+            potato
+                .fetch_messages_with_retry(params, potato.max_fetch_attempts())
+                .await
+        }
+    };
+    match fetched {
+        Ok(mut msgs) => {
+            if state.id_cursor {
+                // Id order keeps the cursor from passing a message that is
+                // still to be forwarded.
+                msgs.sort_by_key(|m| m.id);
+            } else {
+                // sort by rx_time so we process by actual receipt time
+                msgs.sort_by_key(|m| m.rx_time);
+            }
 
-                    if state.failing_msg_attempts >= MAX_FORWARD_ATTEMPTS {
-                        // Poison message: it has failed too many polls in a row,
-                        // so skip it rather than block the whole batch behind it
-                        // indefinitely. Advance the watermark past it (as if
-                        // processed) and continue with the rest. Dropping this
-                        // one message is the lesser evil versus stalling forever.
-                        warn!(
-                            "Skipping message {} after {} failed forward attempts; advancing past it",
-                            msg.id, state.failing_msg_attempts
-                        );
-                        state.failing_msg_id = None;
-                        state.failing_msg_attempts = 0;
-                        state.update_with(msg);
-                        persist_state(state, state_path);
+            let mut completed = true;
+            if in_maintenance {
+                for msg in &msgs {
+                    if !state.should_forward(msg) {
                         continue;
                     }
-
-                    // Below the skip threshold: stop the batch here. The failed
-                    // message and everything after it stay uncommitted and are
-                    // retried, in order, on the next poll — so a *transient*
-                    // failure never loses, reorders, or duplicates anything. (The
-                    // watermark advances only on success, so a later success can
-                    // never jump past this failure — the silent-loss bug.)
-                    break;
+                    // Hold the message for after the window, but checkpoint
+                    // it so it is not fetched again in the meantime.
+                    debug!(
+                        message_id = msg.id,
+                        "Holding message during maintenance window"
+                    );
+                    state.held_messages.push(msg.clone());
+                    state.update_with(msg);
+                    persist_state(state, state_path);
                 }
-
-                // Success clears any failure tracking for this message.
-                if state.failing_msg_id == Some(msg.id) {
-                    state.failing_msg_id = None;
-                    state.failing_msg_attempts = 0;
-                }
-
-                // persist after each processed message
+            } else {
+                completed = forward_batch(
+                    potato, matrix, bridge_cfg, state, state_path, &msgs, true, &mut run,
+                )
+                .await;
+            }
+            // Only a batch handled to the end may move the local-clock
+            // checkpoint; otherwise the stopped message would not be refetched.
+            if completed && time_source == CheckpointTimeSource::Local {
+                state.last_polled_at = Some(now);
+                persist_state(state, state_path);
+            }
+            // Everything past the cursor was fetched and handled.
+            if let Some(last) = msgs.last().filter(|_| completed && state.id_cursor) {
+                state.catch_up_channels(last.id);
                 persist_state(state, state_path);
             }
         }
         Err(e) => {
-            error!("Error fetching PotatoMesh messages: {:?}", e);
+            state.metrics.record_fetch_error();
+            error!(error = ?e, "Error fetching PotatoMesh messages");
         }
     }
 }
 
-fn spawn_synapse_listener(addr: SocketAddr, token: String) -> tokio::task::JoinHandle<()> {
-    tokio::spawn(async move {
-        if let Err(e) = run_synapse_listener(addr, token).await {
-            error!("Synapse listener failed: {:?}", e);
+/// Forward `msgs` in order, stopping at the first message that has to wait
+/// for a later poll. With `only_new`, messages the checkpoint already
+/// covers are skipped. Returns whether the whole batch was handled.
+///
+/// With `concurrency` above 1 the sends overlap; see [`ConcurrentSends`].
+#[allow(clippy::too_many_arguments)]
+async fn forward_batch(
+    potato: &PotatoClient,
+    matrix: &MatrixAppserviceClient,
+    bridge_cfg: &BridgeConfig,
+    state: &mut BridgeState,
+    state_path: &str,
+    msgs: &[PotatoMessage],
+    only_new: bool,
+    run: &mut PollRun,
+) -> bool {
+    // One concurrent lookup per distinct sender instead of one round trip
+    // per message.
+    let node_ids: Vec<String> = msgs
+        .iter()
+        .filter(|msg| (!only_new || state.should_forward(msg)) && !is_position_beacon(msg))
+        .map(|msg| msg.node_id.clone())
+        .collect();
+    potato.get_nodes(&node_ids).await;
+
+    let groups = match bridge_cfg.coalesce_secs {
+        Some(window_secs) => coalesce_messages(msgs, window_secs, |msg| {
+            filtered_by_config(potato, bridge_cfg, msg, run.now).is_none()
+        }),
+        None => msgs.iter().map(|msg| vec![msg]).collect(),
+    };
+    let mut sends = (bridge_cfg.concurrency > 1).then(|| ConcurrentSends::new(bridge_cfg));
+    let mut completed = true;
+    for mut group in groups {
+        if only_new {
+            group.retain(|msg| state.should_forward(msg));
         }
-    })
+        let merged;
+        let msg = match group.as_slice() {
+            [] => continue,
+            [msg] => *msg,
+            parts => {
+                // Recording the parts' content lets the checkpoint pass them
+                // exactly when it passes the merged message.
+                for part in parts {
+                    state.seen_content.record(part);
+                }
+                merged = merge_messages(parts, bridge_cfg.trim_text);
+                &merged
+            }
+        };
+        let flow = match &mut sends {
+            Some(sends) => {
+                sends
+                    .forward(potato, matrix, bridge_cfg, state, state_path, msg, run)
+                    .await
+            }
+            None => process_message(potato, matrix, bridge_cfg, state, state_path, msg, run).await,
+        };
+        if let Flow::Stop = flow {
+            completed = false;
+            break;
+        }
+    }
+    if let Some(sends) = sends {
+        completed &= sends
+            .finish(matrix, bridge_cfg, state, state_path, run)
+            .await;
+        if completed && only_new {
+            // Every message past the checkpoint came by, so any id sent
+            // ahead has been skipped by now.
+            state.delivered_ahead.clear();
+        }
+    }
+    completed
 }
 
-#[cfg(not(test))]
-#[tokio::main]
-async fn main() -> Result<()> {
-    // Logging: RUST_LOG=info,bridge=debug,reqwest=warn ...
-    tracing_subscriber::fmt()
-        .with_env_filter(
-            tracing_subscriber::EnvFilter::from_default_env()
-                .add_directive("potatomesh_matrix_bridge=info".parse().unwrap_or_default())
-                .add_directive("reqwest=warn".parse().unwrap_or_default()),
-        )
-        .init();
-
-    let cli = Cli::parse();
-    let cfg = config::load(cli.to_inputs())?;
-    log_config(&cfg);
-
-    // Bound every HTTP request so a hung homeserver or PotatoMesh API cannot
-    // stall the single-threaded poll loop indefinitely. `timeout` caps the
-    // whole request/response; `connect_timeout` caps TCP/TLS establishment.
-    let http = reqwest::Client::builder()
-        .timeout(Duration::from_secs(30))
-        .connect_timeout(Duration::from_secs(10))
-        .build()?;
-    let potato = PotatoClient::new(http.clone(), cfg.potatomesh.clone());
-    potato.health_check().await?;
-    let matrix = MatrixAppserviceClient::new(http.clone(), cfg.matrix.clone());
-    matrix.health_check().await?;
-
-    let synapse_addr = SocketAddr::from(([0, 0, 0, 0], 41448));
-    let synapse_token = cfg.matrix.hs_token.clone();
-    let _synapse_handle = spawn_synapse_listener(synapse_addr, synapse_token);
-
-    let state_path = &cfg.state.state_file;
-    let mut state = BridgeState::load(state_path)?;
-    info!("Loaded state: {:?}", state);
-
-    let poll_interval = Duration::from_secs(cfg.potatomesh.poll_interval_secs);
-
-    loop {
-        poll_once(&potato, &matrix, &mut state, state_path).await;
+/// Split `msgs` into runs to send as one Matrix message each: consecutive
+/// `joinable` messages from the same node to the same destination on the
+/// same channel, each within `window_secs` of the one before. A reply always
+/// starts a new run.
+fn coalesce_messages(
+    msgs: &[PotatoMessage],
+    window_secs: u64,
+    joinable: impl Fn(&PotatoMessage) -> bool,
+) -> Vec<Vec<&PotatoMessage>> {
+    let mut groups: Vec<Vec<&PotatoMessage>> = Vec::new();
+    for msg in msgs {
+        if let Some(group) = groups.last_mut() {
+            let prev = group[group.len() - 1];
+            if msg.reply_id.is_none()
+                && msg.node_id == prev.node_id
+                && msg.channel == prev.channel
+                && msg.to_id == prev.to_id
+                && msg.rx_time.saturating_sub(prev.rx_time) <= window_secs
+                && joinable(prev)
+                && joinable(msg)
+            {
+                group.push(msg);
+                continue;
+            }
+        }
+        groups.push(vec![msg]);
+    }
+    groups
+}
 
-        sleep(poll_interval).await;
+/// One message standing for `parts`: their texts one per line, the last
+/// part's id, receive time and signal stats, and the first part's reply
+/// target. The other parts' ids are kept in `merged_ids`.
+fn merge_messages(parts: &[&PotatoMessage], trim: bool) -> PotatoMessage {
+    let lines: Vec<Cow<'_, str>> = parts
+        .iter()
+        .map(|part| {
+            let line = Cow::Borrowed(part.text.as_str());
+            if trim {
+                text::trim_padding(line)
+            } else {
+                line
+            }
+        })
+        .collect();
+    let (last, earlier) = parts.split_last().expect("a merge has parts");
+    PotatoMessage {
+        text: lines.join("\n"),
+        reply_id: parts[0].reply_id,
+        merged_ids: earlier.iter().map(|part| part.id).collect(),
+        ..(*last).clone()
     }
 }
 
-async fn handle_message(
+/// Forward the `count` most recent messages once, in id order, whether or
+/// not the checkpoint already covers them. Returns how many were fetched.
+async fn backfill(
     potato: &PotatoClient,
     matrix: &MatrixAppserviceClient,
+    bridge_cfg: &BridgeConfig,
     state: &mut BridgeState,
-    msg: &PotatoMessage,
-) -> Result<()> {
-    let node = potato.get_node(&msg.node_id).await?;
-    let localpart = MatrixAppserviceClient::localpart_from_node_id(&msg.node_id);
-    let user_id = matrix.user_id(&localpart);
-
-    // Ensure puppet exists & has display name
-    matrix.ensure_user_registered(&localpart).await?;
-    matrix.ensure_user_joined_room(&user_id).await?;
-    let display_name = display_name_for_node(&node);
-    matrix.set_display_name(&user_id, &display_name).await?;
-
-    // Format the bridged message. `lora_freq` is `u32`, so 0 stands in for
-    // "unknown" — collapse that to `None` to match the JS pipeline (which
-    // runs `normalizeFrequency` and discards 0/non-finite before reaching
-    // the preset lookup).
-    let freq_mhz = if msg.lora_freq > 0 {
-        Some(msg.lora_freq as f64)
-    } else {
-        None
+    state_path: &str,
+    count: usize,
+) -> Result<usize> {
+    let msgs = potato.fetch_recent(count).await?;
+    let mut run = PollRun {
+        now: potatomesh::now_secs(),
+        registrations: 0,
     };
-    let abbr = preset::abbreviate_preset(&msg.modem_preset, freq_mhz);
-    let preset_short = preset::normalize_preset_slot(abbr.as_deref());
-    let tag = protocol_tag(msg.protocol.as_deref());
-    let prefix = format!(
-        "{tag}[{freq}][{preset_short}][{channel}]",
-        freq = msg.lora_freq,
-        preset_short = preset_short,
-        channel = msg.channel_name,
-    );
-    let (body, formatted_body) = format_message_bodies(&prefix, &msg.text);
+    if !forward_batch(
+        potato, matrix, bridge_cfg, state, state_path, &msgs, false, &mut run,
+    )
+    .await
+    {
+        warn!("Backfill stopped early; a message could not be forwarded");
+    }
+    Ok(msgs.len())
+}
 
-    matrix
-        .send_formatted_message_as(&user_id, &body, &formatted_body)
-        .await?;
+/// For `--since-now`: give a state without a checkpoint one at the newest
+/// message PotatoMesh holds (or at `now` when it holds none), forwarding
+/// nothing, and save it right away so a crash cannot replay history.
+/// Returns whether a baseline was set.
+async fn start_from_now(
+    potato: &PotatoClient,
+    state: &mut BridgeState,
+    state_path: &str,
+    now: u64,
+) -> Result<bool> {
+    if state.has_message_checkpoint() || state.last_rx_time.is_some() {
+        return Ok(false);
+    }
+    match potato.fetch_recent(1).await?.last() {
+        Some(newest) => state.update_with(newest),
+        None => state.last_rx_time = Some(now),
+    }
+    state.save(state_path)?;
+    Ok(true)
+}
 
-    info!("Bridged message: {:?}", msg);
-    state.update_with(msg);
-    log_state_update(state);
-    Ok(())
+/// Whether `msg` is a position packet without text, announced (with a
+/// freshly fetched position) rather than forwarded.
+fn is_position_beacon(msg: &PotatoMessage) -> bool {
+    msg.portnum.as_deref() == Some("POSITION_APP") && msg.text.trim().is_empty()
 }
 
-/// Short tag prepended to the message prefix so readers can tell the source
-/// mesh protocol apart at a glance. `"[MT]"` identifies Meshtastic (also the
-/// default when the protocol field is missing, since the full stack treats a
-/// missing protocol as Meshtastic) and `"[MC]"` identifies MeshCore. Any other
-/// value renders as `"[??]"` so unknown protocols surface visibly instead of
-/// being silently relabeled as Meshtastic.
-fn protocol_tag(protocol: Option<&str>) -> &'static str {
-    match protocol {
-        Some("meshcore") => "[MC]",
-        Some("meshtastic") | None => "[MT]",
-        Some(_) => "[??]",
-    }
+/// Bookkeeping shared by the messages of one poll.
+struct PollRun {
+    /// Unix time the poll started at.
+    now: u64,
+    /// Puppets registered so far, for `max_registrations_per_poll`.
+    registrations: u32,
 }
 
-/// Build plain text + HTML message bodies with inline-code metadata.
-fn format_message_bodies(prefix: &str, text: &str) -> (String, String) {
-    let body = format!("`{}` {}", prefix, text);
-    let formatted_body = format!("<code>{}</code> {}", escape_html(prefix), escape_html(text));
-    (body, formatted_body)
+/// Outcome of [`process_message`] for the rest of the batch.
+enum Flow {
+    /// Carry on with the next message.
+    Next,
+    /// Stop the batch; this message and everything after it are retried.
+    Stop,
 }
 
-/// Build the Matrix display name from a node's long/short names.
-fn display_name_for_node(node: &PotatoNode) -> String {
-    match node
-        .short_name
-        .as_deref()
-        .map(str::trim)
-        .filter(|s| !s.is_empty())
-    {
-        Some(short) if short != node.long_name => format!("{} ({})", node.long_name, short),
-        _ => node.long_name.clone(),
+/// Forward messages held during a maintenance window, oldest first.
+///
+/// Returns `false` when a message could not be delivered yet; it and the
+/// rest stay held for the next poll.
+async fn flush_held_messages(
+    potato: &PotatoClient,
+    matrix: &MatrixAppserviceClient,
+    bridge_cfg: &BridgeConfig,
+    state: &mut BridgeState,
+    state_path: &str,
+    run: &mut PollRun,
+) -> bool {
+    info!(
+        "Flushing {} messages held during maintenance",
+        state.held_messages.len()
+    );
+    while let Some(msg) = state.held_messages.first().cloned() {
+        if let Flow::Stop =
+            process_message(potato, matrix, bridge_cfg, state, state_path, &msg, run).await
+        {
+            return false;
+        }
+        state.held_messages.remove(0);
+        persist_state(state, state_path);
     }
+    true
 }
 
-/// Minimal HTML escaping for Matrix formatted_body payloads.
-fn escape_html(input: &str) -> String {
-    let mut escaped = String::with_capacity(input.len());
-    for ch in input.chars() {
-        match ch {
-            '&' => escaped.push_str("&amp;"),
-            '<' => escaped.push_str("&lt;"),
-            '>' => escaped.push_str("&gt;"),
-            '"' => escaped.push_str("&quot;"),
-            '\'' => escaped.push_str("&#39;"),
-            _ => escaped.push(ch),
+/// Take a message from a node in cooldown off the batch: park it for a
+/// later retry or drop it, per `node_cooldown.action`. Either way the
+/// checkpoint moves past it so the node's trouble does not block others.
+fn set_aside_during_cooldown(
+    cooldown: &NodeCooldown,
+    state: &mut BridgeState,
+    state_path: &str,
+    msg: &PotatoMessage,
+) {
+    match cooldown.action {
+        CooldownAction::Defer => {
+            debug!(
+                "Parking message {} until {} cools down",
+                msg.id, msg.node_id
+            );
+            state.cooldown_messages.push(msg.clone());
+        }
+        CooldownAction::Drop => {
+            warn!(
+                "Dropping message {} from {} during its cooldown",
+                msg.id, msg.node_id
+            );
         }
     }
-    escaped
+    state.update_with(msg);
+    log_state_update(state);
+    persist_state(state, state_path);
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::config::{MatrixConfig, PotatomeshConfig};
-    use crate::matrix::MatrixAppserviceClient;
-    use crate::potatomesh::PotatoClient;
-
-    fn sample_msg(id: u64) -> PotatoMessage {
-        PotatoMessage {
-            id,
-            rx_time: 0,
-            rx_iso: "2025-11-27T00:00:00Z".to_string(),
-            from_id: "!abcd1234".to_string(),
-            to_id: "^all".to_string(),
-            channel: 1,
-            portnum: Some("TEXT_MESSAGE_APP".to_string()),
-            text: "Ping".to_string(),
-            rssi: Some(-100),
-            hop_limit: Some(1),
-            lora_freq: 868,
-            modem_preset: "MediumFast".to_string(),
-            channel_name: "TEST".to_string(),
-            snr: Some(0.0),
-            reply_id: None,
-            node_id: "!abcd1234".to_string(),
-            protocol: Some("meshtastic".to_string()),
+/// Retry messages parked by `node_cooldown` whose node has cooled down.
+///
+/// These are already behind the checkpoint, so a message that fails again
+/// simply stays parked instead of holding up the poll.
+async fn retry_cooldown_messages(
+    potato: &PotatoClient,
+    matrix: &MatrixAppserviceClient,
+    bridge_cfg: &BridgeConfig,
+    state: &mut BridgeState,
+    state_path: &str,
+    run: &mut PollRun,
+) {
+    for msg in state.cooldown_messages.clone() {
+        if retry_expired(bridge_cfg, &msg, run.now) {
+            state.cooldown_messages.retain(|parked| parked.id != msg.id);
+            persist_state(state, state_path);
+            continue;
+        }
+        if state.node_cooling_down(&msg.node_id, run.now) {
+            continue;
+        }
+        // Unpark first: a renewed cooldown parks the message again.
+        state.cooldown_messages.retain(|parked| parked.id != msg.id);
+        if let Flow::Stop =
+            process_message(potato, matrix, bridge_cfg, state, state_path, &msg, run).await
+        {
+            state.cooldown_messages.push(msg);
         }
+        persist_state(state, state_path);
     }
+}
 
-    fn sample_node(short_name: Option<&str>, long_name: &str) -> PotatoNode {
-        PotatoNode {
-            node_id: "!abcd1234".to_string(),
-            short_name: short_name.map(str::to_string),
-            long_name: long_name.to_string(),
-            role: None,
-            hw_model: None,
-            last_heard: None,
-            first_heard: None,
-            latitude: None,
-            longitude: None,
-            altitude: None,
+/// Retry messages left behind under `ordering = "relaxed"`, dropping one
+/// after [`MAX_FORWARD_ATTEMPTS`] failed tries or once past
+/// `retry_queue_ttl_secs`.
+async fn retry_out_of_order(
+    potato: &PotatoClient,
+    matrix: &MatrixAppserviceClient,
+    bridge_cfg: &BridgeConfig,
+    state: &mut BridgeState,
+    state_path: &str,
+    now: u64,
+) {
+    // Each entry leaves the queue only once it is settled, so a state saved
+    // mid-pass still holds the retries not tried yet.
+    for retry in state.retry_messages.clone() {
+        let id = retry.msg.id;
+        if retry_expired(bridge_cfg, &retry.msg, now) {
+            state.retry_messages.retain(|queued| queued.msg.id != id);
+            persist_state(state, state_path);
+            continue;
+        }
+        match handle_message(potato, matrix, bridge_cfg, state, &retry.msg).await {
+            Ok(()) => {
+                info!("Forwarded message {} out of order", id);
+                state.retry_messages.retain(|queued| queued.msg.id != id);
+            }
+            Err(e) => {
+                let attempts = retry.attempts + 1;
+                if attempts >= MAX_FORWARD_ATTEMPTS {
+                    warn!(
+                        "Dropping message {} after {} failed forward attempts: {:?}",
+                        id, attempts, e
+                    );
+                    dead_letter(bridge_cfg, &retry.msg, "max_attempts", now);
+                    state.retry_messages.retain(|queued| queued.msg.id != id);
+                } else {
+                    error!("Error retrying message {}: {:?}", id, e);
+                    if let Some(queued) = state
+                        .retry_messages
+                        .iter_mut()
+                        .find(|queued| queued.msg.id == id)
+                    {
+                        queued.attempts = attempts;
+                    }
+                }
+            }
         }
+        persist_state(state, state_path);
     }
+}
 
-    #[test]
-    fn format_message_bodies_escape_html() {
-        let (body, formatted) = format_message_bodies("[868][LF]", "Hello <&>");
-        assert_eq!(body, "`[868][LF]` Hello <&>");
-        assert_eq!(formatted, "<code>[868][LF]</code> Hello &lt;&amp;&gt;");
+/// Whether `msg` is older than `retry_queue_ttl_secs`. An expired message is
+/// logged and dead-lettered, so the caller only has to unqueue it.
+fn retry_expired(bridge_cfg: &BridgeConfig, msg: &PotatoMessage, now: u64) -> bool {
+    let Some(ttl) = bridge_cfg.retry_queue_ttl_secs else {
+        return false;
+    };
+    let age = now.saturating_sub(effective_rx_time(msg, bridge_cfg.max_future_skew_secs, now));
+    if age <= ttl {
+        return false;
     }
+    warn!(
+        "Dropping message {} from the retry queue: {}s old, past retry_queue_ttl_secs",
+        msg.id, age
+    );
+    dead_letter(bridge_cfg, msg, "expired", now);
+    true
+}
 
-    #[test]
-    fn protocol_tag_returns_expected_label() {
-        assert_eq!(protocol_tag(Some("meshcore")), "[MC]");
-        assert_eq!(protocol_tag(Some("meshtastic")), "[MT]");
-        // Missing protocol keeps the Meshtastic default for legacy payloads.
-        assert_eq!(protocol_tag(None), "[MT]");
-        // Unknown protocols surface as "[??]" rather than silently claiming Meshtastic.
-        assert_eq!(protocol_tag(Some("reticulum")), "[??]");
-        assert_eq!(protocol_tag(Some("")), "[??]");
+/// Append the delivery of `msg` as `event_id` to the `delivery_journal`,
+/// when one is configured, once for each message merged into it. Dry runs
+/// deliver nothing and are not journaled.
+fn journal_delivery(bridge_cfg: &BridgeConfig, msg: &PotatoMessage, room_id: &str, event_id: &str) {
+    let Some(journal) = &bridge_cfg.delivery_journal else {
+        return;
+    };
+    if bridge_cfg.dry_run {
+        return;
     }
-
-    #[test]
-    fn escape_html_escapes_quotes() {
-        assert_eq!(escape_html("a\"b'c"), "a&quot;b&#39;c");
+    let delivered_at = potatomesh::now_secs();
+    for &mesh_id in msg.merged_ids.iter().chain([&msg.id]) {
+        let delivery = journal::Delivery {
+            mesh_id,
+            node_id: msg.node_id.clone(),
+            room_id: room_id.to_string(),
+            event_id: event_id.to_string(),
+            delivered_at,
+        };
+        if let Err(e) = journal::append(journal, &delivery) {
+            error!("Error writing delivery to {}: {:?}", journal.path, e);
+        }
     }
+}
 
-    #[test]
-    fn display_name_for_node_includes_short_when_present() {
-        let node = sample_node(Some("TN"), "Test Node");
-        assert_eq!(display_name_for_node(&node), "Test Node (TN)");
+/// Record a message dropped from a retry queue for `reason`, appending it to
+/// `dead_letter_file` when one is configured.
+fn dead_letter(bridge_cfg: &BridgeConfig, msg: &PotatoMessage, reason: &str, now: u64) {
+    let Some(path) = &bridge_cfg.dead_letter_file else {
+        return;
+    };
+    let entry = serde_json::json!({
+        "reason": reason,
+        "dropped_at": now,
+        "message": msg,
+    });
+    let written = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| writeln!(file, "{}", entry));
+    if let Err(e) = written {
+        error!("Error writing dead letter to {}: {:?}", path, e);
     }
+}
 
-    #[test]
-    fn display_name_for_node_ignores_empty_or_duplicate_short() {
-        let empty_short = sample_node(Some(""), "Test Node");
-        assert_eq!(display_name_for_node(&empty_short), "Test Node");
+/// Run one fetched message through filtering and forwarding.
+async fn process_message(
+    potato: &PotatoClient,
+    matrix: &MatrixAppserviceClient,
+    bridge_cfg: &BridgeConfig,
+    state: &mut BridgeState,
+    state_path: &str,
+    msg: &PotatoMessage,
+    run: &mut PollRun,
+) -> Flow {
+    let outcome = match screen_message(potato, matrix, bridge_cfg, state, msg, run).await {
+        Some(outcome) => outcome,
+        None => Outcome::Handled(handle_message(potato, matrix, bridge_cfg, state, msg).await),
+    };
+    settle_message(bridge_cfg, state, state_path, msg, run, outcome)
+}
 
-        let duplicate_short = sample_node(Some("Test Node"), "Test Node");
-        assert_eq!(display_name_for_node(&duplicate_short), "Test Node");
+/// How far a message got; [`settle_message`] moves the checkpoint and
+/// tracks failures accordingly.
+enum Outcome {
+    /// Filtered out; the checkpoint moves past it.
+    Skipped,
+    /// Its node is in a `node_cooldown`.
+    SetAside,
+    /// Left for the next poll, along with everything after it.
+    Deferred,
+    /// Forwarded by [`handle_message`], or failed to be.
+    Handled(Result<()>),
+}
+
+/// Under `reply_cold_start = "quote"`, cache the text of a message about
+/// to be forwarded, so a reply to it can quote it should the send fail.
+/// Sending it fills in its event. Messages that are filtered out are never
+/// cached, so their text cannot reach Matrix through a quote.
+fn remember_text(bridge_cfg: &BridgeConfig, state: &mut BridgeState, msg: &PotatoMessage) {
+    if bridge_cfg.reply_cold_start != ReplyColdStart::Quote
+        || msg.text.is_empty()
+        || state.recent_messages.get(msg.id).is_some()
+    {
+        return;
     }
+    let text = bridged_text(bridge_cfg, &msg.text);
+    state.recent_messages.record(RecentMessage {
+        id: msg.id,
+        node_id: msg.node_id.clone(),
+        text: Some(text.into_owned()),
+        ..Default::default()
+    });
+}
 
-    #[test]
-    fn bridge_state_initially_forwards_all() {
-        let state = BridgeState::default();
-        let msg = sample_msg(42);
-
-        assert!(state.should_forward(&msg));
+/// `text` as it is bridged, after `unescape_unicode` and `trim_text`.
+fn bridged_text<'a>(bridge_cfg: &BridgeConfig, text: &'a str) -> Cow<'a, str> {
+    let text = if bridge_cfg.unescape_unicode {
+        text::unescape_unicode(text)
+    } else {
+        Cow::Borrowed(text)
+    };
+    if bridge_cfg.trim_text {
+        text::trim_padding(text)
+    } else {
+        text
     }
+}
 
-    #[test]
-    fn bridge_state_tracks_latest_rx_time_and_skips_older() {
-        let mut state = BridgeState::default();
-        let m1 = sample_msg(10);
-        let m2 = sample_msg(20);
-        let m3 = sample_msg(15);
-        let m1 = PotatoMessage { rx_time: 10, ..m1 };
-        let m2 = PotatoMessage { rx_time: 20, ..m2 };
-        let m3 = PotatoMessage { rx_time: 15, ..m3 };
-
-        // First message, should forward
-        assert!(state.should_forward(&m1));
-        state.update_with(&m1);
-        assert_eq!(state.last_message_id, Some(10));
-        assert_eq!(state.last_rx_time, Some(10));
+/// A filter that keeps a message from being bridged on purpose.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Filter {
+    /// Its channel is turned off under `channels`.
+    DisabledChannel,
+    /// Its hop count is outside `min_hops`..`max_hops`.
+    HopRange,
+    /// It is older than `max_message_age_secs`.
+    TooOld,
+    /// It is a position packet without text, announced rather than bridged.
+    PositionBeacon,
+    /// Its port is not in `forward_portnums`.
+    Portnum,
+    /// It repeats its sender's name, under `drop_name_echo`.
+    NameEcho,
+}
 
-        // Second message, higher id, should forward
-        assert!(state.should_forward(&m2));
-        state.update_with(&m2);
-        assert_eq!(state.last_message_id, Some(20));
-        assert_eq!(state.last_rx_time, Some(20));
+/// The first [`Filter`] of those that need no lookup to keep `msg` from
+/// being bridged at `now`, if any.
+fn filtered_by_config(
+    potato: &PotatoClient,
+    bridge_cfg: &BridgeConfig,
+    msg: &PotatoMessage,
+    now: u64,
+) -> Option<Filter> {
+    if !bridge_cfg.channel_enabled(&msg.channel_name) {
+        Some(Filter::DisabledChannel)
+    } else if !bridge_cfg.hops_in_range(msg.hops) {
+        Some(Filter::HopRange)
+    } else if bridge_cfg.too_old(msg.rx_time, now) {
+        Some(Filter::TooOld)
+    } else if is_position_beacon(msg) {
+        Some(Filter::PositionBeacon)
+    } else if !potato.forwards_portnum(msg.portnum.as_deref()) {
+        Some(Filter::Portnum)
+    } else {
+        None
+    }
+}
 
-        // Third message, lower than last, should NOT forward
-        assert!(!state.should_forward(&m3));
-        // state remains unchanged
-        assert_eq!(state.last_message_id, Some(20));
-        assert_eq!(state.last_rx_time, Some(20));
+/// The first [`Filter`] that keeps `msg` from being bridged at `now`, if
+/// any. Shared by the bridge and `audit --missing`, so messages filtered
+/// out on purpose are not reported as lost.
+async fn filtered_out(
+    potato: &PotatoClient,
+    bridge_cfg: &BridgeConfig,
+    msg: &PotatoMessage,
+    now: u64,
+) -> Option<Filter> {
+    if let Some(filter) = filtered_by_config(potato, bridge_cfg, msg, now) {
+        return Some(filter);
     }
+    (bridge_cfg.drop_name_echo && is_name_echo(potato, msg).await).then_some(Filter::NameEcho)
+}
 
-    #[test]
-    fn bridge_state_uses_legacy_id_filter_when_rx_time_missing() {
-        let state = BridgeState {
-            last_message_id: Some(10),
-            last_rx_time: None,
-            last_rx_time_ids: vec![],
-            last_checked_at: None,
-            ..Default::default()
-        };
-        let older = sample_msg(9);
-        let newer = sample_msg(11);
+/// Decide whether `msg` should be forwarded: `None` when it should, else
+/// why not. Leaves the checkpoint alone.
+async fn screen_message(
+    potato: &PotatoClient,
+    matrix: &MatrixAppserviceClient,
+    bridge_cfg: &BridgeConfig,
+    state: &mut BridgeState,
+    msg: &PotatoMessage,
+    run: &mut PollRun,
+) -> Option<Outcome> {
+    if state.delivered_ahead.contains(&msg.id) {
+        debug!(
+            message_id = msg.id,
+            "Skipping message sent before an earlier one failed"
+        );
+        return Some(Outcome::Skipped);
+    }
 
-        assert!(!state.should_forward(&older));
-        assert!(state.should_forward(&newer));
+    if let Some(filter) = filtered_out(potato, bridge_cfg, msg, run.now).await {
+        match filter {
+            Filter::DisabledChannel => debug!(
+                message_id = msg.id,
+                channel = msg.channel_name.as_str(),
+                "Skipping message on disabled channel"
+            ),
+            Filter::HopRange => debug!(
+                message_id = msg.id,
+                hops = ?msg.hops,
+                "Skipping message outside the hop range"
+            ),
+            Filter::TooOld => debug!(
+                message_id = msg.id,
+                rx_time = msg.rx_time,
+                "Skipping message older than max_message_age_secs"
+            ),
+            // Best effort: a failed beacon never holds up the batch.
+            Filter::PositionBeacon if bridge_cfg.location_events => {
+                if let Err(e) = send_position_location(potato, matrix, bridge_cfg, state, msg).await
+                {
+                    warn!(message_id = msg.id, error = ?e, "Failed to send location");
+                }
+            }
+            Filter::PositionBeacon => {
+                if let Some(template) = &bridge_cfg.position_beacon_template {
+                    if let Err(e) = announce_position(potato, matrix, template, state, msg).await {
+                        warn!(message_id = msg.id, error = ?e, "Failed to announce position");
+                    }
+                }
+            }
+            Filter::Portnum => {}
+            Filter::NameEcho => info!(message_id = msg.id, "Dropping name echo message"),
+        }
+        return Some(Outcome::Skipped);
     }
 
-    #[test]
-    fn bridge_state_dedupes_same_timestamp() {
-        let mut state = BridgeState::default();
-        let m1 = PotatoMessage {
-            rx_time: 100,
-            ..sample_msg(10)
-        };
-        let m2 = PotatoMessage {
-            rx_time: 100,
-            ..sample_msg(9)
-        };
-        let dup = PotatoMessage {
-            rx_time: 100,
-            ..sample_msg(10)
-        };
+    if bridge_cfg.node_cooldown.is_some() && state.node_cooling_down(&msg.node_id, run.now) {
+        return Some(Outcome::SetAside);
+    }
 
-        assert!(state.should_forward(&m1));
-        state.update_with(&m1);
-        assert!(state.should_forward(&m2));
-        state.update_with(&m2);
-        assert!(!state.should_forward(&dup));
-        assert_eq!(state.last_rx_time, Some(100));
-        assert_eq!(state.last_rx_time_ids, vec![10, 9]);
+    if let Some(max) = bridge_cfg.max_registrations_per_poll {
+        let localpart = sender_localpart(matrix, bridge_cfg, msg);
+        if !matrix.is_registered(&localpart) {
+            if run.registrations >= max {
+                // Stop here rather than skip ahead: the checkpoint
+                // stays before this message so it (and everything
+                // after it) is retried, in order, next poll.
+                info!(
+                    message_id = msg.id,
+                    registrations = run.registrations,
+                    "Registration limit reached this poll; deferring message to the next poll"
+                );
+                return Some(Outcome::Deferred);
+            }
+            run.registrations += 1;
+        }
     }
+    remember_text(bridge_cfg, state, msg);
+    None
+}
 
-    #[test]
-    fn bridge_state_load_save_roundtrip() {
-        let tmp_dir = tempfile::tempdir().unwrap();
-        let file_path = tmp_dir.path().join("state.json");
-        let path_str = file_path.to_str().unwrap();
+/// Move the checkpoint past `msg` according to its `outcome`, tracking
+/// repeated failures so a poison message is eventually skipped.
+fn settle_message(
+    bridge_cfg: &BridgeConfig,
+    state: &mut BridgeState,
+    state_path: &str,
+    msg: &PotatoMessage,
+    run: &PollRun,
+    outcome: Outcome,
+) -> Flow {
+    let result = match outcome {
+        Outcome::Skipped => {
+            state.delivered_ahead.remove(&msg.id);
+            state.update_with(msg);
+            log_state_update(state);
+            persist_state(state, state_path);
+            return Flow::Next;
+        }
+        Outcome::SetAside => {
+            if let Some(cooldown) = &bridge_cfg.node_cooldown {
+                set_aside_during_cooldown(cooldown, state, state_path, msg);
+            }
+            return Flow::Next;
+        }
+        Outcome::Deferred => return Flow::Stop,
+        Outcome::Handled(result) => result,
+    };
 
-        let state = BridgeState {
-            last_message_id: Some(12345),
-            last_rx_time: Some(99),
-            last_rx_time_ids: vec![123],
-            last_checked_at: Some(77),
-            ..Default::default()
-        };
-        state.save(path_str).unwrap();
+    if let Err(e) = result {
+        error!(
+            message_id = msg.id,
+            node_id = msg.node_id.as_str(),
+            error = ?e,
+            "Error handling message"
+        );
+        if state.in_startup_grace(run.now) {
+            // The homeserver may still be settling; retry soon without
+            // counting this failure against the node or the message.
+            info!(
+                message_id = msg.id,
+                "Retrying message after the startup grace poll"
+            );
+            return Flow::Stop;
+        }
+        if let Some(cooldown) = &bridge_cfg.node_cooldown {
+            if state.record_node_failure(&msg.node_id, cooldown, run.now) {
+                warn!(
+                    node_id = msg.node_id.as_str(),
+                    failures = cooldown.failures,
+                    cooldown_secs = cooldown.secs,
+                    "Node failed repeatedly; pausing it"
+                );
+                state.failing_msg_id = None;
+                state.failing_msg_attempts = 0;
+                set_aside_during_cooldown(cooldown, state, state_path, msg);
+                return Flow::Next;
+            }
+        }
+        if bridge_cfg.ordering == MessageOrdering::Relaxed {
+            // Let the rest of the batch through; this one is retried on
+            // the next poll, after them.
+            state.retry_messages.push(RetryMessage {
+                msg: msg.clone(),
+                attempts: 1,
+            });
+            state.update_with(msg);
+            persist_state(state, state_path);
+            return Flow::Next;
+        }
+        // Track consecutive failures of THIS specific message across
+        // polls (the batch is refetched each poll while the
+        // watermark is stuck, so the same id reappears at the head).
+        if state.failing_msg_id == Some(msg.id) {
+            state.failing_msg_attempts += 1;
+        } else {
+            state.failing_msg_id = Some(msg.id);
+            state.failing_msg_attempts = 1;
+        }
 
-        let loaded_state = BridgeState::load(path_str).unwrap();
-        assert_eq!(loaded_state.last_message_id, Some(12345));
-        assert_eq!(loaded_state.last_rx_time, Some(99));
-        assert_eq!(loaded_state.last_rx_time_ids, vec![123]);
-        assert_eq!(loaded_state.last_checked_at, None);
-    }
+        if state.failing_msg_attempts >= MAX_FORWARD_ATTEMPTS {
+            // Poison message: it has failed too many polls in a row,
+            // so skip it rather than block the whole batch behind it
+            // indefinitely. Advance the watermark past it (as if
+            // processed) and continue with the rest. Dropping this
+            // one message is the lesser evil versus stalling forever.
+            warn!(
+                message_id = msg.id,
+                attempts = state.failing_msg_attempts,
+                "Skipping message after repeated failed forward attempts; advancing past it"
+            );
+            state.failing_msg_id = None;
+            state.failing_msg_attempts = 0;
+            state.update_with(msg);
+            persist_state(state, state_path);
+            return Flow::Next;
+        }
 
-    #[test]
-    fn bridge_state_load_nonexistent() {
-        let tmp_dir = tempfile::tempdir().unwrap();
-        let file_path = tmp_dir.path().join("nonexistent.json");
-        let path_str = file_path.to_str().unwrap();
+        // Below the skip threshold: stop the batch here. The failed
+        // message and everything after it stay uncommitted and are
+        // retried, in order, on the next poll — so a *transient*
+        // failure never loses, reorders, or duplicates anything. (The
+        // watermark advances only on success, so a later success can
+        // never jump past this failure — the silent-loss bug.)
+        return Flow::Stop;
+    }
 
-        let state = BridgeState::load(path_str).unwrap();
-        assert_eq!(state.last_message_id, None);
-        assert_eq!(state.last_rx_time, None);
-        assert!(state.last_rx_time_ids.is_empty());
+    // Success clears any failure tracking for this message.
+    if state.failing_msg_id == Some(msg.id) {
+        state.failing_msg_id = None;
+        state.failing_msg_attempts = 0;
     }
+    state
+        .node_failures
+        .remove(&potatomesh::normalize_node_hex(&msg.node_id));
 
-    #[test]
-    fn bridge_state_load_empty_file() {
-        let tmp_dir = tempfile::tempdir().unwrap();
-        let file_path = tmp_dir.path().join("empty.json");
-        let path_str = file_path.to_str().unwrap();
+    // persist after each processed message
+    persist_state(state, state_path);
+    Flow::Next
+}
 
-        fs::write(path_str, "").unwrap();
+/// The message a source labelled `label` bridged into the room of a
+/// `!redact` command under its mesh id, if it has an event to redact.
+/// Entries saved before rooms were kept are taken to be in that room.
+fn redact_candidate(
+    label: Option<&str>,
+    state: &BridgeState,
+    cmd: &RedactCommand,
+) -> Option<RecentMessage> {
+    if cmd
+        .source
+        .as_deref()
+        .is_some_and(|source| label != Some(source))
+    {
+        return None;
+    }
+    state
+        .recent_messages
+        .get(cmd.mesh_id)
+        .filter(|recent| {
+            recent.event_id.is_some()
+                && recent
+                    .room_id
+                    .as_deref()
+                    .is_none_or(|room_id| room_id == cmd.room_id)
+        })
+        .cloned()
+}
 
-        let state = BridgeState::load(path_str).unwrap();
-        assert_eq!(state.last_message_id, None);
-        assert_eq!(state.last_rx_time, None);
-        assert!(state.last_rx_time_ids.is_empty());
-        assert_eq!(state.last_checked_at, None);
+/// Redact the Matrix event bridged from the mesh message a `!redact`
+/// command names, if its sender is one of `admin_users`. `bridged` holds
+/// each source's [`redact_candidate`]; when more than one source bridged
+/// that id into the room, nothing is redacted and the room is told to name
+/// the source.
+async fn handle_redact_command(
+    matrix: &MatrixAppserviceClient,
+    bridge_cfg: &BridgeConfig,
+    bridged: Vec<RecentMessage>,
+    cmd: RedactCommand,
+) {
+    if !bridge_cfg.admin_users.contains(&cmd.sender) {
+        warn!(
+            sender = cmd.sender.as_str(),
+            message_id = cmd.mesh_id,
+            "Ignoring redact command from a user not in admin_users"
+        );
+        return;
+    }
+    let event_id = match bridged.as_slice() {
+        [] => {
+            warn!(
+                message_id = cmd.mesh_id,
+                "Cannot redact: no bridged event recorded for mesh message"
+            );
+            return;
+        }
+        [recent] => recent.event_id.clone().unwrap_or_default(),
+        _ => {
+            warn!(
+                message_id = cmd.mesh_id,
+                sources = bridged.len(),
+                "Cannot redact: mesh message id was bridged into the room by several sources"
+            );
+            let body = format!(
+                "Mesh message {} was bridged here from {} sources; use !redact <source> {} to pick one",
+                cmd.mesh_id,
+                bridged.len(),
+                cmd.mesh_id
+            );
+            if let Err(e) = matrix
+                .send_notice(&cmd.room_id, NoticeLevel::Warn, &body)
+                .await
+            {
+                warn!(error = ?e, "Posting the ambiguous redact notice failed");
+            }
+            return;
+        }
+    };
+    let room_id = cmd.room_id;
+    let reason = format!("Redacted by {}", cmd.sender);
+    match matrix.redact_event(&room_id, &event_id, &reason).await {
+        Ok(()) => info!(
+            message_id = cmd.mesh_id,
+            room_id = room_id.as_str(),
+            event_id = event_id.as_str(),
+            "Redacted bridged message"
+        ),
+        Err(e) => warn!(
+            message_id = cmd.mesh_id,
+            room_id = room_id.as_str(),
+            event_id = event_id.as_str(),
+            error = ?e,
+            "Redacting bridged message failed"
+        ),
     }
+}
 
-    #[test]
-    fn bridge_state_migrates_legacy_checkpoint() {
-        let tmp_dir = tempfile::tempdir().unwrap();
-        let file_path = tmp_dir.path().join("legacy_state.json");
-        let path_str = file_path.to_str().unwrap();
+fn spawn_synapse_listener(
+    addr: SocketAddr,
+    token: String,
+    txn_path: String,
+    potato: PotatoClient,
+    metrics: Metrics,
+    commands: mpsc::Sender<RedactCommand>,
+    rooms: BridgedRooms,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        if let Err(e) =
+            run_synapse_listener(addr, token, txn_path, potato, metrics, commands, rooms).await
+        {
+            error!("Synapse listener failed: {:?}", e);
+        }
+    })
+}
 
-        fs::write(
-            path_str,
-            r#"{"last_message_id":42,"last_checked_at":1710000000}"#,
+#[cfg(not(test))]
+#[tokio::main]
+async fn main() -> Result<()> {
+    // Logging: RUST_LOG=info,bridge=debug,reqwest=warn ...
+    // The log-room layer is installed up front so startup warnings are
+    // queued; they are only delivered if a `log_room` is configured.
+    let cli = Cli::parse();
+    let log_format = LogFormat::resolve(
+        cli.log_format,
+        std::env::var("RUST_LOG_FORMAT").ok().as_deref(),
+    );
+    let (log_room_layer, log_room_rx) =
+        log_room::LogRoomLayer::new(log_room::LOG_ROOM_MAX_NOTICES, log_room::LOG_ROOM_WINDOW);
+    tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::EnvFilter::from_default_env()
+                .add_directive("potatomesh_matrix_bridge=info".parse().unwrap_or_default())
+                .add_directive("reqwest=warn".parse().unwrap_or_default()),
         )
-        .unwrap();
+        .with((log_format == LogFormat::Text).then(tracing_subscriber::fmt::layer))
+        .with(
+            (log_format == LogFormat::Json)
+                .then(|| tracing_subscriber::fmt::layer().json().flatten_event(true)),
+        )
+        .with(log_room_layer)
+        .init();
 
-        let state = BridgeState::load(path_str).unwrap();
-        assert_eq!(state.last_message_id, Some(42));
-        assert_eq!(state.last_rx_time, Some(1_710_000_000));
-        assert!(state.last_rx_time_ids.is_empty());
+    if let Some(Command::GenRegistration {
+        url,
+        sender_localpart,
+    }) = &cli.command
+    {
+        let settings = config::load_registration_settings(cli.to_inputs())?;
+        return run_gen_registration(&settings, url, sender_localpart);
     }
+    let mut cfg = config::load(cli.to_inputs())?;
+    log_config(&cfg);
 
-    #[test]
-    fn fetch_params_respects_missing_last_message_id() {
-        let state = BridgeState {
-            last_message_id: None,
-            last_rx_time: Some(123),
-            last_rx_time_ids: vec![],
-            last_checked_at: None,
-            ..Default::default()
-        };
-
-        let params = build_fetch_params(&state);
-        assert_eq!(params.limit, None);
-        assert_eq!(params.since, None);
+    let http = cfg.http.client()?;
+    let mut potato = PotatoClient::new(http.clone(), cfg.potatomesh.clone());
+    if let Some(Command::Check) = cli.command {
+        let matrix = MatrixAppserviceClient::new(http.clone(), cfg.matrix.clone());
+        return run_check(&potato, &matrix).await;
     }
-
-    #[test]
-    fn fetch_params_uses_since_when_safe() {
-        let state = BridgeState {
-            last_message_id: Some(1),
-            last_rx_time: Some(123),
-            last_rx_time_ids: vec![],
-            last_checked_at: None,
-            ..Default::default()
-        };
-
-        let params = build_fetch_params(&state);
-        assert_eq!(params.limit, None);
-        assert_eq!(params.since, Some(123));
+    if let Some(Command::Audit { count, .. }) = cli.command {
+        return run_audit(&cfg.bridge, &potato, count).await;
     }
-
-    #[test]
-    fn fetch_params_defaults_to_small_window() {
-        let state = BridgeState {
-            last_message_id: Some(1),
-            last_rx_time: None,
-            last_rx_time_ids: vec![],
-            last_checked_at: None,
-            ..Default::default()
-        };
-
-        let params = build_fetch_params(&state);
-        assert_eq!(params.limit, Some(10));
-        assert_eq!(params.since, None);
+    potato.health_check().await?;
+    if potato.detect_id_cursor().await {
+        info!("PotatoMesh supports id cursors; fetching messages by id");
     }
-
-    #[test]
-    fn log_state_update_emits_info() {
-        let state = BridgeState::default();
-        log_state_update(&state);
+    let mut matrix = MatrixAppserviceClient::new(http.clone(), cfg.matrix.clone());
+    matrix.set_dry_run(cfg.bridge.dry_run);
+    if cfg.bridge.dry_run {
+        warn!("Dry run: messages are logged, nothing is written to Matrix");
     }
+    matrix.health_check().await?;
 
-    #[test]
-    fn persist_state_writes_file() {
-        let tmp_dir = tempfile::tempdir().unwrap();
-        let file_path = tmp_dir.path().join("state.json");
-        let path_str = file_path.to_str().unwrap();
+    if let Some(Command::Backfill { count, commit }) = cli.command {
+        return run_backfill(&cfg, &potato, &mut matrix, count, commit).await;
+    }
 
-        let state = BridgeState {
-            last_message_id: Some(42),
-            last_rx_time: Some(123),
-            last_rx_time_ids: vec![42],
-            last_checked_at: None,
-            ..Default::default()
-        };
+    match cfg.matrix.log_room.clone() {
+        Some(room_id) => {
+            let _log_room_handle =
+                log_room::spawn_log_room_forwarder(matrix.clone(), room_id, log_room_rx);
+        }
+        None => drop(log_room_rx),
+    }
 
-        persist_state(&state, path_str);
+    let synapse_addr = SocketAddr::from(([0, 0, 0, 0], 41448));
+    let synapse_token = cfg.matrix.hs_token.clone();
+    let metrics = Metrics::default();
+    potato.set_metrics(metrics.clone());
+    let (redact_tx, mut redact_rx) = mpsc::channel(REDACT_QUEUE);
+    // Filled in once the rooms are known; until then every event is dropped.
+    let bridged_rooms = BridgedRooms::default();
+    let _synapse_handle = spawn_synapse_listener(
+        synapse_addr,
+        synapse_token,
+        cfg.state.txn_file.clone(),
+        potato.clone(),
+        metrics.clone(),
+        redact_tx,
+        bridged_rooms.clone(),
+    );
 
-        let loaded = BridgeState::load(path_str).unwrap();
-        assert_eq!(loaded.last_message_id, Some(42));
+    // The state section is not reloadable; keep owned copies so the config
+    // can be swapped on SIGHUP.
+    let state_path = cfg.state.state_file.clone();
+    let mut state = BridgeState::load_or_recover(&state_path, cfg.state.recover_corrupt_state)?;
+    info!("Loaded state: {:?}", state);
+    state.metrics = metrics.clone();
+    state.hold_checkpoint = cfg.bridge.dry_run && cfg.bridge.dry_run_hold_checkpoint;
+    state.id_cursor = potato.supports_id_cursor();
+    if cli.since_now
+        && start_from_now(&potato, &mut state, &state_path, potatomesh::now_secs()).await?
+    {
+        info!("Fresh state: skipping messages received before startup");
+    }
+    state.publish_last_message_id();
+    restore_created_room(&state, &matrix);
+    state.txn_counter = matrix.resume_txn_counter(&state.txn_counter);
+    // A dry run registers and renames nothing, so it must not record that it did.
+    if !cfg.bridge.dry_run {
+        matrix.set_puppet_cache(state.puppets.clone());
+    }
+    let rooms: Vec<String> = matrix
+        .bridged_rooms()
+        .into_iter()
+        .chain(cfg.matrix.log_room.clone())
+        .collect();
+    matrix.check_room_membership(&rooms).await?;
+    bridged_rooms.replace(matrix.bridged_rooms());
+
+    let mut sources = Vec::new();
+    for source_cfg in &cfg.extra_sources {
+        let (mut source_potato, source_state) = open_extra_source(
+            http.clone(),
+            source_cfg.clone(),
+            &mut state,
+            &state_path,
+            cli.since_now,
+        )
+        .await?;
+        source_potato.set_metrics(metrics.clone());
+        sources.push((source_potato, source_state));
+    }
+    sources.insert(0, (potato, state));
+    if sources.len() > 1 {
+        for (potato, _) in &mut sources {
+            potato.set_shares_rooms(true);
+        }
     }
 
-    #[test]
-    fn persist_state_logs_on_error() {
-        let tmp_dir = tempfile::tempdir().unwrap();
-        let dir_path = tmp_dir.path().to_str().unwrap();
-        let state = BridgeState::default();
-
-        // Writing to a directory path should trigger the error branch.
-        persist_state(&state, dir_path);
+    startup_delay(cfg.potatomesh.startup_delay_secs).await;
+    let startup_grace_until = cfg
+        .bridge
+        .startup_grace_secs
+        .map(|secs| potatomesh::now_secs().saturating_add(secs));
+    let (live_tx, live_rx) = tokio::sync::watch::channel(Live {
+        cfg: cfg.clone(),
+        matrix: matrix.clone(),
+    });
+    let mut tasks = Vec::new();
+    for (index, (potato, mut state)) in sources.into_iter().enumerate() {
+        state
+            .seen_content
+            .set_capacity(cfg.bridge.content_dedup_size);
+        state.startup_grace_until = startup_grace_until;
+        let node_cache_path =
+            cfg.state
+                .node_cache_file
+                .as_deref()
+                .map(|path| match &state.source {
+                    Some(label) => labelled_path(path, label),
+                    None => path.to_string(),
+                });
+        if let Some(path) = &node_cache_path {
+            match potato.load_nodes_cache(path).await {
+                Ok(count) => info!("Loaded {} cached nodes from {}", count, path),
+                Err(e) => warn!("Ignoring unreadable node cache {}: {:?}", path, e),
+            }
+        }
+        let state = Arc::new(tokio::sync::Mutex::new(state));
+        let span = info_span!("source", label = potato.cfg.label.as_deref());
+        let task = tokio::spawn(
+            run_source(
+                index,
+                potato.clone(),
+                state.clone(),
+                live_rx.clone(),
+                bridged_rooms.clone(),
+                state_path.clone(),
+                node_cache_path,
+                Duration::from_secs(cfg.state.node_cache_flush_interval_secs),
+            )
+            .instrument(span),
+        );
+        tasks.push((potato, state, task));
     }
 
-    #[tokio::test]
-    async fn spawn_synapse_listener_starts_task() {
-        let addr = SocketAddr::from(([127, 0, 0, 1], 0));
-        let handle = spawn_synapse_listener(addr, "HS_TOKEN".to_string());
-        tokio::time::sleep(Duration::from_millis(10)).await;
-        handle.abort();
+    let mut room_topic = None;
+    let mut next_topic_update = Instant::now();
+    let shutdown = shutdown_signal()?;
+    tokio::pin!(shutdown);
+    let reload = reload_signal()?;
+
+    loop {
+        tokio::select! {
+            _ = &mut shutdown => break,
+            _ = reload.notified() => {
+                let new_cfg = match config::load(cli.to_inputs()) {
+                    Ok(new_cfg) => new_cfg,
+                    Err(e) => {
+                        error!("Config reload failed, keeping the current config: {:?}", e);
+                        continue;
+                    }
+                };
+                let main_state = tasks[0].1.lock().await;
+                if let Err(e) = reload_config(&mut cfg, new_cfg, &mut matrix, &main_state).await {
+                    error!("Config reload failed, keeping the current config: {:?}", e);
+                    continue;
+                }
+                drop(main_state);
+                for ((potato, _, _), source_cfg) in tasks.iter_mut().zip(cfg.sources()) {
+                    *potato = potato.with_config(source_cfg.clone());
+                }
+                bridged_rooms.replace(matrix.bridged_rooms());
+                live_tx.send_replace(Live {
+                    cfg: cfg.clone(),
+                    matrix: matrix.clone(),
+                });
+            }
+            Some(cmd) = redact_rx.recv() => {
+                // Mesh ids are per source, and sources can share a room, so
+                // every source is asked. Matches are copied out so no
+                // source's lock is held while the redaction waits out rate
+                // limits.
+                let mut bridged = Vec::new();
+                for (potato, state, _) in &tasks {
+                    let state = state.lock().await;
+                    bridged.extend(redact_candidate(potato.label(), &state, &cmd));
+                }
+                handle_redact_command(&matrix, &cfg.bridge, bridged, cmd).await;
+            }
+            _ = tokio::time::sleep_until(next_topic_update),
+                if cfg.bridge.room_topic_interval_secs.is_some() =>
+            {
+                let mut last_rx_time = None;
+                for (_, state, _) in &tasks {
+                    last_rx_time = last_rx_time.max(state.lock().await.last_rx_time);
+                }
+                let potatoes: Vec<&PotatoClient> =
+                    tasks.iter().map(|(potato, _, _)| potato).collect();
+                update_room_topic(&potatoes, &matrix, last_rx_time, &mut room_topic).await;
+                let interval = cfg.bridge.room_topic_interval_secs.unwrap_or_default();
+                next_topic_update = Instant::now() + Duration::from_secs(interval);
+            }
+        }
     }
 
-    #[tokio::test]
-    async fn spawn_synapse_listener_logs_error_on_bind_failure() {
-        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
-        let addr = listener.local_addr().unwrap();
-        let handle = spawn_synapse_listener(addr, "HS_TOKEN".to_string());
-        let _ = handle.await;
+    // Closing the channel stops every source once its current poll is done.
+    drop(live_tx);
+    for (_, _, task) in tasks {
+        if let Err(e) = task.await {
+            error!("Source task failed: {:?}", e);
+        }
     }
+    info!("Shut down cleanly; state saved to {}", state_path);
+    Ok(())
+}
 
-    #[tokio::test]
-    async fn poll_once_leaves_state_unchanged_without_messages() {
-        let tmp_dir = tempfile::tempdir().unwrap();
-        let state_path = tmp_dir.path().join("state.json");
-        let state_str = state_path.to_str().unwrap();
+/// What a config reload can change, handed from the main loop to every
+/// source's task.
+#[cfg(not(test))]
+#[derive(Clone)]
+struct Live {
+    cfg: Config,
+    matrix: MatrixAppserviceClient,
+}
 
-        let mut server = mockito::Server::new_async().await;
-        let mock_msgs = server
-            .mock("GET", "/api/messages")
-            .match_query(mockito::Matcher::Any)
-            .with_status(200)
-            .with_header("content-type", "application/json")
-            .with_body("[]")
-            .create();
+/// Poll the source at `index` in [`Config::sources`] into the shared rooms
+/// until `live` is closed, switching to each config it is sent. Its node
+/// presence and node cache are handled here too, so every source is bridged
+/// the same way; its state is saved on the way out.
+#[cfg(not(test))]
+#[allow(clippy::too_many_arguments)]
+async fn run_source(
+    index: usize,
+    mut potato: PotatoClient,
+    state: Arc<tokio::sync::Mutex<BridgeState>>,
+    mut live: tokio::sync::watch::Receiver<Live>,
+    bridged_rooms: BridgedRooms,
+    state_path: String,
+    node_cache_path: Option<String>,
+    node_cache_flush_interval: Duration,
+) {
+    // Never notified unless live updates are on, leaving the plain interval.
+    let messages_changed = Arc::new(tokio::sync::Notify::new());
+    if potato.cfg.live_updates {
+        potatomesh::spawn_live_updates(potato.clone(), messages_changed.clone());
+    }
+    let Live {
+        mut cfg,
+        mut matrix,
+    } = live.borrow_and_update().clone();
+    let mut last_node_cache_flush = Instant::now();
+    let mut last_presence_check: Option<Instant> = None;
 
-        let http_client = reqwest::Client::new();
-        let potatomesh_cfg = PotatomeshConfig {
-            base_url: server.url(),
-            poll_interval_secs: 1,
-        };
-        let matrix_cfg = MatrixConfig {
-            homeserver: server.url(),
-            as_token: "AS_TOKEN".to_string(),
-            hs_token: "HS_TOKEN".to_string(),
-            server_name: "example.org".to_string(),
-            room_id: "!roomid:example.org".to_string(),
-        };
+    loop {
+        let mut source_state = state.lock().await;
+        poll_once(
+            &potato,
+            &matrix,
+            &cfg.bridge,
+            &mut source_state,
+            &state_path,
+        )
+        .await;
+        // A created room may have changed the rooms.
+        bridged_rooms.replace(matrix.bridged_rooms());
+
+        if let Some(path) = &node_cache_path {
+            if last_node_cache_flush.elapsed() >= node_cache_flush_interval {
+                flush_nodes_cache(&potato, path).await;
+                last_node_cache_flush = Instant::now();
+            }
+        }
 
-        let potato = PotatoClient::new(http_client.clone(), potatomesh_cfg);
-        let matrix = MatrixAppserviceClient::new(http_client, matrix_cfg);
+        if let Some(presence) = &cfg.bridge.node_presence {
+            let interval = Duration::from_secs(presence.poll_interval_secs);
+            if last_presence_check.is_none_or(|at| at.elapsed() >= interval) {
+                check_node_presence(&potato, &matrix, presence, &mut source_state, &state_path)
+                    .await;
+                last_presence_check = Some(Instant::now());
+            }
+        }
 
-        let mut state = BridgeState {
-            last_message_id: Some(1),
-            last_rx_time: Some(100),
-            last_rx_time_ids: vec![1],
-            last_checked_at: None,
-            ..Default::default()
+        let poll_interval = Duration::from_secs(potato.cfg.poll_interval_secs);
+        let pause = if source_state.in_startup_grace(potatomesh::now_secs()) {
+            poll_interval.min(STARTUP_GRACE_POLL_INTERVAL)
+        } else {
+            poll_interval
         };
+        drop(source_state);
+        match pause_until_next_poll(pause, &mut live, &messages_changed).await {
+            Wake::Poll => {}
+            Wake::Shutdown => break,
+            Wake::Reload => {
+                Live { cfg, matrix } = live.borrow_and_update().clone();
+                if let Some(source_cfg) = cfg.sources().nth(index) {
+                    potato = potato.with_config(source_cfg.clone());
+                }
+                state
+                    .lock()
+                    .await
+                    .seen_content
+                    .set_capacity(cfg.bridge.content_dedup_size);
+            }
+        }
+    }
 
-        poll_once(&potato, &matrix, &mut state, state_str).await;
+    persist_state(&*state.lock().await, &state_path);
+    if let Some(path) = &node_cache_path {
+        flush_nodes_cache(&potato, path).await;
+    }
+}
 
-        mock_msgs.assert();
+/// Connect to a `[[potatomesh]]` source after the first and take its state
+/// from `state`, the main source's. With `since_now` a fresh state starts
+/// at the source's newest message.
+#[cfg(not(test))]
+async fn open_extra_source(
+    http: reqwest::Client,
+    source_cfg: config::PotatomeshConfig,
+    state: &mut BridgeState,
+    state_path: &str,
+    since_now: bool,
+) -> Result<(PotatoClient, BridgeState)> {
+    // Validation makes sure every further source has a label.
+    let label = source_cfg.label.clone().unwrap_or_default();
+    let potato = PotatoClient::new(http, source_cfg);
+    // One unreachable mesh should not keep the others from being bridged.
+    if let Err(e) = potato.health_check().await {
+        warn!(source = label.as_str(), error = ?e, "PotatoMesh source not reachable yet");
+    }
+    if potato.detect_id_cursor().await {
+        info!(
+            source = label.as_str(),
+            "PotatoMesh source supports id cursors"
+        );
+    }
+    let mut source_state = state.take_source(&label);
+    info!("Loaded state of source {}: {:?}", label, source_state);
+    source_state.id_cursor = potato.supports_id_cursor();
+    if since_now
+        && start_from_now(
+            &potato,
+            &mut source_state,
+            state_path,
+            potatomesh::now_secs(),
+        )
+        .await?
+    {
+        info!(
+            source = label.as_str(),
+            "Fresh state: skipping messages received before startup"
+        );
+    }
+    source_state.publish_last_message_id();
+    Ok((potato, source_state))
+}
 
-        // No new data means state remains unchanged and is not persisted.
-        assert_eq!(state.last_rx_time, Some(100));
-        assert_eq!(state.last_rx_time_ids, vec![1]);
-        assert!(!state_path.exists());
+/// `path` with `label` before its extension, e.g. `nodes.south.json`, for
+/// the file of the further source `label`.
+fn labelled_path(path: &str, label: &str) -> String {
+    let file = Path::new(path);
+    match file.extension().and_then(|ext| ext.to_str()) {
+        Some(ext) => file
+            .with_extension(format!("{label}.{ext}"))
+            .to_string_lossy()
+            .into_owned(),
+        None => format!("{path}.{label}"),
     }
+}
 
-    #[tokio::test]
-    async fn poll_once_persists_state_for_non_text_messages() {
-        let tmp_dir = tempfile::tempdir().unwrap();
-        let state_path = tmp_dir.path().join("state.json");
+/// Future resolving on the first SIGTERM or SIGINT. The handlers are
+/// installed right away, so a signal arriving mid-poll is not lost.
+#[cfg(not(test))]
+fn shutdown_signal() -> Result<impl std::future::Future<Output = ()>> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate())?;
+        let mut interrupt = signal(SignalKind::interrupt())?;
+        Ok(async move {
+            tokio::select! {
+                _ = terminate.recv() => info!("Received SIGTERM, shutting down"),
+                _ = interrupt.recv() => info!("Received SIGINT, shutting down"),
+            }
+        })
+    }
+    #[cfg(not(unix))]
+    {
+        Ok(async {
+            let _ = tokio::signal::ctrl_c().await;
+            info!("Received Ctrl-C, shutting down");
+        })
+    }
+}
+
+/// Notified on every SIGHUP. A signal arriving mid-poll leaves a permit, so
+/// the reload happens as soon as the poll is done.
+#[cfg(not(test))]
+fn reload_signal() -> Result<std::sync::Arc<tokio::sync::Notify>> {
+    let reload = std::sync::Arc::new(tokio::sync::Notify::new());
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut hangup = signal(SignalKind::hangup())?;
+        let notify = reload.clone();
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                info!("Received SIGHUP, reloading config");
+                notify.notify_one();
+            }
+        });
+    }
+    Ok(reload)
+}
+
+/// Why [`pause_until_next_poll`] returned.
+#[derive(Debug, PartialEq, Eq)]
+enum Wake {
+    /// The pause is over, or PotatoMesh reported new messages.
+    Poll,
+    /// The config was reloaded.
+    Reload,
+    /// The bridge is shutting down.
+    Shutdown,
+}
+
+/// Wait `pause` before the next poll, returning early when
+/// `messages_changed` is notified or `live` is sent a new value or closed.
+async fn pause_until_next_poll<T>(
+    pause: Duration,
+    live: &mut tokio::sync::watch::Receiver<T>,
+    messages_changed: &tokio::sync::Notify,
+) -> Wake {
+    tokio::select! {
+        changed = live.changed() => match changed {
+            Ok(()) => Wake::Reload,
+            Err(_) => Wake::Shutdown,
+        },
+        _ = messages_changed.notified() => Wake::Poll,
+        _ = sleep(pause) => Wake::Poll,
+    }
+}
+
+/// Switch to a freshly loaded config: the `[[potatomesh]]` sources, the
+/// `[bridge]` section and the Matrix room mapping take effect with each
+/// source's next poll. The node caches and known puppet registrations carry
+/// over. Sources cannot be added or removed without a restart.
+///
+/// `homeserver`, `server_name` and `puppet_prefix` are baked into puppet
+/// ids, so changes to them are ignored with a warning. Everything else (the
+/// `[state]` and `[http]` sections, tokens the listener uses, `log_room`)
+/// keeps its startup value until a restart. If the new rooms fail the membership
+/// check, nothing is changed.
+async fn reload_config(
+    cfg: &mut Config,
+    mut new_cfg: Config,
+    matrix: &mut MatrixAppserviceClient,
+    state: &BridgeState,
+) -> Result<()> {
+    let labels = |cfg: &Config| -> Vec<Option<String>> {
+        cfg.extra_sources.iter().map(|s| s.label.clone()).collect()
+    };
+    if labels(&new_cfg) != labels(cfg) {
+        anyhow::bail!("[[potatomesh]] sources cannot be added, removed or renamed by a reload");
+    }
+    if new_cfg.matrix.homeserver != cfg.matrix.homeserver {
+        warn!(
+            "matrix.homeserver cannot be reloaded; keeping {}",
+            cfg.matrix.homeserver
+        );
+        new_cfg.matrix.homeserver = cfg.matrix.homeserver.clone();
+    }
+    if new_cfg.matrix.server_name != cfg.matrix.server_name {
+        warn!(
+            "matrix.server_name cannot be reloaded; keeping {}",
+            cfg.matrix.server_name
+        );
+        new_cfg.matrix.server_name = cfg.matrix.server_name.clone();
+    }
+    if new_cfg.matrix.puppet_prefix != cfg.matrix.puppet_prefix {
+        warn!(
+            "matrix.puppet_prefix cannot be reloaded; keeping {}",
+            cfg.matrix.puppet_prefix
+        );
+        new_cfg.matrix.puppet_prefix = cfg.matrix.puppet_prefix.clone();
+    }
+    new_cfg.matrix.hs_token = cfg.matrix.hs_token.clone();
+    new_cfg.matrix.log_room = cfg.matrix.log_room.clone();
+    if (
+        new_cfg.bridge.dry_run,
+        new_cfg.bridge.dry_run_hold_checkpoint,
+    ) != (cfg.bridge.dry_run, cfg.bridge.dry_run_hold_checkpoint)
+    {
+        warn!("bridge.dry_run cannot be reloaded; restart to change it");
+        new_cfg.bridge.dry_run = cfg.bridge.dry_run;
+        new_cfg.bridge.dry_run_hold_checkpoint = cfg.bridge.dry_run_hold_checkpoint;
+    }
+
+    let new_matrix = matrix.with_config(new_cfg.matrix.clone());
+    restore_created_room(state, &new_matrix);
+    new_matrix
+        .check_room_membership(&new_matrix.bridged_rooms())
+        .await?;
+
+    *matrix = new_matrix;
+    cfg.potatomesh = new_cfg.potatomesh;
+    cfg.extra_sources = new_cfg.extra_sources;
+    cfg.matrix = new_cfg.matrix;
+    cfg.bridge = new_cfg.bridge;
+    info!(
+        "Reloaded config: polling every {}s into {:?}",
+        cfg.potatomesh.poll_interval_secs,
+        matrix.bridged_rooms()
+    );
+    Ok(())
+}
+
+/// Point `matrix` at the room a previous run created with `auto_create_room`,
+/// as long as the configured room it replaced has not been changed since.
+fn restore_created_room(state: &BridgeState, matrix: &MatrixAppserviceClient) {
+    let Some(room) = &state.created_room else {
+        return;
+    };
+    if matrix.cfg.auto_create_room.is_some() && matrix.cfg.room_id.as_ref() == Some(&room.replaces)
+    {
+        info!(
+            "Using room {} created in place of {}",
+            room.room_id, room.replaces
+        );
+        matrix.set_room_id(&room.room_id);
+    }
+}
+
+/// Hold off the first poll for `secs` seconds, if configured.
+async fn startup_delay(secs: u64) {
+    if secs == 0 {
+        return;
+    }
+    info!("Waiting {}s before the first poll", secs);
+    tokio::time::sleep(Duration::from_secs(secs)).await;
+}
+
+/// Give a puppet its identicon the first time its node is seen, and redraw it
+/// when the node's hardware model changes. Returns the avatar to record in
+/// place of `current`, if it was replaced. Failures are logged and retried
+/// on the node's next message rather than holding up the bridge.
+async fn ensure_puppet_avatar(
+    matrix: &MatrixAppserviceClient,
+    current: Option<&PuppetAvatar>,
+    user_id: &str,
+    node_id: &str,
+    hw_model: Option<String>,
+) -> Option<PuppetAvatar> {
+    if matrix.is_dry_run() || current.is_some_and(|avatar| avatar.hw_model == hw_model) {
+        return None;
+    }
+    let node = potatomesh::normalize_node_hex(node_id);
+    let png = identicon::identicon_png(&node, hw_model.as_deref());
+    let result = async {
+        let mxc_uri = matrix.upload_media(png, "image/png").await?;
+        matrix.set_avatar_url(user_id, &mxc_uri).await?;
+        anyhow::Ok(mxc_uri)
+    }
+    .await;
+    match result {
+        Ok(mxc_uri) => Some(PuppetAvatar { mxc_uri, hw_model }),
+        Err(e) => {
+            warn!("Failed to set avatar for {}: {:#}", user_id, e);
+            None
+        }
+    }
+}
+
+/// Forward `msg` inside a `mesh_message` span, so every log line of its
+/// lifecycle (node lookup, puppet registration, send) carries `mesh_id` and
+/// `node_id`, and those after the send the resulting `event_id`.
+async fn handle_message(
+    potato: &PotatoClient,
+    matrix: &MatrixAppserviceClient,
+    bridge_cfg: &BridgeConfig,
+    state: &mut BridgeState,
+    msg: &PotatoMessage,
+) -> Result<()> {
+    forward_message(potato, matrix, bridge_cfg, state, msg)
+        .instrument(message_span(msg))
+        .await
+}
+
+/// The `mesh_message` span [`handle_message`] runs in; `event_id` is
+/// recorded once the message is sent.
+fn message_span(msg: &PotatoMessage) -> Span {
+    info_span!(
+        "mesh_message",
+        mesh_id = msg.id,
+        node_id = msg.node_id.as_str(),
+        event_id = tracing::field::Empty,
+    )
+}
+
+async fn forward_message(
+    potato: &PotatoClient,
+    matrix: &MatrixAppserviceClient,
+    bridge_cfg: &BridgeConfig,
+    state: &mut BridgeState,
+    msg: &PotatoMessage,
+) -> Result<()> {
+    if let Prepared::Send(out) = prepare_message(potato, matrix, bridge_cfg, state, msg).await? {
+        let event_id = out.send(matrix).await?;
+        record_sent(matrix, bridge_cfg, state, msg, out, event_id);
+    }
+    state.update_with(msg);
+    log_state_update(state);
+    Ok(())
+}
+
+/// What [`prepare_message`] left to do for a message.
+enum Prepared {
+    /// It repeated an earlier message and was folded into a reaction to it.
+    Collapsed,
+    /// It still has to be sent.
+    Send(OutgoingMessage),
+}
+
+/// A bridged message ready to be sent, with its puppet set up.
+struct OutgoingMessage {
+    user_id: String,
+    room_id: String,
+    body: String,
+    formatted_body: String,
+    in_reply_to: Option<String>,
+    /// The mesh text after unescaping and trimming, as remembered for
+    /// replies and repeats.
+    text: String,
+    /// Unix time the message was prepared at.
+    prepared_at: u64,
+}
+
+impl OutgoingMessage {
+    /// Send the message, returning its event id.
+    async fn send(&self, matrix: &MatrixAppserviceClient) -> Result<String> {
+        matrix
+            .send_formatted_message_as(
+                &self.user_id,
+                &self.room_id,
+                &self.body,
+                &self.formatted_body,
+                self.in_reply_to.as_deref(),
+            )
+            .await
+    }
+}
+
+/// Everything [`forward_message`] does before the send: look up the node,
+/// set up its puppet and render the message. Only reads the checkpoint.
+async fn prepare_message(
+    potato: &PotatoClient,
+    matrix: &MatrixAppserviceClient,
+    bridge_cfg: &BridgeConfig,
+    state: &mut BridgeState,
+    msg: &PotatoMessage,
+) -> Result<Prepared> {
+    let view = StateView::new(matrix, bridge_cfg, state, msg);
+    prepare_with(potato, matrix, bridge_cfg, view, msg)
+        .await
+        .apply(state, msg)
+}
+
+/// What [`prepare_with`] reads from the state, taken before it starts so
+/// the state stays free while messages are prepared side by side.
+struct StateView {
+    /// The node's current puppet avatar.
+    avatar: Option<PuppetAvatar>,
+    /// SNR trend arrow, under `snr_trend`.
+    snr_trend: Option<&'static str>,
+    /// Event the message repeats, under `collapse_duplicates_secs`.
+    repeat_of: Option<String>,
+    /// The bridged message this one replies to.
+    parent: Option<RecentMessage>,
+    /// Unix time the view was taken at.
+    now: u64,
+}
+
+impl StateView {
+    fn new(
+        matrix: &MatrixAppserviceClient,
+        bridge_cfg: &BridgeConfig,
+        state: &BridgeState,
+        msg: &PotatoMessage,
+    ) -> Self {
+        let now = potatomesh::now_secs();
+        // A channel bot can react to a message only once, so repeats from
+        // several nodes cannot be counted; they are posted instead.
+        let collapse_window = match bridge_cfg.sender_mode {
+            SenderMode::Puppet => bridge_cfg.collapse_duplicates_secs,
+            SenderMode::ChannelBot => None,
+        };
+        let repeat_of = collapse_window.and_then(|window| {
+            let room_id = matrix
+                .room_for_message(msg.channel, msg.is_broadcast())
+                .ok()?;
+            let text = bridged_text(bridge_cfg, &msg.text);
+            state
+                .duplicate_of(&room_id, &text, window, now)
+                .map(str::to_string)
+        });
+        Self {
+            avatar: state
+                .avatars
+                .get(&potatomesh::normalize_node_hex(&msg.node_id))
+                .cloned(),
+            snr_trend: bridge_cfg
+                .snr_trend
+                .then(|| state.snr_trend(&msg.node_id, msg.snr))
+                .flatten(),
+            repeat_of,
+            parent: msg
+                .reply_id
+                .and_then(|id| state.recent_messages.get(id))
+                .cloned(),
+            now,
+        }
+    }
+}
+
+/// A message [`prepare_with`] is done with: what it left to do, and the
+/// puppet avatar it uploaded, for the state.
+struct PreparedMessage {
+    avatar: Option<PuppetAvatar>,
+    result: Result<Prepared>,
+}
+
+impl PreparedMessage {
+    /// Record the new avatar of `msg`'s node, if any, in `state`.
+    fn apply(self, state: &mut BridgeState, msg: &PotatoMessage) -> Result<Prepared> {
+        if let Some(avatar) = self.avatar {
+            state
+                .avatars
+                .insert(potatomesh::normalize_node_hex(&msg.node_id), avatar);
+        }
+        self.result
+    }
+}
+
+/// [`prepare_message`] against `view` rather than the state itself.
+async fn prepare_with(
+    potato: &PotatoClient,
+    matrix: &MatrixAppserviceClient,
+    bridge_cfg: &BridgeConfig,
+    view: StateView,
+    msg: &PotatoMessage,
+) -> PreparedMessage {
+    let mut avatar = None;
+    let result = prepare_message_body(potato, matrix, bridge_cfg, &view, msg, &mut avatar).await;
+    PreparedMessage { avatar, result }
+}
+
+async fn prepare_message_body(
+    potato: &PotatoClient,
+    matrix: &MatrixAppserviceClient,
+    bridge_cfg: &BridgeConfig,
+    view: &StateView,
+    msg: &PotatoMessage,
+    avatar: &mut Option<PuppetAvatar>,
+) -> Result<Prepared> {
+    let node = match potato.get_node(&msg.node_id).await {
+        Ok(node) => Some(node),
+        Err(e) => match bridge_cfg.unknown_node_name_template {
+            Some(_) if potatomesh::is_not_found(&e) => None,
+            _ => return Err(e),
+        },
+    };
+    let display_name = match &node {
+        Some(node) => display_name_for_node(node, bridge_cfg.max_display_name_chars),
+        None => unknown_node_name(
+            bridge_cfg.unknown_node_name_template.as_deref(),
+            &msg.node_id,
+        ),
+    };
+    let altitude = node.as_ref().and_then(|node| node.altitude);
+    let role = node.as_ref().and_then(|node| node.role.clone());
+    let hw_model = node.as_ref().and_then(|node| node.hw_model.clone());
+    let localpart = sender_localpart(matrix, bridge_cfg, msg);
+    let user_id = matrix.user_id(&localpart);
+    // A channel bot speaks for many nodes, so the node is named in the body.
+    let (sender_name, embedded_name) = match bridge_cfg.sender_mode {
+        SenderMode::Puppet => (display_name.as_str(), None),
+        SenderMode::ChannelBot => (msg.channel_name.as_str(), Some(display_name.as_str())),
+    };
+
+    let room_id = matrix.room_for_message(msg.channel, msg.is_broadcast())?;
+
+    // Ensure puppet exists & has display name
+    matrix.ensure_user_registered(&localpart).await?;
+    matrix.ensure_user_joined_room(&user_id, &room_id).await?;
+    matrix.set_display_name(&user_id, sender_name).await?;
+    if bridge_cfg.sender_mode == SenderMode::Puppet {
+        *avatar = ensure_puppet_avatar(
+            matrix,
+            view.avatar.as_ref(),
+            &user_id,
+            &msg.node_id,
+            hw_model.clone(),
+        )
+        .await;
+    }
+
+    // Format the bridged message. `lora_freq` is `u32`, so 0 stands in for
+    // "unknown" — collapse that to `None` to match the JS pipeline (which
+    // runs `normalizeFrequency` and discards 0/non-finite before reaching
+    // the preset lookup).
+    let freq_mhz = if msg.lora_freq > 0 {
+        Some(msg.lora_freq as f64)
+    } else {
+        None
+    };
+    let abbr = preset::abbreviate_preset(&msg.modem_preset, freq_mhz);
+    let preset_short = preset::normalize_preset_slot(abbr.as_deref());
+    let tag = protocol_tag(msg.protocol.as_deref());
+    let rssi = msg.rssi.map(|rssi| format!("{rssi} dBm"));
+    let snr = msg.snr.map(|snr| format!("{snr} dB"));
+    let altitude = format_altitude(altitude);
+    let unknown: Vec<&str> = [
+        ("rssi", rssi.is_none()),
+        ("snr", snr.is_none()),
+        ("altitude", altitude.is_empty()),
+        ("role", role.is_none()),
+        ("hw_model", hw_model.is_none()),
+    ]
+    .into_iter()
+    .filter_map(|(name, unknown)| unknown.then_some(name))
+    .collect();
+    let template = match &bridge_cfg.direct_metadata_template {
+        Some(template) if !msg.is_broadcast() => template,
+        _ => &bridge_cfg.metadata_template,
+    };
+    let template = if bridge_cfg.hide_unknown_metadata {
+        Cow::Owned(drop_unknown_fields(template, &unknown))
+    } else {
+        Cow::Borrowed(template.as_str())
+    };
+    let mut prefix = text::fill_placeholders(
+        &template,
+        &[
+            ("tag", tag),
+            ("freq", &msg.lora_freq.to_string()),
+            ("preset", &preset_short),
+            ("channel", &msg.channel_name),
+            ("source", potato.label().unwrap_or_default()),
+            ("altitude", &altitude),
+            ("rssi", rssi.as_deref().unwrap_or("n/a")),
+            ("snr", snr.as_deref().unwrap_or("n/a")),
+            ("role", role.as_deref().unwrap_or("n/a")),
+            ("hw_model", hw_model.as_deref().unwrap_or("n/a")),
+        ],
+    );
+    if let Some(arrow) = view.snr_trend {
+        prefix.push_str(&format!("[SNR{arrow}]"));
+    }
+    // Sources sharing a room are told apart even by a template without it.
+    if let Some(label) = potato
+        .source_tag()
+        .filter(|_| !template.contains("{source}"))
+    {
+        prefix.push_str(&format!("[{label}]"));
+    }
+    let text = bridged_text(bridge_cfg, &msg.text);
+    if let Some(event_id) = &view.repeat_of {
+        // Each repeating node adds its reaction, so clients show the
+        // repeat count on the original message.
+        matrix
+            .send_reaction_as(&user_id, &room_id, event_id, DUPLICATE_REACTION_KEY)
+            .await?;
+        Span::current().record("event_id", event_id.as_str());
+        info!(
+            message_id = msg.id,
+            node_id = msg.node_id.as_str(),
+            room_id = room_id.as_str(),
+            event_id = event_id.as_str(),
+            "Collapsed repeated message into a reaction"
+        );
+        journal_delivery(bridge_cfg, msg, &room_id, event_id);
+        return Ok(Prepared::Collapsed);
+    }
+
+    let (mut body, mut formatted_body) = match &bridge_cfg.message_template {
+        Some(template) => {
+            let short = node.as_ref().and_then(short_name);
+            let long = node
+                .as_ref()
+                .map_or(display_name.as_str(), |node| node.long_name.as_str());
+            render_message_template(
+                template,
+                &[
+                    ("metadata", &prefix),
+                    ("short", short.as_deref().unwrap_or("n/a")),
+                    ("long", long),
+                    ("text", &text),
+                    ("from_id", msg.sender_id()),
+                    ("to_id", msg.to_id.as_deref().unwrap_or("n/a")),
+                    ("node_id", &msg.node_id),
+                    ("rssi", rssi.as_deref().unwrap_or("n/a")),
+                    ("snr", snr.as_deref().unwrap_or("n/a")),
+                    ("channel", &msg.channel_name),
+                    ("preset", &preset_short),
+                ],
+            )
+        }
+        None => {
+            let (body, formatted_body) = format_message_bodies(&prefix, embedded_name, &text);
+            match bridge_cfg.html_layout {
+                HtmlLayout::Code => (body, formatted_body),
+                HtmlLayout::Signal => {
+                    let short = node.as_ref().and_then(short_name);
+                    let rssi = rssi.as_ref().map(|rssi| format!("RSSI {rssi}"));
+                    let snr = snr.as_ref().map(|snr| format!("SNR {snr}"));
+                    let channel = format!("{}/{}", msg.channel_name, preset_short);
+                    let signal: Vec<&str> = [rssi.as_deref(), snr.as_deref(), Some(&channel)]
+                        .into_iter()
+                        .flatten()
+                        .collect();
+                    let sender = short.as_deref().unwrap_or(&display_name);
+                    (body, format_signal_html(sender, &text, &signal))
+                }
+            }
+        }
+    };
+    if let Some(template) = &bridge_cfg.permalink_template {
+        let url = template.replace("{id}", &msg.id.to_string());
+        body.push_str(&format!(" {}", url));
+        formatted_body.push_str(&format!(" <a href=\"{}\">↗</a>", text::escape_html(&url)));
+    }
+    if let Some(fallback) = reply_fallback(potato, bridge_cfg, view.parent.as_ref()).await {
+        body = format!("{}\n\n{}", fallback, body);
+    }
+
+    let in_reply_to = view
+        .parent
+        .as_ref()
+        .and_then(|parent| parent.event_id.clone());
+    Ok(Prepared::Send(OutgoingMessage {
+        user_id,
+        room_id,
+        body,
+        formatted_body,
+        in_reply_to,
+        text: text.into_owned(),
+        prepared_at: view.now,
+    }))
+}
+
+/// Record `out`, sent as `event_id`, for replies, repeats and metrics. The
+/// checkpoint is left to the caller.
+fn record_sent(
+    matrix: &MatrixAppserviceClient,
+    bridge_cfg: &BridgeConfig,
+    state: &mut BridgeState,
+    msg: &PotatoMessage,
+    out: OutgoingMessage,
+    event_id: String,
+) {
+    Span::current().record("event_id", event_id.as_str());
+    info!(
+        message_id = msg.id,
+        node_id = msg.node_id.as_str(),
+        room_id = out.room_id.as_str(),
+        event_id = event_id.as_str(),
+        "Bridged message"
+    );
+    journal_delivery(bridge_cfg, msg, &out.room_id, &event_id);
+    debug!("Bridged message: {:?}", msg);
+    state.metrics.record_forwarded();
+    if let (Some(created), Some(replaces)) = (matrix.created_room_id(), &matrix.cfg.room_id) {
+        state.created_room = Some(CreatedRoom {
+            replaces: replaces.clone(),
+            room_id: created,
+        });
+    }
+    if bridge_cfg.snr_trend {
+        state.record_snr(&msg.node_id, msg.snr);
+    }
+    // The send may have moved the default room to a newly created one.
+    let room_id = matrix
+        .room_for_message(msg.channel, msg.is_broadcast())
+        .unwrap_or(out.room_id);
+    let quote = bridge_cfg.reply_cold_start == ReplyColdStart::Quote;
+    state.recent_messages.record(RecentMessage {
+        id: msg.id,
+        node_id: msg.node_id.clone(),
+        text: quote.then(|| out.text.clone()),
+        event_id: Some(event_id.clone()),
+        room_id: Some(room_id.clone()),
+    });
+    state.last_sent.insert(
+        room_id,
+        LastSent {
+            text: out.text,
+            event_id,
+            sent_at: out.prepared_at,
+        },
+    );
+    let rx_time = effective_rx_time(msg, bridge_cfg.max_future_skew_secs, out.prepared_at);
+    debug!(
+        message_id = msg.id,
+        delay_secs = out.prepared_at.saturating_sub(rx_time),
+        "Message bridged after receipt"
+    );
+}
+
+/// `rx_time` to use for age calculations, clamped to `now` when it runs more
+/// than `max_future_skew_secs` ahead (a node with a wrong clock). The message
+/// itself and the fetch checkpoint keep the reported value.
+fn effective_rx_time(msg: &PotatoMessage, max_future_skew_secs: Option<u64>, now: u64) -> u64 {
+    match max_future_skew_secs {
+        Some(max_skew) if msg.rx_time > now.saturating_add(max_skew) => {
+            debug!(
+                "Message {} has rx_time {} {}s in the future; using now",
+                msg.id,
+                msg.rx_time,
+                msg.rx_time - now
+            );
+            now
+        }
+        _ => msg.rx_time,
+    }
+}
+
+/// Whether a message merely repeats its sender's short or long name, as some
+/// beacons do. Unknown node metadata never counts as an echo.
+async fn is_name_echo(potato: &PotatoClient, msg: &PotatoMessage) -> bool {
+    match potato.get_node(&msg.node_id).await {
+        Ok(node) => text_matches_node_name(&msg.text, &node),
+        Err(e) => {
+            warn!("Cannot check message {} for a name echo: {:?}", msg.id, e);
+            false
+        }
+    }
+}
+
+/// Compare message text against a node's names, ignoring surrounding whitespace.
+fn text_matches_node_name(text: &str, node: &PotatoNode) -> bool {
+    let text = text.trim();
+    if text.is_empty() {
+        return false;
+    }
+    text == node.long_name.trim() || node.short_name.as_deref().map(str::trim) == Some(text)
+}
+
+/// Post a notice for a text-less position packet when the sender's position
+/// changed since the last one announced.
+///
+/// Packets carry no coordinates, so the node is re-fetched for its current
+/// position; nodes without one are skipped.
+async fn announce_position(
+    potato: &PotatoClient,
+    matrix: &MatrixAppserviceClient,
+    template: &str,
+    state: &mut BridgeState,
+    msg: &PotatoMessage,
+) -> Result<()> {
+    let node = potato.refresh_node(&msg.node_id).await?;
+    let (Some(lat), Some(lon)) = (node.latitude, node.longitude) else {
+        debug!("Node {} has no position to announce", msg.node_id);
+        return Ok(());
+    };
+    let key = potatomesh::normalize_node_hex(&msg.node_id);
+    if state.last_positions.get(&key) == Some(&(lat, lon)) {
+        debug!("Position of {} unchanged; not announcing", msg.node_id);
+        return Ok(());
+    }
+
+    let body = render_position_beacon(template, &short_or_long_name(&node), lat, lon);
+    matrix
+        .send_notice(
+            &matrix.room_for_channel(msg.channel)?,
+            NoticeLevel::Info,
+            &body,
+        )
+        .await?;
+    state.last_positions.insert(key, (lat, lon));
+    Ok(())
+}
+
+/// Post a text-less position packet as an `m.location` event from the
+/// sender, once the node moved at least `location_min_distance_m` from the
+/// last position posted for it.
+///
+/// Like [`announce_position`], the coordinates come from a fresh lookup of
+/// the node.
+async fn send_position_location(
+    potato: &PotatoClient,
+    matrix: &MatrixAppserviceClient,
+    bridge_cfg: &BridgeConfig,
+    state: &mut BridgeState,
+    msg: &PotatoMessage,
+) -> Result<()> {
+    let node = potato.refresh_node(&msg.node_id).await?;
+    let (Some(lat), Some(lon)) = (node.latitude, node.longitude) else {
+        debug!("Node {} has no position to send", msg.node_id);
+        return Ok(());
+    };
+    let key = potatomesh::normalize_node_hex(&msg.node_id);
+    if let Some(&last) = state.last_positions.get(&key) {
+        if distance_m(last, (lat, lon)) < bridge_cfg.location_min_distance_m {
+            debug!("{} has not moved far enough; not sending", msg.node_id);
+            return Ok(());
+        }
+    }
+
+    let localpart = sender_localpart(matrix, bridge_cfg, msg);
+    let user_id = matrix.user_id(&localpart);
+    let room_id = matrix.room_for_channel(msg.channel)?;
+    let sender_name = match bridge_cfg.sender_mode {
+        SenderMode::Puppet => display_name_for_node(&node, bridge_cfg.max_display_name_chars),
+        SenderMode::ChannelBot => msg.channel_name.clone(),
+    };
+    matrix.ensure_user_registered(&localpart).await?;
+    matrix.ensure_user_joined_room(&user_id, &room_id).await?;
+    matrix.set_display_name(&user_id, &sender_name).await?;
+
+    let body = render_position_beacon(
+        "{name} moved to {lat}, {lon}",
+        &short_or_long_name(&node),
+        lat,
+        lon,
+    );
+    matrix
+        .send_location_as(&user_id, &room_id, lat, lon, &body)
+        .await?;
+    state.last_positions.insert(key, (lat, lon));
+    Ok(())
+}
+
+/// Great-circle distance in meters between two `(lat, lon)` points.
+fn distance_m(a: (f64, f64), b: (f64, f64)) -> f64 {
+    const EARTH_RADIUS_M: f64 = 6_371_000.0;
+    let (lat_a, lat_b) = (a.0.to_radians(), b.0.to_radians());
+    let d_lat = lat_b - lat_a;
+    let d_lon = (b.1 - a.1).to_radians();
+    let h = (d_lat / 2.0).sin().powi(2) + lat_a.cos() * lat_b.cos() * (d_lon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_M * h.sqrt().asin()
+}
+
+/// Matrix localpart of the user that sends `msg`, per `sender_mode`.
+fn sender_localpart(
+    matrix: &MatrixAppserviceClient,
+    bridge_cfg: &BridgeConfig,
+    msg: &PotatoMessage,
+) -> String {
+    match bridge_cfg.sender_mode {
+        SenderMode::Puppet => matrix.localpart_from_node_id(&msg.node_id),
+        SenderMode::ChannelBot => matrix.localpart_from_channel(&msg.channel_name, msg.channel),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{DeliveryJournal, MatrixConfig, PotatomeshConfig, RetryConfig, SinceUnit};
+    use crate::matrix::MatrixAppserviceClient;
+    use crate::potatomesh::PotatoClient;
+    use crate::state::NodeFailures;
+    use crate::subcommands::missing_deliveries;
+    use std::collections::{BTreeSet, HashMap};
+    use std::sync::{Arc, Mutex};
+
+    fn sample_msg(id: u64) -> PotatoMessage {
+        PotatoMessage {
+            id,
+            rx_time: 0,
+            rx_iso: "2025-11-27T00:00:00Z".to_string(),
+            from_id: Some("!abcd1234".to_string()),
+            to_id: Some("^all".to_string()),
+            channel: 1,
+            portnum: Some("TEXT_MESSAGE_APP".to_string()),
+            text: "Ping".to_string(),
+            rssi: Some(-100),
+            hop_limit: Some(1),
+            hops: None,
+            lora_freq: 868,
+            modem_preset: "MediumFast".to_string(),
+            channel_name: "TEST".to_string(),
+            snr: Some(0.0),
+            reply_id: None,
+            node_id: "!abcd1234".to_string(),
+            protocol: Some("meshtastic".to_string()),
+            merged_ids: Vec::new(),
+        }
+    }
+
+    fn sample_node(short_name: Option<&str>, long_name: &str) -> PotatoNode {
+        PotatoNode {
+            node_id: "!abcd1234".to_string(),
+            short_name: short_name.map(str::to_string),
+            long_name: long_name.to_string(),
+            role: None,
+            hw_model: None,
+            last_heard: None,
+            first_heard: None,
+            latitude: None,
+            longitude: None,
+            altitude: None,
+        }
+    }
+
+    fn offline_potato() -> PotatoClient {
+        PotatoClient::new(
+            reqwest::Client::new(),
+            PotatomeshConfig::for_test("http://localhost:8080"),
+        )
+    }
+
+    #[tokio::test]
+    async fn flush_nodes_cache_writes_file() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("nodes_cache.json");
+        let potato = offline_potato();
+
+        flush_nodes_cache(&potato, path.to_str().unwrap()).await;
+
+        assert!(path.exists());
+    }
+
+    #[tokio::test]
+    async fn flush_nodes_cache_logs_on_error() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let potato = offline_potato();
+
+        // Writing to a directory path fails; the error is logged, not raised.
+        flush_nodes_cache(&potato, tmp_dir.path().to_str().unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn spawn_synapse_listener_starts_task() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let txn_path = tmp_dir.path().join("txns.json");
+        let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        let handle = spawn_synapse_listener(
+            addr,
+            "HS_TOKEN".to_string(),
+            txn_path.to_str().unwrap().to_string(),
+            offline_potato(),
+            Metrics::default(),
+            mpsc::channel(1).0,
+            BridgedRooms::default(),
+        );
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        handle.abort();
+    }
+
+    #[tokio::test]
+    async fn spawn_synapse_listener_logs_error_on_bind_failure() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let txn_path = tmp_dir.path().join("txns.json");
+        let handle = spawn_synapse_listener(
+            addr,
+            "HS_TOKEN".to_string(),
+            txn_path.to_str().unwrap().to_string(),
+            offline_potato(),
+            Metrics::default(),
+            mpsc::channel(1).0,
+            BridgedRooms::default(),
+        );
+        let _ = handle.await;
+    }
+
+    #[tokio::test]
+    async fn poll_once_leaves_state_unchanged_without_messages() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let state_path = tmp_dir.path().join("state.json");
+        let state_str = state_path.to_str().unwrap();
+
+        let mut server = mockito::Server::new_async().await;
+        let mock_msgs = server
+            .mock("GET", "/api/messages")
+            .match_query(mockito::Matcher::Any)
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body("[]")
+            .create();
+
+        let http_client = reqwest::Client::new();
+        let potatomesh_cfg = PotatomeshConfig {
+            ..PotatomeshConfig::for_test(&server.url())
+        };
+        let matrix_cfg = MatrixConfig {
+            homeserver: server.url(),
+            as_token: "AS_TOKEN".to_string(),
+            hs_token: "HS_TOKEN".to_string(),
+            server_name: "example.org".to_string(),
+            room_id: Some("!roomid:example.org".to_string()),
+            log_room: None,
+            max_retry_after_secs: 60,
+            auto_create_room: None,
+            membership_check: Default::default(),
+            channel_rooms: Default::default(),
+            direct_room: None,
+            rich_notices: false,
+            max_rate_limit_retries: 3,
+            sends_per_sec: 0.0,
+            puppet_prefix: "potato_".to_string(),
+        };
+
+        let potato = PotatoClient::new(http_client.clone(), potatomesh_cfg);
+        let matrix = MatrixAppserviceClient::new(http_client, matrix_cfg);
+
+        let mut state = BridgeState {
+            last_message_id: HashMap::from([(1, 1)]),
+            last_rx_time: Some(100),
+            last_rx_time_ids: vec![1],
+            last_checked_at: None,
+            ..Default::default()
+        };
+
+        poll_once(
+            &potato,
+            &matrix,
+            &BridgeConfig::default(),
+            &mut state,
+            state_str,
+        )
+        .await;
+
+        mock_msgs.assert();
+
+        // No new data means state remains unchanged and is not persisted.
+        assert_eq!(state.last_rx_time, Some(100));
+        assert_eq!(state.last_rx_time_ids, vec![1]);
+        assert!(!state_path.exists());
+    }
+
+    #[tokio::test]
+    async fn poll_once_persists_state_for_non_text_messages() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let state_path = tmp_dir.path().join("state.json");
+        let state_str = state_path.to_str().unwrap();
+
+        let mut server = mockito::Server::new_async().await;
+        let mock_msgs = server
+            .mock("GET", "/api/messages")
+            .match_query(mockito::Matcher::Any)
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(
+                r#"[{"id":1,"rx_time":100,"rx_iso":"2025-11-27T00:00:00Z","from_id":"!abcd1234","to_id":"^all","channel":1,"portnum":"POSITION_APP","text":"","rssi":-100,"hop_limit":1,"lora_freq":868,"modem_preset":"MediumFast","channel_name":"TEST","snr":0.0,"node_id":"!abcd1234"}]"#,
+            )
+            .create();
+
+        let http_client = reqwest::Client::new();
+        let potatomesh_cfg = PotatomeshConfig {
+            ..PotatomeshConfig::for_test(&server.url())
+        };
+        let matrix_cfg = MatrixConfig {
+            homeserver: server.url(),
+            as_token: "AS_TOKEN".to_string(),
+            hs_token: "HS_TOKEN".to_string(),
+            server_name: "example.org".to_string(),
+            room_id: Some("!roomid:example.org".to_string()),
+            log_room: None,
+            max_retry_after_secs: 60,
+            auto_create_room: None,
+            membership_check: Default::default(),
+            channel_rooms: Default::default(),
+            direct_room: None,
+            rich_notices: false,
+            max_rate_limit_retries: 3,
+            sends_per_sec: 0.0,
+            puppet_prefix: "potato_".to_string(),
+        };
+
+        let potato = PotatoClient::new(http_client.clone(), potatomesh_cfg);
+        let matrix = MatrixAppserviceClient::new(http_client, matrix_cfg);
+        let mut state = BridgeState::default();
+
+        poll_once(
+            &potato,
+            &matrix,
+            &BridgeConfig::default(),
+            &mut state,
+            state_str,
+        )
+        .await;
+
+        mock_msgs.assert();
+        assert!(state_path.exists());
+        let loaded = BridgeState::load(state_str).unwrap();
+        assert_eq!(loaded.last_message_id(1), Some(1));
+        assert_eq!(loaded.last_rx_time, Some(100));
+        assert_eq!(loaded.last_rx_time_ids, vec![1]);
+    }
+
+    #[test]
+    fn text_matches_node_name_checks_short_and_long_names() {
+        let node = sample_node(Some("TN"), "Test Node");
+        assert!(text_matches_node_name("Test Node", &node));
+        assert!(text_matches_node_name(" TN ", &node));
+        assert!(!text_matches_node_name("Hello Test Node", &node));
+        assert!(!text_matches_node_name("", &sample_node(Some(""), "")));
+    }
+
+    #[test]
+    fn effective_rx_time_clamps_future_timestamp_to_now() {
+        let msg = PotatoMessage {
+            rx_time: 10_000,
+            ..sample_msg(1)
+        };
+        assert_eq!(effective_rx_time(&msg, Some(300), 1_000), 1_000);
+        // Unchecked without a configured skew.
+        assert_eq!(effective_rx_time(&msg, None, 1_000), 10_000);
+    }
+
+    #[test]
+    fn effective_rx_time_keeps_timestamp_within_skew() {
+        let msg = PotatoMessage {
+            rx_time: 1_200,
+            ..sample_msg(1)
+        };
+        assert_eq!(effective_rx_time(&msg, Some(300), 1_000), 1_200);
+        let past = PotatoMessage {
+            rx_time: 900,
+            ..sample_msg(1)
+        };
+        assert_eq!(effective_rx_time(&past, Some(300), 1_000), 900);
+    }
+
+    /// `/api/messages` entry for a message with id 1 from `!abcd1234`.
+    fn message_json(text: &str) -> serde_json::Value {
+        serde_json::json!({
+            "id": 1, "rx_time": 100, "rx_iso": "2025-11-27T00:00:00Z",
+            "from_id": "!abcd1234", "to_id": "^all", "channel": 1,
+            "portnum": "TEXT_MESSAGE_APP", "text": text, "lora_freq": 868,
+            "modem_preset": "MediumFast", "channel_name": "TEST", "node_id": "!abcd1234"
+        })
+    }
+
+    /// A text message with the given id and `rx_time` from node `!{hex}`.
+    fn message_from(id: u64, rx_time: u64, hex: &str) -> serde_json::Value {
+        let mut msg = message_json("Ping");
+        msg["id"] = id.into();
+        msg["rx_time"] = rx_time.into();
+        msg["from_id"] = format!("!{hex}").into();
+        msg["node_id"] = format!("!{hex}").into();
+        msg
+    }
+
+    /// A poll run by [`run_poll`]. Build one with [`TestPoll::of`] or
+    /// [`TestPoll::single`] and override the rest with struct update syntax.
+    struct TestPoll<'a> {
+        /// What `/api/messages` returns.
+        messages: serde_json::Value,
+        state: BridgeState,
+        now: u64,
+        time_source: CheckpointTimeSource,
+        /// Client to forward with; one for `server` when unset.
+        matrix: Option<&'a MatrixAppserviceClient>,
+    }
+
+    impl TestPoll<'_> {
+        /// A poll from a default state, at the current time, returning
+        /// `messages`.
+        fn of(messages: serde_json::Value) -> Self {
+            Self {
+                messages,
+                state: BridgeState::default(),
+                now: potatomesh::now_secs(),
+                time_source: CheckpointTimeSource::default(),
+                matrix: None,
+            }
+        }
+
+        /// [`TestPoll::of`] a single [`message_json`] on `portnum`.
+        fn single(portnum: &str, text: &str) -> Self {
+            let mut message = message_json(text);
+            message["portnum"] = portnum.into();
+            Self::of(serde_json::json!([message]))
+        }
+    }
+
+    /// Run `poll` against `server` and return the resulting state. Node and
+    /// Matrix mocks are left to the caller.
+    async fn run_poll(
+        server: &mut mockito::ServerGuard,
+        bridge_cfg: &BridgeConfig,
+        poll: TestPoll<'_>,
+    ) -> BridgeState {
+        let TestPoll {
+            messages,
+            mut state,
+            now,
+            time_source,
+            matrix,
+        } = poll;
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let state_path = tmp_dir.path().join("state.json");
+        let _mock_msgs = server
+            .mock("GET", "/api/messages")
+            .match_query(mockito::Matcher::Any)
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(messages.to_string())
+            .create();
+
+        let potato = PotatoClient::new(
+            reqwest::Client::new(),
+            PotatomeshConfig {
+                checkpoint_time_source: time_source,
+                ..PotatomeshConfig::for_test(&server.url())
+            },
+        );
+        let own_matrix;
+        let matrix = match matrix {
+            Some(matrix) => matrix,
+            None => {
+                own_matrix = matrix_client_for(server);
+                &own_matrix
+            }
+        };
+        poll_once_at(
+            &potato,
+            matrix,
+            bridge_cfg,
+            &mut state,
+            state_path.to_str().unwrap(),
+            now,
+        )
+        .await;
+        state
+    }
+
+    /// Mock the full puppet + send chain and return the send mock.
+    fn mock_forward_chain(server: &mut mockito::ServerGuard) -> mockito::Mock {
+        server
+            .mock("POST", "/_matrix/client/v3/register")
+            .match_query(mockito::Matcher::Any)
+            .with_status(200)
+            .create();
+        server
+            .mock(
+                "POST",
+                mockito::Matcher::Regex(r"/_matrix/client/v3/rooms/.+/join".to_string()),
+            )
+            .match_query(mockito::Matcher::Any)
+            .with_status(200)
+            .create();
+        server
+            .mock(
+                "PUT",
+                mockito::Matcher::Regex(r"/_matrix/client/v3/profile/.+/displayname".to_string()),
+            )
+            .match_query(mockito::Matcher::Any)
+            .with_status(200)
+            .create();
+        server
+            .mock(
+                "PUT",
+                mockito::Matcher::Regex(r"/_matrix/client/v3/rooms/.+/send/.+".to_string()),
+            )
+            .match_query(mockito::Matcher::Any)
+            .with_status(200)
+            .with_body(r#"{"event_id":"$bridged"}"#)
+    }
+
+    fn mock_test_node(server: &mut mockito::ServerGuard) {
+        server
+            .mock("GET", "/api/nodes/abcd1234")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"node_id": "!abcd1234", "long_name": "Test Node", "short_name": "TN"}"#)
+            .create();
+    }
+
+    #[tokio::test]
+    async fn poll_once_drops_name_echo_and_advances_checkpoint() {
+        let mut server = mockito::Server::new_async().await;
+        mock_test_node(&mut server);
+        let mock_send = mock_forward_chain(&mut server).expect(0).create();
+        let bridge_cfg = BridgeConfig {
+            drop_name_echo: true,
+            ..BridgeConfig::default()
+        };
+
+        let state = run_poll(
+            &mut server,
+            &bridge_cfg,
+            TestPoll::single("TEXT_MESSAGE_APP", "Test Node"),
+        )
+        .await;
+
+        mock_send.assert();
+        assert_eq!(state.last_message_id(1), Some(1));
+        assert_eq!(state.last_rx_time, Some(100));
+    }
+
+    #[tokio::test]
+    async fn poll_once_forwards_normal_message_with_name_echo_enabled() {
+        let mut server = mockito::Server::new_async().await;
+        mock_test_node(&mut server);
+        let mock_send = mock_forward_chain(&mut server).expect(1).create();
+        let bridge_cfg = BridgeConfig {
+            drop_name_echo: true,
+            ..BridgeConfig::default()
+        };
+
+        let state = run_poll(
+            &mut server,
+            &bridge_cfg,
+            TestPoll::single("TEXT_MESSAGE_APP", "Ping"),
+        )
+        .await;
+
+        mock_send.assert();
+        assert_eq!(state.last_message_id(1), Some(1));
+    }
+
+    #[tokio::test]
+    async fn poll_once_forwards_message_with_null_sender_fields() {
+        let mut server = mockito::Server::new_async().await;
+        mock_test_node(&mut server);
+        let mock_send = mock_forward_chain(&mut server).expect(2).create();
+        let mut message = message_json("Ping");
+        message["from_id"] = serde_json::Value::Null;
+        message["to_id"] = serde_json::Value::Null;
+        message["modem_preset"] = serde_json::Value::Null;
+        message["channel_name"] = serde_json::Value::Null;
+        let mut next = message_from(2, 200, "abcd1234");
+        next["text"] = "Pong".into();
+
+        let state = run_poll(
+            &mut server,
+            &BridgeConfig::default(),
+            TestPoll::of(serde_json::json!([message, next])),
+        )
+        .await;
+
+        mock_send.assert();
+        assert_eq!(state.last_message_id(1), Some(2));
+    }
+
+    #[tokio::test]
+    async fn poll_once_coalesces_rapid_messages_from_one_node() {
+        let mut server = mockito::Server::new_async().await;
+        mock_test_node(&mut server);
+        let mock_send = mock_forward_chain(&mut server)
+            .match_body(mockito::Matcher::PartialJson(serde_json::json!({
+                "body": "`[MT][868][MF][TEST]` one\ntwo\nthree"
+            })))
+            .expect(1)
+            .create();
+        let mut messages = Vec::new();
+        for (id, rx_time, text) in [(1, 100, "one"), (2, 104, "two "), (3, 110, "three")] {
+            let mut msg = message_from(id, rx_time, "abcd1234");
+            msg["text"] = text.into();
+            messages.push(msg);
+        }
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let bridge_cfg = BridgeConfig {
+            coalesce_secs: Some(8),
+            delivery_journal: Some(journal_in(&tmp_dir)),
+            ..BridgeConfig::default()
+        };
+
+        let state = run_poll(
+            &mut server,
+            &bridge_cfg,
+            TestPoll::of(serde_json::Value::from(messages.clone())),
+        )
+        .await;
+
+        mock_send.assert();
+        assert_eq!(state.last_message_id(1), Some(3));
+        for msg in messages {
+            let msg: PotatoMessage = serde_json::from_value(msg).unwrap();
+            assert!(!state.should_forward(&msg));
+        }
+        let journal = bridge_cfg.delivery_journal.unwrap();
+        assert_eq!(
+            journal::delivered_ids(&journal).unwrap(),
+            std::collections::HashSet::from([1, 2, 3])
+        );
+    }
+
+    #[test]
+    fn coalesce_messages_splits_on_node_gap_and_reply() {
+        let at = |id, rx_time, node: &str| PotatoMessage {
+            rx_time,
+            node_id: node.to_string(),
+            ..sample_msg(id)
+        };
+        let msgs = vec![
+            at(1, 100, "!abcd1234"),
+            at(2, 105, "!abcd1234"),
+            at(3, 106, "!0badc0de"),
+            at(4, 107, "!abcd1234"),
+            at(5, 120, "!abcd1234"),
+            PotatoMessage {
+                reply_id: Some(5),
+                ..at(6, 121, "!abcd1234")
+            },
+            at(7, 122, "!abcd1234"),
+        ];
+
+        let ids: Vec<Vec<u64>> = coalesce_messages(&msgs, 8, |_| true)
+            .iter()
+            .map(|group| group.iter().map(|msg| msg.id).collect())
+            .collect();
+        assert_eq!(ids, vec![vec![1, 2], vec![3], vec![4], vec![5], vec![6, 7]]);
+
+        let none_joinable = coalesce_messages(&msgs, 8, |_| false);
+        assert_eq!(none_joinable.len(), msgs.len());
+    }
+
+    #[tokio::test]
+    async fn poll_once_keeps_name_echo_when_node_metadata_unavailable() {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("GET", "/api/nodes/abcd1234")
+            .with_status(500)
+            .expect_at_least(1)
+            .create();
+        let bridge_cfg = BridgeConfig {
+            drop_name_echo: true,
+            ..BridgeConfig::default()
+        };
+
+        let state = run_poll(
+            &mut server,
+            &bridge_cfg,
+            TestPoll::single("TEXT_MESSAGE_APP", "Test Node"),
+        )
+        .await;
+
+        // Not dropped: the message went on to forwarding, failed on the same
+        // missing metadata, and stays queued for retry.
+        assert_eq!(state.last_message_id(1), None);
+        assert_eq!(state.failing_msg_id, Some(1));
+    }
+
+    fn maintenance_cfg() -> BridgeConfig {
+        toml::from_str(r#"maintenance_window = { start = "02:00", end = "03:00" }"#).unwrap()
+    }
+
+    const DURING_MAINTENANCE: u64 = 2 * 3600 + 600;
+    const AFTER_MAINTENANCE: u64 = 3 * 3600 + 600;
+
+    #[tokio::test]
+    async fn poll_once_holds_messages_during_maintenance_window() {
+        let mut server = mockito::Server::new_async().await;
+        let send_mock = mock_forward_chain(&mut server).expect(0).create();
+
+        let state = run_poll(
+            &mut server,
+            &maintenance_cfg(),
+            TestPoll {
+                now: DURING_MAINTENANCE,
+                ..TestPoll::single("TEXT_MESSAGE_APP", "Ping")
+            },
+        )
+        .await;
+
+        send_mock.assert();
+        assert_eq!(state.last_message_id(1), Some(1));
+        assert_eq!(state.last_rx_time, Some(100));
+        let ids: Vec<u64> = state.held_messages.iter().map(|m| m.id).collect();
+        assert_eq!(ids, vec![1]);
+
+        // Held messages are part of the persisted state.
+        let json = serde_json::to_string(&state).unwrap();
+        let restored: BridgeState = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.held_messages.len(), 1);
+        assert_eq!(restored.held_messages[0].text, "Ping");
+    }
+
+    #[tokio::test]
+    async fn poll_once_flushes_held_messages_after_maintenance_window() {
+        let mut server = mockito::Server::new_async().await;
+        mock_test_node(&mut server);
+        let send_mock = mock_forward_chain(&mut server).expect(1).create();
+        let cfg = maintenance_cfg();
+
+        let state = run_poll(
+            &mut server,
+            &cfg,
+            TestPoll {
+                now: DURING_MAINTENANCE,
+                ..TestPoll::single("TEXT_MESSAGE_APP", "Ping")
+            },
+        )
+        .await;
+        // The API keeps returning the held message; the checkpoint means it
+        // is only sent once, from the held buffer.
+        let state = run_poll(
+            &mut server,
+            &cfg,
+            TestPoll {
+                state,
+                now: AFTER_MAINTENANCE,
+                ..TestPoll::single("TEXT_MESSAGE_APP", "Ping")
+            },
+        )
+        .await;
+
+        send_mock.assert();
+        assert!(state.held_messages.is_empty());
+        assert_eq!(state.last_message_id(1), Some(1));
+    }
+
+    /// Poll a direct (0 hops), a relayed (2 hops) and a message without hop
+    /// data (ids 1, 2, 3), and return the ids that were bridged.
+    async fn poll_hop_mix(bridge_cfg: &BridgeConfig) -> Vec<u64> {
+        let mut server = mockito::Server::new_async().await;
+        mock_test_node(&mut server);
+        let _send_mock = mock_forward_chain(&mut server).create();
+        let messages = [(1, Some(0)), (2, Some(2)), (3, None)].map(|(id, hops)| {
+            let mut message = message_json("Ping");
+            message["id"] = id.into();
+            message["hops"] = hops.into();
+            message
+        });
+
+        let state = run_poll(
+            &mut server,
+            bridge_cfg,
+            TestPoll::of(serde_json::json!(messages)),
+        )
+        .await;
+
+        // Skipped messages still move the checkpoint.
+        assert_eq!(state.last_rx_time_ids, vec![1, 2, 3]);
+        (1..=3)
+            .filter(|id| {
+                state
+                    .recent_messages
+                    .get(*id)
+                    .is_some_and(|recent| recent.event_id.is_some())
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn poll_once_direct_only_filter_skips_relayed_messages() {
+        let bridge_cfg = BridgeConfig {
+            max_hops: Some(0),
+            ..BridgeConfig::default()
+        };
+        assert_eq!(poll_hop_mix(&bridge_cfg).await, vec![1, 3]);
+    }
+
+    #[tokio::test]
+    async fn poll_once_relayed_only_filter_skips_direct_messages() {
+        let bridge_cfg = BridgeConfig {
+            min_hops: Some(1),
+            ..BridgeConfig::default()
+        };
+        assert_eq!(poll_hop_mix(&bridge_cfg).await, vec![2, 3]);
+    }
+
+    #[tokio::test]
+    async fn poll_once_skips_messages_past_max_message_age() {
+        let mut server = mockito::Server::new_async().await;
+        mock_test_node(&mut server);
+        let send_mock = mock_forward_chain(&mut server).expect(2).create();
+        let now = 1_000_000;
+        // Too old, within the cutoff, and without a receive time.
+        let messages = [(1, now - 7200), (2, now - 60), (3, 0)].map(|(id, rx_time)| {
+            let mut message = message_json("Ping");
+            message["id"] = id.into();
+            message["rx_time"] = rx_time.into();
+            message
+        });
+        let bridge_cfg = BridgeConfig {
+            max_message_age_secs: Some(3600),
+            ..BridgeConfig::default()
+        };
+
+        let state = run_poll(
+            &mut server,
+            &bridge_cfg,
+            TestPoll {
+                now,
+                ..TestPoll::of(serde_json::json!(messages))
+            },
+        )
+        .await;
+
+        send_mock.assert();
+        assert!(state.recent_messages.get(1).is_none());
+        assert!(state.recent_messages.get(2).is_some());
+        assert!(state.recent_messages.get(3).is_some());
+        assert_eq!(state.last_message_id(1), Some(3));
+    }
+
+    #[tokio::test]
+    async fn poll_once_quotes_a_parent_that_failed_to_send_on_cold_start() {
+        let mut server = mockito::Server::new_async().await;
+        mock_test_node(&mut server);
+        let failed_mock = server
+            .mock(
+                "PUT",
+                mockito::Matcher::Regex(r"/_matrix/client/v3/rooms/.+/send/.+".to_string()),
+            )
+            .match_query(mockito::Matcher::Any)
+            .match_body(mockito::Matcher::PartialJson(serde_json::json!({
+                "body": "`[MT][868][MF][TEST]` Hello",
+            })))
+            .with_status(500)
+            .create();
+        let send_mock = mock_forward_chain(&mut server)
+            .match_body(mockito::Matcher::PartialJson(serde_json::json!({
+                "body": "> <TN> Hello\n\n`[MT][868][MF][TEST]` Ping",
+            })))
+            .create();
+        let mut reply = message_json("Ping");
+        reply["id"] = 2.into();
+        reply["reply_id"] = 1.into();
+        let bridge_cfg = BridgeConfig {
+            ordering: MessageOrdering::Relaxed,
+            reply_cold_start: ReplyColdStart::Quote,
+            ..BridgeConfig::default()
+        };
+
+        run_poll(
+            &mut server,
+            &bridge_cfg,
+            TestPoll {
+                now: 0,
+                ..TestPoll::of(serde_json::json!([message_json("Hello"), reply]))
+            },
+        )
+        .await;
+
+        failed_mock.assert();
+        send_mock.assert();
+    }
+
+    #[tokio::test]
+    async fn poll_once_never_quotes_or_caches_a_filtered_out_parent() {
+        let mut server = mockito::Server::new_async().await;
+        mock_test_node(&mut server);
+        let send_mock = mock_forward_chain(&mut server)
+            .match_body(mockito::Matcher::PartialJson(serde_json::json!({
+                "body": "`[MT][868][MF][TEST]` Ping",
+            })))
+            .create();
+        let now = 1_000_000;
+        // The parent is too old to bridge, and the state starts empty.
+        let mut parent = message_json("Hello");
+        parent["rx_time"] = (now - 7200).into();
+        let mut reply = message_json("Ping");
+        reply["id"] = 2.into();
+        reply["rx_time"] = (now - 60).into();
+        reply["reply_id"] = 1.into();
+        let bridge_cfg = BridgeConfig {
+            max_message_age_secs: Some(3600),
+            reply_cold_start: ReplyColdStart::Quote,
+            ..BridgeConfig::default()
+        };
+
+        let state = run_poll(
+            &mut server,
+            &bridge_cfg,
+            TestPoll {
+                now,
+                ..TestPoll::of(serde_json::json!([parent, reply]))
+            },
+        )
+        .await;
+
+        send_mock.assert();
+        assert!(state.recent_messages.get(1).is_none());
+        assert_eq!(
+            state.recent_messages.get(2).unwrap().text.as_deref(),
+            Some("Ping")
+        );
+    }
+
+    #[tokio::test]
+    async fn poll_once_keeps_no_text_without_cold_start_quotes() {
+        let mut server = mockito::Server::new_async().await;
+        mock_test_node(&mut server);
+        mock_forward_chain(&mut server).create();
+
+        let state = run_poll(
+            &mut server,
+            &BridgeConfig::default(),
+            TestPoll {
+                now: 0,
+                ..TestPoll::of(serde_json::json!([message_json("Hello")]))
+            },
+        )
+        .await;
+
+        let recent = state.recent_messages.get(1).unwrap();
+        assert_eq!(recent.event_id.as_deref(), Some("$bridged"));
+        assert_eq!(recent.room_id.as_deref(), Some("!roomid:example.org"));
+        assert_eq!(recent.text, None);
+    }
+
+    fn cooldown_cfg() -> BridgeConfig {
+        toml::from_str("node_cooldown = { failures = 2, secs = 600 }").unwrap()
+    }
+
+    const COOLDOWN_START: u64 = 1_700_000_000;
+
+    #[tokio::test]
+    async fn poll_once_parks_node_after_repeated_failures() {
+        let mut server = mockito::Server::new_async().await;
+        mock_test_node(&mut server);
+        let send_mock = mock_forward_chain(&mut server)
+            .with_status(500)
+            .expect(2)
+            .create();
+        let cfg = cooldown_cfg();
+
+        // First failure: retried in place, as without a cooldown.
+        let state = run_poll(
+            &mut server,
+            &cfg,
+            TestPoll {
+                now: COOLDOWN_START,
+                ..TestPoll::single("TEXT_MESSAGE_APP", "Ping")
+            },
+        )
+        .await;
+        assert_eq!(state.last_message_id(1), None);
+        assert!(state.cooldown_messages.is_empty());
+
+        // Second failure starts the cooldown: the message is parked and the
+        // checkpoint moves on.
+        let state = run_poll(
+            &mut server,
+            &cfg,
+            TestPoll {
+                state,
+                now: COOLDOWN_START + 10,
+                ..TestPoll::single("TEXT_MESSAGE_APP", "Ping")
+            },
+        )
+        .await;
+        assert_eq!(state.last_message_id(1), Some(1));
+        assert_eq!(state.cooldown_messages.len(), 1);
+
+        // Still cooling down: the parked message is left alone.
+        let state = run_poll(
+            &mut server,
+            &cfg,
+            TestPoll {
+                state,
+                now: COOLDOWN_START + 300,
+                ..TestPoll::single("TEXT_MESSAGE_APP", "Ping")
+            },
+        )
+        .await;
+        assert_eq!(state.cooldown_messages.len(), 1);
+
+        send_mock.assert();
+    }
+
+    #[tokio::test]
+    async fn poll_once_ignores_failures_during_startup_grace() {
+        let mut server = mockito::Server::new_async().await;
+        mock_test_node(&mut server);
+        let send_mock = mock_forward_chain(&mut server)
+            .with_status(500)
+            .expect(4)
+            .create();
+        let cfg = cooldown_cfg();
+        let mut state = BridgeState {
+            startup_grace_until: Some(COOLDOWN_START + 60),
+            ..BridgeState::default()
+        };
+
+        // Failures inside the grace period neither start a cooldown nor
+        // count toward the poison-message limit.
+        for offset in [0, 10] {
+            state = run_poll(
+                &mut server,
+                &cfg,
+                TestPoll {
+                    state,
+                    now: COOLDOWN_START + offset,
+                    ..TestPoll::single("TEXT_MESSAGE_APP", "Ping")
+                },
+            )
+            .await;
+        }
+        assert_eq!(state.last_message_id(1), None);
+        assert!(state.cooldown_messages.is_empty());
+        assert!(state.node_failures.is_empty());
+        assert_eq!(state.failing_msg_attempts, 0);
+
+        // After it, the same failures trip the cooldown as usual.
+        for offset in [60, 70] {
+            state = run_poll(
+                &mut server,
+                &cfg,
+                TestPoll {
+                    state,
+                    now: COOLDOWN_START + offset,
+                    ..TestPoll::single("TEXT_MESSAGE_APP", "Ping")
+                },
+            )
+            .await;
+        }
+        assert_eq!(state.last_message_id(1), Some(1));
+        assert_eq!(state.cooldown_messages.len(), 1);
+
+        send_mock.assert();
+    }
+
+    #[tokio::test]
+    async fn poll_once_retries_parked_messages_after_cooldown() {
+        let mut server = mockito::Server::new_async().await;
+        mock_test_node(&mut server);
+        let send_mock = mock_forward_chain(&mut server).expect(1).create();
+        let mut state = BridgeState {
+            last_message_id: HashMap::from([(1, 1)]),
+            last_rx_time: Some(100),
+            last_rx_time_ids: vec![1],
+            cooldown_messages: vec![PotatoMessage {
+                rx_time: 100,
+                ..sample_msg(1)
+            }],
+            ..BridgeState::default()
+        };
+        state.node_failures.insert(
+            "abcd1234".to_string(),
+            NodeFailures {
+                count: 2,
+                cooling_until: Some(COOLDOWN_START + 600),
+            },
+        );
+
+        let state = run_poll(
+            &mut server,
+            &cooldown_cfg(),
+            TestPoll {
+                state,
+                now: COOLDOWN_START + 601,
+                ..TestPoll::single("TEXT_MESSAGE_APP", "Ping")
+            },
+        )
+        .await;
+
+        send_mock.assert();
+        assert!(state.cooldown_messages.is_empty());
+        assert!(state.node_failures.is_empty());
+    }
+
+    #[tokio::test]
+    async fn poll_once_skips_disabled_channel_and_advances_checkpoint() {
+        let mut server = mockito::Server::new_async().await;
+        let send_mock = mock_forward_chain(&mut server).expect(0).create();
+        let bridge_cfg: BridgeConfig = toml::from_str(
+            r#"
+            [channels.TEST]
+            enabled = false
+        "#,
+        )
+        .unwrap();
+
+        let state = run_poll(
+            &mut server,
+            &bridge_cfg,
+            TestPoll::single("TEXT_MESSAGE_APP", "Ping"),
+        )
+        .await;
+
+        send_mock.assert();
+        assert_eq!(state.last_message_id(1), Some(1));
+    }
+
+    #[tokio::test]
+    async fn poll_once_forwards_enabled_channel() {
+        let mut server = mockito::Server::new_async().await;
+        mock_test_node(&mut server);
+        let send_mock = mock_forward_chain(&mut server).expect(1).create();
+        let bridge_cfg: BridgeConfig = toml::from_str(
+            r#"
+            [channels.TEST]
+            enabled = true
+        "#,
+        )
+        .unwrap();
+
+        let state = run_poll(
+            &mut server,
+            &bridge_cfg,
+            TestPoll::single("TEXT_MESSAGE_APP", "Ping"),
+        )
+        .await;
+
+        send_mock.assert();
+        assert_eq!(state.last_message_id(1), Some(1));
+    }
+
+    fn mock_positioned_node(server: &mut mockito::ServerGuard) -> mockito::Mock {
+        server
+            .mock("GET", "/api/nodes/abcd1234")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(
+                r#"{"node_id": "!abcd1234", "long_name": "Test Node", "short_name": "TN",
+                    "latitude": 52.464912, "longitude": 13.485301}"#,
+            )
+            .create()
+    }
+
+    fn position_beacon_cfg() -> BridgeConfig {
+        BridgeConfig {
+            position_beacon_template: Some("📍 {name} moved to ({lat}, {lon})".to_string()),
+            ..BridgeConfig::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn poll_once_announces_position_beacon() {
+        let mut server = mockito::Server::new_async().await;
+        let node_mock = mock_positioned_node(&mut server);
+        let notice_mock = server
+            .mock(
+                "PUT",
+                mockito::Matcher::Regex(
+                    r"/_matrix/client/v3/rooms/%21roomid%3Aexample.org/send/m.room.message/.+"
+                        .to_string(),
+                ),
+            )
+            .match_body(mockito::Matcher::Json(serde_json::json!({
+                "msgtype": "m.notice",
+                "body": "📍 TN moved to (52.4649, 13.4853)",
+            })))
+            .with_status(200)
+            .with_body(r#"{"event_id":"$bridged"}"#)
+            .expect(1)
+            .create();
+
+        let state = run_poll(
+            &mut server,
+            &position_beacon_cfg(),
+            TestPoll::single("POSITION_APP", ""),
+        )
+        .await;
+
+        node_mock.assert();
+        notice_mock.assert();
+        assert_eq!(state.last_message_id(1), Some(1));
+        assert_eq!(
+            state.last_positions.get("abcd1234"),
+            Some(&(52.464912, 13.485301))
+        );
+    }
+
+    fn location_events_cfg() -> BridgeConfig {
+        BridgeConfig {
+            location_events: true,
+            ..position_beacon_cfg()
+        }
+    }
+
+    #[tokio::test]
+    async fn poll_once_sends_position_as_location_event() {
+        let mut server = mockito::Server::new_async().await;
+        mock_positioned_node(&mut server);
+        let send_mock = mock_forward_chain(&mut server)
+            .match_body(mockito::Matcher::Json(serde_json::json!({
+                "msgtype": "m.location",
+                "body": "TN moved to 52.4649, 13.4853",
+                "geo_uri": "geo:52.464912,13.485301",
+            })))
+            .expect(1)
+            .create();
+        let mut state = BridgeState::default();
+        // About 200 m away.
+        state
+            .last_positions
+            .insert("abcd1234".to_string(), (52.463112, 13.485301));
+
+        let state = run_poll(
+            &mut server,
+            &location_events_cfg(),
+            TestPoll {
+                state,
+                ..TestPoll::single("POSITION_APP", "")
+            },
+        )
+        .await;
+
+        send_mock.assert();
+        assert_eq!(state.last_message_id(1), Some(1));
+        assert_eq!(
+            state.last_positions.get("abcd1234"),
+            Some(&(52.464912, 13.485301))
+        );
+    }
+
+    #[tokio::test]
+    async fn poll_once_skips_location_event_for_small_moves() {
+        let mut server = mockito::Server::new_async().await;
+        mock_positioned_node(&mut server);
+        let send_mock = server
+            .mock(
+                "PUT",
+                mockito::Matcher::Regex(r"/_matrix/client/v3/rooms/.+/send/.+".to_string()),
+            )
+            .expect(0)
+            .create();
+        let mut state = BridgeState::default();
+        // About 20 m away, under the 50 m default.
+        state
+            .last_positions
+            .insert("abcd1234".to_string(), (52.464732, 13.485301));
+
+        let state = run_poll(
+            &mut server,
+            &location_events_cfg(),
+            TestPoll {
+                state,
+                ..TestPoll::single("POSITION_APP", "")
+            },
+        )
+        .await;
+
+        send_mock.assert();
+        assert_eq!(state.last_message_id(1), Some(1));
+        assert_eq!(
+            state.last_positions.get("abcd1234"),
+            Some(&(52.464732, 13.485301))
+        );
+    }
+
+    #[test]
+    fn distance_m_matches_known_distances() {
+        assert_eq!(distance_m((52.5, 13.4), (52.5, 13.4)), 0.0);
+        // One thousandth of a degree of latitude is about 111 m.
+        let d = distance_m((52.464, 13.4853), (52.465, 13.4853));
+        assert!((d - 111.2).abs() < 0.5, "{d}");
+        // Berlin to Hamburg, roughly 255 km.
+        let d = distance_m((52.52, 13.405), (53.551, 9.994));
+        assert!((d - 255_000.0).abs() < 2_000.0, "{d}");
+    }
+
+    #[tokio::test]
+    async fn poll_once_suppresses_unchanged_position_beacon() {
+        let mut server = mockito::Server::new_async().await;
+        mock_positioned_node(&mut server);
+        let notice_mock = server
+            .mock(
+                "PUT",
+                mockito::Matcher::Regex(r"/_matrix/client/v3/rooms/.+/send/.+".to_string()),
+            )
+            .expect(0)
+            .create();
+        let mut state = BridgeState::default();
+        state
+            .last_positions
+            .insert("abcd1234".to_string(), (52.464912, 13.485301));
+
+        let state = run_poll(
+            &mut server,
+            &position_beacon_cfg(),
+            TestPoll {
+                state,
+                ..TestPoll::single("POSITION_APP", "")
+            },
+        )
+        .await;
+
+        notice_mock.assert();
+        assert_eq!(state.last_message_id(1), Some(1));
+    }
+
+    #[tokio::test]
+    async fn poll_once_spreads_registrations_across_polls() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let state_path = tmp_dir.path().join("state.json");
+        let state_str = state_path.to_str().unwrap();
+
+        let mut server = mockito::Server::new_async().await;
+        let msgs: Vec<serde_json::Value> = (1..=3)
+            .map(|id| {
+                serde_json::json!({
+                    "id": id, "rx_time": 100 + id, "rx_iso": "2025-11-27T00:00:00Z",
+                    "from_id": format!("!0000000{id}"), "to_id": "^all", "channel": 1,
+                    "portnum": "TEXT_MESSAGE_APP", "text": "Ping", "lora_freq": 868,
+                    "modem_preset": "MediumFast", "channel_name": "TEST",
+                    "node_id": format!("!0000000{id}")
+                })
+            })
+            .collect();
+        let _mock_msgs = server
+            .mock("GET", "/api/messages")
+            .match_query(mockito::Matcher::Any)
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(serde_json::Value::Array(msgs).to_string())
+            .create();
+        let _mock_nodes = server
+            .mock(
+                "GET",
+                mockito::Matcher::Regex(r"^/api/nodes/0000000\d$".to_string()),
+            )
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"node_id": "!00000001", "long_name": "Test Node"}"#)
+            .create();
+        let mock_register = server
+            .mock("POST", "/_matrix/client/v3/register")
+            .match_query(mockito::Matcher::Any)
+            .with_status(200)
+            .expect(3)
+            .create();
+        let mock_send = mock_forward_chain(&mut server).expect(3).create();
+
+        let http_client = reqwest::Client::new();
+        let potato = PotatoClient::new(
+            http_client.clone(),
+            PotatomeshConfig {
+                ..PotatomeshConfig::for_test(&server.url())
+            },
+        );
+        let matrix = MatrixAppserviceClient::new(
+            http_client,
+            MatrixConfig {
+                homeserver: server.url(),
+                as_token: "AS_TOKEN".to_string(),
+                hs_token: "HS_TOKEN".to_string(),
+                server_name: "example.org".to_string(),
+                room_id: Some("!roomid:example.org".to_string()),
+                log_room: None,
+                max_retry_after_secs: 60,
+                auto_create_room: None,
+                membership_check: Default::default(),
+                channel_rooms: Default::default(),
+                direct_room: None,
+                rich_notices: false,
+                max_rate_limit_retries: 3,
+                sends_per_sec: 0.0,
+                puppet_prefix: "potato_".to_string(),
+            },
+        );
+        let bridge_cfg = BridgeConfig {
+            max_registrations_per_poll: Some(2),
+            ..BridgeConfig::default()
+        };
+        let mut state = BridgeState::default();
+
+        poll_once(&potato, &matrix, &bridge_cfg, &mut state, state_str).await;
+        assert_eq!(state.last_message_id(1), Some(2));
+        assert_eq!(state.last_rx_time, Some(102));
+        assert_eq!(state.failing_msg_id, None);
+
+        poll_once(&potato, &matrix, &bridge_cfg, &mut state, state_str).await;
+        assert_eq!(state.last_message_id(1), Some(3));
+
+        mock_register.assert();
+        mock_send.assert();
+    }
+
+    /// Regression test for the watermark-advance bug: within a single batch,
+    /// an *earlier* message (lower `rx_time`) that fails to forward must not be
+    /// silently skipped forever just because a *later* message would succeed.
+    ///
+    /// The batch is `[A(rx_time=10, fails), B(rx_time=20, would succeed)]`.
+    /// `A` fails because its node lookup returns 500; `B`'s full forward chain
+    /// is mocked to succeed. With the fix, `poll_once` stops at the first
+    /// failure, so the watermark never advances past `A` and `A` stays eligible
+    /// for retry. Under the old code, `B` succeeded and pushed `last_rx_time` to
+    /// 20, permanently stranding `A` — which this test would catch.
+    #[tokio::test]
+    async fn poll_once_does_not_advance_watermark_past_failed_message() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let state_path = tmp_dir.path().join("state.json");
+        let state_str = state_path.to_str().unwrap();
+
+        let mut server = mockito::Server::new_async().await;
+
+        // Batch of two TEXT messages; poll_once sorts ascending by rx_time so A
+        // (rx_time=10) is processed before B (rx_time=20) regardless of order.
+        let mock_msgs = server
+            .mock("GET", "/api/messages")
+            .match_query(mockito::Matcher::Any)
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(
+                r#"[
+                    {"id":1,"rx_time":10,"rx_iso":"2025-11-27T00:00:00Z","from_id":"!aaaaaaaa","to_id":"^all","channel":1,"portnum":"TEXT_MESSAGE_APP","text":"Ping","lora_freq":868,"modem_preset":"MediumFast","channel_name":"TEST","node_id":"!aaaaaaaa"},
+                    {"id":2,"rx_time":20,"rx_iso":"2025-11-27T00:00:00Z","from_id":"!bbbbbbbb","to_id":"^all","channel":1,"portnum":"TEXT_MESSAGE_APP","text":"Ping","lora_freq":868,"modem_preset":"MediumFast","channel_name":"TEST","node_id":"!bbbbbbbb"}
+                ]"#,
+            )
+            .create();
+
+        // Earlier message A fails: its node lookup returns 500.
+        // Looked up once while warming the node cache and once more when A
+        // is handled.
+        let mock_node_a = server
+            .mock("GET", "/api/nodes/aaaaaaaa")
+            .match_query(mockito::Matcher::Any)
+            .with_status(500)
+            .expect(2)
+            .create();
+
+        // Later message B would fully succeed (node + register + join +
+        // displayname + send). These stay unused under the fix, but make the
+        // test a true regression: under the buggy code B forwards and advances
+        // the watermark to 20.
+        let _mock_node_b = server
+            .mock("GET", "/api/nodes/bbbbbbbb")
+            .match_query(mockito::Matcher::Any)
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"node_id":"!bbbbbbbb","long_name":"Node B","short_name":"NB"}"#)
+            .create();
+        let _mock_register = server
+            .mock("POST", "/_matrix/client/v3/register")
+            .match_query(mockito::Matcher::Any)
+            .with_status(200)
+            .create();
+        let _mock_join = server
+            .mock(
+                "POST",
+                mockito::Matcher::Regex(r"/_matrix/client/v3/rooms/.+/join".to_string()),
+            )
+            .match_query(mockito::Matcher::Any)
+            .with_status(200)
+            .create();
+        let _mock_display = server
+            .mock(
+                "PUT",
+                mockito::Matcher::Regex(r"/_matrix/client/v3/profile/.+/displayname".to_string()),
+            )
+            .match_query(mockito::Matcher::Any)
+            .with_status(200)
+            .create();
+        let _mock_send = server
+            .mock(
+                "PUT",
+                mockito::Matcher::Regex(r"/_matrix/client/v3/rooms/.+/send/.+".to_string()),
+            )
+            .match_query(mockito::Matcher::Any)
+            .with_status(200)
+            .with_body(r#"{"event_id":"$bridged"}"#)
+            .create();
+
+        let http_client = reqwest::Client::new();
+        let potatomesh_cfg = PotatomeshConfig {
+            ..PotatomeshConfig::for_test(&server.url())
+        };
+        let matrix_cfg = MatrixConfig {
+            homeserver: server.url(),
+            as_token: "AS_TOKEN".to_string(),
+            hs_token: "HS_TOKEN".to_string(),
+            server_name: "example.org".to_string(),
+            room_id: Some("!roomid:example.org".to_string()),
+            log_room: None,
+            max_retry_after_secs: 60,
+            auto_create_room: None,
+            membership_check: Default::default(),
+            channel_rooms: Default::default(),
+            direct_room: None,
+            rich_notices: false,
+            max_rate_limit_retries: 3,
+            sends_per_sec: 0.0,
+            puppet_prefix: "potato_".to_string(),
+        };
+
+        let potato = PotatoClient::new(http_client.clone(), potatomesh_cfg);
+        let matrix = MatrixAppserviceClient::new(http_client, matrix_cfg);
+        let mut state = BridgeState::default();
+
+        poll_once(
+            &potato,
+            &matrix,
+            &BridgeConfig::default(),
+            &mut state,
+            state_str,
+        )
+        .await;
+
+        // A's node lookup was attempted and failed.
+        mock_msgs.assert();
+        mock_node_a.assert();
+
+        // The watermark must NOT have advanced past the failed message, so A
+        // remains eligible to be refetched and retried on the next poll.
+        let msg_a = PotatoMessage {
+            id: 1,
+            rx_time: 10,
+            node_id: "!aaaaaaaa".to_string(),
+            ..sample_msg(1)
+        };
+        assert_eq!(
+            state.last_rx_time, None,
+            "watermark advanced despite a failed earlier message"
+        );
+        assert_ne!(
+            state.last_rx_time,
+            Some(20),
+            "watermark jumped past the failed message to the later success"
+        );
+        assert!(
+            state.should_forward(&msg_a),
+            "failed message must remain eligible for retry"
+        );
+    }
+
+    /// Poll two messages where the first one's node lookup fails, returning
+    /// the state and the send mock (expecting `sends` posts).
+    async fn poll_with_failing_first(
+        server: &mut mockito::ServerGuard,
+        ordering: MessageOrdering,
+        sends: usize,
+    ) -> (BridgeState, mockito::Mock) {
+        server
+            .mock("GET", "/api/nodes/aaaaaaaa")
+            .with_status(500)
+            .create();
+        server
+            .mock("GET", "/api/nodes/bbbbbbbb")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"node_id":"!bbbbbbbb","long_name":"Node B"}"#)
+            .create();
+        let send_mock = mock_forward_chain(server).expect(sends).create();
+        let bridge_cfg = BridgeConfig {
+            ordering,
+            ..BridgeConfig::default()
+        };
+        let state = run_poll(
+            server,
+            &bridge_cfg,
+            TestPoll {
+                now: 0,
+                ..TestPoll::of(serde_json::json!([
+                    message_from(1, 10, "aaaaaaaa"),
+                    message_from(2, 20, "bbbbbbbb"),
+                ]))
+            },
+        )
+        .await;
+        (state, send_mock)
+    }
+
+    /// Checkpoint left by polling messages received at 100 and 200 when the
+    /// bridge clock reads 1000.
+    async fn checkpoint_after_batch(time_source: CheckpointTimeSource) -> (BridgeState, u64) {
+        let mut server = mockito::Server::new_async().await;
+        mock_test_node(&mut server);
+        mock_forward_chain(&mut server).expect(2).create();
+        let state = run_poll(
+            &mut server,
+            &BridgeConfig::default(),
+            TestPoll {
+                now: 1000,
+                time_source,
+                ..TestPoll::of(serde_json::json!([
+                    message_from(1, 100, "abcd1234"),
+                    message_from(2, 200, "abcd1234"),
+                ]))
+            },
+        )
+        .await;
+        let since = build_fetch_params(&state, SinceUnit::Secs, time_source)
+            .since
+            .unwrap();
+        (state, since)
+    }
+
+    #[tokio::test]
+    async fn poll_once_updates_metrics() {
+        let (state, _) = checkpoint_after_batch(CheckpointTimeSource::MaxRxTime).await;
+
+        let text = state.metrics.render_prometheus();
+        assert!(text.contains("bridge_messages_forwarded_total 2\n"));
+        assert!(text.contains("bridge_last_message_id{channel=\"1\"} 2\n"));
+        assert_eq!(state.metrics.last_poll_secs_ago(1000), Some(0));
+
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("GET", "/api/messages")
+            .match_query(mockito::Matcher::Any)
+            .with_status(500)
+            .create();
+        let potato = PotatoClient::new(
+            reqwest::Client::new(),
+            PotatomeshConfig {
+                retry: RetryConfig {
+                    max_attempts: 1,
+                    ..Default::default()
+                },
+                ..PotatomeshConfig::for_test(&server.url())
+            },
+        );
+        let matrix = matrix_client_for(&server);
+        let mut state = state;
+        poll_once_at(
+            &potato,
+            &matrix,
+            &BridgeConfig::default(),
+            &mut state,
+            "",
+            1000,
+        )
+        .await;
+
+        assert!(state
+            .metrics
+            .render_prometheus()
+            .contains("bridge_fetch_errors_total 1\n"));
+    }
+
+    #[tokio::test]
+    async fn checkpoint_follows_max_rx_time_by_default() {
+        let (state, since) = checkpoint_after_batch(CheckpointTimeSource::MaxRxTime).await;
+
+        assert_eq!(since, 200);
+        assert_eq!(state.last_polled_at, None);
+    }
+
+    #[tokio::test]
+    async fn checkpoint_follows_bridge_clock_when_local() {
+        let (state, since) = checkpoint_after_batch(CheckpointTimeSource::Local).await;
+
+        assert_eq!(since, 1000);
+        assert_eq!(state.last_rx_time, Some(200));
+    }
+
+    #[tokio::test]
+    async fn local_checkpoint_stays_put_when_batch_stops() {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("GET", "/api/nodes/aaaaaaaa")
+            .with_status(500)
+            .create();
+        let state = BridgeState {
+            last_polled_at: Some(5),
+            ..BridgeState::default()
+        };
+        let state = run_poll(
+            &mut server,
+            &BridgeConfig::default(),
+            TestPoll {
+                state,
+                now: 1000,
+                time_source: CheckpointTimeSource::Local,
+                ..TestPoll::of(serde_json::json!([message_from(1, 100, "aaaaaaaa")]))
+            },
+        )
+        .await;
+
+        assert_eq!(state.last_polled_at, Some(5));
+    }
+
+    #[tokio::test]
+    async fn poll_once_strict_ordering_holds_back_later_messages() {
+        let mut server = mockito::Server::new_async().await;
+        let (state, send_mock) =
+            poll_with_failing_first(&mut server, MessageOrdering::Strict, 0).await;
+
+        send_mock.assert();
+        assert_eq!(state.last_rx_time, None);
+        assert!(state.retry_messages.is_empty());
+    }
+
+    /// Messages 1 to 3 on channels 0 to 2, received at 10, 20 and 30;
+    /// message 2 reads "Boom".
+    fn messages_on_three_channels() -> serde_json::Value {
+        let messages: Vec<_> = (1..=3u64)
+            .map(|id| {
+                let mut msg = message_from(id, id * 10, "abcd1234");
+                msg["channel"] = (id - 1).into();
+                if id == 2 {
+                    msg["text"] = "Boom".into();
+                }
+                msg
+            })
+            .collect();
+        serde_json::json!(messages)
+    }
+
+    fn concurrent_cfg() -> BridgeConfig {
+        BridgeConfig {
+            concurrency: 3,
+            ..BridgeConfig::default()
+        }
+    }
+
+    /// A Matrix client sending mesh channel `c` into room `rooms[c]`.
+    fn matrix_with_channel_rooms(
+        server: &mockito::ServerGuard,
+        rooms: &[&str],
+    ) -> MatrixAppserviceClient {
+        let mut cfg = matrix_client_for(server).cfg.clone();
+        cfg.channel_rooms = (0u8..)
+            .zip(rooms.iter().map(|room| room.to_string()))
+            .collect();
+        MatrixAppserviceClient::new(reqwest::Client::new(), cfg)
+    }
+
+    /// [`poll_messages_at`] of [`messages_on_three_channels`] with
+    /// [`concurrent_cfg`], the channels going into `rooms`.
+    async fn poll_three_channels_into(
+        server: &mut mockito::ServerGuard,
+        rooms: &[&str],
+        state: BridgeState,
+    ) -> BridgeState {
+        let matrix = matrix_with_channel_rooms(server, rooms);
+        run_poll(
+            server,
+            &concurrent_cfg(),
+            TestPoll {
+                state,
+                now: 0,
+                matrix: Some(&matrix),
+                ..TestPoll::of(messages_on_three_channels())
+            },
+        )
+        .await
+    }
+
+    const THREE_ROOMS: [&str; 3] = ["!a:example.org", "!b:example.org", "!c:example.org"];
+
+    #[tokio::test]
+    async fn poll_once_concurrent_sends_forward_the_whole_batch() {
+        let mut server = mockito::Server::new_async().await;
+        mock_test_node(&mut server);
+        let send_mock = mock_forward_chain(&mut server).expect(3).create();
+
+        let state = run_poll(
+            &mut server,
+            &concurrent_cfg(),
+            TestPoll {
+                now: 0,
+                ..TestPoll::of(messages_on_three_channels())
+            },
+        )
+        .await;
+
+        send_mock.assert();
+        assert_eq!(state.last_rx_time, Some(30));
+        assert_eq!(
+            state.recent_messages.get(2).unwrap().event_id.as_deref(),
+            Some("$bridged")
+        );
+    }
+
+    #[tokio::test]
+    async fn poll_once_concurrent_sends_do_not_pass_a_failed_message() {
+        let mut server = mockito::Server::new_async().await;
+        mock_test_node(&mut server);
+        let ping_mock = mock_forward_chain(&mut server)
+            .match_body(mockito::Matcher::Regex("Ping".to_string()))
+            .expect(2)
+            .create();
+        let boom_mock = server
+            .mock(
+                "PUT",
+                mockito::Matcher::Regex(r"/_matrix/client/v3/rooms/.+/send/.+".to_string()),
+            )
+            .match_query(mockito::Matcher::Any)
+            .match_body(mockito::Matcher::Regex("Boom".to_string()))
+            .with_status(500)
+            .create();
+
+        let state =
+            poll_three_channels_into(&mut server, &THREE_ROOMS, BridgeState::default()).await;
+
+        // Message 3 went out alongside 2, but the checkpoint stops at 1.
+        assert_eq!(state.last_rx_time, Some(10));
+        assert_eq!(state.delivered_ahead, BTreeSet::from([3]));
+
+        // Once 2 goes through, the checkpoint passes 3 without resending it.
+        boom_mock.remove();
+        let boom_mock = server
+            .mock(
+                "PUT",
+                mockito::Matcher::Regex(r"/_matrix/client/v3/rooms/.+/send/.+".to_string()),
+            )
+            .match_query(mockito::Matcher::Any)
+            .match_body(mockito::Matcher::Regex("Boom".to_string()))
+            .with_status(200)
+            .with_body(r#"{"event_id":"$boom"}"#)
+            .create();
+        let state = poll_three_channels_into(&mut server, &THREE_ROOMS, state).await;
+
+        ping_mock.assert();
+        boom_mock.assert();
+        assert_eq!(state.last_rx_time, Some(30));
+        assert!(state.delivered_ahead.is_empty());
+    }
+
+    #[tokio::test]
+    async fn poll_once_concurrent_sends_keep_one_send_per_room() {
+        let mut server = mockito::Server::new_async().await;
+        mock_test_node(&mut server);
+        let ping_mock = mock_forward_chain(&mut server)
+            .match_body(mockito::Matcher::Regex("Ping".to_string()))
+            .expect(1)
+            .create();
+        let boom_mock = server
+            .mock(
+                "PUT",
+                mockito::Matcher::Regex(r"/_matrix/client/v3/rooms/.+/send/.+".to_string()),
+            )
+            .match_query(mockito::Matcher::Any)
+            .match_body(mockito::Matcher::Regex("Boom".to_string()))
+            .with_status(500)
+            .create();
+        // Channels 1 and 2 share a room, so message 3 waits for 2.
+        let rooms = ["!a:example.org", "!b:example.org", "!b:example.org"];
+
+        let state = poll_three_channels_into(&mut server, &rooms, BridgeState::default()).await;
+
+        ping_mock.assert();
+        boom_mock.assert();
+        assert_eq!(state.last_rx_time, Some(10));
+        assert!(state.delivered_ahead.is_empty());
+    }
+
+    /// Poll [`messages_on_three_channels`] into the one configured room
+    /// with [`concurrent_cfg`] under `ordering`, holding the reply to the
+    /// send of message 2 until message 3 has been sent too (or a second has
+    /// passed). Returns whether they overlapped.
+    async fn sends_overlap_in_one_room(ordering: MessageOrdering) -> bool {
+        use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+        let mut server = mockito::Server::new_async().await;
+        mock_test_node(&mut server);
+        let pings = Arc::new(AtomicUsize::new(0));
+        let overlapped = Arc::new(AtomicBool::new(false));
+        let counter = pings.clone();
+        let ping_mock = mock_forward_chain(&mut server)
+            .match_body(mockito::Matcher::Regex("Ping".to_string()))
+            .with_body_from_request(move |_| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                r#"{"event_id":"$bridged"}"#.into()
+            })
+            .expect(2)
+            .create();
+        let (counter, seen) = (pings.clone(), overlapped.clone());
+        let boom_mock = server
+            .mock(
+                "PUT",
+                mockito::Matcher::Regex(r"/_matrix/client/v3/rooms/.+/send/.+".to_string()),
+            )
+            .match_query(mockito::Matcher::Any)
+            .match_body(mockito::Matcher::Regex("Boom".to_string()))
+            .with_status(200)
+            .with_chunked_body(move |w| {
+                let deadline = std::time::Instant::now() + Duration::from_secs(1);
+                while counter.load(Ordering::SeqCst) < 2 && std::time::Instant::now() < deadline {
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+                seen.store(counter.load(Ordering::SeqCst) == 2, Ordering::SeqCst);
+                w.write_all(br#"{"event_id":"$boom"}"#)
+            })
+            .create();
+        let bridge_cfg = BridgeConfig {
+            ordering,
+            ..concurrent_cfg()
+        };
+
+        let state = run_poll(
+            &mut server,
+            &bridge_cfg,
+            TestPoll {
+                now: 0,
+                ..TestPoll::of(messages_on_three_channels())
+            },
+        )
+        .await;
+
+        ping_mock.assert();
+        boom_mock.assert();
+        assert_eq!(state.last_rx_time, Some(30));
+        overlapped.load(Ordering::SeqCst)
+    }
+
+    #[tokio::test]
+    async fn poll_once_relaxed_concurrent_sends_overlap_in_one_room() {
+        assert!(sends_overlap_in_one_room(MessageOrdering::Relaxed).await);
+    }
+
+    #[tokio::test]
+    async fn poll_once_strict_concurrent_sends_take_turns_in_one_room() {
+        assert!(!sends_overlap_in_one_room(MessageOrdering::Strict).await);
+    }
+
+    #[tokio::test]
+    async fn poll_once_relaxed_concurrent_sends_collapse_repeats_in_one_room() {
+        let mut server = mockito::Server::new_async().await;
+        mock_test_node(&mut server);
+        let post = mock_forward_chain(&mut server)
+            .match_body(mockito::Matcher::Regex("m.text".to_string()))
+            .expect(1)
+            .create();
+        let reaction = mock_forward_chain(&mut server)
+            .match_body(mockito::Matcher::Regex("m.annotation".to_string()))
+            .expect(1)
+            .create();
+        let bridge_cfg = BridgeConfig {
+            ordering: MessageOrdering::Relaxed,
+            collapse_duplicates_secs: Some(30),
+            ..concurrent_cfg()
+        };
+
+        let state = run_poll(
+            &mut server,
+            &bridge_cfg,
+            TestPoll::of(serde_json::json!([
+                message_from(1, 10, "abcd1234"),
+                message_from(2, 20, "abcd1234"),
+            ])),
+        )
+        .await;
+
+        post.assert();
+        reaction.assert();
+        assert_eq!(state.last_rx_time, Some(20));
+    }
+
+    #[tokio::test]
+    async fn poll_once_relaxed_ordering_forwards_past_failed_message() {
+        let mut server = mockito::Server::new_async().await;
+        let (state, send_mock) =
+            poll_with_failing_first(&mut server, MessageOrdering::Relaxed, 1).await;
+
+        // The later message goes out first; the failed one waits for a retry.
+        send_mock.assert();
+        assert_eq!(state.last_rx_time, Some(20));
+        assert_eq!(state.retry_messages.len(), 1);
+        assert_eq!(state.retry_messages[0].msg.id, 1);
+
+        server.reset();
+        server
+            .mock("GET", "/api/nodes/aaaaaaaa")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"node_id":"!aaaaaaaa","long_name":"Node A"}"#)
+            .create();
+        let retry_send = mock_forward_chain(&mut server).expect(1).create();
+        let bridge_cfg = BridgeConfig {
+            ordering: MessageOrdering::Relaxed,
+            ..BridgeConfig::default()
+        };
+        let state = run_poll(
+            &mut server,
+            &bridge_cfg,
+            TestPoll {
+                state,
+                now: 0,
+                ..TestPoll::of(serde_json::json!([]))
+            },
+        )
+        .await;
+
+        retry_send.assert();
+        assert!(state.retry_messages.is_empty());
+    }
+
+    #[tokio::test]
+    async fn poll_once_relaxed_ordering_drops_message_after_max_attempts() {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("GET", "/api/nodes/aaaaaaaa")
+            .with_status(500)
+            .create();
+        let bridge_cfg = BridgeConfig {
+            ordering: MessageOrdering::Relaxed,
+            ..BridgeConfig::default()
+        };
+        let mut state = BridgeState {
+            retry_messages: vec![RetryMessage {
+                msg: PotatoMessage {
+                    node_id: "!aaaaaaaa".to_string(),
+                    ..sample_msg(1)
+                },
+                attempts: MAX_FORWARD_ATTEMPTS - 2,
+            }],
+            ..BridgeState::default()
+        };
+
+        state = run_poll(
+            &mut server,
+            &bridge_cfg,
+            TestPoll {
+                state,
+                now: 0,
+                ..TestPoll::of(serde_json::json!([]))
+            },
+        )
+        .await;
+        assert_eq!(state.retry_messages[0].attempts, MAX_FORWARD_ATTEMPTS - 1);
+
+        state = run_poll(
+            &mut server,
+            &bridge_cfg,
+            TestPoll {
+                state,
+                now: 0,
+                ..TestPoll::of(serde_json::json!([]))
+            },
+        )
+        .await;
+        assert!(state.retry_messages.is_empty());
+    }
+
+    #[tokio::test]
+    async fn retry_out_of_order_saves_untried_retries_mid_pass() {
+        let mut server = mockito::Server::new_async().await;
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let state_path = tmp_dir.path().join("state.json");
+        server
+            .mock("GET", "/api/nodes/aaaaaaaa")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"node_id":"!aaaaaaaa","long_name":"Node A"}"#)
+            .create();
+        // Looking up the second retry's node reads the state saved after the
+        // first was forwarded, then fails.
+        let saved = Arc::new(std::sync::Mutex::new(None));
+        let seen = saved.clone();
+        let path = state_path.clone();
+        server
+            .mock("GET", "/api/nodes/bbbbbbbb")
+            .with_status(500)
+            .with_body_from_request(move |_| {
+                *seen.lock().unwrap() = Some(BridgeState::load(path.to_str().unwrap()).unwrap());
+                Vec::new()
+            })
+            .create();
+        mock_forward_chain(&mut server).create();
+        let retry = |id, node_id: &str| RetryMessage {
+            msg: PotatoMessage {
+                node_id: node_id.to_string(),
+                ..sample_msg(id)
+            },
+            attempts: 1,
+        };
+        let mut state = BridgeState {
+            retry_messages: vec![retry(1, "!aaaaaaaa"), retry(2, "!bbbbbbbb")],
+            ..BridgeState::default()
+        };
+        let potato = PotatoClient::new(
+            reqwest::Client::new(),
+            PotatomeshConfig::for_test(&server.url()),
+        );
+        let matrix = matrix_client_for(&server);
+
+        retry_out_of_order(
+            &potato,
+            &matrix,
+            &BridgeConfig::default(),
+            &mut state,
+            state_path.to_str().unwrap(),
+            0,
+        )
+        .await;
+
+        let saved = saved.lock().unwrap().take().unwrap();
+        let queued: Vec<u64> = saved.retry_messages.iter().map(|r| r.msg.id).collect();
+        assert_eq!(queued, vec![2]);
+        assert_eq!(state.retry_messages.len(), 1);
+        assert_eq!(state.retry_messages[0].attempts, 2);
+        let reloaded = BridgeState::load(state_path.to_str().unwrap()).unwrap();
+        assert_eq!(reloaded.retry_messages[0].msg.id, 2);
+    }
+
+    /// Poll once at 1000 with one relaxed-ordering retry received at
+    /// `rx_time` queued and a one-hour `retry_queue_ttl_secs`, returning the
+    /// state and the dead-letter file contents.
+    async fn poll_with_queued_retry(
+        server: &mut mockito::ServerGuard,
+        rx_time: u64,
+    ) -> (BridgeState, String) {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let dead_letters = tmp_dir.path().join("dead_letters.jsonl");
+        let bridge_cfg = BridgeConfig {
+            ordering: MessageOrdering::Relaxed,
+            retry_queue_ttl_secs: Some(3600),
+            dead_letter_file: Some(dead_letters.to_str().unwrap().to_string()),
+            ..BridgeConfig::default()
+        };
+        let state = BridgeState {
+            retry_messages: vec![RetryMessage {
+                msg: PotatoMessage {
+                    rx_time,
+                    ..sample_msg(1)
+                },
+                attempts: 1,
+            }],
+            ..BridgeState::default()
+        };
+        let state = run_poll(
+            server,
+            &bridge_cfg,
+            TestPoll {
+                state,
+                now: 5000,
+                ..TestPoll::of(serde_json::json!([]))
+            },
+        )
+        .await;
+        (state, fs::read_to_string(dead_letters).unwrap_or_default())
+    }
+
+    #[tokio::test]
+    async fn poll_once_retries_queued_message_within_ttl() {
+        let mut server = mockito::Server::new_async().await;
+        mock_test_node(&mut server);
+        let send = mock_forward_chain(&mut server).expect(1).create();
+
+        let (state, dead_letters) = poll_with_queued_retry(&mut server, 4000).await;
+
+        send.assert();
+        assert!(state.retry_messages.is_empty());
+        assert!(dead_letters.is_empty());
+    }
+
+    #[tokio::test]
+    async fn poll_once_drops_queued_message_past_ttl() {
+        let mut server = mockito::Server::new_async().await;
+        mock_test_node(&mut server);
+        let send = mock_forward_chain(&mut server).expect(0).create();
+
+        let (state, dead_letters) = poll_with_queued_retry(&mut server, 1000).await;
+
+        send.assert();
+        assert!(state.retry_messages.is_empty());
+        let entry: serde_json::Value = serde_json::from_str(dead_letters.trim()).unwrap();
+        assert_eq!(entry["reason"], "expired");
+        assert_eq!(entry["dropped_at"], 5000);
+        assert_eq!(entry["message"]["id"], 1);
+    }
+
+    #[tokio::test]
+    async fn poll_once_skips_poison_message_after_max_attempts() {
+        // A permanently-failing message must not block the batch forever. A
+        // (rx_time=10) always fails (node lookup 500); B (rx_time=20) succeeds.
+        // After MAX_FORWARD_ATTEMPTS polls the bridge skips A (advances past it)
+        // and then forwards B, so the watermark reaches 20 — progress is made
+        // while earlier polls still refused to jump past the failure.
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let state_path = tmp_dir.path().join("state.json");
+        let state_str = state_path.to_str().unwrap();
+
+        let mut server = mockito::Server::new_async().await;
+
+        let _mock_msgs = server
+            .mock("GET", "/api/messages")
+            .match_query(mockito::Matcher::Any)
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(
+                r#"[
+                    {"id":1,"rx_time":10,"rx_iso":"2025-11-27T00:00:00Z","from_id":"!aaaaaaaa","to_id":"^all","channel":1,"portnum":"TEXT_MESSAGE_APP","text":"Ping","lora_freq":868,"modem_preset":"MediumFast","channel_name":"TEST","node_id":"!aaaaaaaa"},
+                    {"id":2,"rx_time":20,"rx_iso":"2025-11-27T00:00:00Z","from_id":"!bbbbbbbb","to_id":"^all","channel":1,"portnum":"TEXT_MESSAGE_APP","text":"Ping","lora_freq":868,"modem_preset":"MediumFast","channel_name":"TEST","node_id":"!bbbbbbbb"}
+                ]"#,
+            )
+            .create();
+
+        // A always fails; B's full forward chain always succeeds.
+        let _mock_node_a = server
+            .mock("GET", "/api/nodes/aaaaaaaa")
+            .match_query(mockito::Matcher::Any)
+            .with_status(500)
+            .create();
+        let _mock_node_b = server
+            .mock("GET", "/api/nodes/bbbbbbbb")
+            .match_query(mockito::Matcher::Any)
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"node_id":"!bbbbbbbb","long_name":"Node B","short_name":"NB"}"#)
+            .create();
+        let _mock_register = server
+            .mock("POST", "/_matrix/client/v3/register")
+            .match_query(mockito::Matcher::Any)
+            .with_status(200)
+            .create();
+        let _mock_join = server
+            .mock(
+                "POST",
+                mockito::Matcher::Regex(r"/_matrix/client/v3/rooms/.+/join".to_string()),
+            )
+            .match_query(mockito::Matcher::Any)
+            .with_status(200)
+            .create();
+        let _mock_display = server
+            .mock(
+                "PUT",
+                mockito::Matcher::Regex(r"/_matrix/client/v3/profile/.+/displayname".to_string()),
+            )
+            .match_query(mockito::Matcher::Any)
+            .with_status(200)
+            .create();
+        let _mock_send = server
+            .mock(
+                "PUT",
+                mockito::Matcher::Regex(r"/_matrix/client/v3/rooms/.+/send/.+".to_string()),
+            )
+            .match_query(mockito::Matcher::Any)
+            .with_status(200)
+            .with_body(r#"{"event_id":"$bridged"}"#)
+            .create();
+
+        let http_client = reqwest::Client::new();
+        let potato = PotatoClient::new(
+            http_client.clone(),
+            PotatomeshConfig {
+                ..PotatomeshConfig::for_test(&server.url())
+            },
+        );
+        let matrix = MatrixAppserviceClient::new(
+            http_client,
+            MatrixConfig {
+                homeserver: server.url(),
+                as_token: "AS_TOKEN".to_string(),
+                hs_token: "HS_TOKEN".to_string(),
+                server_name: "example.org".to_string(),
+                room_id: Some("!roomid:example.org".to_string()),
+                log_room: None,
+                max_retry_after_secs: 60,
+                auto_create_room: None,
+                membership_check: Default::default(),
+                channel_rooms: Default::default(),
+                direct_room: None,
+                rich_notices: false,
+                max_rate_limit_retries: 3,
+                sends_per_sec: 0.0,
+                puppet_prefix: "potato_".to_string(),
+            },
+        );
+        let mut state = BridgeState::default();
+
+        // Poll up to (and including) the skip threshold.
+        for _ in 0..MAX_FORWARD_ATTEMPTS {
+            poll_once(
+                &potato,
+                &matrix,
+                &BridgeConfig::default(),
+                &mut state,
+                state_str,
+            )
+            .await;
+        }
+
+        // A was skipped on the final poll and B then forwarded, so the watermark
+        // advanced to B's rx_time. A is no longer eligible (it is behind the
+        // watermark), and the failure tracker was cleared.
+        assert_eq!(
+            state.last_rx_time,
+            Some(20),
+            "poison message should have been skipped and the later message forwarded"
+        );
+        let msg_a = PotatoMessage {
+            id: 1,
+            rx_time: 10,
+            from_id: Some("!aaaaaaaa".to_string()),
+            node_id: "!aaaaaaaa".to_string(),
+            ..sample_msg(1)
+        };
+        assert!(
+            !state.should_forward(&msg_a),
+            "skipped message must not be reprocessed"
+        );
+    }
+
+    /// Companion to the two failure-path tests above, covering the
+    /// success-path tracker reset (the `if state.failing_msg_id == Some(msg.id)`
+    /// clear after a successful `handle_message`): a message that failed
+    /// transiently and then succeeds on a later poll must clear the tracker
+    /// and let the watermark advance. Neither prior test executes that reset —
+    /// the watermark test stops at the first failure and never re-polls, and
+    /// in the poison test the tracker is already cleared by the skip before
+    /// the next message succeeds. Without this test a regression in the reset
+    /// (not clearing, or clearing the wrong id) would pass CI, and the stale
+    /// attempt count would let a single later transient failure of the same
+    /// message hit `MAX_FORWARD_ATTEMPTS` and drop it.
+    #[tokio::test]
+    async fn poll_once_clears_failure_tracker_when_failed_message_recovers() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let state_path = tmp_dir.path().join("state.json");
         let state_str = state_path.to_str().unwrap();
 
         let mut server = mockito::Server::new_async().await;
+
+        // Batch of two TEXT messages, refetched on every poll while the
+        // watermark is stuck (same shape as the watermark/poison tests).
+        let _mock_msgs = server
+            .mock("GET", "/api/messages")
+            .match_query(mockito::Matcher::Any)
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(
+                r#"[
+                    {"id":1,"rx_time":10,"rx_iso":"2025-11-27T00:00:00Z","from_id":"!aaaaaaaa","to_id":"^all","channel":1,"portnum":"TEXT_MESSAGE_APP","text":"Ping","lora_freq":868,"modem_preset":"MediumFast","channel_name":"TEST","node_id":"!aaaaaaaa"},
+                    {"id":2,"rx_time":20,"rx_iso":"2025-11-27T00:00:00Z","from_id":"!bbbbbbbb","to_id":"^all","channel":1,"portnum":"TEXT_MESSAGE_APP","text":"Ping","lora_freq":868,"modem_preset":"MediumFast","channel_name":"TEST","node_id":"!bbbbbbbb"}
+                ]"#,
+            )
+            .create();
+
+        // Poll 1: A's node lookup fails transiently (500), arming the tracker.
+        let mock_node_a_fail = server
+            .mock("GET", "/api/nodes/aaaaaaaa")
+            .match_query(mockito::Matcher::Any)
+            .with_status(500)
+            .create();
+
+        // B's node lookup and the Matrix forward chain (shared by A once its
+        // node lookup recovers) always succeed.
+        let _mock_node_b = server
+            .mock("GET", "/api/nodes/bbbbbbbb")
+            .match_query(mockito::Matcher::Any)
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"node_id":"!bbbbbbbb","long_name":"Node B","short_name":"NB"}"#)
+            .create();
+        let _mock_register = server
+            .mock("POST", "/_matrix/client/v3/register")
+            .match_query(mockito::Matcher::Any)
+            .with_status(200)
+            .create();
+        let _mock_join = server
+            .mock(
+                "POST",
+                mockito::Matcher::Regex(r"/_matrix/client/v3/rooms/.+/join".to_string()),
+            )
+            .match_query(mockito::Matcher::Any)
+            .with_status(200)
+            .create();
+        let _mock_display = server
+            .mock(
+                "PUT",
+                mockito::Matcher::Regex(r"/_matrix/client/v3/profile/.+/displayname".to_string()),
+            )
+            .match_query(mockito::Matcher::Any)
+            .with_status(200)
+            .create();
+        let _mock_send = server
+            .mock(
+                "PUT",
+                mockito::Matcher::Regex(r"/_matrix/client/v3/rooms/.+/send/.+".to_string()),
+            )
+            .match_query(mockito::Matcher::Any)
+            .with_status(200)
+            .with_body(r#"{"event_id":"$bridged"}"#)
+            .create();
+
+        let http_client = reqwest::Client::new();
+        let potato = PotatoClient::new(
+            http_client.clone(),
+            PotatomeshConfig {
+                ..PotatomeshConfig::for_test(&server.url())
+            },
+        );
+        let matrix = MatrixAppserviceClient::new(
+            http_client,
+            MatrixConfig {
+                homeserver: server.url(),
+                as_token: "AS_TOKEN".to_string(),
+                hs_token: "HS_TOKEN".to_string(),
+                server_name: "example.org".to_string(),
+                room_id: Some("!roomid:example.org".to_string()),
+                log_room: None,
+                max_retry_after_secs: 60,
+                auto_create_room: None,
+                membership_check: Default::default(),
+                channel_rooms: Default::default(),
+                direct_room: None,
+                rich_notices: false,
+                max_rate_limit_retries: 3,
+                sends_per_sec: 0.0,
+                puppet_prefix: "potato_".to_string(),
+            },
+        );
+        let mut state = BridgeState::default();
+
+        poll_once(
+            &potato,
+            &matrix,
+            &BridgeConfig::default(),
+            &mut state,
+            state_str,
+        )
+        .await;
+
+        // The transient failure armed the tracker and stalled the watermark.
+        assert_eq!(state.failing_msg_id, Some(1));
+        assert_eq!(state.failing_msg_attempts, 1);
+        assert_eq!(state.last_rx_time, None);
+
+        // Poll 2: A's node lookup now succeeds, like B's.
+        mock_node_a_fail.remove_async().await;
+        let _mock_node_a_ok = server
+            .mock("GET", "/api/nodes/aaaaaaaa")
+            .match_query(mockito::Matcher::Any)
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"node_id":"!aaaaaaaa","long_name":"Node A","short_name":"NA"}"#)
+            .create();
+
+        poll_once(
+            &potato,
+            &matrix,
+            &BridgeConfig::default(),
+            &mut state,
+            state_str,
+        )
+        .await;
+
+        // The success-path reset cleared the tracker for the recovered id...
+        assert_eq!(
+            state.failing_msg_id, None,
+            "tracker id must be cleared when the failing message finally forwards"
+        );
+        assert_eq!(
+            state.failing_msg_attempts, 0,
+            "attempt count must be reset when the failing message finally forwards"
+        );
+        // ...and the watermark advanced through A to B.
+        assert_eq!(
+            state.last_rx_time,
+            Some(20),
+            "watermark should advance once the transient failure recovers"
+        );
+        let msg_a = PotatoMessage {
+            id: 1,
+            rx_time: 10,
+            from_id: Some("!aaaaaaaa".to_string()),
+            node_id: "!aaaaaaaa".to_string(),
+            ..sample_msg(1)
+        };
+        assert!(
+            !state.should_forward(&msg_a),
+            "recovered message must not be reprocessed"
+        );
+    }
+
+    /// Drive `handle_message` end-to-end against a mocked Matrix homeserver
+    /// and PotatoMesh API, asserting that the bridged message body carries
+    /// the expected protocol tag and preset abbreviation. Shared by the
+    /// per-protocol test cases below. `lora_freq` is plumbed through both
+    /// the input message and the expected body so the missing-freq path
+    /// (`lora_freq = 0`) can be exercised alongside the populated cases.
+    async fn assert_handle_message_emits_tag(
+        protocol: Option<&str>,
+        expected_tag: &str,
+        modem_preset: &str,
+        lora_freq: u32,
+        expected_preset_slot: &str,
+    ) {
+        let mut server = mockito::Server::new_async().await;
+
+        let potatomesh_cfg = PotatomeshConfig {
+            ..PotatomeshConfig::for_test(&server.url())
+        };
+        let matrix_cfg = MatrixConfig {
+            homeserver: server.url(),
+            as_token: "AS_TOKEN".to_string(),
+            hs_token: "HS_TOKEN".to_string(),
+            server_name: "example.org".to_string(),
+            room_id: Some("!roomid:example.org".to_string()),
+            log_room: None,
+            max_retry_after_secs: 60,
+            auto_create_room: None,
+            membership_check: Default::default(),
+            channel_rooms: Default::default(),
+            direct_room: None,
+            rich_notices: false,
+            max_rate_limit_retries: 3,
+            sends_per_sec: 0.0,
+            puppet_prefix: "potato_".to_string(),
+        };
+
+        let node_id = "abcd1234";
+        let user_id = format!("@potato_{}:{}", node_id, matrix_cfg.server_name);
+        let encoded_user = urlencoding::encode(&user_id);
+        let room_id = matrix_cfg.room_id.clone().unwrap();
+        let encoded_room = urlencoding::encode(&room_id);
+
+        let mock_get_node = server
+            .mock("GET", "/api/nodes/abcd1234")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"node_id": "!abcd1234", "long_name": "Test Node", "short_name": "TN"}"#)
+            .create();
+
+        let mock_register = server
+            .mock("POST", "/_matrix/client/v3/register")
+            .match_query("kind=user")
+            .match_header("authorization", "Bearer AS_TOKEN")
+            .with_status(200)
+            .create();
+
+        let mock_join = server
+            .mock(
+                "POST",
+                format!("/_matrix/client/v3/rooms/{}/join", encoded_room).as_str(),
+            )
+            .match_query(format!("user_id={}", encoded_user).as_str())
+            .match_header("authorization", "Bearer AS_TOKEN")
+            .with_status(200)
+            .create();
+
+        let mock_display_name = server
+            .mock(
+                "PUT",
+                format!("/_matrix/client/v3/profile/{}/displayname", encoded_user).as_str(),
+            )
+            .match_query(format!("user_id={}", encoded_user).as_str())
+            .match_header("authorization", "Bearer AS_TOKEN")
+            .match_body(mockito::Matcher::PartialJson(serde_json::json!({
+                "displayname": "Test Node (TN)"
+            })))
+            .with_status(200)
+            .create();
+
+        let http_client = reqwest::Client::new();
+        let matrix_client = MatrixAppserviceClient::new(http_client.clone(), matrix_cfg);
+        let txn_id = matrix_client.upcoming_txn_id();
+
+        let expected_body =
+            format!("`{expected_tag}[{lora_freq}][{expected_preset_slot}][TEST]` Ping");
+        let expected_formatted =
+            format!("<code>{expected_tag}[{lora_freq}][{expected_preset_slot}][TEST]</code> Ping");
+
+        let mock_send = server
+            .mock(
+                "PUT",
+                format!(
+                    "/_matrix/client/v3/rooms/{}/send/m.room.message/{}",
+                    encoded_room, txn_id
+                )
+                .as_str(),
+            )
+            .match_query(format!("user_id={}", encoded_user).as_str())
+            .match_header("authorization", "Bearer AS_TOKEN")
+            .match_body(mockito::Matcher::PartialJson(serde_json::json!({
+                "msgtype": "m.text",
+                "body": expected_body,
+                "format": "org.matrix.custom.html",
+                "formatted_body": expected_formatted,
+            })))
+            .with_status(200)
+            .with_body(r#"{"event_id":"$bridged"}"#)
+            .create();
+
+        let potato_client = PotatoClient::new(http_client.clone(), potatomesh_cfg);
+        let mut state = BridgeState::default();
+        let msg = PotatoMessage {
+            protocol: protocol.map(str::to_string),
+            modem_preset: modem_preset.to_string(),
+            lora_freq,
+            ..sample_msg(100)
+        };
+
+        let result = handle_message(
+            &potato_client,
+            &matrix_client,
+            &BridgeConfig::default(),
+            &mut state,
+            &msg,
+        )
+        .await;
+
+        assert!(result.is_ok());
+        mock_get_node.assert();
+        mock_register.assert();
+        mock_join.assert();
+        mock_display_name.assert();
+        mock_send.assert();
+
+        assert_eq!(state.last_message_id(1), Some(100));
+    }
+
+    /// Drive `handle_message` for `msg` against a mocked PotatoMesh API and
+    /// homeserver, asserting the `m.room.message` content sent to Matrix
+    /// contains `expected_content`. Puppet setup calls are mocked loosely so
+    /// callers only have to describe the rendered message they care about.
+    async fn assert_handle_message_sends(
+        bridge_cfg: &BridgeConfig,
+        state: &mut BridgeState,
+        msg: PotatoMessage,
+        expected_content: serde_json::Value,
+    ) {
+        assert_source_sends(
+            None,
+            TEST_NODE_JSON,
+            bridge_cfg,
+            state,
+            msg,
+            expected_content,
+        )
+        .await;
+    }
+
+    const TEST_NODE_JSON: &str =
+        r#"{"node_id": "!abcd1234", "long_name": "Test Node", "short_name": "TN"}"#;
+
+    /// [`assert_handle_message_sends`] for a PotatoMesh source named `label`
+    /// whose `/api/nodes` record for the sender is `node_json`.
+    async fn assert_source_sends(
+        label: Option<&str>,
+        node_json: &str,
+        bridge_cfg: &BridgeConfig,
+        state: &mut BridgeState,
+        msg: PotatoMessage,
+        expected_content: serde_json::Value,
+    ) {
+        let mut server = mockito::Server::new_async().await;
+        let _mock_get_node = server
+            .mock("GET", "/api/nodes/abcd1234")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(node_json)
+            .create();
+        let _mock_register = server
+            .mock("POST", "/_matrix/client/v3/register")
+            .match_query(mockito::Matcher::Any)
+            .with_status(200)
+            .create();
+        let _mock_join = server
+            .mock(
+                "POST",
+                mockito::Matcher::Regex(r"/_matrix/client/v3/rooms/.+/join".to_string()),
+            )
+            .match_query(mockito::Matcher::Any)
+            .with_status(200)
+            .create();
+        let _mock_display = server
+            .mock(
+                "PUT",
+                mockito::Matcher::Regex(r"/_matrix/client/v3/profile/.+/displayname".to_string()),
+            )
+            .match_query(mockito::Matcher::Any)
+            .with_status(200)
+            .create();
+        let mock_send = server
+            .mock(
+                "PUT",
+                mockito::Matcher::Regex(r"/_matrix/client/v3/rooms/.+/send/.+".to_string()),
+            )
+            .match_query(mockito::Matcher::Any)
+            .match_body(mockito::Matcher::PartialJson(expected_content))
+            .with_status(200)
+            .with_body(r#"{"event_id": "$sent"}"#)
+            .create();
+
+        let http_client = reqwest::Client::new();
+        let mut potato = PotatoClient::new(
+            http_client.clone(),
+            PotatomeshConfig {
+                label: label.map(str::to_string),
+                ..PotatomeshConfig::for_test(&server.url())
+            },
+        );
+        // A labelled source stands for one of several sharing the room.
+        potato.set_shares_rooms(label.is_some());
+        let matrix = MatrixAppserviceClient::new(
+            http_client,
+            MatrixConfig {
+                homeserver: server.url(),
+                as_token: "AS_TOKEN".to_string(),
+                hs_token: "HS_TOKEN".to_string(),
+                server_name: "example.org".to_string(),
+                room_id: Some("!roomid:example.org".to_string()),
+                log_room: None,
+                max_retry_after_secs: 60,
+                auto_create_room: None,
+                membership_check: Default::default(),
+                channel_rooms: Default::default(),
+                direct_room: None,
+                rich_notices: false,
+                max_rate_limit_retries: 3,
+                sends_per_sec: 0.0,
+                puppet_prefix: "potato_".to_string(),
+            },
+        );
+        let result = handle_message(&potato, &matrix, bridge_cfg, state, &msg).await;
+
+        assert!(result.is_ok(), "handle_message failed: {result:?}");
+        mock_send.assert();
+    }
+
+    #[tokio::test]
+    async fn handle_message_logs_inside_a_span_linking_mesh_id_to_event_id() {
+        let logs = Arc::new(Mutex::new(Vec::new()));
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .json()
+            .with_writer(move || CapturedLogs(writer.clone()))
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        assert_handle_message_sends(
+            &BridgeConfig::default(),
+            &mut BridgeState::default(),
+            sample_msg(100),
+            serde_json::json!({}),
+        )
+        .await;
+
+        let logs = String::from_utf8(logs.lock().unwrap().clone()).unwrap();
+        let bridged: serde_json::Value = logs
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .find(|line: &serde_json::Value| line["fields"]["message"] == "Bridged message")
+            .expect("no Bridged message line");
+        assert_eq!(bridged["span"]["name"], "mesh_message");
+        assert_eq!(bridged["span"]["mesh_id"], 100);
+        assert_eq!(bridged["span"]["node_id"], "!abcd1234");
+        assert_eq!(bridged["span"]["event_id"], "$sent");
+    }
+
+    /// Log writer appending to a shared buffer.
+    struct CapturedLogs(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn handle_message_decodes_escaped_unicode_when_enabled() {
+        let bridge_cfg = BridgeConfig {
+            unescape_unicode: true,
+            ..BridgeConfig::default()
+        };
+        let msg = PotatoMessage {
+            text: r"Gr\u00fc\u00dfe".to_string(),
+            ..sample_msg(100)
+        };
+        assert_handle_message_sends(
+            &bridge_cfg,
+            &mut BridgeState::default(),
+            msg,
+            serde_json::json!({
+                "body": "`[MT][868][MF][TEST]` Grüße",
+                "formatted_body": "<code>[MT][868][MF][TEST]</code> Grüße",
+            }),
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn handle_message_keeps_escaped_unicode_by_default() {
+        let msg = PotatoMessage {
+            text: r"Gr\u00fc\u00dfe".to_string(),
+            ..sample_msg(100)
+        };
+        assert_handle_message_sends(
+            &BridgeConfig::default(),
+            &mut BridgeState::default(),
+            msg,
+            serde_json::json!({
+                "body": r"`[MT][868][MF][TEST]` Gr\u00fc\u00dfe",
+            }),
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn handle_message_trims_padded_text_by_default() {
+        let msg = PotatoMessage {
+            text: "  Ping \0".to_string(),
+            ..sample_msg(100)
+        };
+        assert_handle_message_sends(
+            &BridgeConfig::default(),
+            &mut BridgeState::default(),
+            msg,
+            serde_json::json!({
+                "body": "`[MT][868][MF][TEST]` Ping",
+                "formatted_body": "<code>[MT][868][MF][TEST]</code> Ping",
+            }),
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn handle_message_keeps_padding_when_trim_disabled() {
+        let bridge_cfg = BridgeConfig {
+            trim_text: false,
+            ..BridgeConfig::default()
+        };
+        let msg = PotatoMessage {
+            text: "Ping ".to_string(),
+            ..sample_msg(100)
+        };
+        assert_handle_message_sends(
+            &bridge_cfg,
+            &mut BridgeState::default(),
+            msg,
+            serde_json::json!({
+                "body": "`[MT][868][MF][TEST]` Ping ",
+            }),
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn handle_message_appends_snr_trend_and_records_reading() {
+        let bridge_cfg = BridgeConfig {
+            snr_trend: true,
+            ..BridgeConfig::default()
+        };
+        let mut state = BridgeState::default();
+        state.record_snr("!abcd1234", Some(-6.0));
+
+        assert_handle_message_sends(
+            &bridge_cfg,
+            &mut state,
+            sample_msg(100),
+            serde_json::json!({
+                "body": "`[MT][868][MF][TEST][SNR↑]` Ping",
+            }),
+        )
+        .await;
+
+        assert_eq!(state.last_snr.get("abcd1234"), Some(&0.0));
+    }
+
+    #[tokio::test]
+    async fn handle_message_omits_snr_trend_for_first_message() {
+        let bridge_cfg = BridgeConfig {
+            snr_trend: true,
+            ..BridgeConfig::default()
+        };
+        assert_handle_message_sends(
+            &bridge_cfg,
+            &mut BridgeState::default(),
+            sample_msg(100),
+            serde_json::json!({
+                "body": "`[MT][868][MF][TEST]` Ping",
+            }),
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn handle_message_sends_as_channel_bot_and_names_node() {
+        let mut server = mockito::Server::new_async().await;
+        mock_test_node(&mut server);
+        let register = server
+            .mock("POST", "/_matrix/client/v3/register")
+            .match_query(mockito::Matcher::Any)
+            .match_body(mockito::Matcher::PartialJson(
+                serde_json::json!({ "username": "potato_channel_test" }),
+            ))
+            .with_status(200)
+            .create();
+        let user_query = mockito::Matcher::UrlEncoded(
+            "user_id".into(),
+            "@potato_channel_test:example.org".into(),
+        );
+        server
+            .mock(
+                "POST",
+                mockito::Matcher::Regex(r"/_matrix/client/v3/rooms/.+/join".to_string()),
+            )
+            .match_query(user_query.clone())
+            .with_status(200)
+            .create();
+        let display_name = server
+            .mock(
+                "PUT",
+                mockito::Matcher::Regex(r"/_matrix/client/v3/profile/.+/displayname".to_string()),
+            )
+            .match_query(user_query.clone())
+            .match_body(mockito::Matcher::PartialJson(
+                serde_json::json!({ "displayname": "TEST" }),
+            ))
+            .with_status(200)
+            .create();
+        let send = server
+            .mock(
+                "PUT",
+                mockito::Matcher::Regex(r"/_matrix/client/v3/rooms/.+/send/.+".to_string()),
+            )
+            .match_query(user_query)
+            .match_body(mockito::Matcher::PartialJson(serde_json::json!({
+                "body": "`[MT][868][MF][TEST]` Test Node (TN): Ping",
+                "formatted_body": "<code>[MT][868][MF][TEST]</code> <strong>Test Node (TN)</strong>: Ping",
+            })))
+            .with_status(200)
+            .with_body(r#"{"event_id":"$bridged"}"#)
+            .create();
+        let potato = PotatoClient::new(
+            reqwest::Client::new(),
+            PotatomeshConfig {
+                ..PotatomeshConfig::for_test(&server.url())
+            },
+        );
+        let matrix = MatrixAppserviceClient::new(
+            reqwest::Client::new(),
+            MatrixConfig {
+                homeserver: server.url(),
+                as_token: "AS_TOKEN".to_string(),
+                hs_token: "HS_TOKEN".to_string(),
+                server_name: "example.org".to_string(),
+                room_id: Some("!roomid:example.org".to_string()),
+                log_room: None,
+                max_retry_after_secs: 60,
+                auto_create_room: None,
+                membership_check: Default::default(),
+                channel_rooms: Default::default(),
+                direct_room: None,
+                rich_notices: false,
+                max_rate_limit_retries: 3,
+                sends_per_sec: 0.0,
+                puppet_prefix: "potato_".to_string(),
+            },
+        );
+        let bridge_cfg = BridgeConfig {
+            sender_mode: SenderMode::ChannelBot,
+            ..BridgeConfig::default()
+        };
+
+        handle_message(
+            &potato,
+            &matrix,
+            &bridge_cfg,
+            &mut BridgeState::default(),
+            &sample_msg(1),
+        )
+        .await
+        .unwrap();
+
+        register.assert();
+        display_name.assert();
+        send.assert();
+    }
+
+    #[tokio::test]
+    async fn handle_message_puppet_mode_keeps_node_name_out_of_body() {
+        assert_handle_message_sends(
+            &BridgeConfig::default(),
+            &mut BridgeState::default(),
+            sample_msg(1),
+            serde_json::json!({
+                "body": "`[MT][868][MF][TEST]` Ping",
+                "formatted_body": "<code>[MT][868][MF][TEST]</code> Ping",
+            }),
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn handle_message_labels_each_source() {
+        let bridge_cfg = BridgeConfig {
+            metadata_template: "[{source}]{tag}[{channel}]".to_string(),
+            ..BridgeConfig::default()
+        };
+        for (label, expected_body) in [
+            ("berlin", "`[berlin][MT][TEST]` Ping"),
+            ("hamburg", "`[hamburg][MT][TEST]` Ping"),
+        ] {
+            assert_source_sends(
+                Some(label),
+                TEST_NODE_JSON,
+                &bridge_cfg,
+                &mut BridgeState::default(),
+                sample_msg(100),
+                serde_json::json!({ "body": expected_body }),
+            )
+            .await;
+        }
+    }
+
+    #[tokio::test]
+    async fn handle_message_tags_shared_sources_without_source_placeholder() {
+        assert_source_sends(
+            Some("berlin"),
+            TEST_NODE_JSON,
+            &BridgeConfig::default(),
+            &mut BridgeState::default(),
+            sample_msg(100),
+            serde_json::json!({ "body": "`[MT][868][MF][TEST][berlin]` Ping" }),
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn handle_message_renders_node_altitude() {
+        let bridge_cfg = BridgeConfig {
+            metadata_template: "{tag}[{channel}][{altitude}]".to_string(),
+            ..BridgeConfig::default()
+        };
+        for (node_json, expected_body) in [
+            (
+                r#"{"node_id": "!abcd1234", "long_name": "Test Node", "altitude": 312.4}"#,
+                "`[MT][TEST][312 m]` Ping",
+            ),
+            (TEST_NODE_JSON, "`[MT][TEST][]` Ping"),
+        ] {
+            assert_source_sends(
+                None,
+                node_json,
+                &bridge_cfg,
+                &mut BridgeState::default(),
+                sample_msg(100),
+                serde_json::json!({ "body": expected_body }),
+            )
+            .await;
+        }
+    }
+
+    #[tokio::test]
+    async fn handle_message_renders_node_role_and_hardware() {
+        let node_json = r#"{"node_id": "!abcd1234", "long_name": "Test Node",
+            "role": "ROUTER", "hw_model": "HELTEC_V3"}"#;
+        for (template, hide_unknown, node_json, expected_body) in [
+            (
+                "{tag}[{role}][{hw_model}]",
+                false,
+                node_json,
+                "`[MT][ROUTER][HELTEC_V3]` Ping",
+            ),
+            (
+                "{tag}[{role}][{hw_model}]",
+                false,
+                TEST_NODE_JSON,
+                "`[MT][n/a][n/a]` Ping",
+            ),
+            (
+                "{tag}[{role}][{hw_model}]",
+                true,
+                TEST_NODE_JSON,
+                "`[MT]` Ping",
+            ),
+        ] {
+            let bridge_cfg = BridgeConfig {
+                metadata_template: template.to_string(),
+                hide_unknown_metadata: hide_unknown,
+                ..BridgeConfig::default()
+            };
+            assert_source_sends(
+                None,
+                node_json,
+                &bridge_cfg,
+                &mut BridgeState::default(),
+                sample_msg(100),
+                serde_json::json!({ "body": expected_body }),
+            )
+            .await;
+        }
+    }
+
+    #[tokio::test]
+    async fn handle_message_uses_direct_metadata_template_for_directed_messages() {
+        let bridge_cfg = BridgeConfig {
+            direct_metadata_template: Some("{tag}".to_string()),
+            ..BridgeConfig::default()
+        };
+        for (to_id, expected_body) in [
+            ("^all", "`[MT][868][MF][TEST]` Ping"),
+            ("!ffffffff", "`[MT][868][MF][TEST]` Ping"),
+            ("!0badc0de", "`[MT]` Ping"),
+        ] {
+            let msg = PotatoMessage {
+                to_id: Some(to_id.to_string()),
+                ..sample_msg(100)
+            };
+            assert_source_sends(
+                None,
+                TEST_NODE_JSON,
+                &bridge_cfg,
+                &mut BridgeState::default(),
+                msg,
+                serde_json::json!({ "body": expected_body }),
+            )
+            .await;
+        }
+    }
+
+    #[tokio::test]
+    async fn handle_message_renders_message_template() {
+        let bridge_cfg = BridgeConfig {
+            message_template: Some("[{short}] {text}\n({long}, {node_id}, {rssi})".to_string()),
+            ..BridgeConfig::default()
+        };
+        assert_source_sends(
+            None,
+            TEST_NODE_JSON,
+            &bridge_cfg,
+            &mut BridgeState::default(),
+            sample_msg(100),
+            serde_json::json!({
+                "body": "[TN] Ping\n(Test Node, !abcd1234, -100 dBm)",
+                "formatted_body": "[TN] Ping<br>(Test Node, !abcd1234, -100 dBm)",
+            }),
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn handle_message_signal_layout_bolds_short_name_and_keeps_plain_body() {
+        let bridge_cfg = BridgeConfig {
+            html_layout: HtmlLayout::Signal,
+            ..BridgeConfig::default()
+        };
+        let mut msg = sample_msg(100);
+        msg.text = "a <b> & c".to_string();
+        assert_source_sends(
+            None,
+            TEST_NODE_JSON,
+            &bridge_cfg,
+            &mut BridgeState::default(),
+            msg,
+            serde_json::json!({
+                "body": "`[MT][868][MF][TEST]` a <b> & c",
+                "formatted_body": "<strong>TN</strong> a &lt;b&gt; &amp; c<br><small>RSSI -100 dBm · SNR 0 dB · TEST/MF</small>",
+            }),
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn handle_message_appends_permalink_when_configured() {
+        let bridge_cfg = BridgeConfig {
+            permalink_template: Some("https://potatomesh.net/messages/{id}".to_string()),
+            ..BridgeConfig::default()
+        };
+        assert_handle_message_sends(
+            &bridge_cfg,
+            &mut BridgeState::default(),
+            sample_msg(100),
+            serde_json::json!({
+                "body": "`[MT][868][MF][TEST]` Ping https://potatomesh.net/messages/100",
+                "formatted_body": "<code>[MT][868][MF][TEST]</code> Ping <a href=\"https://potatomesh.net/messages/100\">↗</a>",
+            }),
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn handle_message_omits_permalink_by_default() {
+        assert_handle_message_sends(
+            &BridgeConfig::default(),
+            &mut BridgeState::default(),
+            sample_msg(100),
+            serde_json::json!({
+                "body": "`[MT][868][MF][TEST]` Ping",
+                "formatted_body": "<code>[MT][868][MF][TEST]</code> Ping",
+            }),
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn handle_message_hides_unknown_signal_metadata_when_enabled() {
+        let bridge_cfg = BridgeConfig {
+            metadata_template: "{tag}[{channel}][RSSI {rssi}, SNR {snr}]".to_string(),
+            hide_unknown_metadata: true,
+            ..BridgeConfig::default()
+        };
+        let msg = PotatoMessage {
+            rssi: None,
+            snr: None,
+            ..sample_msg(100)
+        };
+        assert_handle_message_sends(
+            &bridge_cfg,
+            &mut BridgeState::default(),
+            msg,
+            serde_json::json!({ "body": "`[MT][TEST]` Ping" }),
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn handle_message_shows_known_signal_metadata_when_hiding_unknown() {
+        let bridge_cfg = BridgeConfig {
+            metadata_template: "{tag}[{channel}][RSSI {rssi}, SNR {snr}]".to_string(),
+            hide_unknown_metadata: true,
+            ..BridgeConfig::default()
+        };
+        assert_handle_message_sends(
+            &bridge_cfg,
+            &mut BridgeState::default(),
+            sample_msg(100),
+            serde_json::json!({ "body": "`[MT][TEST][RSSI -100 dBm, SNR 0 dB]` Ping" }),
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn handle_message_shows_unknown_signal_metadata_by_default() {
+        let bridge_cfg = BridgeConfig {
+            metadata_template: "{tag}[RSSI {rssi}, SNR {snr}]".to_string(),
+            ..BridgeConfig::default()
+        };
+        let msg = PotatoMessage {
+            rssi: None,
+            snr: None,
+            ..sample_msg(100)
+        };
+        assert_handle_message_sends(
+            &bridge_cfg,
+            &mut BridgeState::default(),
+            msg,
+            serde_json::json!({ "body": "`[MT][RSSI n/a, SNR n/a]` Ping" }),
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn handle_message_does_not_expand_placeholders_in_values() {
+        let bridge_cfg = BridgeConfig {
+            metadata_template: "{tag}[{channel}][RSSI {rssi}]".to_string(),
+            ..BridgeConfig::default()
+        };
+        let msg = PotatoMessage {
+            channel_name: "{long_name} {rssi}".to_string(),
+            rssi: Some(-90),
+            ..sample_msg(100)
+        };
+        assert_handle_message_sends(
+            &bridge_cfg,
+            &mut BridgeState::default(),
+            msg,
+            serde_json::json!({ "body": "`[MT][{long_name} {rssi}][RSSI -90 dBm]` Ping" }),
+        )
+        .await;
+    }
+
+    fn reply_to(parent_id: u64) -> PotatoMessage {
+        PotatoMessage {
+            reply_id: Some(parent_id),
+            ..sample_msg(200)
+        }
+    }
+
+    #[tokio::test]
+    async fn handle_message_prepends_reply_fallback_prefix_when_enabled() {
+        let bridge_cfg = BridgeConfig {
+            reply_fallback_prefix: true,
+            ..BridgeConfig::default()
+        };
+        let mut state = BridgeState::default();
+        state.recent_messages.record(recent_parent(None));
+
+        assert_handle_message_sends(
+            &bridge_cfg,
+            &mut state,
+            reply_to(150),
+            serde_json::json!({
+                "body": "> in reply to TN\n\n`[MT][868][MF][TEST]` Ping",
+                "formatted_body": "<code>[MT][868][MF][TEST]</code> Ping",
+            }),
+        )
+        .await;
+        assert!(state.recent_messages.get(200).is_some());
+    }
+
+    #[tokio::test]
+    async fn handle_message_omits_reply_fallback_prefix_by_default() {
+        let mut state = BridgeState::default();
+        state.recent_messages.record(recent_parent(None));
+
+        assert_handle_message_sends(
+            &BridgeConfig::default(),
+            &mut state,
+            reply_to(150),
+            serde_json::json!({ "body": "`[MT][868][MF][TEST]` Ping" }),
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn handle_message_sends_cold_start_reply_as_plain_message_by_default() {
+        let mut state = BridgeState::default();
+        state.recent_messages.record(recent_parent(None));
+
+        assert_handle_message_sends(
+            &BridgeConfig::default(),
+            &mut state,
+            reply_to(150),
+            serde_json::json!({ "body": "`[MT][868][MF][TEST]` Ping" }),
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn handle_message_quotes_cached_parent_on_cold_start() {
+        let bridge_cfg = BridgeConfig {
+            reply_cold_start: ReplyColdStart::Quote,
+            ..BridgeConfig::default()
+        };
+        let mut state = BridgeState::default();
+        state.recent_messages.record(recent_parent(None));
+
+        assert_handle_message_sends(
+            &bridge_cfg,
+            &mut state,
+            reply_to(150),
+            serde_json::json!({
+                "body": "> <TN> Hello\n\n`[MT][868][MF][TEST]` Ping",
+                "formatted_body": "<code>[MT][868][MF][TEST]</code> Ping",
+            }),
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn handle_message_cold_start_quote_falls_back_without_cached_text() {
+        let bridge_cfg = BridgeConfig {
+            reply_cold_start: ReplyColdStart::Quote,
+            reply_fallback_prefix: true,
+            ..BridgeConfig::default()
+        };
+        // Entry persisted before parent text was cached.
+        let mut state = BridgeState {
+            recent_messages: serde_json::from_str(r#"[{"id": 150, "node_id": "!abcd1234"}]"#)
+                .unwrap(),
+            ..Default::default()
+        };
+
+        assert_handle_message_sends(
+            &bridge_cfg,
+            &mut state,
+            reply_to(150),
+            serde_json::json!({ "body": "> in reply to TN\n\n`[MT][868][MF][TEST]` Ping" }),
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn handle_message_threads_reply_under_bridged_parent() {
+        let bridge_cfg = BridgeConfig {
+            reply_cold_start: ReplyColdStart::Quote,
+            ..BridgeConfig::default()
+        };
+        let mut state = BridgeState::default();
+        state.recent_messages.record(recent_parent(Some("$parent")));
+
+        // A threaded reply needs no quote of its parent.
+        assert_handle_message_sends(
+            &bridge_cfg,
+            &mut state,
+            reply_to(150),
+            serde_json::json!({
+                "body": "`[MT][868][MF][TEST]` Ping",
+                "m.relates_to": {"m.in_reply_to": {"event_id": "$parent"}},
+            }),
+        )
+        .await;
+        assert_eq!(
+            state.recent_messages.get(200).unwrap().event_id.as_deref(),
+            Some("$sent")
+        );
+    }
+
+    #[tokio::test]
+    async fn handle_message_keeps_fallback_prefix_on_threaded_reply() {
+        let bridge_cfg = BridgeConfig {
+            reply_fallback_prefix: true,
+            ..BridgeConfig::default()
+        };
+        let mut state = BridgeState::default();
+        state.recent_messages.record(recent_parent(Some("$parent")));
+
+        assert_handle_message_sends(
+            &bridge_cfg,
+            &mut state,
+            reply_to(150),
+            serde_json::json!({
+                "body": "> in reply to TN\n\n`[MT][868][MF][TEST]` Ping",
+                "m.relates_to": {"m.in_reply_to": {"event_id": "$parent"}},
+            }),
+        )
+        .await;
+    }
+
+    /// Matrix client whose homeserver is `server`.
+    fn matrix_client_for(server: &mockito::ServerGuard) -> MatrixAppserviceClient {
+        let cfg = MatrixConfig {
+            homeserver: server.url(),
+            as_token: "AS_TOKEN".to_string(),
+            hs_token: "HS_TOKEN".to_string(),
+            server_name: "example.org".to_string(),
+            room_id: Some("!roomid:example.org".to_string()),
+            log_room: None,
+            max_retry_after_secs: 60,
+            auto_create_room: None,
+            membership_check: Default::default(),
+            channel_rooms: Default::default(),
+            direct_room: None,
+            rich_notices: false,
+            max_rate_limit_retries: 3,
+            sends_per_sec: 0.0,
+            puppet_prefix: "potato_".to_string(),
+        };
+        MatrixAppserviceClient::new(reqwest::Client::new(), cfg)
+    }
+
+    #[tokio::test]
+    async fn backfill_forwards_checkpointed_messages_without_saving() {
+        let mut server = mockito::Server::new_async().await;
+        mock_test_node(&mut server);
+        let mut routing = message_from(7, 1003, "abcd1234");
+        routing["portnum"] = "ROUTING_APP".into();
         let mock_msgs = server
             .mock("GET", "/api/messages")
-            .match_query(mockito::Matcher::Any)
+            .match_query(mockito::Matcher::UrlEncoded("limit".into(), "3".into()))
             .with_status(200)
-            .with_header("content-type", "application/json")
             .with_body(
-                r#"[{"id":1,"rx_time":100,"rx_iso":"2025-11-27T00:00:00Z","from_id":"!abcd1234","to_id":"^all","channel":1,"portnum":"POSITION_APP","text":"","rssi":-100,"hop_limit":1,"lora_freq":868,"modem_preset":"MediumFast","channel_name":"TEST","snr":0.0,"node_id":"!abcd1234"}]"#,
+                serde_json::json!([
+                    routing,
+                    message_from(6, 1002, "abcd1234"),
+                    message_from(5, 1001, "abcd1234"),
+                ])
+                .to_string(),
             )
             .create();
-
-        let http_client = reqwest::Client::new();
-        let potatomesh_cfg = PotatomeshConfig {
-            base_url: server.url(),
-            poll_interval_secs: 1,
+        let mock_send = mock_forward_chain(&mut server).expect(2).create();
+        let potato = PotatoClient::new(
+            reqwest::Client::new(),
+            PotatomeshConfig {
+                ..PotatomeshConfig::for_test(&server.url())
+            },
+        );
+        let matrix = matrix_client_for(&server);
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let state_path = tmp_dir.path().join("state.json");
+        let mut state = BridgeState {
+            last_message_id: HashMap::from([(1, 10)]),
+            last_rx_time: Some(2000),
+            hold_checkpoint: true,
+            ..Default::default()
         };
-        let matrix_cfg = MatrixConfig {
-            homeserver: server.url(),
-            as_token: "AS_TOKEN".to_string(),
-            hs_token: "HS_TOKEN".to_string(),
-            server_name: "example.org".to_string(),
-            room_id: "!roomid:example.org".to_string(),
+
+        let fetched = backfill(
+            &potato,
+            &matrix,
+            &BridgeConfig::default(),
+            &mut state,
+            state_path.to_str().unwrap(),
+            3,
+        )
+        .await
+        .unwrap();
+
+        mock_msgs.assert();
+        mock_send.assert();
+        assert_eq!(fetched, 3);
+        assert!(state.recent_messages.get(5).is_some());
+        assert!(state.recent_messages.get(6).is_some());
+        assert!(!state_path.exists());
+    }
+
+    fn potato_client_for(server: &mockito::ServerGuard) -> PotatoClient {
+        PotatoClient::new(
+            reqwest::Client::new(),
+            PotatomeshConfig {
+                ..PotatomeshConfig::for_test(&server.url())
+            },
+        )
+    }
+
+    #[tokio::test]
+    async fn poll_once_id_cursor_starts_after_the_channel_furthest_behind() {
+        let mut server = mockito::Server::new_async().await;
+        mock_test_node(&mut server);
+        let send_mock = mock_forward_chain(&mut server).expect(2).create();
+        let mut behind = message_from(30, 100, "abcd1234");
+        behind["channel"] = 2.into();
+        let mut ahead = message_from(60, 90, "abcd1234");
+        ahead["channel"] = 0.into();
+        let mock_msgs = server
+            .mock("GET", "/api/messages")
+            .match_query(mockito::Matcher::UrlEncoded("after_id".into(), "20".into()))
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(serde_json::json!([behind, ahead]).to_string())
+            .create();
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let state_path = tmp_dir.path().join("state.json");
+        // Channel 2 is behind channel 0.
+        let mut state = BridgeState {
+            last_message_id: HashMap::from([(0, 50), (2, 20)]),
+            id_cursor: true,
+            ..BridgeState::default()
         };
 
-        let potato = PotatoClient::new(http_client.clone(), potatomesh_cfg);
-        let matrix = MatrixAppserviceClient::new(http_client, matrix_cfg);
+        poll_once_at(
+            &potato_client_for(&server),
+            &matrix_client_for(&server),
+            &BridgeConfig::default(),
+            &mut state,
+            state_path.to_str().unwrap(),
+            1000,
+        )
+        .await;
+
+        mock_msgs.assert();
+        send_mock.assert();
+        // The whole batch went through, so both channels are at its end.
+        assert_eq!(state.last_message_id, HashMap::from([(0, 60), (2, 60)]));
+    }
+
+    #[tokio::test]
+    async fn start_from_now_saves_the_newest_message_as_checkpoint_once() {
+        let mut server = mockito::Server::new_async().await;
+        let mock_msgs = server
+            .mock("GET", "/api/messages")
+            .match_query(mockito::Matcher::UrlEncoded("limit".into(), "1".into()))
+            .with_status(200)
+            .with_body(serde_json::json!([message_from(9, 1500, "abcd1234")]).to_string())
+            .create();
+        let potato = potato_client_for(&server);
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let state_path = tmp_dir.path().join("state.json");
+        let state_path = state_path.to_str().unwrap();
         let mut state = BridgeState::default();
 
-        poll_once(&potato, &matrix, &mut state, state_str).await;
+        assert!(start_from_now(&potato, &mut state, state_path, 2000)
+            .await
+            .unwrap());
+        assert!(!start_from_now(&potato, &mut state, state_path, 2000)
+            .await
+            .unwrap());
 
         mock_msgs.assert();
-        assert!(state_path.exists());
-        let loaded = BridgeState::load(state_str).unwrap();
-        assert_eq!(loaded.last_message_id, Some(1));
-        assert_eq!(loaded.last_rx_time, Some(100));
-        assert_eq!(loaded.last_rx_time_ids, vec![1]);
+        let saved = BridgeState::load(state_path).unwrap();
+        assert_eq!(saved.last_message_id(1), Some(9));
+        assert_eq!(saved.last_rx_time, Some(1500));
+    }
+
+    fn journal_in(dir: &tempfile::TempDir) -> DeliveryJournal {
+        DeliveryJournal {
+            path: dir
+                .path()
+                .join("deliveries.jsonl")
+                .to_string_lossy()
+                .into_owned(),
+            max_bytes: 1 << 20,
+            max_files: 1,
+        }
     }
 
-    /// Regression test for the watermark-advance bug: within a single batch,
-    /// an *earlier* message (lower `rx_time`) that fails to forward must not be
-    /// silently skipped forever just because a *later* message would succeed.
-    ///
-    /// The batch is `[A(rx_time=10, fails), B(rx_time=20, would succeed)]`.
-    /// `A` fails because its node lookup returns 500; `B`'s full forward chain
-    /// is mocked to succeed. With the fix, `poll_once` stops at the first
-    /// failure, so the watermark never advances past `A` and `A` stays eligible
-    /// for retry. Under the old code, `B` succeeded and pushed `last_rx_time` to
-    /// 20, permanently stranding `A` — which this test would catch.
     #[tokio::test]
-    async fn poll_once_does_not_advance_watermark_past_failed_message() {
+    async fn handle_message_journals_the_delivery() {
         let tmp_dir = tempfile::tempdir().unwrap();
-        let state_path = tmp_dir.path().join("state.json");
-        let state_str = state_path.to_str().unwrap();
+        let bridge_cfg = BridgeConfig {
+            delivery_journal: Some(journal_in(&tmp_dir)),
+            ..BridgeConfig::default()
+        };
 
-        let mut server = mockito::Server::new_async().await;
+        assert_handle_message_sends(
+            &bridge_cfg,
+            &mut BridgeState::default(),
+            sample_msg(7),
+            serde_json::json!({}),
+        )
+        .await;
+
+        let journal = bridge_cfg.delivery_journal.unwrap();
+        let line = fs::read_to_string(&journal.path).unwrap();
+        let delivery: journal::Delivery = serde_json::from_str(line.trim()).unwrap();
+        assert_eq!(delivery.mesh_id, 7);
+        assert_eq!(delivery.room_id, "!roomid:example.org");
+        assert_eq!(delivery.event_id, "$sent");
+    }
 
-        // Batch of two TEXT messages; poll_once sorts ascending by rx_time so A
-        // (rx_time=10) is processed before B (rx_time=20) regardless of order.
-        let mock_msgs = server
+    #[tokio::test]
+    async fn missing_deliveries_lists_unjournaled_messages_to_bridge() {
+        let mut server = mockito::Server::new_async().await;
+        let mut beacon = message_from(4, 400, "abcd1234");
+        beacon["portnum"] = "POSITION_APP".into();
+        beacon["text"] = "".into();
+        server
             .mock("GET", "/api/messages")
             .match_query(mockito::Matcher::Any)
             .with_status(200)
-            .with_header("content-type", "application/json")
             .with_body(
-                r#"[
-                    {"id":1,"rx_time":10,"rx_iso":"2025-11-27T00:00:00Z","from_id":"!aaaaaaaa","to_id":"^all","channel":1,"portnum":"TEXT_MESSAGE_APP","text":"Ping","lora_freq":868,"modem_preset":"MediumFast","channel_name":"TEST","node_id":"!aaaaaaaa"},
-                    {"id":2,"rx_time":20,"rx_iso":"2025-11-27T00:00:00Z","from_id":"!bbbbbbbb","to_id":"^all","channel":1,"portnum":"TEXT_MESSAGE_APP","text":"Ping","lora_freq":868,"modem_preset":"MediumFast","channel_name":"TEST","node_id":"!bbbbbbbb"}
-                ]"#,
+                serde_json::json!([
+                    beacon,
+                    message_from(3, 300, "abcd1234"),
+                    message_from(2, 200, "abcd1234"),
+                    message_from(1, 100, "abcd1234"),
+                ])
+                .to_string(),
             )
             .create();
+        let potato = potato_client_for(&server);
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let journal = journal_in(&tmp_dir);
+        for mesh_id in [1, 3] {
+            let delivery = journal::Delivery {
+                mesh_id,
+                node_id: "!abcd1234".to_string(),
+                room_id: "!roomid:example.org".to_string(),
+                event_id: "$sent".to_string(),
+                delivered_at: 1000,
+            };
+            journal::append(&journal, &delivery).unwrap();
+        }
 
-        // Earlier message A fails: its node lookup returns 500.
-        let mock_node_a = server
-            .mock("GET", "/api/nodes/aaaaaaaa")
+        let (missing, expected) =
+            missing_deliveries(&potato, &BridgeConfig::default(), &journal, 10)
+                .await
+                .unwrap();
+
+        assert_eq!(missing, vec![2]);
+        assert_eq!(expected, 3);
+    }
+
+    #[tokio::test]
+    async fn missing_deliveries_leaves_out_messages_filtered_on_purpose() {
+        let mut server = mockito::Server::new_async().await;
+        mock_test_node(&mut server);
+        let now = potatomesh::now_secs();
+        let mut echo = message_from(2, now, "abcd1234");
+        echo["text"] = "Test Node".into();
+        server
+            .mock("GET", "/api/messages")
             .match_query(mockito::Matcher::Any)
-            .with_status(500)
+            .with_status(200)
+            .with_body(
+                serde_json::json!([
+                    message_from(3, now, "abcd1234"),
+                    echo,
+                    message_from(1, now - 7200, "abcd1234"),
+                ])
+                .to_string(),
+            )
             .create();
+        let potato = potato_client_for(&server);
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let bridge_cfg = BridgeConfig {
+            max_message_age_secs: Some(3600),
+            drop_name_echo: true,
+            ..BridgeConfig::default()
+        };
 
-        // Later message B would fully succeed (node + register + join +
-        // displayname + send). These stay unused under the fix, but make the
-        // test a true regression: under the buggy code B forwards and advances
-        // the watermark to 20.
-        let _mock_node_b = server
-            .mock("GET", "/api/nodes/bbbbbbbb")
+        let (missing, expected) =
+            missing_deliveries(&potato, &bridge_cfg, &journal_in(&tmp_dir), 10)
+                .await
+                .unwrap();
+
+        assert_eq!(missing, vec![3]);
+        assert_eq!(expected, 1);
+    }
+
+    #[tokio::test]
+    async fn start_from_now_uses_the_current_time_on_an_empty_mesh() {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("GET", "/api/messages")
             .match_query(mockito::Matcher::Any)
             .with_status(200)
-            .with_header("content-type", "application/json")
-            .with_body(r#"{"node_id":"!bbbbbbbb","long_name":"Node B","short_name":"NB"}"#)
+            .with_body("[]")
             .create();
-        let _mock_register = server
-            .mock("POST", "/_matrix/client/v3/register")
-            .match_query(mockito::Matcher::Any)
+        let potato = potato_client_for(&server);
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let state_path = tmp_dir.path().join("state.json");
+        let state_path = state_path.to_str().unwrap();
+        let mut state = BridgeState::default();
+
+        assert!(start_from_now(&potato, &mut state, state_path, 2000)
+            .await
+            .unwrap());
+
+        let saved = BridgeState::load(state_path).unwrap();
+        assert_eq!(saved.last_rx_time, Some(2000));
+        assert!(saved.last_message_id.is_empty());
+    }
+
+    /// Message 150 from `!abcd1234` reading "Hello", sent as `event_id`.
+    fn recent_parent(event_id: Option<&str>) -> RecentMessage {
+        RecentMessage {
+            id: 150,
+            node_id: "!abcd1234".to_string(),
+            text: Some("Hello".to_string()),
+            event_id: event_id.map(str::to_string),
+            room_id: None,
+        }
+    }
+
+    fn redact(sender: &str, mesh_id: u64) -> RedactCommand {
+        RedactCommand {
+            sender: sender.to_string(),
+            room_id: "!roomid:example.org".to_string(),
+            source: None,
+            mesh_id,
+        }
+    }
+
+    #[test]
+    fn redact_candidate_matches_the_command_room_and_source() {
+        let mut state = BridgeState::default();
+        state.recent_messages.record(RecentMessage {
+            room_id: Some("!roomid:example.org".to_string()),
+            ..recent_parent(Some("$bridged"))
+        });
+        let cmd = redact("@admin:example.org", 150);
+        assert!(redact_candidate(Some("berlin"), &state, &cmd).is_some());
+
+        let elsewhere = RedactCommand {
+            room_id: "!other:example.org".to_string(),
+            ..cmd.clone()
+        };
+        assert!(redact_candidate(Some("berlin"), &state, &elsewhere).is_none());
+
+        let named = RedactCommand {
+            source: Some("berlin".to_string()),
+            ..cmd.clone()
+        };
+        assert!(redact_candidate(Some("berlin"), &state, &named).is_some());
+        assert!(redact_candidate(Some("paris"), &state, &named).is_none());
+        assert!(redact_candidate(None, &state, &named).is_none());
+
+        // Entries saved before rooms were kept count as the command room's.
+        let mut legacy = BridgeState::default();
+        legacy
+            .recent_messages
+            .record(recent_parent(Some("$bridged")));
+        assert!(redact_candidate(None, &legacy, &cmd).is_some());
+        let mut unsent = BridgeState::default();
+        unsent.recent_messages.record(recent_parent(None));
+        assert!(redact_candidate(None, &unsent, &cmd).is_none());
+    }
+
+    #[tokio::test]
+    async fn handle_redact_command_redacts_in_the_command_room() {
+        let mut server = mockito::Server::new_async().await;
+        let matrix = matrix_client_for(&server);
+        let bridge_cfg = BridgeConfig {
+            admin_users: vec!["@admin:example.org".to_string()],
+            ..BridgeConfig::default()
+        };
+        let mock_redact = server
+            .mock(
+                "PUT",
+                mockito::Matcher::Regex(format!(
+                    "^/_matrix/client/v3/rooms/{}/redact/{}/",
+                    urlencoding::encode("!roomid:example.org"),
+                    urlencoding::encode("$bridged")
+                )),
+            )
+            .match_body(mockito::Matcher::Json(serde_json::json!({
+                "reason": "Redacted by @admin:example.org",
+            })))
             .with_status(200)
+            .with_body(r#"{"event_id":"$redaction"}"#)
+            .create();
+
+        handle_redact_command(
+            &matrix,
+            &bridge_cfg,
+            vec![recent_parent(Some("$bridged"))],
+            redact("@admin:example.org", 150),
+        )
+        .await;
+
+        mock_redact.assert();
+    }
+
+    #[tokio::test]
+    async fn handle_redact_command_refuses_an_id_bridged_by_several_sources() {
+        let mut server = mockito::Server::new_async().await;
+        let matrix = matrix_client_for(&server);
+        let bridge_cfg = BridgeConfig {
+            admin_users: vec!["@admin:example.org".to_string()],
+            ..BridgeConfig::default()
+        };
+        let mock_redact = server
+            .mock("PUT", mockito::Matcher::Regex("/redact/".to_string()))
+            .expect(0)
             .create();
-        let _mock_join = server
+        let mock_notice = server
             .mock(
-                "POST",
-                mockito::Matcher::Regex(r"/_matrix/client/v3/rooms/.+/join".to_string()),
+                "PUT",
+                mockito::Matcher::Regex(format!(
+                    "^/_matrix/client/v3/rooms/{}/send/m.room.message/",
+                    urlencoding::encode("!roomid:example.org"),
+                )),
             )
-            .match_query(mockito::Matcher::Any)
+            .match_body(mockito::Matcher::Regex(
+                "use !redact <source> 150".to_string(),
+            ))
             .with_status(200)
+            .with_body(r#"{"event_id":"$notice"}"#)
             .create();
-        let _mock_display = server
-            .mock(
-                "PUT",
-                mockito::Matcher::Regex(r"/_matrix/client/v3/profile/.+/displayname".to_string()),
-            )
-            .match_query(mockito::Matcher::Any)
+
+        handle_redact_command(
+            &matrix,
+            &bridge_cfg,
+            vec![
+                recent_parent(Some("$first")),
+                recent_parent(Some("$second")),
+            ],
+            redact("@admin:example.org", 150),
+        )
+        .await;
+
+        mock_redact.assert();
+        mock_notice.assert();
+    }
+
+    #[tokio::test]
+    async fn handle_redact_command_ignores_unlisted_users_and_unknown_ids() {
+        let mut server = mockito::Server::new_async().await;
+        let matrix = matrix_client_for(&server);
+        let bridge_cfg = BridgeConfig {
+            admin_users: vec!["@admin:example.org".to_string()],
+            ..BridgeConfig::default()
+        };
+        let mock_redact = server.mock("PUT", mockito::Matcher::Any).expect(0).create();
+
+        handle_redact_command(
+            &matrix,
+            &bridge_cfg,
+            vec![recent_parent(Some("$bridged"))],
+            redact("@mallory:example.org", 150),
+        )
+        .await;
+        handle_redact_command(
+            &matrix,
+            &bridge_cfg,
+            Vec::new(),
+            redact("@admin:example.org", 151),
+        )
+        .await;
+
+        mock_redact.assert();
+    }
+
+    /// Mocks for one avatar upload and the profile update that uses it.
+    fn mock_avatar_update(server: &mut mockito::ServerGuard) -> (mockito::Mock, mockito::Mock) {
+        let upload = server
+            .mock("POST", "/_matrix/media/v3/upload")
+            .match_header("content-type", "image/png")
             .with_status(200)
+            .with_body(r#"{"content_uri": "mxc://example.org/avatar"}"#)
             .create();
-        let _mock_send = server
+        let set_avatar = server
             .mock(
                 "PUT",
-                mockito::Matcher::Regex(r"/_matrix/client/v3/rooms/.+/send/.+".to_string()),
+                "/_matrix/client/v3/profile/%40potato_abcd1234%3Aexample.org/avatar_url",
             )
             .match_query(mockito::Matcher::Any)
+            .match_body(mockito::Matcher::Json(serde_json::json!({
+                "avatar_url": "mxc://example.org/avatar",
+            })))
             .with_status(200)
             .create();
+        (upload, set_avatar)
+    }
 
-        let http_client = reqwest::Client::new();
-        let potatomesh_cfg = PotatomeshConfig {
-            base_url: server.url(),
-            poll_interval_secs: 1,
-        };
-        let matrix_cfg = MatrixConfig {
-            homeserver: server.url(),
-            as_token: "AS_TOKEN".to_string(),
-            hs_token: "HS_TOKEN".to_string(),
-            server_name: "example.org".to_string(),
-            room_id: "!roomid:example.org".to_string(),
+    #[tokio::test]
+    async fn puppet_avatar_is_uploaded_once_and_cached() {
+        let mut server = mockito::Server::new_async().await;
+        let matrix = matrix_client_for(&server);
+        let (upload, set_avatar) = mock_avatar_update(&mut server);
+        let mut current = None;
+        let user_id = "@potato_abcd1234:example.org";
+
+        for _ in 0..2 {
+            let hw_model = Some("TBEAM".to_string());
+            if let Some(avatar) =
+                ensure_puppet_avatar(&matrix, current.as_ref(), user_id, "!ABCD1234", hw_model)
+                    .await
+            {
+                current = Some(avatar);
+            }
+        }
+
+        upload.assert();
+        set_avatar.assert();
+        assert_eq!(
+            current,
+            Some(PuppetAvatar {
+                mxc_uri: "mxc://example.org/avatar".to_string(),
+                hw_model: Some("TBEAM".to_string()),
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn puppet_avatar_is_redrawn_when_hardware_model_changes() {
+        let mut server = mockito::Server::new_async().await;
+        let matrix = matrix_client_for(&server);
+        let (upload, set_avatar) = mock_avatar_update(&mut server);
+        let current = PuppetAvatar {
+            mxc_uri: "mxc://example.org/old".to_string(),
+            hw_model: Some("TBEAM".to_string()),
         };
 
-        let potato = PotatoClient::new(http_client.clone(), potatomesh_cfg);
-        let matrix = MatrixAppserviceClient::new(http_client, matrix_cfg);
-        let mut state = BridgeState::default();
+        let hw_model = Some("HELTEC_V3".to_string());
+        let avatar = ensure_puppet_avatar(
+            &matrix,
+            Some(&current),
+            "@potato_abcd1234:example.org",
+            "!abcd1234",
+            hw_model,
+        )
+        .await
+        .unwrap();
 
-        poll_once(&potato, &matrix, &mut state, state_str).await;
+        upload.assert();
+        set_avatar.assert();
+        assert_eq!(avatar.mxc_uri, "mxc://example.org/avatar");
+        assert_eq!(avatar.hw_model.as_deref(), Some("HELTEC_V3"));
+    }
 
-        // A's node lookup was attempted and failed.
-        mock_msgs.assert();
-        mock_node_a.assert();
+    #[tokio::test]
+    async fn puppet_avatar_failure_is_not_cached() {
+        let mut server = mockito::Server::new_async().await;
+        let upload = server
+            .mock("POST", "/_matrix/media/v3/upload")
+            .with_status(500)
+            .create();
+        let matrix = matrix_client_for(&server);
+
+        let avatar = ensure_puppet_avatar(
+            &matrix,
+            None,
+            "@potato_abcd1234:example.org",
+            "!abcd1234",
+            None,
+        )
+        .await;
 
-        // The watermark must NOT have advanced past the failed message, so A
-        // remains eligible to be refetched and retried on the next poll.
-        let msg_a = PotatoMessage {
-            id: 1,
-            rx_time: 10,
-            node_id: "!aaaaaaaa".to_string(),
-            ..sample_msg(1)
+        upload.assert();
+        assert!(avatar.is_none());
+    }
+
+    #[test]
+    fn restore_created_room_only_replaces_the_room_it_was_created_for() {
+        let matrix_for = |room_id: &str| {
+            MatrixAppserviceClient::new(
+                reqwest::Client::new(),
+                MatrixConfig {
+                    homeserver: "http://localhost:8008".to_string(),
+                    as_token: "AS_TOKEN".to_string(),
+                    hs_token: "HS_TOKEN".to_string(),
+                    server_name: "example.org".to_string(),
+                    room_id: Some(room_id.to_string()),
+                    log_room: None,
+                    max_retry_after_secs: 60,
+                    auto_create_room: Some(Default::default()),
+                    membership_check: Default::default(),
+                    channel_rooms: Default::default(),
+                    direct_room: None,
+                    rich_notices: false,
+                    max_rate_limit_retries: 3,
+                    sends_per_sec: 0.0,
+                    puppet_prefix: "potato_".to_string(),
+                },
+            )
         };
+        let state = BridgeState {
+            created_room: Some(CreatedRoom {
+                replaces: "!missing:example.org".to_string(),
+                room_id: "!new:example.org".to_string(),
+            }),
+            ..BridgeState::default()
+        };
+
+        let matrix = matrix_for("!missing:example.org");
+        restore_created_room(&state, &matrix);
+        assert_eq!(matrix.room_id().as_deref(), Some("!new:example.org"));
+
+        // The operator has since pointed the bridge at another room.
+        let matrix = matrix_for("!other:example.org");
+        restore_created_room(&state, &matrix);
+        assert_eq!(matrix.room_id().as_deref(), Some("!other:example.org"));
+    }
+
+    #[test]
+    fn labelled_path_puts_label_before_extension() {
         assert_eq!(
-            state.last_rx_time, None,
-            "watermark advanced despite a failed earlier message"
-        );
-        assert_ne!(
-            state.last_rx_time,
-            Some(20),
-            "watermark jumped past the failed message to the later success"
-        );
-        assert!(
-            state.should_forward(&msg_a),
-            "failed message must remain eligible for retry"
+            labelled_path("/data/nodes.json", "south"),
+            "/data/nodes.south.json"
         );
+        assert_eq!(labelled_path("nodes.db", "south"), "nodes.south.db");
+        assert_eq!(labelled_path("nodes", "south"), "nodes.south");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn startup_delay_waits_configured_duration() {
+        let start = tokio::time::Instant::now();
+        startup_delay(20).await;
+        assert_eq!(start.elapsed(), Duration::from_secs(20));
+
+        let start = tokio::time::Instant::now();
+        startup_delay(0).await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pause_until_next_poll_sleeps_full_interval_without_signal() {
+        let start = tokio::time::Instant::now();
+        let (_live_tx, mut live) = tokio::sync::watch::channel(());
+
+        let wake = pause_until_next_poll(
+            Duration::from_secs(30),
+            &mut live,
+            &tokio::sync::Notify::new(),
+        )
+        .await;
+        assert_eq!(wake, Wake::Poll);
+        assert_eq!(start.elapsed(), Duration::from_secs(30));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pause_until_next_poll_returns_early_on_shutdown() {
+        let start = tokio::time::Instant::now();
+        let (live_tx, mut live) = tokio::sync::watch::channel(());
+        tokio::spawn(async move {
+            sleep(Duration::from_secs(5)).await;
+            drop(live_tx);
+        });
+
+        let wake = pause_until_next_poll(
+            Duration::from_secs(30),
+            &mut live,
+            &tokio::sync::Notify::new(),
+        )
+        .await;
+        assert_eq!(wake, Wake::Shutdown);
+        assert_eq!(start.elapsed(), Duration::from_secs(5));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pause_until_next_poll_wakes_for_reload_sent_mid_poll() {
+        let start = tokio::time::Instant::now();
+        let (live_tx, mut live) = tokio::sync::watch::channel(());
+        live_tx.send_replace(());
+
+        let wake = pause_until_next_poll(
+            Duration::from_secs(30),
+            &mut live,
+            &tokio::sync::Notify::new(),
+        )
+        .await;
+        assert_eq!(wake, Wake::Reload);
+        assert_eq!(start.elapsed(), Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pause_until_next_poll_wakes_when_messages_change() {
+        let start = tokio::time::Instant::now();
+        let (_live_tx, mut live) = tokio::sync::watch::channel(());
+        let messages_changed = tokio::sync::Notify::new();
+        messages_changed.notify_one();
+
+        let wake =
+            pause_until_next_poll(Duration::from_secs(30), &mut live, &messages_changed).await;
+        assert_eq!(wake, Wake::Poll);
+        assert_eq!(start.elapsed(), Duration::ZERO);
+    }
+
+    fn reload_test_config(server: &mockito::ServerGuard) -> Config {
+        toml::from_str(&format!(
+            r#"
+            [potatomesh]
+            base_url = "{url}"
+            poll_interval_secs = 60
+
+            [matrix]
+            homeserver = "{url}"
+            as_token = "AS_TOKEN"
+            hs_token = "HS_TOKEN"
+            server_name = "example.org"
+            room_id = "!roomid:example.org"
+
+            [state]
+            state_file = "bridge_state.json"
+        "#,
+            url = server.url()
+        ))
+        .unwrap()
     }
 
     #[tokio::test]
-    async fn poll_once_skips_poison_message_after_max_attempts() {
-        // A permanently-failing message must not block the batch forever. A
-        // (rx_time=10) always fails (node lookup 500); B (rx_time=20) succeeds.
-        // After MAX_FORWARD_ATTEMPTS polls the bridge skips A (advances past it)
-        // and then forwards B, so the watermark reaches 20 — progress is made
-        // while earlier polls still refused to jump past the failure.
-        let tmp_dir = tempfile::tempdir().unwrap();
-        let state_path = tmp_dir.path().join("state.json");
-        let state_str = state_path.to_str().unwrap();
+    async fn reload_config_swaps_poll_settings_and_rooms_but_not_server_name() {
+        let server = mockito::Server::new_async().await;
+        let mut cfg = reload_test_config(&server);
+        let mut matrix = MatrixAppserviceClient::new(reqwest::Client::new(), cfg.matrix.clone());
+
+        let mut new_cfg = reload_test_config(&server);
+        new_cfg.potatomesh.poll_interval_secs = 5;
+        new_cfg.potatomesh.forward_portnums = Vec::new();
+        new_cfg.matrix.server_name = "other.example".to_string();
+        new_cfg
+            .matrix
+            .channel_rooms
+            .insert(2, "!admin:example.org".to_string());
+        new_cfg.bridge.trim_text = false;
+
+        reload_config(&mut cfg, new_cfg, &mut matrix, &BridgeState::default())
+            .await
+            .unwrap();
+
+        assert_eq!(cfg.potatomesh.poll_interval_secs, 5);
+        assert!(cfg.potatomesh.forward_portnums.is_empty());
+        assert!(!cfg.bridge.trim_text);
+        assert_eq!(matrix.room_for_channel(2).unwrap(), "!admin:example.org");
+        assert_eq!(matrix.cfg.server_name, "example.org");
+        assert_eq!(cfg.matrix.server_name, "example.org");
+    }
 
+    #[tokio::test]
+    async fn reload_config_keeps_old_config_when_membership_check_fails() {
         let mut server = mockito::Server::new_async().await;
-
-        let _mock_msgs = server
-            .mock("GET", "/api/messages")
+        let joined = server
+            .mock("GET", "/_matrix/client/v3/joined_rooms")
             .match_query(mockito::Matcher::Any)
             .with_status(200)
-            .with_header("content-type", "application/json")
-            .with_body(
-                r#"[
-                    {"id":1,"rx_time":10,"rx_iso":"2025-11-27T00:00:00Z","from_id":"!aaaaaaaa","to_id":"^all","channel":1,"portnum":"TEXT_MESSAGE_APP","text":"Ping","lora_freq":868,"modem_preset":"MediumFast","channel_name":"TEST","node_id":"!aaaaaaaa"},
-                    {"id":2,"rx_time":20,"rx_iso":"2025-11-27T00:00:00Z","from_id":"!bbbbbbbb","to_id":"^all","channel":1,"portnum":"TEXT_MESSAGE_APP","text":"Ping","lora_freq":868,"modem_preset":"MediumFast","channel_name":"TEST","node_id":"!bbbbbbbb"}
-                ]"#,
-            )
+            .with_body(r#"{"joined_rooms": ["!roomid:example.org"]}"#)
             .create();
+        let mut cfg = reload_test_config(&server);
+        let mut matrix = MatrixAppserviceClient::new(reqwest::Client::new(), cfg.matrix.clone());
 
-        // A always fails; B's full forward chain always succeeds.
-        let _mock_node_a = server
-            .mock("GET", "/api/nodes/aaaaaaaa")
-            .match_query(mockito::Matcher::Any)
-            .with_status(500)
-            .create();
-        let _mock_node_b = server
-            .mock("GET", "/api/nodes/bbbbbbbb")
+        let mut new_cfg = reload_test_config(&server);
+        new_cfg.potatomesh.poll_interval_secs = 5;
+        new_cfg.matrix.room_id = Some("!elsewhere:example.org".to_string());
+        new_cfg.matrix.membership_check = config::MembershipCheck::Require;
+
+        let result = reload_config(&mut cfg, new_cfg, &mut matrix, &BridgeState::default()).await;
+
+        joined.assert();
+        assert!(result.is_err());
+        assert_eq!(cfg.potatomesh.poll_interval_secs, 60);
+        assert_eq!(matrix.room_for_channel(0).unwrap(), "!roomid:example.org");
+    }
+
+    #[tokio::test]
+    async fn reload_config_refuses_to_change_the_sources() {
+        let server = mockito::Server::new_async().await;
+        let mut cfg = reload_test_config(&server);
+        let mut matrix = MatrixAppserviceClient::new(reqwest::Client::new(), cfg.matrix.clone());
+
+        let mut new_cfg = reload_test_config(&server);
+        new_cfg.potatomesh.poll_interval_secs = 5;
+        let mut south = new_cfg.potatomesh.clone();
+        south.label = Some("south".to_string());
+        new_cfg.extra_sources.push(south);
+
+        let result = reload_config(&mut cfg, new_cfg, &mut matrix, &BridgeState::default()).await;
+
+        assert!(result.is_err());
+        assert!(cfg.extra_sources.is_empty());
+        assert_eq!(cfg.potatomesh.poll_interval_secs, 60);
+    }
+
+    fn heard_at(last_heard: u64) -> PotatoNode {
+        PotatoNode {
+            last_heard: Some(last_heard),
+            ..sample_node(Some("FC"), "FireCracker")
+        }
+    }
+
+    fn presence_cfg() -> NodePresence {
+        NodePresence {
+            online_within_secs: 300,
+            offline_after_secs: 1800,
+            poll_interval_secs: 60,
+            min_notice_interval_secs: 900,
+        }
+    }
+
+    /// Online flags of the changes [`presence_changes`] reports.
+    fn presence_flags(state: &mut BridgeState, node: PotatoNode, now: u64) -> Vec<bool> {
+        presence_changes(state, &presence_cfg(), &[node], now)
+            .into_iter()
+            .map(|(_, online)| online)
+            .collect()
+    }
+
+    #[test]
+    fn presence_changes_reports_transitions_after_first_sighting() {
+        let mut state = BridgeState::default();
+        let now = 100_000;
+
+        // First sighting is only recorded.
+        assert!(presence_flags(&mut state, heard_at(now), now).is_empty());
+        // Silent, but not yet past offline_after_secs.
+        assert!(presence_flags(&mut state, heard_at(now), now + 1000).is_empty());
+        assert_eq!(
+            presence_flags(&mut state, heard_at(now), now + 2000),
+            [false]
+        );
+        assert!(presence_flags(&mut state, heard_at(now), now + 3000).is_empty());
+
+        let later = now + 5000;
+        assert_eq!(presence_flags(&mut state, heard_at(later), later), [true]);
+        assert_eq!(
+            state.last_seen.get("abcd1234"),
+            Some(&NodeSeen {
+                online: true,
+                notified_at: Some(later),
+            })
+        );
+    }
+
+    #[test]
+    fn presence_changes_hold_back_flapping_nodes() {
+        let mut state = BridgeState::default();
+        let now = 100_000;
+        presence_flags(&mut state, heard_at(now), now);
+        assert_eq!(
+            presence_flags(&mut state, heard_at(now), now + 2000),
+            [false]
+        );
+
+        // Back within min_notice_interval_secs of the offline notice.
+        let back = now + 2100;
+        assert!(presence_flags(&mut state, heard_at(back), back).is_empty());
+        assert!(!state.last_seen["abcd1234"].online);
+        // Still online once the interval has passed.
+        let at = now + 2000 + 900;
+        assert_eq!(presence_flags(&mut state, heard_at(at), at), [true]);
+    }
+
+    #[test]
+    fn presence_changes_skip_nodes_never_heard() {
+        let mut state = BridgeState::default();
+        let node = sample_node(Some("FC"), "FireCracker");
+        assert!(presence_changes(&mut state, &presence_cfg(), &[node], 100).is_empty());
+        assert!(state.last_seen.is_empty());
+    }
+
+    #[test]
+    fn mesh_topic_counts_nodes_and_message_age() {
+        let now = 100_000;
+        let nodes = [
+            heard_at(now - 60),
+            heard_at(now - 7200),
+            sample_node(None, "Never heard"),
+        ];
+        assert_eq!(
+            mesh_topic(&nodes, Some(now - 200), now),
+            "PotatoMesh — 3 nodes seen, 1 active in last hour, last msg 3m ago"
+        );
+        assert_eq!(
+            mesh_topic(&nodes[..1], None, now),
+            "PotatoMesh — 1 node seen, 1 active in last hour"
+        );
+    }
+
+    #[tokio::test]
+    async fn update_room_topic_skips_unchanged_topic() {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("GET", "/api/nodes")
             .match_query(mockito::Matcher::Any)
             .with_status(200)
             .with_header("content-type", "application/json")
-            .with_body(r#"{"node_id":"!bbbbbbbb","long_name":"Node B","short_name":"NB"}"#)
-            .create();
-        let _mock_register = server
-            .mock("POST", "/_matrix/client/v3/register")
-            .match_query(mockito::Matcher::Any)
-            .with_status(200)
-            .create();
-        let _mock_join = server
-            .mock(
-                "POST",
-                mockito::Matcher::Regex(r"/_matrix/client/v3/rooms/.+/join".to_string()),
-            )
-            .match_query(mockito::Matcher::Any)
-            .with_status(200)
+            .with_body(r#"[{"node_id": "!abcd1234", "long_name": "FireCracker"}]"#)
+            .expect(2)
             .create();
-        let _mock_display = server
+        let topic_mock = server
             .mock(
                 "PUT",
-                mockito::Matcher::Regex(r"/_matrix/client/v3/profile/.+/displayname".to_string()),
+                "/_matrix/client/v3/rooms/%21roomid%3Aexample.org/state/m.room.topic",
             )
-            .match_query(mockito::Matcher::Any)
+            .match_body(mockito::Matcher::Json(serde_json::json!({
+                "topic": "PotatoMesh — 1 node seen, 0 active in last hour",
+            })))
             .with_status(200)
+            .expect(1)
             .create();
-        let _mock_send = server
+        let (potato, matrix) = mock_clients(&server);
+        let mut room_topic = None;
+
+        update_room_topic(&[&potato], &matrix, None, &mut room_topic).await;
+        update_room_topic(&[&potato], &matrix, None, &mut room_topic).await;
+
+        topic_mock.assert();
+        assert_eq!(
+            room_topic.as_deref(),
+            Some("PotatoMesh — 1 node seen, 0 active in last hour")
+        );
+    }
+
+    #[tokio::test]
+    async fn update_room_topic_counts_the_nodes_of_every_source() {
+        let mut server = mockito::Server::new_async().await;
+        let mut south_server = mockito::Server::new_async().await;
+        for (server, node_id) in [(&mut server, "!abcd1234"), (&mut south_server, "!beef0001")] {
+            server
+                .mock("GET", "/api/nodes")
+                .match_query(mockito::Matcher::Any)
+                .with_status(200)
+                .with_header("content-type", "application/json")
+                .with_body(format!(
+                    r#"[{{"node_id": "{node_id}", "long_name": "Node"}}]"#
+                ))
+                .create();
+        }
+        let topic_mock = server
             .mock(
                 "PUT",
-                mockito::Matcher::Regex(r"/_matrix/client/v3/rooms/.+/send/.+".to_string()),
+                "/_matrix/client/v3/rooms/%21roomid%3Aexample.org/state/m.room.topic",
             )
-            .match_query(mockito::Matcher::Any)
+            .match_body(mockito::Matcher::Json(serde_json::json!({
+                "topic": "PotatoMesh — 2 nodes seen, 0 active in last hour",
+            })))
             .with_status(200)
             .create();
+        let (potato, matrix) = mock_clients(&server);
+        let (south, _) = mock_clients(&south_server);
+        let mut room_topic = None;
+
+        update_room_topic(&[&potato, &south], &matrix, None, &mut room_topic).await;
+
+        topic_mock.assert();
+    }
 
+    /// PotatoMesh and Matrix clients both pointing at `server`.
+    fn mock_clients(server: &mockito::ServerGuard) -> (PotatoClient, MatrixAppserviceClient) {
         let http_client = reqwest::Client::new();
         let potato = PotatoClient::new(
             http_client.clone(),
             PotatomeshConfig {
-                base_url: server.url(),
-                poll_interval_secs: 1,
-            },
-        );
-        let matrix = MatrixAppserviceClient::new(
-            http_client,
-            MatrixConfig {
-                homeserver: server.url(),
-                as_token: "AS_TOKEN".to_string(),
-                hs_token: "HS_TOKEN".to_string(),
-                server_name: "example.org".to_string(),
-                room_id: "!roomid:example.org".to_string(),
-            },
-        );
-        let mut state = BridgeState::default();
-
-        // Poll up to (and including) the skip threshold.
-        for _ in 0..MAX_FORWARD_ATTEMPTS {
-            poll_once(&potato, &matrix, &mut state, state_str).await;
-        }
-
-        // A was skipped on the final poll and B then forwarded, so the watermark
-        // advanced to B's rx_time. A is no longer eligible (it is behind the
-        // watermark), and the failure tracker was cleared.
-        assert_eq!(
-            state.last_rx_time,
-            Some(20),
-            "poison message should have been skipped and the later message forwarded"
-        );
-        let msg_a = PotatoMessage {
-            id: 1,
-            rx_time: 10,
-            node_id: "!aaaaaaaa".to_string(),
-            ..sample_msg(1)
-        };
-        assert!(
-            !state.should_forward(&msg_a),
-            "skipped message must not be reprocessed"
+                ..PotatomeshConfig::for_test(&server.url())
+            },
+        );
+        let matrix = MatrixAppserviceClient::new(
+            http_client,
+            MatrixConfig {
+                homeserver: server.url(),
+                as_token: "AS_TOKEN".to_string(),
+                hs_token: "HS_TOKEN".to_string(),
+                server_name: "example.org".to_string(),
+                room_id: Some("!roomid:example.org".to_string()),
+                log_room: None,
+                max_retry_after_secs: 60,
+                auto_create_room: None,
+                membership_check: Default::default(),
+                channel_rooms: Default::default(),
+                direct_room: None,
+                rich_notices: false,
+                max_rate_limit_retries: 3,
+                sends_per_sec: 0.0,
+                puppet_prefix: "potato_".to_string(),
+            },
         );
+        (potato, matrix)
     }
 
-    /// Companion to the two failure-path tests above, covering the
-    /// success-path tracker reset (the `if state.failing_msg_id == Some(msg.id)`
-    /// clear after a successful `handle_message`): a message that failed
-    /// transiently and then succeeds on a later poll must clear the tracker
-    /// and let the watermark advance. Neither prior test executes that reset —
-    /// the watermark test stops at the first failure and never re-polls, and
-    /// in the poison test the tracker is already cleared by the skip before
-    /// the next message succeeds. Without this test a regression in the reset
-    /// (not clearing, or clearing the wrong id) would pass CI, and the stale
-    /// attempt count would let a single later transient failure of the same
-    /// message hit `MAX_FORWARD_ATTEMPTS` and drop it.
     #[tokio::test]
-    async fn poll_once_clears_failure_tracker_when_failed_message_recovers() {
+    async fn poll_once_dry_run_writes_nothing_to_matrix() {
+        let mut server = mockito::Server::new_async().await;
+        mock_test_node(&mut server);
+        server
+            .mock("GET", "/api/messages")
+            .match_query(mockito::Matcher::Any)
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(serde_json::json!([message_json("Ping")]).to_string())
+            .create();
+        let matrix_mock = server
+            .mock("ANY", mockito::Matcher::Regex("^/_matrix/".to_string()))
+            .expect(0)
+            .create();
+        let (potato, mut matrix) = mock_clients(&server);
+        matrix.set_dry_run(true);
         let tmp_dir = tempfile::tempdir().unwrap();
         let state_path = tmp_dir.path().join("state.json");
-        let state_str = state_path.to_str().unwrap();
+        let state_path = state_path.to_str().unwrap();
 
-        let mut server = mockito::Server::new_async().await;
+        let mut state = BridgeState::default();
+        poll_once(
+            &potato,
+            &matrix,
+            &BridgeConfig::default(),
+            &mut state,
+            state_path,
+        )
+        .await;
+        assert_eq!(state.last_message_id(1), Some(1));
+        assert_eq!(
+            BridgeState::load(state_path).unwrap().last_message_id(1),
+            Some(1)
+        );
 
-        // Batch of two TEXT messages, refetched on every poll while the
-        // watermark is stuck (same shape as the watermark/poison tests).
-        let _mock_msgs = server
-            .mock("GET", "/api/messages")
+        let mut held = BridgeState {
+            hold_checkpoint: true,
+            ..BridgeState::default()
+        };
+        let held_path = tmp_dir.path().join("held.json");
+        let held_path = held_path.to_str().unwrap();
+        poll_once(
+            &potato,
+            &matrix,
+            &BridgeConfig::default(),
+            &mut held,
+            held_path,
+        )
+        .await;
+        assert_eq!(held.last_message_id(1), Some(1));
+        assert!(!Path::new(held_path).exists());
+
+        matrix_mock.assert();
+    }
+
+    #[tokio::test]
+    async fn check_node_presence_posts_notice() {
+        let mut server = mockito::Server::new_async().await;
+        let now = potatomesh::now_secs();
+        let nodes_mock = server
+            .mock("GET", "/api/nodes")
             .match_query(mockito::Matcher::Any)
             .with_status(200)
             .with_header("content-type", "application/json")
             .with_body(
-                r#"[
-                    {"id":1,"rx_time":10,"rx_iso":"2025-11-27T00:00:00Z","from_id":"!aaaaaaaa","to_id":"^all","channel":1,"portnum":"TEXT_MESSAGE_APP","text":"Ping","lora_freq":868,"modem_preset":"MediumFast","channel_name":"TEST","node_id":"!aaaaaaaa"},
-                    {"id":2,"rx_time":20,"rx_iso":"2025-11-27T00:00:00Z","from_id":"!bbbbbbbb","to_id":"^all","channel":1,"portnum":"TEXT_MESSAGE_APP","text":"Ping","lora_freq":868,"modem_preset":"MediumFast","channel_name":"TEST","node_id":"!bbbbbbbb"}
-                ]"#,
+                serde_json::json!([{
+                    "node_id": "!abcd1234", "long_name": "FireCracker", "last_heard": now
+                }])
+                .to_string(),
             )
             .create();
-
-        // Poll 1: A's node lookup fails transiently (500), arming the tracker.
-        let mock_node_a_fail = server
-            .mock("GET", "/api/nodes/aaaaaaaa")
-            .match_query(mockito::Matcher::Any)
-            .with_status(500)
+        let notice_mock = server
+            .mock(
+                "PUT",
+                mockito::Matcher::Regex(
+                    r"/_matrix/client/v3/rooms/%21roomid%3Aexample.org/send/m.room.message/.+"
+                        .to_string(),
+                ),
+            )
+            .match_body(mockito::Matcher::Json(serde_json::json!({
+                "msgtype": "m.notice",
+                "body": "🟢 FireCracker is back online",
+            })))
+            .with_status(200)
+            .with_body(r#"{"event_id":"$bridged"}"#)
             .create();
+        let mut state = BridgeState::default();
+        state.last_seen.insert(
+            "abcd1234".to_string(),
+            NodeSeen {
+                online: false,
+                notified_at: None,
+            },
+        );
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let state_path = tmp_dir.path().join("state.json");
 
-        // B's node lookup and the Matrix forward chain (shared by A once its
-        // node lookup recovers) always succeed.
-        let _mock_node_b = server
-            .mock("GET", "/api/nodes/bbbbbbbb")
-            .match_query(mockito::Matcher::Any)
-            .with_status(200)
+        let (potato, matrix) = mock_clients(&server);
+
+        check_node_presence(
+            &potato,
+            &matrix,
+            &presence_cfg(),
+            &mut state,
+            state_path.to_str().unwrap(),
+        )
+        .await;
+
+        nodes_mock.assert();
+        notice_mock.assert();
+        assert!(state.last_seen["abcd1234"].online);
+        assert!(
+            BridgeState::load(state_path.to_str().unwrap())
+                .unwrap()
+                .last_seen["abcd1234"]
+                .online
+        );
+    }
+
+    /// Forward `sample_msg(1)` while the node lookup answers `node_status`,
+    /// asserting the puppet display name set along the way.
+    async fn assert_forwarded_with_display_name(
+        bridge_cfg: &BridgeConfig,
+        node_status: usize,
+        expected_name: &str,
+    ) {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("GET", "/api/nodes/abcd1234")
+            .with_status(node_status)
             .with_header("content-type", "application/json")
-            .with_body(r#"{"node_id":"!bbbbbbbb","long_name":"Node B","short_name":"NB"}"#)
+            .with_body(r#"{"node_id": "!abcd1234", "long_name": "Test Node", "short_name": "TN"}"#)
             .create();
-        let _mock_register = server
+        server
             .mock("POST", "/_matrix/client/v3/register")
             .match_query(mockito::Matcher::Any)
             .with_status(200)
             .create();
-        let _mock_join = server
+        server
             .mock(
                 "POST",
                 mockito::Matcher::Regex(r"/_matrix/client/v3/rooms/.+/join".to_string()),
@@ -1118,29 +6829,32 @@ mod tests {
             .match_query(mockito::Matcher::Any)
             .with_status(200)
             .create();
-        let _mock_display = server
+        let mock_display = server
             .mock(
                 "PUT",
                 mockito::Matcher::Regex(r"/_matrix/client/v3/profile/.+/displayname".to_string()),
             )
             .match_query(mockito::Matcher::Any)
+            .match_body(mockito::Matcher::Json(
+                serde_json::json!({ "displayname": expected_name }),
+            ))
             .with_status(200)
             .create();
-        let _mock_send = server
+        let mock_send = server
             .mock(
                 "PUT",
                 mockito::Matcher::Regex(r"/_matrix/client/v3/rooms/.+/send/.+".to_string()),
             )
             .match_query(mockito::Matcher::Any)
             .with_status(200)
+            .with_body(r#"{"event_id":"$bridged"}"#)
             .create();
 
         let http_client = reqwest::Client::new();
         let potato = PotatoClient::new(
             http_client.clone(),
             PotatomeshConfig {
-                base_url: server.url(),
-                poll_interval_secs: 1,
+                ..PotatomeshConfig::for_test(&server.url())
             },
         );
         let matrix = MatrixAppserviceClient::new(
@@ -1150,177 +6864,151 @@ mod tests {
                 as_token: "AS_TOKEN".to_string(),
                 hs_token: "HS_TOKEN".to_string(),
                 server_name: "example.org".to_string(),
-                room_id: "!roomid:example.org".to_string(),
+                room_id: Some("!roomid:example.org".to_string()),
+                log_room: None,
+                max_retry_after_secs: 60,
+                auto_create_room: None,
+                membership_check: Default::default(),
+                channel_rooms: Default::default(),
+                direct_room: None,
+                rich_notices: false,
+                max_rate_limit_retries: 3,
+                sends_per_sec: 0.0,
+                puppet_prefix: "potato_".to_string(),
             },
         );
         let mut state = BridgeState::default();
+        let result = handle_message(&potato, &matrix, bridge_cfg, &mut state, &sample_msg(1)).await;
 
-        poll_once(&potato, &matrix, &mut state, state_str).await;
-
-        // The transient failure armed the tracker and stalled the watermark.
-        assert_eq!(state.failing_msg_id, Some(1));
-        assert_eq!(state.failing_msg_attempts, 1);
-        assert_eq!(state.last_rx_time, None);
+        assert!(result.is_ok(), "handle_message failed: {result:?}");
+        mock_display.assert();
+        mock_send.assert();
+    }
 
-        // Poll 2: A's node lookup now succeeds, like B's.
-        mock_node_a_fail.remove_async().await;
-        let _mock_node_a_ok = server
-            .mock("GET", "/api/nodes/aaaaaaaa")
-            .match_query(mockito::Matcher::Any)
-            .with_status(200)
-            .with_header("content-type", "application/json")
-            .with_body(r#"{"node_id":"!aaaaaaaa","long_name":"Node A","short_name":"NA"}"#)
-            .create();
+    fn unknown_node_cfg() -> BridgeConfig {
+        BridgeConfig {
+            unknown_node_name_template: Some("Node {hex}".to_string()),
+            ..BridgeConfig::default()
+        }
+    }
 
-        poll_once(&potato, &matrix, &mut state, state_str).await;
+    #[tokio::test]
+    async fn handle_message_names_unknown_node_from_template() {
+        assert_forwarded_with_display_name(&unknown_node_cfg(), 404, "Node abcd1234").await;
+    }
 
-        // The success-path reset cleared the tracker for the recovered id...
-        assert_eq!(
-            state.failing_msg_id, None,
-            "tracker id must be cleared when the failing message finally forwards"
-        );
-        assert_eq!(
-            state.failing_msg_attempts, 0,
-            "attempt count must be reset when the failing message finally forwards"
-        );
-        // ...and the watermark advanced through A to B.
-        assert_eq!(
-            state.last_rx_time,
-            Some(20),
-            "watermark should advance once the transient failure recovers"
-        );
-        let msg_a = PotatoMessage {
-            id: 1,
-            rx_time: 10,
-            node_id: "!aaaaaaaa".to_string(),
-            ..sample_msg(1)
-        };
-        assert!(
-            !state.should_forward(&msg_a),
-            "recovered message must not be reprocessed"
-        );
+    #[tokio::test]
+    async fn handle_message_ignores_template_for_known_node() {
+        assert_forwarded_with_display_name(&unknown_node_cfg(), 200, "Test Node (TN)").await;
     }
 
-    /// Drive `handle_message` end-to-end against a mocked Matrix homeserver
-    /// and PotatoMesh API, asserting that the bridged message body carries
-    /// the expected protocol tag and preset abbreviation. Shared by the
-    /// per-protocol test cases below. `lora_freq` is plumbed through both
-    /// the input message and the expected body so the missing-freq path
-    /// (`lora_freq = 0`) can be exercised alongside the populated cases.
-    async fn assert_handle_message_emits_tag(
-        protocol: Option<&str>,
-        expected_tag: &str,
-        modem_preset: &str,
-        lora_freq: u32,
-        expected_preset_slot: &str,
-    ) {
-        let mut server = mockito::Server::new_async().await;
+    fn state_after_sending(text: &str, sent_at: u64) -> BridgeState {
+        BridgeState {
+            last_sent: HashMap::from([(
+                "!roomid:example.org".to_string(),
+                LastSent {
+                    text: text.to_string(),
+                    event_id: "$prev".to_string(),
+                    sent_at,
+                },
+            )]),
+            ..BridgeState::default()
+        }
+    }
 
-        let potatomesh_cfg = PotatomeshConfig {
-            base_url: server.url(),
-            poll_interval_secs: 1,
-        };
-        let matrix_cfg = MatrixConfig {
-            homeserver: server.url(),
-            as_token: "AS_TOKEN".to_string(),
-            hs_token: "HS_TOKEN".to_string(),
-            server_name: "example.org".to_string(),
-            room_id: "!roomid:example.org".to_string(),
-        };
+    fn collapse_cfg() -> BridgeConfig {
+        BridgeConfig {
+            collapse_duplicates_secs: Some(30),
+            ..BridgeConfig::default()
+        }
+    }
 
-        let node_id = "abcd1234";
-        let user_id = format!("@potato_{}:{}", node_id, matrix_cfg.server_name);
-        let encoded_user = urlencoding::encode(&user_id);
-        let room_id = matrix_cfg.room_id.clone();
-        let encoded_room = urlencoding::encode(&room_id);
+    #[tokio::test]
+    async fn handle_message_collapses_repeat_within_window_into_reaction() {
+        let mut state = state_after_sending("Ping", potatomesh::now_secs());
+
+        assert_handle_message_sends(
+            &collapse_cfg(),
+            &mut state,
+            sample_msg(1),
+            serde_json::json!({
+                "m.relates_to": {"rel_type": "m.annotation", "event_id": "$prev", "key": "🔁"}
+            }),
+        )
+        .await;
 
-        let mock_get_node = server
-            .mock("GET", "/api/nodes/abcd1234")
-            .with_status(200)
-            .with_header("content-type", "application/json")
-            .with_body(r#"{"node_id": "!abcd1234", "long_name": "Test Node", "short_name": "TN"}"#)
-            .create();
+        assert_eq!(state.last_message_id(1), Some(1));
+        assert_eq!(state.last_sent["!roomid:example.org"].event_id, "$prev");
+    }
 
-        let mock_register = server
-            .mock("POST", "/_matrix/client/v3/register")
-            .match_query("kind=user")
-            .match_header("authorization", "Bearer AS_TOKEN")
-            .with_status(200)
-            .create();
+    #[tokio::test]
+    async fn handle_message_posts_repeat_outside_window() {
+        let mut state = state_after_sending("Ping", potatomesh::now_secs() - 60);
+
+        assert_handle_message_sends(
+            &collapse_cfg(),
+            &mut state,
+            sample_msg(1),
+            serde_json::json!({"msgtype": "m.text"}),
+        )
+        .await;
 
-        let mock_join = server
-            .mock(
-                "POST",
-                format!("/_matrix/client/v3/rooms/{}/join", encoded_room).as_str(),
-            )
-            .match_query(format!("user_id={}", encoded_user).as_str())
-            .match_header("authorization", "Bearer AS_TOKEN")
-            .with_status(200)
-            .create();
+        assert_eq!(state.last_sent["!roomid:example.org"].event_id, "$sent");
+    }
 
-        let mock_display_name = server
-            .mock(
-                "PUT",
-                format!("/_matrix/client/v3/profile/{}/displayname", encoded_user).as_str(),
-            )
-            .match_query(format!("user_id={}", encoded_user).as_str())
-            .match_header("authorization", "Bearer AS_TOKEN")
-            .match_body(mockito::Matcher::PartialJson(serde_json::json!({
-                "displayname": "Test Node (TN)"
-            })))
-            .with_status(200)
-            .create();
+    #[tokio::test]
+    async fn handle_message_posts_different_text_within_window() {
+        let mut state = state_after_sending("Pong", potatomesh::now_secs());
+
+        assert_handle_message_sends(
+            &collapse_cfg(),
+            &mut state,
+            sample_msg(1),
+            serde_json::json!({"msgtype": "m.text"}),
+        )
+        .await;
+    }
 
-        let http_client = reqwest::Client::new();
-        let matrix_client = MatrixAppserviceClient::new(http_client.clone(), matrix_cfg);
-        let txn_id = matrix_client
-            .txn_counter
-            .load(std::sync::atomic::Ordering::SeqCst);
+    #[tokio::test]
+    async fn handle_message_collapses_repeat_after_a_post_to_another_room() {
+        let now = potatomesh::now_secs();
+        let mut state = state_after_sending("Ping", now - 5);
+        state.last_sent.insert(
+            "!other:example.org".to_string(),
+            LastSent {
+                text: "Pong".to_string(),
+                event_id: "$other".to_string(),
+                sent_at: now,
+            },
+        );
 
-        let expected_body =
-            format!("`{expected_tag}[{lora_freq}][{expected_preset_slot}][TEST]` Ping");
-        let expected_formatted =
-            format!("<code>{expected_tag}[{lora_freq}][{expected_preset_slot}][TEST]</code> Ping");
+        assert_handle_message_sends(
+            &collapse_cfg(),
+            &mut state,
+            sample_msg(1),
+            serde_json::json!({
+                "m.relates_to": {"rel_type": "m.annotation", "event_id": "$prev", "key": "🔁"}
+            }),
+        )
+        .await;
 
-        let mock_send = server
-            .mock(
-                "PUT",
-                format!(
-                    "/_matrix/client/v3/rooms/{}/send/m.room.message/{}",
-                    encoded_room, txn_id
-                )
-                .as_str(),
-            )
-            .match_query(format!("user_id={}", encoded_user).as_str())
-            .match_header("authorization", "Bearer AS_TOKEN")
-            .match_body(mockito::Matcher::PartialJson(serde_json::json!({
-                "msgtype": "m.text",
-                "body": expected_body,
-                "format": "org.matrix.custom.html",
-                "formatted_body": expected_formatted,
-            })))
-            .with_status(200)
-            .create();
+        assert_eq!(state.last_sent["!other:example.org"].event_id, "$other");
+    }
 
-        let potato_client = PotatoClient::new(http_client.clone(), potatomesh_cfg);
-        let mut state = BridgeState::default();
-        let msg = PotatoMessage {
-            protocol: protocol.map(str::to_string),
-            modem_preset: modem_preset.to_string(),
-            lora_freq,
-            ..sample_msg(100)
+    #[tokio::test]
+    async fn handle_message_omits_reply_fallback_prefix_for_unknown_parent() {
+        let bridge_cfg = BridgeConfig {
+            reply_fallback_prefix: true,
+            ..BridgeConfig::default()
         };
 
-        let result = handle_message(&potato_client, &matrix_client, &mut state, &msg).await;
-
-        assert!(result.is_ok());
-        mock_get_node.assert();
-        mock_register.assert();
-        mock_join.assert();
-        mock_display_name.assert();
-        mock_send.assert();
-
-        assert_eq!(state.last_message_id, Some(100));
+        assert_handle_message_sends(
+            &bridge_cfg,
+            &mut BridgeState::default(),
+            reply_to(150),
+            serde_json::json!({ "body": "`[MT][868][MF][TEST]` Ping" }),
+        )
+        .await;
     }
 
     #[tokio::test]
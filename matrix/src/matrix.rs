@@ -12,35 +12,130 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use serde::Serialize;
+use reqwest::{header::HeaderMap, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
 use std::sync::{
     atomic::{AtomicU64, Ordering},
-    Arc,
+    Arc, Mutex, RwLock,
 };
+use std::time::{Duration, Instant};
 
-use crate::config::MatrixConfig;
+use crate::config::{
+    AutoCreateRoom, MatrixConfig, MembershipCheck, RoomPowerLevels, RoomVisibility,
+};
+use crate::potatomesh::normalize_node_hex;
+use crate::text::escape_html;
 
 #[derive(Clone)]
 pub struct MatrixAppserviceClient {
     http: reqwest::Client,
     pub cfg: MatrixConfig,
-    pub txn_counter: Arc<AtomicU64>,
+    pub txn_counter: TxnCounter,
+    /// Random start of this process's transaction ids.
+    txn_prefix: Arc<str>,
+    /// Puppets known to be registered and the display names last set.
+    puppets: PuppetCache,
+    /// Default room for mesh traffic: `cfg.room_id`, or the room created in
+    /// its place by `auto_create_room`.
+    room_id: Arc<RwLock<Option<String>>>,
+    /// Log writes to the homeserver instead of making them (`dry_run`).
+    dry_run: bool,
+    /// Paces puppet sends to `sends_per_sec` across all clones.
+    limiter: Arc<SendLimiter>,
+    /// (user id, room id) pairs known to be joined this process.
+    joined: Arc<RwLock<HashSet<(String, String)>>>,
 }
 
 impl MatrixAppserviceClient {
     pub fn new(http: reqwest::Client, cfg: MatrixConfig) -> Self {
-        let start = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap_or_default()
-            .as_millis() as u64;
-
+        let room_id = Arc::new(RwLock::new(cfg.room_id.clone()));
+        let limiter = Arc::new(SendLimiter::new(cfg.sends_per_sec));
         Self {
             http,
             cfg,
-            txn_counter: Arc::new(AtomicU64::new(start)),
+            txn_counter: TxnCounter::default(),
+            txn_prefix: random_txn_prefix().into(),
+            puppets: PuppetCache::default(),
+            room_id,
+            dry_run: false,
+            limiter,
+            joined: Arc::new(RwLock::new(HashSet::new())),
+        }
+    }
+
+    /// A client using `cfg` that shares this one's HTTP client, transaction
+    /// ids, puppet cache, room memberships and dry-run setting. The default room
+    /// starts out as `cfg.room_id` again, and sends are paced by a fresh
+    /// limiter for `cfg.sends_per_sec`.
+    pub fn with_config(&self, cfg: MatrixConfig) -> Self {
+        Self {
+            http: self.http.clone(),
+            txn_counter: self.txn_counter.clone(),
+            txn_prefix: self.txn_prefix.clone(),
+            puppets: self.puppets.clone(),
+            room_id: Arc::new(RwLock::new(cfg.room_id.clone())),
+            limiter: Arc::new(SendLimiter::new(cfg.sends_per_sec)),
+            joined: self.joined.clone(),
+            cfg,
+            dry_run: self.dry_run,
+        }
+    }
+
+    /// Log every write to the homeserver (registrations, joins, profile
+    /// updates, sends) instead of making it. Reads still go through.
+    pub fn set_dry_run(&mut self, dry_run: bool) {
+        self.dry_run = dry_run;
+    }
+
+    /// Whether writes are only logged; see [`Self::set_dry_run`].
+    pub fn is_dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    /// Whether the puppet `localpart` is already known to be registered, so
+    /// [`Self::ensure_user_registered`] would not hit the homeserver.
+    pub fn is_registered(&self, localpart: &str) -> bool {
+        self.puppets
+            .registered
+            .read()
+            .map(|set| set.contains(localpart))
+            .unwrap_or(false)
+    }
+
+    fn mark_registered(&self, localpart: &str) {
+        if let Ok(mut set) = self.puppets.registered.write() {
+            set.insert(localpart.to_string());
         }
     }
 
+    /// Keep registrations and display names in `puppets` from now on, e.g.
+    /// the cache restored from the bridge state, so a restart does not
+    /// register and rename every puppet again.
+    pub fn set_puppet_cache(&mut self, puppets: PuppetCache) {
+        self.puppets = puppets;
+    }
+
+    /// Continue transaction ids past `saved`, the counter a previous run
+    /// persisted, and return the live counter to persist from now on.
+    pub fn resume_txn_counter(&self, saved: &TxnCounter) -> TxnCounter {
+        self.txn_counter.advance_to(saved.peek());
+        self.txn_counter.clone()
+    }
+
+    /// Transaction id for the next write: the per-process prefix and the
+    /// counter, so a restart cannot reuse an id even within one millisecond.
+    fn next_txn_id(&self) -> String {
+        format!("{}-{}", self.txn_prefix, self.txn_counter.next())
+    }
+
+    /// The transaction id the next write will use.
+    #[cfg(test)]
+    pub fn upcoming_txn_id(&self) -> String {
+        format!("{}-{}", self.txn_prefix, self.txn_counter.peek())
+    }
+
     /// Basic liveness check against the homeserver.
     pub async fn health_check(&self) -> anyhow::Result<()> {
         let url = format!("{}/_matrix/client/versions", self.cfg.homeserver);
@@ -56,9 +151,213 @@ impl MatrixAppserviceClient {
         }
     }
 
-    /// Convert a node_id like "!deadbeef" into Matrix localpart "potato_deadbeef".
-    pub fn localpart_from_node_id(node_id: &str) -> String {
-        format!("potato_{}", node_id.trim_start_matches('!'))
+    /// Check at startup that the bot is joined to each of `room_ids`, per
+    /// `membership_check`: missing rooms are joined or reported as an error.
+    pub async fn check_room_membership(&self, room_ids: &[String]) -> anyhow::Result<()> {
+        if self.cfg.membership_check == MembershipCheck::Off {
+            return Ok(());
+        }
+
+        let joined = self.joined_rooms().await?;
+        for room_id in room_ids.iter().filter(|room| !joined.contains(room)) {
+            match self.cfg.membership_check {
+                MembershipCheck::Join => {
+                    tracing::info!("Bot is not joined to {}; joining", room_id);
+                    self.join_room_as_bot(room_id).await?;
+                }
+                _ => {
+                    return Err(anyhow::anyhow!(
+                        "The appservice bot is not joined to {}, so bridged messages would not \
+                         appear there. Invite and join the bot, or set \
+                         `membership_check = \"join\"` to let the bridge join it",
+                        room_id
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Rooms the appservice bot is joined to.
+    async fn joined_rooms(&self) -> anyhow::Result<Vec<String>> {
+        #[derive(serde::Deserialize)]
+        struct JoinedRooms {
+            joined_rooms: Vec<String>,
+        }
+
+        let url = format!("{}/_matrix/client/v3/joined_rooms", self.cfg.homeserver);
+        let resp = self
+            .http
+            .get(&url)
+            .bearer_auth(&self.cfg.as_token)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(resp.json::<JoinedRooms>().await?.joined_rooms)
+    }
+
+    /// Whether the appservice bot is joined to `room_id`, and so sees it.
+    pub async fn bot_joined_to(&self, room_id: &str) -> anyhow::Result<bool> {
+        Ok(self
+            .joined_rooms()
+            .await?
+            .iter()
+            .any(|room| room == room_id))
+    }
+
+    /// User id the `as_token` authenticates as, per `/account/whoami`.
+    pub async fn whoami(&self) -> anyhow::Result<String> {
+        #[derive(serde::Deserialize)]
+        struct WhoAmI {
+            user_id: String,
+        }
+
+        let url = format!("{}/_matrix/client/v3/account/whoami", self.cfg.homeserver);
+        let resp = self
+            .http
+            .get(&url)
+            .bearer_auth(&self.cfg.as_token)
+            .send()
+            .await?;
+        if !resp.status().is_success() {
+            let status = resp.status();
+            let body_snip = resp.text().await.unwrap_or_default();
+            return Err(anyhow::anyhow!(
+                "whoami failed with status {} ({})",
+                status,
+                body_snip
+            ));
+        }
+        Ok(resp.json::<WhoAmI>().await?.user_id)
+    }
+
+    /// Join `room_id` as the appservice bot.
+    async fn join_room_as_bot(&self, room_id: &str) -> anyhow::Result<()> {
+        if self.dry_run {
+            tracing::info!("Dry run: bot would join {}", room_id);
+            return Ok(());
+        }
+        let url = format!(
+            "{}/_matrix/client/v3/rooms/{}/join",
+            self.cfg.homeserver,
+            urlencoding::encode(room_id)
+        );
+        let resp = self
+            .http
+            .post(&url)
+            .bearer_auth(&self.cfg.as_token)
+            .json(&serde_json::json!({}))
+            .send()
+            .await?;
+        if resp.status().is_success() {
+            Ok(())
+        } else {
+            let status = resp.status();
+            let body_snip = resp.text().await.unwrap_or_default();
+            Err(anyhow::anyhow!(
+                "Bot could not join {} (status {}, {}); invite it to the room first",
+                room_id,
+                status,
+                body_snip
+            ))
+        }
+    }
+
+    /// Convert a node_id like "!DeadBeef" into the puppet localpart
+    /// `puppet_prefix` + hex, "potato_deadbeef" by default.
+    pub fn localpart_from_node_id(&self, node_id: &str) -> String {
+        format!("{}{}", self.cfg.puppet_prefix, normalize_node_hex(node_id))
+    }
+
+    /// Localpart of a bridge-owned puppet named `name` rather than a node.
+    pub fn puppet_localpart(&self, name: &str) -> String {
+        format!("{}{}", self.cfg.puppet_prefix, name)
+    }
+
+    /// Default room the bridge forwards mesh traffic into, if any.
+    pub fn room_id(&self) -> Option<String> {
+        self.room_id
+            .read()
+            .map(|room| room.clone())
+            .unwrap_or_else(|_| self.cfg.room_id.clone())
+    }
+
+    /// Forward into `room_id` instead of the configured default room.
+    pub fn set_room_id(&self, room_id: &str) {
+        if let Ok(mut room) = self.room_id.write() {
+            *room = Some(room_id.to_string());
+        }
+    }
+
+    /// Id of the room created by `auto_create_room`, if it replaced the
+    /// configured default room.
+    pub fn created_room_id(&self) -> Option<String> {
+        self.room_id()
+            .filter(|room_id| Some(room_id) != self.cfg.room_id.as_ref())
+    }
+
+    /// Room that messages on mesh channel `channel` go to: its
+    /// `channel_rooms` entry, else the default room.
+    pub fn room_for_channel(&self, channel: u8) -> anyhow::Result<String> {
+        self.cfg
+            .channel_rooms
+            .get(&channel)
+            .cloned()
+            .or_else(|| self.room_id())
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "No Matrix room for mesh channel {}; map it in matrix.channel_rooms \
+                     or set matrix.room_id",
+                    channel
+                )
+            })
+    }
+
+    /// Room a message on mesh `channel` is forwarded into: `direct_room` for
+    /// a direct message when one is configured, else the channel's room.
+    pub fn room_for_message(&self, channel: u8, broadcast: bool) -> anyhow::Result<String> {
+        match &self.cfg.direct_room {
+            Some(room_id) if !broadcast => Ok(room_id.clone()),
+            _ => self.room_for_channel(channel),
+        }
+    }
+
+    /// Every room mesh traffic can be forwarded into, default room first.
+    pub fn bridged_rooms(&self) -> Vec<String> {
+        let mut rooms: Vec<String> = self.room_id().into_iter().collect();
+        let mut channels: Vec<_> = self.cfg.channel_rooms.iter().collect();
+        channels.sort();
+        for room_id in channels
+            .into_iter()
+            .map(|(_, room_id)| room_id)
+            .chain(&self.cfg.direct_room)
+        {
+            if !rooms.contains(room_id) {
+                rooms.push(room_id.clone());
+            }
+        }
+        rooms
+    }
+
+    /// Localpart of the bot that speaks for mesh channel `name`, e.g.
+    /// "LongFast" → "potato_channel_longfast", so it falls in the puppet
+    /// namespace. Characters Matrix does not allow become `_`; an unnamed
+    /// channel falls back to its `index`.
+    pub fn localpart_from_channel(&self, name: &str, index: u8) -> String {
+        let name: String = name
+            .trim()
+            .to_lowercase()
+            .chars()
+            .map(|c| match c {
+                'a'..='z' | '0'..='9' | '.' | '_' | '=' | '-' => c,
+                _ => '_',
+            })
+            .collect();
+        if name.is_empty() {
+            self.puppet_localpart(&format!("channel_{}", index))
+        } else {
+            self.puppet_localpart(&format!("channel_{}", name))
+        }
     }
 
     /// Build a full Matrix user_id from localpart.
@@ -67,7 +366,19 @@ impl MatrixAppserviceClient {
     }
 
     /// Ensure the puppet user exists (register via appservice registration).
+    ///
+    /// Successful registrations are remembered so each puppet is registered
+    /// at most once per process.
     pub async fn ensure_user_registered(&self, localpart: &str) -> anyhow::Result<()> {
+        if self.is_registered(localpart) {
+            return Ok(());
+        }
+        if self.dry_run {
+            tracing::info!("Dry run: would register puppet {}", localpart);
+            self.mark_registered(localpart);
+            return Ok(());
+        }
+
         #[derive(Serialize)]
         struct RegisterReq<'a> {
             #[serde(rename = "type")]
@@ -93,34 +404,63 @@ impl MatrixAppserviceClient {
             .send()
             .await?;
         if resp.status().is_success() {
+            self.mark_registered(localpart);
             Ok(())
         } else {
             // If the puppet already exists, Synapse / HS returns 400 M_USER_IN_USE,
-            // which is expected and safely ignored. Anything else (e.g. 401/403 from
-            // a misconfigured `as_token`) is surfaced with the status and the Matrix
-            // error body so the failure is diagnosable instead of being swallowed
-            // here and only manifesting as a downstream error.
+            // which is expected and safely ignored. Anything else (a 403 from a
+            // misconfigured `as_token`, a 502 from a homeserver that is down) is
+            // returned as an error with the Matrix error body, so the message is
+            // left undelivered and retried instead of failing further along.
             let status = resp.status();
             let body_snip = resp.text().await.unwrap_or_default();
-            // Only the specific M_USER_IN_USE errcode means "puppet already
-            // exists" -- keying off the bare 400 status would also swallow real
-            // failures returned as 400 (malformed request, config issues) and
-            // skip the diagnostic warning below.
-            let already_registered = body_snip.contains("M_USER_IN_USE");
-            if !already_registered {
-                tracing::warn!(
-                    "Unexpected response registering puppet user {}: status {}, body: {}",
+            match matrix_errcode(&body_snip).as_deref() {
+                // Keyed off the errcode rather than the bare 400 status, which
+                // is also used for malformed requests.
+                Some("M_USER_IN_USE") => {
+                    self.mark_registered(localpart);
+                    Ok(())
+                }
+                // M_EXCLUSIVE means the localpart is outside the namespaces this
+                // appservice registered; every later send as the puppet would
+                // fail too, so point at the registration file.
+                Some("M_EXCLUSIVE") => Err(anyhow::anyhow!(
+                    "Homeserver rejected puppet @{}:{} with M_EXCLUSIVE: the localpart is not \
+                     covered by the appservice namespaces. Make `namespaces.users` in the \
+                     registration file match `@{}.*:{}`, which covers node puppets \
+                     (`@{}[0-9a-f]{{8}}`) and channel bots (`@{}channel_…`), and restart the \
+                     homeserver",
+                    localpart,
+                    self.cfg.server_name,
+                    self.cfg.puppet_prefix,
+                    self.cfg.server_name,
+                    self.cfg.puppet_prefix,
+                    self.cfg.puppet_prefix
+                )),
+                _ => Err(anyhow::anyhow!(
+                    "Registering puppet user {} failed with status {}, body: {}",
                     localpart,
                     status,
                     body_snip
-                );
+                )),
             }
-            Ok(())
         }
     }
 
-    /// Set display name for puppet user.
+    /// Set display name for puppet user, unless it was already set to
+    /// `display_name`.
     pub async fn set_display_name(&self, user_id: &str, display_name: &str) -> anyhow::Result<()> {
+        if self.puppets.display_name(user_id).as_deref() == Some(display_name) {
+            return Ok(());
+        }
+        if self.dry_run {
+            tracing::info!(
+                "Dry run: would set display name of {} to {:?}",
+                user_id,
+                display_name
+            );
+            return Ok(());
+        }
         #[derive(Serialize)]
         struct DisplayNameReq<'a> {
             displayname: &'a str,
@@ -144,9 +484,10 @@ impl MatrixAppserviceClient {
             .send()
             .await?;
         if resp.status().is_success() {
+            self.puppets.record_display_name(user_id, display_name);
             Ok(())
         } else {
-            // Non-fatal.
+            // Non-fatal; the name is tried again with the next message.
             tracing::warn!(
                 "Failed to set display name for {}: {}",
                 user_id,
@@ -156,104 +497,923 @@ impl MatrixAppserviceClient {
         }
     }
 
-    /// Ensure the puppet user is joined to the configured room.
-    pub async fn ensure_user_joined_room(&self, user_id: &str) -> anyhow::Result<()> {
+    /// Upload `bytes` to the media repository as the appservice bot and
+    /// return the resulting `mxc://` URI.
+    pub async fn upload_media(&self, bytes: Vec<u8>, content_type: &str) -> anyhow::Result<String> {
+        #[derive(serde::Deserialize)]
+        struct UploadResp {
+            content_uri: String,
+        }
+        if self.dry_run {
+            return Err(anyhow::anyhow!("Dry run: media upload skipped"));
+        }
+
+        let url = format!("{}/_matrix/media/v3/upload", self.cfg.homeserver);
+        let resp = self
+            .http
+            .post(&url)
+            .bearer_auth(&self.cfg.as_token)
+            .header(reqwest::header::CONTENT_TYPE, content_type)
+            .body(bytes)
+            .send()
+            .await?;
+        if !resp.status().is_success() {
+            return Err(anyhow::anyhow!(
+                "Matrix media upload failed with status {}",
+                resp.status()
+            ));
+        }
+        Ok(resp.json::<UploadResp>().await?.content_uri)
+    }
+
+    /// Set avatar for puppet user.
+    pub async fn set_avatar_url(&self, user_id: &str, mxc_uri: &str) -> anyhow::Result<()> {
+        if self.dry_run {
+            tracing::info!("Dry run: would set avatar of {} to {}", user_id, mxc_uri);
+            return Ok(());
+        }
         #[derive(Serialize)]
-        struct JoinReq {}
+        struct AvatarUrlReq<'a> {
+            avatar_url: &'a str,
+        }
 
-        let encoded_room = urlencoding::encode(&self.cfg.room_id);
         let encoded_user = urlencoding::encode(user_id);
         let url = format!(
-            "{}/_matrix/client/v3/rooms/{}/join?user_id={}",
-            self.cfg.homeserver, encoded_room, encoded_user
+            "{}/_matrix/client/v3/profile/{}/avatar_url?user_id={}",
+            self.cfg.homeserver, encoded_user, encoded_user
         );
 
         let resp = self
             .http
-            .post(&url)
+            .put(&url)
             .bearer_auth(&self.cfg.as_token)
-            .json(&JoinReq {})
+            .json(&AvatarUrlReq {
+                avatar_url: mxc_uri,
+            })
             .send()
             .await?;
         if resp.status().is_success() {
             Ok(())
+        } else {
+            Err(anyhow::anyhow!(
+                "Failed to set avatar for {}: {}",
+                user_id,
+                resp.status()
+            ))
+        }
+    }
+
+    /// Ensure the puppet user is joined to `room_id`.
+    ///
+    /// Joins are remembered per user and room, so only the first send of a
+    /// puppet into a room asks the homeserver. A room that refuses the join
+    /// (403 `M_FORBIDDEN`, e.g. invite-only) gets the puppet invited by the
+    /// bot, and the join is retried.
+    pub async fn ensure_user_joined_room(
+        &self,
+        user_id: &str,
+        room_id: &str,
+    ) -> anyhow::Result<()> {
+        if self.is_joined(user_id, room_id) {
+            return Ok(());
+        }
+        if self.dry_run {
+            tracing::info!("Dry run: {} would join {}", user_id, room_id);
+            return Ok(());
+        }
+        #[derive(Serialize)]
+        struct JoinReq {}
+
+        let join = |room_id: String| {
+            let url = format!(
+                "{}/_matrix/client/v3/rooms/{}/join?user_id={}",
+                self.cfg.homeserver,
+                urlencoding::encode(&room_id),
+                urlencoding::encode(user_id)
+            );
+            self.http
+                .post(url)
+                .bearer_auth(&self.cfg.as_token)
+                .json(&JoinReq {})
+                .send()
+        };
+
+        let mut room_id = room_id.to_string();
+        let mut resp = join(room_id.clone()).await?;
+        if !resp.status().is_success() {
+            let status = resp.status();
+            let body_snip = resp.text().await.unwrap_or_default();
+            if status == StatusCode::FORBIDDEN
+                && matrix_errcode(&body_snip).as_deref() == Some("M_FORBIDDEN")
+            {
+                self.invite_as_bot(user_id, &room_id).await?;
+                resp = join(room_id.clone()).await?;
+            } else {
+                let Some(created) = self
+                    .replace_missing_room(&room_id, status, &body_snip)
+                    .await?
+                else {
+                    return Err(anyhow::anyhow!(
+                        "Matrix join failed for {} in {} with status {} ({})",
+                        user_id,
+                        room_id,
+                        status,
+                        body_snip
+                    ));
+                };
+                room_id = created;
+                resp = join(room_id.clone()).await?;
+            }
+        }
+
+        if resp.status().is_success() {
+            if let Ok(mut joined) = self.joined.write() {
+                joined.insert((user_id.to_string(), room_id));
+            }
+            Ok(())
         } else {
             let status = resp.status();
             let body_snip = resp.text().await.unwrap_or_default();
             Err(anyhow::anyhow!(
                 "Matrix join failed for {} in {} with status {} ({})",
                 user_id,
-                self.cfg.room_id,
+                room_id,
+                status,
+                body_snip
+            ))
+        }
+    }
+
+    fn is_joined(&self, user_id: &str, room_id: &str) -> bool {
+        self.joined
+            .read()
+            .map(|joined| joined.contains(&(user_id.to_string(), room_id.to_string())))
+            .unwrap_or(false)
+    }
+
+    /// Drop a remembered membership, e.g. after the puppet was refused a
+    /// send, so the next send joins again.
+    fn forget_joined(&self, user_id: &str, room_id: &str) {
+        if let Ok(mut joined) = self.joined.write() {
+            joined.remove(&(user_id.to_string(), room_id.to_string()));
+        }
+    }
+
+    /// Invite `user_id` into `room_id` as the appservice bot user.
+    async fn invite_as_bot(&self, user_id: &str, room_id: &str) -> anyhow::Result<()> {
+        let url = format!(
+            "{}/_matrix/client/v3/rooms/{}/invite",
+            self.cfg.homeserver,
+            urlencoding::encode(room_id)
+        );
+        let resp = self
+            .http
+            .post(&url)
+            .bearer_auth(&self.cfg.as_token)
+            .json(&serde_json::json!({ "user_id": user_id }))
+            .send()
+            .await?;
+        if resp.status().is_success() {
+            Ok(())
+        } else {
+            let status = resp.status();
+            let body_snip = resp.text().await.unwrap_or_default();
+            Err(anyhow::anyhow!(
+                "{} may not join {} and the bot could not invite it (status {}, {})",
+                user_id,
+                room_id,
                 status,
                 body_snip
             ))
         }
     }
 
-    /// Send a text message with HTML formatting into the configured room as puppet user_id.
+    /// With `auto_create_room` set, replace the default room `room_id` when
+    /// the homeserver reports it as missing (404 `M_NOT_FOUND`/`M_UNKNOWN`)
+    /// by a newly created one. Rooms from `channel_rooms` are never replaced.
+    ///
+    /// Returns the new room when there is one, i.e. the request is worth
+    /// retrying there.
+    async fn replace_missing_room(
+        &self,
+        room_id: &str,
+        status: StatusCode,
+        body: &str,
+    ) -> anyhow::Result<Option<String>> {
+        let Some(settings) = &self.cfg.auto_create_room else {
+            return Ok(None);
+        };
+        if !is_missing_room(status, body) || self.room_id().as_deref() != Some(room_id) {
+            return Ok(None);
+        }
+
+        let created = self.create_room(settings).await?;
+        tracing::warn!(
+            "Room {} does not exist; created {} in its place. Set matrix.room_id to it",
+            room_id,
+            created
+        );
+        self.set_room_id(&created);
+        Ok(Some(created))
+    }
+
+    /// Create a room as the appservice bot and return its id.
+    ///
+    /// `visibility` picks both the directory listing and the preset: a
+    /// private room is invite-only (`private_chat`), and puppets that may not
+    /// join get invited by the bot. The room's power levels follow
+    /// `power_levels`; the bot is looked up to be listed in them.
+    async fn create_room(&self, settings: &AutoCreateRoom) -> anyhow::Result<String> {
+        #[derive(Serialize)]
+        struct CreateRoomReq<'a> {
+            preset: &'a str,
+            visibility: &'a str,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            name: Option<&'a str>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            topic: Option<&'a str>,
+            power_level_content_override: serde_json::Value,
+        }
+
+        // The override replaces the creator's entry in `users`, so the bot
+        // has to be named in it to keep any power at all.
+        let bot = self.whoami().await?;
+
+        let url = format!("{}/_matrix/client/v3/createRoom", self.cfg.homeserver);
+        let (preset, visibility) = match settings.visibility {
+            RoomVisibility::Private => ("private_chat", "private"),
+            RoomVisibility::Public => ("public_chat", "public"),
+        };
+        let body = CreateRoomReq {
+            preset,
+            visibility,
+            name: settings.name.as_deref(),
+            topic: settings.topic.as_deref(),
+            power_level_content_override: power_levels_content(&bot, &settings.power_levels),
+        };
+
+        let resp = self
+            .http
+            .post(&url)
+            .bearer_auth(&self.cfg.as_token)
+            .json(&body)
+            .send()
+            .await?;
+        if !resp.status().is_success() {
+            let status = resp.status();
+            let body_snip = resp.text().await.unwrap_or_default();
+            return Err(anyhow::anyhow!(
+                "Matrix room creation failed with status {} ({})",
+                status,
+                body_snip
+            ));
+        }
+
+        let body: serde_json::Value = resp.json().await?;
+        body.get("room_id")
+            .and_then(|id| id.as_str())
+            .map(str::to_string)
+            .ok_or_else(|| anyhow::anyhow!("Matrix createRoom response has no room_id"))
+    }
+
+    /// Send an HTML-formatted `m.text` message as `user_id` into `room_id`,
+    /// as a rich reply to `in_reply_to` when given.
+    ///
+    /// Returns the new event's id. A failed send, or a success response
+    /// without an `event_id`, is an error. A dry run returns a made-up
+    /// `$dry-run-…` id.
     pub async fn send_formatted_message_as(
         &self,
         user_id: &str,
+        room_id: &str,
         body_text: &str,
         formatted_body: &str,
-    ) -> anyhow::Result<()> {
+        in_reply_to: Option<&str>,
+    ) -> anyhow::Result<String> {
         #[derive(Serialize)]
         struct MsgContent<'a> {
             msgtype: &'a str,
             body: &'a str,
             format: &'a str,
             formatted_body: &'a str,
+            #[serde(rename = "m.relates_to", skip_serializing_if = "Option::is_none")]
+            relates_to: Option<serde_json::Value>,
         }
 
-        let txn_id = self.txn_counter.fetch_add(1, Ordering::SeqCst);
-        let encoded_room = urlencoding::encode(&self.cfg.room_id);
-        let encoded_user = urlencoding::encode(user_id);
-
-        let url = format!(
-            "{}/_matrix/client/v3/rooms/{}/send/m.room.message/{}?user_id={}",
-            self.cfg.homeserver, encoded_room, txn_id, encoded_user
-        );
+        if self.dry_run {
+            tracing::info!(
+                "Dry run: would send to {} as {} (reply to {:?}): {:?}",
+                room_id,
+                user_id,
+                in_reply_to,
+                body_text
+            );
+            let txn_id = self.next_txn_id();
+            return Ok(format!("$dry-run-{}", txn_id));
+        }
 
         let content = MsgContent {
             msgtype: "m.text",
             body: body_text,
             format: "org.matrix.custom.html",
             formatted_body,
+            relates_to: in_reply_to
+                .map(|event_id| serde_json::json!({ "m.in_reply_to": { "event_id": event_id } })),
         };
 
-        let resp = self
-            .http
-            .put(&url)
-            .bearer_auth(&self.cfg.as_token)
-            .json(&content)
-            .send()
-            .await?;
+        let resp = match self
+            .put_in_room(room_id, Some(user_id), "send/m.room.message", &content)
+            .await?
+        {
+            Ok(resp) => resp,
+            Err(rejected) => {
+                tracing::warn!(
+                    "Failed to send formatted message as {}: status {}, body: {}",
+                    user_id,
+                    rejected.status,
+                    rejected.body
+                );
+                return Err(anyhow::anyhow!(
+                    "Matrix send failed for {} with status {}",
+                    user_id,
+                    rejected.status
+                ));
+            }
+        };
 
-        if !resp.status().is_success() {
-            let status = resp.status();
-            let body_snip = resp.text().await.unwrap_or_default();
+        let body: serde_json::Value = resp.json().await.unwrap_or_default();
+        body.get("event_id")
+            .and_then(|id| id.as_str())
+            .map(str::to_string)
+            .ok_or_else(|| anyhow::anyhow!("Matrix send response for {} has no event_id", user_id))
+    }
 
-            tracing::warn!(
-                "Failed to send formatted message as {}: status {}, body: {}",
+    /// React to `event_id` in `room_id` with `key` as `user_id`.
+    pub async fn send_reaction_as(
+        &self,
+        user_id: &str,
+        room_id: &str,
+        event_id: &str,
+        key: &str,
+    ) -> anyhow::Result<()> {
+        if self.dry_run {
+            tracing::info!(
+                "Dry run: {} would react to {} in {} with {}",
                 user_id,
-                status,
-                body_snip
+                event_id,
+                room_id,
+                key
             );
+            return Ok(());
+        }
 
-            return Err(anyhow::anyhow!(
-                "Matrix send failed for {} with status {}",
+        let content = serde_json::json!({
+            "m.relates_to": {
+                "rel_type": "m.annotation",
+                "event_id": event_id,
+                "key": key,
+            }
+        });
+
+        match self
+            .put_in_room(room_id, Some(user_id), "send/m.reaction", &content)
+            .await?
+        {
+            Ok(_) => Ok(()),
+            Err(rejected) => Err(anyhow::anyhow!(
+                "Matrix reaction failed for {} with status {}",
                 user_id,
-                status
-            ));
+                rejected.status
+            )),
         }
+    }
 
-        Ok(())
+    /// Set the topic of `room_id` as the appservice bot user.
+    pub async fn set_room_topic(&self, room_id: &str, topic: &str) -> anyhow::Result<()> {
+        if self.dry_run {
+            tracing::info!("Dry run: would set topic of {} to {:?}", room_id, topic);
+            return Ok(());
+        }
+
+        let content = serde_json::json!({ "topic": topic });
+        match self
+            .put_in_room(room_id, None, "state/m.room.topic", &content)
+            .await?
+        {
+            Ok(_) => Ok(()),
+            Err(rejected) => Err(anyhow::anyhow!(
+                "Setting the topic of {} failed with status {}",
+                room_id,
+                rejected.status
+            )),
+        }
+    }
+
+    /// Send an `m.location` message for `lat`/`lon` as `user_id` into
+    /// `room_id`, with `body` as the text fallback.
+    pub async fn send_location_as(
+        &self,
+        user_id: &str,
+        room_id: &str,
+        lat: f64,
+        lon: f64,
+        body: &str,
+    ) -> anyhow::Result<()> {
+        if self.dry_run {
+            tracing::info!(
+                "Dry run: would send location to {} as {}: {:?}",
+                room_id,
+                user_id,
+                body
+            );
+            return Ok(());
+        }
+
+        let content = serde_json::json!({
+            "msgtype": "m.location",
+            "body": body,
+            "geo_uri": format!("geo:{},{}", lat, lon),
+        });
+
+        match self
+            .put_in_room(room_id, Some(user_id), "send/m.room.message", &content)
+            .await?
+        {
+            Ok(_) => Ok(()),
+            Err(rejected) => Err(anyhow::anyhow!(
+                "Matrix location send failed for {} with status {}",
+                user_id,
+                rejected.status
+            )),
+        }
+    }
+
+    /// `PUT` `content` to `/rooms/{room_id}/{path}` as `user_id`, or as the
+    /// bot when `None`. `send/…` and `redact/…` paths get a fresh
+    /// transaction id appended.
+    ///
+    /// Every room write goes through here so it is paced by the limiter,
+    /// retried while rate limited, and moved to a replacement room when the
+    /// homeserver reports the room as missing. A transport error is an
+    /// `Err`; a response the homeserver refused comes back as [`Rejected`].
+    async fn put_in_room(
+        &self,
+        room_id: &str,
+        user_id: Option<&str>,
+        path: &str,
+        content: &impl Serialize,
+    ) -> anyhow::Result<Result<reqwest::Response, Rejected>> {
+        let room_url = |room_id: &str| {
+            let mut url = format!(
+                "{}/_matrix/client/v3/rooms/{}/{}",
+                self.cfg.homeserver,
+                urlencoding::encode(room_id),
+                path
+            );
+            if path.starts_with("send/") || path.starts_with("redact/") {
+                url.push('/');
+                url.push_str(&self.next_txn_id());
+            }
+            if let Some(user_id) = user_id {
+                url.push_str("?user_id=");
+                url.push_str(&urlencoding::encode(user_id));
+            }
+            url
+        };
+
+        let resp = self
+            .put_with_retries(&room_url(room_id), user_id, content)
+            .await?;
+        if resp.status().is_success() {
+            return Ok(Ok(resp));
+        }
+        let status = resp.status();
+        let body = resp.text().await.unwrap_or_default();
+        let Some(created) = self.replace_missing_room(room_id, status, &body).await? else {
+            if let (StatusCode::FORBIDDEN, Some(user_id)) = (status, user_id) {
+                self.forget_joined(user_id, room_id);
+            }
+            return Ok(Err(Rejected { status, body }));
+        };
+
+        // The new room may still need the puppet; the bot created it.
+        if let Some(user_id) = user_id {
+            self.ensure_user_joined_room(user_id, &created).await?;
+        }
+        let resp = self
+            .put_with_retries(&room_url(&created), user_id, content)
+            .await?;
+        if resp.status().is_success() {
+            Ok(Ok(resp))
+        } else {
+            let status = resp.status();
+            let body = resp.text().await.unwrap_or_default();
+            Ok(Err(Rejected { status, body }))
+        }
+    }
+
+    /// `PUT` `content` to `url` once the limiter allows it.
+    ///
+    /// Rate limited: wait as asked (within the cap) and retry with the same
+    /// url, and so the same txn id, so a send the server did accept is not
+    /// duplicated. Once the retries run out the 429 is returned as is.
+    async fn put_with_retries(
+        &self,
+        url: &str,
+        user_id: Option<&str>,
+        content: &impl Serialize,
+    ) -> reqwest::Result<reqwest::Response> {
+        let send = || {
+            self.http
+                .put(url)
+                .bearer_auth(&self.cfg.as_token)
+                .json(content)
+                .send()
+        };
+        self.limiter.acquire().await;
+        let mut resp = send().await?;
+
+        let mut retries = 0;
+        while resp.status() == StatusCode::TOO_MANY_REQUESTS
+            && retries < self.cfg.max_rate_limit_retries
+        {
+            retries += 1;
+            let headers = resp.headers().clone();
+            let body = resp.text().await.unwrap_or_default();
+            let cap = Duration::from_secs(self.cfg.max_retry_after_secs);
+            let delay = retry_after_delay(&headers, &body, cap);
+            tracing::info!(
+                "Rate limited sending as {}; retry {}/{} in {:?}",
+                user_id.unwrap_or("the bot"),
+                retries,
+                self.cfg.max_rate_limit_retries,
+                delay
+            );
+            tokio::time::sleep(delay).await;
+            resp = send().await?;
+        }
+        Ok(resp)
+    }
+
+    /// Redact `event_id` in `room_id` as the appservice bot, which needs the
+    /// power level to redact other users' events there.
+    pub async fn redact_event(
+        &self,
+        room_id: &str,
+        event_id: &str,
+        reason: &str,
+    ) -> anyhow::Result<()> {
+        if self.dry_run {
+            tracing::info!(
+                "Dry run: would redact {} in {}: {:?}",
+                event_id,
+                room_id,
+                reason
+            );
+            return Ok(());
+        }
+        let path = format!("redact/{}", urlencoding::encode(event_id));
+        let content = serde_json::json!({ "reason": reason });
+        match self.put_in_room(room_id, None, &path, &content).await? {
+            Ok(_) => Ok(()),
+            Err(rejected) => Err(anyhow::anyhow!(
+                "Matrix redaction of {} in {} failed with status {} ({})",
+                event_id,
+                room_id,
+                rejected.status,
+                rejected.body
+            )),
+        }
+    }
+
+    /// Send an `m.notice` into `room_id` as the appservice bot user.
+    ///
+    /// Warnings and errors get a `[WARN]`/`[ERROR]` tag; with `rich_notices`
+    /// the tag is also rendered bold and coloured in a `formatted_body`.
+    /// Like every room write it is paced and retried while rate limited.
+    /// Failures are returned without being logged here, so callers that
+    /// forward logs into Matrix cannot trigger themselves recursively.
+    pub async fn send_notice(
+        &self,
+        room_id: &str,
+        level: NoticeLevel,
+        body_text: &str,
+    ) -> anyhow::Result<()> {
+        #[derive(Serialize)]
+        struct NoticeContent<'a> {
+            msgtype: &'a str,
+            body: String,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            format: Option<&'a str>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            formatted_body: Option<String>,
+        }
+
+        if self.dry_run {
+            // Info level: the log room only forwards warnings and errors, so
+            // this cannot loop back into another notice.
+            tracing::info!("Dry run: would post notice to {}: {:?}", room_id, body_text);
+            return Ok(());
+        }
+
+        let (body, formatted_body) = format_notice(level, body_text);
+        let content = NoticeContent {
+            msgtype: "m.notice",
+            body,
+            format: self.cfg.rich_notices.then_some("org.matrix.custom.html"),
+            formatted_body: self.cfg.rich_notices.then_some(formatted_body),
+        };
+
+        match self
+            .put_in_room(room_id, None, "send/m.room.message", &content)
+            .await?
+        {
+            Ok(_) => Ok(()),
+            Err(rejected) => Err(anyhow::anyhow!(
+                "Matrix notice send to {} failed with status {}",
+                room_id,
+                rejected.status
+            )),
+        }
+    }
+}
+
+/// Severity of a bot notice.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NoticeLevel {
+    /// Informational, e.g. a position beacon; sent without a tag.
+    Info,
+    Warn,
+    Error,
+}
+
+impl NoticeLevel {
+    /// Tag and colour shown in front of the notice text, if any.
+    fn tag(self) -> Option<(&'static str, &'static str)> {
+        match self {
+            NoticeLevel::Info => None,
+            NoticeLevel::Warn => Some(("WARN", "#e5a50a")),
+            NoticeLevel::Error => Some(("ERROR", "#c01c28")),
+        }
+    }
+}
+
+/// Plain and HTML bodies of a notice with `text` at `level`.
+fn format_notice(level: NoticeLevel, text: &str) -> (String, String) {
+    let html_text = escape_html(text).replace('\n', "<br>");
+    match level.tag() {
+        Some((tag, color)) => (
+            format!("[{tag}] {text}"),
+            format!("<font data-mx-color=\"{color}\"><b>[{tag}]</b></font> {html_text}"),
+        ),
+        None => (text.to_string(), html_text),
+    }
+}
+
+/// Puppets known to be registered (by localpart) and the display name last
+/// set for each (by user id). Clones share the same sets, so the bridge
+/// state can persist what the client learns.
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(from = "PuppetCacheSnapshot", into = "PuppetCacheSnapshot")]
+pub struct PuppetCache {
+    registered: Arc<RwLock<HashSet<String>>>,
+    display_names: Arc<RwLock<HashMap<String, String>>>,
+}
+
+impl fmt::Debug for PuppetCache {
+    // Listing every puppet would flood the startup log; the sizes are enough.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let registered = self.registered.read().map(|set| set.len()).unwrap_or(0);
+        let names = self.display_names.read().map(|n| n.len()).unwrap_or(0);
+        write!(
+            f,
+            "PuppetCache({} registered, {} display names)",
+            registered, names
+        )
+    }
+}
+
+impl PuppetCache {
+    fn display_name(&self, user_id: &str) -> Option<String> {
+        self.display_names.read().ok()?.get(user_id).cloned()
+    }
+
+    fn record_display_name(&self, user_id: &str, display_name: &str) {
+        if let Ok(mut names) = self.display_names.write() {
+            names.insert(user_id.to_string(), display_name.to_string());
+        }
+    }
+}
+
+/// Number in the next transaction id, seeded from the clock. Clones share
+/// the counter, so the bridge state can persist how far it got and the
+/// next run resumes past it even if the clock went backwards.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(from = "u64", into = "u64")]
+pub struct TxnCounter(Arc<AtomicU64>);
+
+impl Default for TxnCounter {
+    fn default() -> Self {
+        let start = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        Self::from(start)
+    }
+}
+
+impl fmt::Debug for TxnCounter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "TxnCounter({})", self.peek())
+    }
+}
+
+impl From<u64> for TxnCounter {
+    fn from(value: u64) -> Self {
+        Self(Arc::new(AtomicU64::new(value)))
+    }
+}
+
+impl From<TxnCounter> for u64 {
+    fn from(counter: TxnCounter) -> Self {
+        counter.peek()
+    }
+}
+
+impl TxnCounter {
+    fn next(&self) -> u64 {
+        self.0.fetch_add(1, Ordering::SeqCst)
+    }
+
+    fn peek(&self) -> u64 {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Move the counter up to `value` unless it is already past it.
+    fn advance_to(&self, value: u64) {
+        self.0.fetch_max(value, Ordering::SeqCst);
+    }
+}
+
+/// Random per-process start of transaction ids, from the OS's randomness.
+/// Should that fail, the start time still tells restarts apart.
+fn random_txn_prefix() -> String {
+    let random = getrandom::u64().unwrap_or_else(|_| {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64)
+    });
+    format!("{:016x}", random)
+}
+
+/// Sorted, lock-free form of [`PuppetCache`] for (de)serialization.
+#[derive(Default, Serialize, Deserialize)]
+struct PuppetCacheSnapshot {
+    #[serde(default)]
+    registered: BTreeSet<String>,
+    #[serde(default)]
+    display_names: BTreeMap<String, String>,
+}
+
+impl From<PuppetCacheSnapshot> for PuppetCache {
+    fn from(snapshot: PuppetCacheSnapshot) -> Self {
+        Self {
+            registered: Arc::new(RwLock::new(snapshot.registered.into_iter().collect())),
+            display_names: Arc::new(RwLock::new(snapshot.display_names.into_iter().collect())),
+        }
+    }
+}
+
+impl From<PuppetCache> for PuppetCacheSnapshot {
+    fn from(cache: PuppetCache) -> Self {
+        Self {
+            registered: cache
+                .registered
+                .read()
+                .map(|set| set.iter().cloned().collect())
+                .unwrap_or_default(),
+            display_names: cache
+                .display_names
+                .read()
+                .map(|names| names.iter().map(|(k, v)| (k.clone(), v.clone())).collect())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// A room write the homeserver refused: its final status and body.
+struct Rejected {
+    status: StatusCode,
+    body: String,
+}
+
+/// Token bucket pacing room writes to a steady rate, allowing a burst of
+/// one second's worth of sends after a quiet spell.
+struct SendLimiter {
+    /// Sends per second; zero or less disables the limiter.
+    rate: f64,
+    /// Tokens left (negative while sends queue for a token) and when the
+    /// count was last brought up to date.
+    bucket: Mutex<(f64, Instant)>,
+}
+
+impl SendLimiter {
+    fn new(rate: f64) -> Self {
+        Self {
+            rate,
+            bucket: Mutex::new((rate.max(1.0), Instant::now())),
+        }
+    }
+
+    /// Take a token at `now` and return how long to wait before using it.
+    /// Tokens are reserved up front so concurrent senders queue in order.
+    fn reserve(&self, now: Instant) -> Duration {
+        if self.rate.is_nan() || self.rate <= 0.0 {
+            return Duration::ZERO;
+        }
+        let Ok(mut bucket) = self.bucket.lock() else {
+            return Duration::ZERO;
+        };
+        let (tokens, counted_at) = *bucket;
+        let refill = now.saturating_duration_since(counted_at).as_secs_f64() * self.rate;
+        let tokens = (tokens + refill).min(self.rate.max(1.0)) - 1.0;
+        *bucket = (tokens, now);
+        if tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-tokens / self.rate)
+        }
+    }
+
+    /// Wait until the next send is within the configured rate.
+    async fn acquire(&self) {
+        let wait = self.reserve(Instant::now());
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// The `errcode` of a Matrix error response body, if it has one.
+fn matrix_errcode(body: &str) -> Option<String> {
+    serde_json::from_str::<serde_json::Value>(body)
+        .ok()?
+        .get("errcode")?
+        .as_str()
+        .map(str::to_string)
+}
+
+/// `m.room.power_levels` content for a room the bot `bot` creates: `bot`
+/// and the configured users at their levels, everyone else at
+/// `users_default`.
+fn power_levels_content(bot: &str, levels: &RoomPowerLevels) -> serde_json::Value {
+    let mut users = levels.users.clone();
+    users.insert(bot.to_string(), levels.bot);
+    serde_json::json!({
+        "users": users,
+        "users_default": levels.users_default,
+    })
+}
+
+/// Whether a failed room request means the room itself does not exist.
+fn is_missing_room(status: StatusCode, body: &str) -> bool {
+    status == StatusCode::NOT_FOUND && (body.contains("M_NOT_FOUND") || body.contains("M_UNKNOWN"))
+}
+
+/// Wait requested by a 429 response: the `Retry-After` header (seconds),
+/// else Matrix's `retry_after_ms` body field, else one second. Values above
+/// `cap` are clamped so a misbehaving server cannot stall the bridge.
+fn retry_after_delay(headers: &HeaderMap, body: &str, cap: Duration) -> Duration {
+    let requested = headers
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .map(Duration::from_secs)
+        .or_else(|| {
+            serde_json::from_str::<serde_json::Value>(body)
+                .ok()?
+                .get("retry_after_ms")?
+                .as_u64()
+                .map(Duration::from_millis)
+        })
+        .unwrap_or(Duration::from_secs(1));
+    if requested > cap {
+        // Info, not warn: log room notices are sent through this retry too,
+        // and a forwarded warning would queue yet another notice.
+        tracing::info!(
+            "Server asked to retry after {:?}; capping at {:?}",
+            requested,
+            cap
+        );
+        return cap;
     }
+    requested
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::collections::HashMap;
 
     fn dummy_cfg() -> MatrixConfig {
         MatrixConfig {
@@ -261,20 +1421,64 @@ mod tests {
             as_token: "AS_TOKEN".to_string(),
             hs_token: "HS_TOKEN".to_string(),
             server_name: "example.org".to_string(),
-            room_id: "!roomid:example.org".to_string(),
+            room_id: Some("!roomid:example.org".to_string()),
+            log_room: None,
+            max_retry_after_secs: 60,
+            auto_create_room: None,
+            membership_check: Default::default(),
+            channel_rooms: Default::default(),
+            direct_room: None,
+            rich_notices: false,
+            max_rate_limit_retries: 3,
+            sends_per_sec: 0.0,
+            puppet_prefix: "potato_".to_string(),
         }
     }
 
     #[test]
     fn localpart_strips_bang_correctly() {
+        let client = MatrixAppserviceClient::new(reqwest::Client::new(), dummy_cfg());
         assert_eq!(
-            MatrixAppserviceClient::localpart_from_node_id("!deadbeef"),
+            client.localpart_from_node_id("!deadbeef"),
             "potato_deadbeef"
         );
+        assert_eq!(client.localpart_from_node_id("cafebabe"), "potato_cafebabe");
+    }
+
+    #[test]
+    fn localpart_is_case_insensitive() {
+        let client = MatrixAppserviceClient::new(reqwest::Client::new(), dummy_cfg());
+        assert_eq!(
+            client.localpart_from_node_id("!67FC83CB"),
+            client.localpart_from_node_id("!67fc83cb"),
+        );
+        assert_eq!(
+            client.localpart_from_node_id("!67Fc83cB"),
+            "potato_67fc83cb"
+        );
+    }
+
+    #[test]
+    fn localpart_uses_configured_puppet_prefix() {
+        let mut cfg = dummy_cfg();
+        cfg.puppet_prefix = "mesh_".to_string();
+        let client = MatrixAppserviceClient::new(reqwest::Client::new(), cfg);
+        assert_eq!(client.localpart_from_node_id("!DeadBeef"), "mesh_deadbeef");
+        assert_eq!(client.puppet_localpart("bridge_check"), "mesh_bridge_check");
+    }
+
+    #[test]
+    fn localpart_from_channel_sanitizes_name() {
+        let client = MatrixAppserviceClient::new(reqwest::Client::new(), dummy_cfg());
+        assert_eq!(
+            client.localpart_from_channel("LongFast", 0),
+            "potato_channel_longfast"
+        );
         assert_eq!(
-            MatrixAppserviceClient::localpart_from_node_id("cafebabe"),
-            "potato_cafebabe"
+            client.localpart_from_channel("Ops Team #1", 2),
+            "potato_channel_ops_team__1"
         );
+        assert_eq!(client.localpart_from_channel("  ", 3), "potato_channel_3");
     }
 
     #[test]
@@ -327,7 +1531,26 @@ mod tests {
         let client = MatrixAppserviceClient::new(http_client, config);
         assert_eq!(client.cfg.homeserver, "https://matrix.example.org");
         assert_eq!(client.cfg.as_token, "AS_TOKEN");
-        assert!(client.txn_counter.load(Ordering::SeqCst) > 0);
+        assert!(client.txn_counter.peek() > 0);
+    }
+
+    #[test]
+    fn txn_ids_are_prefixed_per_process_and_resume_past_the_saved_counter() {
+        let client = MatrixAppserviceClient::new(reqwest::Client::new(), dummy_cfg());
+        let restarted = MatrixAppserviceClient::new(reqwest::Client::new(), dummy_cfg());
+        let saved: TxnCounter = serde_json::from_str("99999999999999").unwrap();
+
+        let live = client.resume_txn_counter(&saved);
+
+        assert_eq!(
+            client.next_txn_id(),
+            format!("{}-99999999999999", client.txn_prefix)
+        );
+        assert_eq!(serde_json::to_string(&live).unwrap(), "100000000000000");
+        assert_ne!(client.txn_prefix, restarted.txn_prefix);
+        // A counter behind the clock is not moved back.
+        restarted.resume_txn_counter(&TxnCounter::from(1));
+        assert!(restarted.txn_counter.peek() > 1);
     }
 
     #[tokio::test]
@@ -347,6 +1570,26 @@ mod tests {
 
         mock.assert();
         assert!(result.is_ok());
+        assert!(client.is_registered("testuser"));
+    }
+
+    #[tokio::test]
+    async fn test_ensure_user_registered_only_registers_once() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", "/_matrix/client/v3/register")
+            .match_query("kind=user")
+            .with_status(200)
+            .expect(1)
+            .create();
+
+        let mut cfg = dummy_cfg();
+        cfg.homeserver = server.url();
+        let client = MatrixAppserviceClient::new(reqwest::Client::new(), cfg);
+        client.ensure_user_registered("testuser").await.unwrap();
+        client.ensure_user_registered("testuser").await.unwrap();
+
+        mock.assert();
     }
 
     #[tokio::test]
@@ -367,14 +1610,13 @@ mod tests {
 
         mock.assert();
         assert!(result.is_ok());
+        assert!(client.is_registered("testuser"));
     }
 
     #[tokio::test]
     async fn test_ensure_user_registered_other_400_is_not_treated_as_in_use() {
-        // A 400 that is NOT M_USER_IN_USE (e.g. a malformed request) must reach
-        // the warn branch rather than being silently ignored as "already
-        // registered". The call still returns Ok(()) so registration is
-        // non-fatal, but the failure is surfaced for diagnosis.
+        // A 400 that is NOT M_USER_IN_USE (e.g. a malformed request) must not
+        // be silently ignored as "already registered".
         let mut server = mockito::Server::new_async().await;
         let mock = server
             .mock("POST", "/_matrix/client/v3/register")
@@ -390,14 +1632,42 @@ mod tests {
         let result = client.ensure_user_registered("testuser").await;
 
         mock.assert();
-        assert!(result.is_ok());
+        assert!(result.unwrap_err().to_string().contains("M_INVALID_PARAM"));
+        assert!(!client.is_registered("testuser"));
     }
 
     #[tokio::test]
-    async fn test_ensure_user_registered_unexpected_status_logs_and_is_ok() {
-        // A non-400 failure (e.g. 403 from a misconfigured as_token) is NOT the
-        // expected "already registered" case, so it exercises the warn branch.
-        // The call still returns Ok(()) so registration remains non-fatal.
+    async fn test_ensure_user_registered_exclusive_explains_namespace() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", "/_matrix/client/v3/register")
+            .match_query("kind=user")
+            .with_status(400)
+            .with_body(
+                r#"{"errcode":"M_EXCLUSIVE","error":"This user ID is reserved by an application service."}"#,
+            )
+            .create();
+
+        let mut cfg = dummy_cfg();
+        cfg.homeserver = server.url();
+        let client = MatrixAppserviceClient::new(reqwest::Client::new(), cfg);
+        let err = client
+            .ensure_user_registered("potato_abcd1234")
+            .await
+            .unwrap_err()
+            .to_string();
+
+        mock.assert();
+        assert!(err.contains("M_EXCLUSIVE"));
+        assert!(err.contains("@potato_abcd1234:example.org"));
+        assert!(err.contains("namespaces.users"));
+        assert!(!client.is_registered("potato_abcd1234"));
+    }
+
+    #[tokio::test]
+    async fn test_ensure_user_registered_forbidden_is_an_error() {
+        // A 403 from a misconfigured as_token is not the "already registered"
+        // case and must fail the delivery so it is retried.
         let mut server = mockito::Server::new_async().await;
         let mock = server
             .mock("POST", "/_matrix/client/v3/register")
@@ -410,38 +1680,54 @@ mod tests {
         let mut cfg = dummy_cfg();
         cfg.homeserver = server.url();
         let client = MatrixAppserviceClient::new(reqwest::Client::new(), cfg);
-        let result = client.ensure_user_registered("testuser").await;
+        let err = client
+            .ensure_user_registered("testuser")
+            .await
+            .unwrap_err()
+            .to_string();
 
         mock.assert();
-        assert!(result.is_ok());
+        assert!(err.contains("403"));
+        assert!(err.contains("M_FORBIDDEN"));
+        assert!(!client.is_registered("testuser"));
     }
 
     #[tokio::test]
-    async fn test_set_display_name_success() {
+    async fn test_ensure_user_registered_bad_gateway_is_an_error() {
         let mut server = mockito::Server::new_async().await;
-        let user_id = "@test:example.org";
-        let encoded_user = urlencoding::encode(user_id);
-        let query = format!("user_id={}", encoded_user);
-        let path = format!("/_matrix/client/v3/profile/{}/displayname", encoded_user);
-
         let mock = server
-            .mock("PUT", path.as_str())
-            .match_query(query.as_str())
-            .match_header("authorization", "Bearer AS_TOKEN")
-            .with_status(200)
+            .mock("POST", "/_matrix/client/v3/register")
+            .match_query("kind=user")
+            .with_status(502)
+            .with_body("<html>Bad Gateway</html>")
             .create();
 
         let mut cfg = dummy_cfg();
         cfg.homeserver = server.url();
         let client = MatrixAppserviceClient::new(reqwest::Client::new(), cfg);
-        let result = client.set_display_name(user_id, "Test Name").await;
+        let err = client
+            .ensure_user_registered("testuser")
+            .await
+            .unwrap_err()
+            .to_string();
 
         mock.assert();
-        assert!(result.is_ok());
+        assert!(err.contains("502"));
+        assert!(!client.is_registered("testuser"));
+    }
+
+    #[test]
+    fn matrix_errcode_reads_json_error_bodies_only() {
+        assert_eq!(
+            matrix_errcode(r#"{"errcode":"M_USER_IN_USE","error":"taken"}"#).as_deref(),
+            Some("M_USER_IN_USE")
+        );
+        assert_eq!(matrix_errcode(r#"{"error":"no code"}"#), None);
+        assert_eq!(matrix_errcode("<html>Bad Gateway</html>"), None);
     }
 
     #[tokio::test]
-    async fn test_set_display_name_fail_is_ok() {
+    async fn test_set_display_name_success() {
         let mut server = mockito::Server::new_async().await;
         let user_id = "@test:example.org";
         let encoded_user = urlencoding::encode(user_id);
@@ -452,7 +1738,7 @@ mod tests {
             .mock("PUT", path.as_str())
             .match_query(query.as_str())
             .match_header("authorization", "Bearer AS_TOKEN")
-            .with_status(500)
+            .with_status(200)
             .create();
 
         let mut cfg = dummy_cfg();
@@ -465,40 +1751,212 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_ensure_user_joined_room_success() {
+    async fn test_set_display_name_only_when_changed() {
         let mut server = mockito::Server::new_async().await;
         let user_id = "@test:example.org";
-        let room_id = "!roomid:example.org";
         let encoded_user = urlencoding::encode(user_id);
-        let encoded_room = urlencoding::encode(room_id);
-        let query = format!("user_id={}", encoded_user);
-        let path = format!("/_matrix/client/v3/rooms/{}/join", encoded_room);
+        let path = format!("/_matrix/client/v3/profile/{}/displayname", encoded_user);
 
-        let mock = server
-            .mock("POST", path.as_str())
-            .match_query(query.as_str())
-            .match_header("authorization", "Bearer AS_TOKEN")
+        let first = server
+            .mock("PUT", path.as_str())
+            .match_query(mockito::Matcher::Any)
+            .match_body(mockito::Matcher::PartialJson(
+                serde_json::json!({ "displayname": "Test Name" }),
+            ))
+            .with_status(200)
+            .expect(1)
+            .create();
+        let renamed = server
+            .mock("PUT", path.as_str())
+            .match_query(mockito::Matcher::Any)
+            .match_body(mockito::Matcher::PartialJson(
+                serde_json::json!({ "displayname": "New Name" }),
+            ))
             .with_status(200)
+            .expect(1)
             .create();
 
         let mut cfg = dummy_cfg();
         cfg.homeserver = server.url();
-        cfg.room_id = room_id.to_string();
         let client = MatrixAppserviceClient::new(reqwest::Client::new(), cfg);
-        let result = client.ensure_user_joined_room(user_id).await;
+        client.set_display_name(user_id, "Test Name").await.unwrap();
+        client.set_display_name(user_id, "Test Name").await.unwrap();
+        client.set_display_name(user_id, "New Name").await.unwrap();
 
-        mock.assert();
-        assert!(result.is_ok());
+        first.assert();
+        renamed.assert();
     }
 
     #[tokio::test]
-    async fn test_ensure_user_joined_room_fail() {
+    async fn test_restored_puppet_cache_skips_register_and_rename() {
         let mut server = mockito::Server::new_async().await;
-        let user_id = "@test:example.org";
-        let room_id = "!roomid:example.org";
-        let encoded_user = urlencoding::encode(user_id);
-        let encoded_room = urlencoding::encode(room_id);
-        let query = format!("user_id={}", encoded_user);
+        let register = server
+            .mock("POST", "/_matrix/client/v3/register")
+            .match_query(mockito::Matcher::Any)
+            .expect(0)
+            .create();
+        let rename = server
+            .mock("PUT", mockito::Matcher::Regex("/displayname".to_string()))
+            .match_query(mockito::Matcher::Any)
+            .expect(0)
+            .create();
+
+        let puppets: PuppetCache = serde_json::from_value(serde_json::json!({
+            "registered": ["potato_abcd1234"],
+            "display_names": { "@potato_abcd1234:example.org": "Test Node" },
+        }))
+        .unwrap();
+        let mut cfg = dummy_cfg();
+        cfg.homeserver = server.url();
+        let mut client = MatrixAppserviceClient::new(reqwest::Client::new(), cfg);
+        client.set_puppet_cache(puppets);
+
+        client
+            .ensure_user_registered("potato_abcd1234")
+            .await
+            .unwrap();
+        client
+            .set_display_name("@potato_abcd1234:example.org", "Test Node")
+            .await
+            .unwrap();
+
+        register.assert();
+        rename.assert();
+    }
+
+    #[test]
+    fn puppet_cache_clones_share_what_they_learn() {
+        let cache = PuppetCache::default();
+        let shared = cache.clone();
+        shared.record_display_name("@potato_abcd1234:example.org", "Test Node");
+        if let Ok(mut set) = shared.registered.write() {
+            set.insert("potato_abcd1234".to_string());
+        }
+
+        let json = serde_json::to_value(&cache).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "registered": ["potato_abcd1234"],
+                "display_names": { "@potato_abcd1234:example.org": "Test Node" },
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_set_display_name_fail_is_ok() {
+        let mut server = mockito::Server::new_async().await;
+        let user_id = "@test:example.org";
+        let encoded_user = urlencoding::encode(user_id);
+        let query = format!("user_id={}", encoded_user);
+        let path = format!("/_matrix/client/v3/profile/{}/displayname", encoded_user);
+
+        let mock = server
+            .mock("PUT", path.as_str())
+            .match_query(query.as_str())
+            .match_header("authorization", "Bearer AS_TOKEN")
+            .with_status(500)
+            .create();
+
+        let mut cfg = dummy_cfg();
+        cfg.homeserver = server.url();
+        let client = MatrixAppserviceClient::new(reqwest::Client::new(), cfg);
+        let result = client.set_display_name(user_id, "Test Name").await;
+
+        mock.assert();
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_ensure_user_joined_room_success() {
+        let mut server = mockito::Server::new_async().await;
+        let user_id = "@test:example.org";
+        let room_id = "!roomid:example.org";
+        let encoded_user = urlencoding::encode(user_id);
+        let encoded_room = urlencoding::encode(room_id);
+        let query = format!("user_id={}", encoded_user);
+        let path = format!("/_matrix/client/v3/rooms/{}/join", encoded_room);
+
+        let mock = server
+            .mock("POST", path.as_str())
+            .match_query(query.as_str())
+            .match_header("authorization", "Bearer AS_TOKEN")
+            .with_status(200)
+            .create();
+
+        let mut cfg = dummy_cfg();
+        cfg.homeserver = server.url();
+        cfg.room_id = Some(room_id.to_string());
+        let client = MatrixAppserviceClient::new(reqwest::Client::new(), cfg);
+        let result = client.ensure_user_joined_room(user_id, room_id).await;
+
+        mock.assert();
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn room_for_channel_prefers_channel_rooms_over_default() {
+        let mut cfg = dummy_cfg();
+        cfg.channel_rooms = HashMap::from([(2, "!admin:example.org".to_string())]);
+        let client = MatrixAppserviceClient::new(reqwest::Client::new(), cfg);
+
+        assert_eq!(client.room_for_channel(2).unwrap(), "!admin:example.org");
+        assert_eq!(client.room_for_channel(0).unwrap(), "!roomid:example.org");
+        assert_eq!(
+            client.bridged_rooms(),
+            vec!["!roomid:example.org", "!admin:example.org"]
+        );
+    }
+
+    #[test]
+    fn room_for_message_sends_direct_messages_to_direct_room() {
+        let mut cfg = dummy_cfg();
+        cfg.channel_rooms = HashMap::from([(2, "!admin:example.org".to_string())]);
+        let client = MatrixAppserviceClient::new(reqwest::Client::new(), cfg.clone());
+        assert_eq!(
+            client.room_for_message(2, false).unwrap(),
+            "!admin:example.org"
+        );
+
+        cfg.direct_room = Some("!dms:example.org".to_string());
+        let client = MatrixAppserviceClient::new(reqwest::Client::new(), cfg);
+        assert_eq!(
+            client.room_for_message(2, false).unwrap(),
+            "!dms:example.org"
+        );
+        assert_eq!(
+            client.room_for_message(2, true).unwrap(),
+            "!admin:example.org"
+        );
+        assert_eq!(
+            client.bridged_rooms(),
+            vec![
+                "!roomid:example.org",
+                "!admin:example.org",
+                "!dms:example.org"
+            ]
+        );
+    }
+
+    #[test]
+    fn room_for_channel_fails_for_unmapped_channel_without_default() {
+        let mut cfg = dummy_cfg();
+        cfg.room_id = None;
+        cfg.channel_rooms = HashMap::from([(0, "!primary:example.org".to_string())]);
+        let client = MatrixAppserviceClient::new(reqwest::Client::new(), cfg);
+
+        assert_eq!(client.room_for_channel(0).unwrap(), "!primary:example.org");
+        assert!(client.room_for_channel(1).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_ensure_user_joined_room_fail() {
+        let mut server = mockito::Server::new_async().await;
+        let user_id = "@test:example.org";
+        let room_id = "!roomid:example.org";
+        let encoded_user = urlencoding::encode(user_id);
+        let encoded_room = urlencoding::encode(room_id);
+        let query = format!("user_id={}", encoded_user);
         let path = format!("/_matrix/client/v3/rooms/{}/join", encoded_room);
 
         let mock = server
@@ -510,14 +1968,179 @@ mod tests {
 
         let mut cfg = dummy_cfg();
         cfg.homeserver = server.url();
-        cfg.room_id = room_id.to_string();
+        cfg.room_id = Some(room_id.to_string());
         let client = MatrixAppserviceClient::new(reqwest::Client::new(), cfg);
-        let result = client.ensure_user_joined_room(user_id).await;
+        let result = client.ensure_user_joined_room(user_id, room_id).await;
 
         mock.assert();
         assert!(result.is_err());
     }
 
+    #[tokio::test]
+    async fn test_ensure_user_joined_room_joins_once() {
+        let mut server = mockito::Server::new_async().await;
+        let room_id = "!roomid:example.org";
+        let path = format!(
+            "/_matrix/client/v3/rooms/{}/join",
+            urlencoding::encode(room_id)
+        );
+        let mock = server
+            .mock("POST", path.as_str())
+            .match_query(mockito::Matcher::Any)
+            .with_status(200)
+            .expect(2)
+            .create();
+
+        let mut cfg = dummy_cfg();
+        cfg.homeserver = server.url();
+        let client = MatrixAppserviceClient::new(reqwest::Client::new(), cfg);
+        for _ in 0..3 {
+            client
+                .ensure_user_joined_room("@a:example.org", room_id)
+                .await
+                .unwrap();
+        }
+        // A different puppet still needs its own join.
+        client
+            .ensure_user_joined_room("@b:example.org", room_id)
+            .await
+            .unwrap();
+
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_ensure_user_joined_room_invites_when_forbidden() {
+        let mut server = mockito::Server::new_async().await;
+        let user_id = "@test:example.org";
+        let room_id = "!roomid:example.org";
+        let encoded_room = urlencoding::encode(room_id);
+        let join_path = format!("/_matrix/client/v3/rooms/{}/join", encoded_room);
+        let invite_path = format!("/_matrix/client/v3/rooms/{}/invite", encoded_room);
+
+        let refused = server
+            .mock("POST", join_path.as_str())
+            .match_query(mockito::Matcher::Any)
+            .with_status(403)
+            .with_body(r#"{"errcode":"M_FORBIDDEN","error":"You are not invited to this room."}"#)
+            .expect(1)
+            .create();
+        let invite = server
+            .mock("POST", invite_path.as_str())
+            .match_query(mockito::Matcher::Missing)
+            .match_body(mockito::Matcher::Json(
+                serde_json::json!({ "user_id": user_id }),
+            ))
+            .with_status(200)
+            .expect(1)
+            .create();
+        let joined = server
+            .mock("POST", join_path.as_str())
+            .match_query(mockito::Matcher::Any)
+            .with_status(200)
+            .expect(1)
+            .create();
+
+        let mut cfg = dummy_cfg();
+        cfg.homeserver = server.url();
+        let client = MatrixAppserviceClient::new(reqwest::Client::new(), cfg);
+        client
+            .ensure_user_joined_room(user_id, room_id)
+            .await
+            .unwrap();
+
+        refused.assert();
+        invite.assert();
+        joined.assert();
+        assert!(client.is_joined(user_id, room_id));
+    }
+
+    #[tokio::test]
+    async fn test_ensure_user_joined_room_reports_failed_invite() {
+        let mut server = mockito::Server::new_async().await;
+        let room_id = "!roomid:example.org";
+        let encoded_room = urlencoding::encode(room_id);
+        let join = server
+            .mock(
+                "POST",
+                format!("/_matrix/client/v3/rooms/{}/join", encoded_room).as_str(),
+            )
+            .match_query(mockito::Matcher::Any)
+            .with_status(403)
+            .with_body(r#"{"errcode":"M_FORBIDDEN","error":"You are not invited to this room."}"#)
+            .expect(1)
+            .create();
+        let invite = server
+            .mock(
+                "POST",
+                format!("/_matrix/client/v3/rooms/{}/invite", encoded_room).as_str(),
+            )
+            .with_status(403)
+            .with_body(r#"{"errcode":"M_FORBIDDEN","error":"Insufficient power level"}"#)
+            .expect(1)
+            .create();
+
+        let mut cfg = dummy_cfg();
+        cfg.homeserver = server.url();
+        let client = MatrixAppserviceClient::new(reqwest::Client::new(), cfg);
+        let err = client
+            .ensure_user_joined_room("@test:example.org", room_id)
+            .await
+            .unwrap_err()
+            .to_string();
+
+        join.assert();
+        invite.assert();
+        assert!(err.contains("could not invite"));
+        assert!(!client.is_joined("@test:example.org", room_id));
+    }
+
+    #[tokio::test]
+    async fn test_forbidden_send_forgets_membership() {
+        let mut server = mockito::Server::new_async().await;
+        let user_id = "@test:example.org";
+        let room_id = "!roomid:example.org";
+        let encoded_room = urlencoding::encode(room_id);
+        let join = server
+            .mock(
+                "POST",
+                format!("/_matrix/client/v3/rooms/{}/join", encoded_room).as_str(),
+            )
+            .match_query(mockito::Matcher::Any)
+            .with_status(200)
+            .expect(2)
+            .create();
+        let send = server
+            .mock(
+                "PUT",
+                mockito::Matcher::Regex(format!("/rooms/{}/send/", encoded_room)),
+            )
+            .match_query(mockito::Matcher::Any)
+            .with_status(403)
+            .with_body(r#"{"errcode":"M_FORBIDDEN","error":"User not in room"}"#)
+            .expect(1)
+            .create();
+
+        let mut cfg = dummy_cfg();
+        cfg.homeserver = server.url();
+        let client = MatrixAppserviceClient::new(reqwest::Client::new(), cfg);
+        client
+            .ensure_user_joined_room(user_id, room_id)
+            .await
+            .unwrap();
+        assert!(client
+            .send_formatted_message_as(user_id, room_id, "hi", "hi", None)
+            .await
+            .is_err());
+        client
+            .ensure_user_joined_room(user_id, room_id)
+            .await
+            .unwrap();
+
+        join.assert();
+        send.assert();
+    }
+
     #[tokio::test]
     async fn test_send_formatted_message_as_success() {
         let mut server = mockito::Server::new_async().await;
@@ -529,10 +2152,10 @@ mod tests {
         let client = {
             let mut cfg = dummy_cfg();
             cfg.homeserver = server.url();
-            cfg.room_id = room_id.to_string();
+            cfg.room_id = Some(room_id.to_string());
             MatrixAppserviceClient::new(reqwest::Client::new(), cfg)
         };
-        let txn_id = client.txn_counter.load(Ordering::SeqCst);
+        let txn_id = client.upcoming_txn_id();
         let query = format!("user_id={}", encoded_user);
         let path = format!(
             "/_matrix/client/v3/rooms/{}/send/m.room.message/{}",
@@ -550,13 +2173,953 @@ mod tests {
                 "formatted_body": "<code>[meta]</code> hello",
             })))
             .with_status(200)
+            .with_body(r#"{"event_id": "$hello"}"#)
             .create();
 
         let result = client
-            .send_formatted_message_as(user_id, "`[meta]` hello", "<code>[meta]</code> hello")
+            .send_formatted_message_as(
+                user_id,
+                room_id,
+                "`[meta]` hello",
+                "<code>[meta]</code> hello",
+                None,
+            )
             .await;
 
         mock.assert();
+        assert_eq!(result.unwrap(), "$hello");
+    }
+
+    fn auto_create_client(server: &mockito::ServerGuard) -> MatrixAppserviceClient {
+        let mut cfg = dummy_cfg();
+        cfg.homeserver = server.url();
+        cfg.auto_create_room = Some(AutoCreateRoom {
+            name: Some("Mesh".to_string()),
+            topic: Some("PotatoMesh traffic".to_string()),
+            visibility: RoomVisibility::Public,
+            power_levels: RoomPowerLevels::default(),
+        });
+        MatrixAppserviceClient::new(reqwest::Client::new(), cfg)
+    }
+
+    /// Mock the bot's `whoami`, looked up when a room is created.
+    fn mock_bot_whoami(server: &mut mockito::ServerGuard) -> mockito::Mock {
+        server
+            .mock("GET", "/_matrix/client/v3/account/whoami")
+            .with_status(200)
+            .with_body(r#"{"user_id": "@bridge:example.org"}"#)
+            .create()
+    }
+
+    /// Mock `method` requests below `/rooms/<room_id>/`.
+    fn mock_room_request(
+        server: &mut mockito::ServerGuard,
+        method: &str,
+        room_id: &str,
+        status: usize,
+        body: &str,
+    ) -> mockito::Mock {
+        let path = format!(
+            "^/_matrix/client/v3/rooms/{}/",
+            urlencoding::encode(room_id)
+        );
+        server
+            .mock(method, mockito::Matcher::Regex(path))
+            .match_query(mockito::Matcher::Any)
+            .with_status(status)
+            .with_body(body)
+            .create()
+    }
+
+    #[tokio::test]
+    async fn test_send_creates_missing_room_and_retries() {
+        let mut server = mockito::Server::new_async().await;
+        let client = auto_create_client(&server);
+        let missing = r#"{"errcode": "M_NOT_FOUND", "error": "Unknown room"}"#;
+        let old_send = mock_room_request(&mut server, "PUT", "!roomid:example.org", 404, missing);
+        mock_bot_whoami(&mut server);
+        let create = server
+            .mock("POST", "/_matrix/client/v3/createRoom")
+            .match_header("authorization", "Bearer AS_TOKEN")
+            .match_body(mockito::Matcher::PartialJson(serde_json::json!({
+                "preset": "public_chat",
+                "visibility": "public",
+                "name": "Mesh",
+                "topic": "PotatoMesh traffic",
+            })))
+            .with_status(200)
+            .with_body(r#"{"room_id": "!new:example.org"}"#)
+            .create();
+        let join = mock_room_request(&mut server, "POST", "!new:example.org", 200, "{}");
+        let new_send = mock_room_request(
+            &mut server,
+            "PUT",
+            "!new:example.org",
+            200,
+            r#"{"event_id": "$hello"}"#,
+        );
+
+        let result = client
+            .send_formatted_message_as(
+                "@test:example.org",
+                "!roomid:example.org",
+                "hello",
+                "hello",
+                None,
+            )
+            .await;
+
+        old_send.assert();
+        create.assert();
+        join.assert();
+        new_send.assert();
+        assert_eq!(result.unwrap(), "$hello");
+        assert_eq!(client.room_id().as_deref(), Some("!new:example.org"));
+        assert_eq!(
+            client.created_room_id().as_deref(),
+            Some("!new:example.org")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_set_room_topic_moves_to_created_room() {
+        let mut server = mockito::Server::new_async().await;
+        let client = auto_create_client(&server);
+        let missing = r#"{"errcode": "M_NOT_FOUND", "error": "Unknown room"}"#;
+        let old_topic = mock_room_request(&mut server, "PUT", "!roomid:example.org", 404, missing);
+        mock_bot_whoami(&mut server);
+        let create = server
+            .mock("POST", "/_matrix/client/v3/createRoom")
+            .with_status(200)
+            .with_body(r#"{"room_id": "!new:example.org"}"#)
+            .create();
+        // The bot created the room, so there is no one to join.
+        let join = mock_room_request(&mut server, "POST", "!new:example.org", 200, "{}").expect(0);
+        let new_topic = mock_room_request(&mut server, "PUT", "!new:example.org", 200, "{}");
+
+        let result = client
+            .set_room_topic("!roomid:example.org", "PotatoMesh — 1 node seen")
+            .await;
+
+        old_topic.assert();
+        create.assert();
+        join.assert();
+        new_topic.assert();
+        assert!(result.is_ok());
+        assert_eq!(client.room_id().as_deref(), Some("!new:example.org"));
+    }
+
+    #[tokio::test]
+    async fn test_create_private_room_is_invite_only() {
+        let mut server = mockito::Server::new_async().await;
+        let client = auto_create_client(&server);
+        mock_bot_whoami(&mut server);
+        let create = server
+            .mock("POST", "/_matrix/client/v3/createRoom")
+            .match_body(mockito::Matcher::PartialJson(serde_json::json!({
+                "preset": "private_chat",
+                "visibility": "private",
+            })))
+            .with_status(200)
+            .with_body(r#"{"room_id": "!private:example.org"}"#)
+            .create();
+
+        let settings = AutoCreateRoom {
+            visibility: RoomVisibility::Private,
+            ..AutoCreateRoom::default()
+        };
+        let room_id = client.create_room(&settings).await.unwrap();
+
+        create.assert();
+        assert_eq!(room_id, "!private:example.org");
+    }
+
+    #[tokio::test]
+    async fn test_create_room_keeps_puppets_at_default_and_bot_moderating() {
+        let mut server = mockito::Server::new_async().await;
+        let client = auto_create_client(&server);
+        mock_bot_whoami(&mut server);
+        let create = server
+            .mock("POST", "/_matrix/client/v3/createRoom")
+            .match_body(mockito::Matcher::PartialJson(serde_json::json!({
+                "power_level_content_override": {
+                    "users": {"@alice:example.org": 100, "@bridge:example.org": 50},
+                    "users_default": 0,
+                },
+            })))
+            .with_status(200)
+            .with_body(r#"{"room_id": "!new:example.org"}"#)
+            .create();
+
+        let settings = AutoCreateRoom {
+            power_levels: RoomPowerLevels {
+                users: BTreeMap::from([("@alice:example.org".to_string(), 100)]),
+                ..RoomPowerLevels::default()
+            },
+            ..AutoCreateRoom::default()
+        };
+        client.create_room(&settings).await.unwrap();
+
+        create.assert();
+    }
+
+    #[tokio::test]
+    async fn test_join_creates_missing_room_and_retries() {
+        let mut server = mockito::Server::new_async().await;
+        let client = auto_create_client(&server);
+        let missing = r#"{"errcode": "M_UNKNOWN", "error": "No known servers"}"#;
+        let old_join = mock_room_request(&mut server, "POST", "!roomid:example.org", 404, missing);
+        mock_bot_whoami(&mut server);
+        let create = server
+            .mock("POST", "/_matrix/client/v3/createRoom")
+            .with_status(200)
+            .with_body(r#"{"room_id": "!new:example.org"}"#)
+            .create();
+        let new_join = mock_room_request(&mut server, "POST", "!new:example.org", 200, "{}");
+
+        let result = client
+            .ensure_user_joined_room("@test:example.org", "!roomid:example.org")
+            .await;
+
+        old_join.assert();
+        create.assert();
+        new_join.assert();
+        assert!(result.is_ok());
+        assert_eq!(client.room_id().as_deref(), Some("!new:example.org"));
+    }
+
+    #[tokio::test]
+    async fn test_send_does_not_create_existing_room() {
+        let mut server = mockito::Server::new_async().await;
+        let client = auto_create_client(&server);
+        let send = mock_room_request(
+            &mut server,
+            "PUT",
+            "!roomid:example.org",
+            200,
+            r#"{"event_id": "$hello"}"#,
+        );
+        mock_bot_whoami(&mut server);
+        let create = server
+            .mock("POST", "/_matrix/client/v3/createRoom")
+            .expect(0)
+            .create();
+
+        let result = client
+            .send_formatted_message_as(
+                "@test:example.org",
+                "!roomid:example.org",
+                "hello",
+                "hello",
+                None,
+            )
+            .await;
+
+        send.assert();
+        create.assert();
         assert!(result.is_ok());
+        assert_eq!(client.created_room_id(), None);
+    }
+
+    #[tokio::test]
+    async fn test_send_to_missing_room_fails_without_auto_create() {
+        let mut server = mockito::Server::new_async().await;
+        let client = {
+            let mut cfg = dummy_cfg();
+            cfg.homeserver = server.url();
+            MatrixAppserviceClient::new(reqwest::Client::new(), cfg)
+        };
+        let missing = r#"{"errcode": "M_NOT_FOUND", "error": "Unknown room"}"#;
+        let send = mock_room_request(&mut server, "PUT", "!roomid:example.org", 404, missing);
+        mock_bot_whoami(&mut server);
+        let create = server
+            .mock("POST", "/_matrix/client/v3/createRoom")
+            .expect(0)
+            .create();
+
+        let result = client
+            .send_formatted_message_as(
+                "@test:example.org",
+                "!roomid:example.org",
+                "hello",
+                "hello",
+                None,
+            )
+            .await;
+
+        send.assert();
+        create.assert();
+        assert!(result.is_err());
+    }
+
+    fn membership_client(
+        server: &mockito::ServerGuard,
+        check: MembershipCheck,
+    ) -> MatrixAppserviceClient {
+        let mut cfg = dummy_cfg();
+        cfg.homeserver = server.url();
+        cfg.membership_check = check;
+        MatrixAppserviceClient::new(reqwest::Client::new(), cfg)
+    }
+
+    fn mock_joined_rooms(server: &mut mockito::ServerGuard, rooms: &[&str]) -> mockito::Mock {
+        server
+            .mock("GET", "/_matrix/client/v3/joined_rooms")
+            .match_header("authorization", "Bearer AS_TOKEN")
+            .with_status(200)
+            .with_body(serde_json::json!({ "joined_rooms": rooms }).to_string())
+            .create()
+    }
+
+    #[tokio::test]
+    async fn test_check_room_membership_accepts_joined_room() {
+        let mut server = mockito::Server::new_async().await;
+        let joined = mock_joined_rooms(&mut server, &["!roomid:example.org"]);
+        let join =
+            mock_room_request(&mut server, "POST", "!roomid:example.org", 200, "{}").expect(0);
+        let client = membership_client(&server, MembershipCheck::Require);
+
+        let result = client
+            .check_room_membership(&["!roomid:example.org".to_string()])
+            .await;
+
+        joined.assert();
+        join.assert();
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_check_room_membership_joins_missing_room() {
+        let mut server = mockito::Server::new_async().await;
+        let _joined = mock_joined_rooms(&mut server, &["!other:example.org"]);
+        let join = server
+            .mock(
+                "POST",
+                format!(
+                    "/_matrix/client/v3/rooms/{}/join",
+                    urlencoding::encode("!roomid:example.org")
+                )
+                .as_str(),
+            )
+            .match_query(mockito::Matcher::Missing)
+            .with_status(200)
+            .with_body("{}")
+            .create();
+        let client = membership_client(&server, MembershipCheck::Join);
+
+        let result = client
+            .check_room_membership(&["!roomid:example.org".to_string()])
+            .await;
+
+        join.assert();
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_check_room_membership_requires_joined_room() {
+        let mut server = mockito::Server::new_async().await;
+        let _joined = mock_joined_rooms(&mut server, &[]);
+        let client = membership_client(&server, MembershipCheck::Require);
+
+        let err = client
+            .check_room_membership(&["!roomid:example.org".to_string()])
+            .await
+            .unwrap_err();
+
+        assert!(err
+            .to_string()
+            .contains("not joined to !roomid:example.org"));
+    }
+
+    #[tokio::test]
+    async fn test_check_room_membership_off_skips_request() {
+        let mut server = mockito::Server::new_async().await;
+        let joined = mock_joined_rooms(&mut server, &[]).expect(0);
+        let client = membership_client(&server, MembershipCheck::Off);
+
+        let result = client
+            .check_room_membership(&["!roomid:example.org".to_string()])
+            .await;
+
+        joined.assert();
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_send_reaction_as() {
+        let mut server = mockito::Server::new_async().await;
+        let client = {
+            let mut cfg = dummy_cfg();
+            cfg.homeserver = server.url();
+            MatrixAppserviceClient::new(reqwest::Client::new(), cfg)
+        };
+        let txn_id = client.upcoming_txn_id();
+        let path = format!(
+            "/_matrix/client/v3/rooms/{}/send/m.reaction/{}",
+            urlencoding::encode("!roomid:example.org"),
+            txn_id
+        );
+
+        let mock = server
+            .mock("PUT", path.as_str())
+            .match_query("user_id=%40test%3Aexample.org")
+            .match_body(mockito::Matcher::Json(serde_json::json!({
+                "m.relates_to": {
+                    "rel_type": "m.annotation",
+                    "event_id": "$orig",
+                    "key": "🔁",
+                }
+            })))
+            .with_status(200)
+            .create();
+
+        let result = client
+            .send_reaction_as("@test:example.org", "!roomid:example.org", "$orig", "🔁")
+            .await;
+
+        mock.assert();
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn dry_run_logs_writes_instead_of_sending() {
+        let mut server = mockito::Server::new_async().await;
+        let mut client = {
+            let mut cfg = dummy_cfg();
+            cfg.homeserver = server.url();
+            MatrixAppserviceClient::new(reqwest::Client::new(), cfg)
+        };
+        client.set_dry_run(true);
+        let mock = server.mock("ANY", mockito::Matcher::Any).expect(0).create();
+
+        client
+            .ensure_user_registered("potato_abcd1234")
+            .await
+            .unwrap();
+        assert!(client.is_registered("potato_abcd1234"));
+        let user_id = "@potato_abcd1234:example.org";
+        client
+            .ensure_user_joined_room(user_id, "!roomid:example.org")
+            .await
+            .unwrap();
+        client.set_display_name(user_id, "Test Node").await.unwrap();
+        let event_id = client
+            .send_formatted_message_as(user_id, "!roomid:example.org", "Ping", "Ping", None)
+            .await
+            .unwrap();
+        assert!(event_id.starts_with("$dry-run-"));
+        client
+            .send_notice("!roomid:example.org", NoticeLevel::Info, "Hi")
+            .await
+            .unwrap();
+        assert!(client.with_config(dummy_cfg()).is_dry_run());
+
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_set_room_topic() {
+        let mut server = mockito::Server::new_async().await;
+        let client = {
+            let mut cfg = dummy_cfg();
+            cfg.homeserver = server.url();
+            MatrixAppserviceClient::new(reqwest::Client::new(), cfg)
+        };
+        let path = format!(
+            "/_matrix/client/v3/rooms/{}/state/m.room.topic",
+            urlencoding::encode("!roomid:example.org")
+        );
+
+        let mock = server
+            .mock("PUT", path.as_str())
+            .match_header("authorization", "Bearer AS_TOKEN")
+            .match_body(mockito::Matcher::Json(
+                serde_json::json!({ "topic": "PotatoMesh — 1 node seen" }),
+            ))
+            .with_status(200)
+            .create();
+
+        let result = client
+            .set_room_topic("!roomid:example.org", "PotatoMesh — 1 node seen")
+            .await;
+
+        mock.assert();
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_send_location_as() {
+        let mut server = mockito::Server::new_async().await;
+        let client = {
+            let mut cfg = dummy_cfg();
+            cfg.homeserver = server.url();
+            MatrixAppserviceClient::new(reqwest::Client::new(), cfg)
+        };
+        let txn_id = client.upcoming_txn_id();
+        let path = format!(
+            "/_matrix/client/v3/rooms/{}/send/m.room.message/{}",
+            urlencoding::encode("!roomid:example.org"),
+            txn_id
+        );
+
+        let mock = server
+            .mock("PUT", path.as_str())
+            .match_query("user_id=%40test%3Aexample.org")
+            .match_body(mockito::Matcher::Json(serde_json::json!({
+                "msgtype": "m.location",
+                "body": "FFVH moved to 52.4649, 13.4853",
+                "geo_uri": "geo:52.4649,13.4853",
+            })))
+            .with_status(200)
+            .create();
+
+        let result = client
+            .send_location_as(
+                "@test:example.org",
+                "!roomid:example.org",
+                52.4649,
+                13.4853,
+                "FFVH moved to 52.4649, 13.4853",
+            )
+            .await;
+
+        mock.assert();
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_redact_event() {
+        let mut server = mockito::Server::new_async().await;
+        let client = {
+            let mut cfg = dummy_cfg();
+            cfg.homeserver = server.url();
+            MatrixAppserviceClient::new(reqwest::Client::new(), cfg)
+        };
+        let txn_id = client.upcoming_txn_id();
+        let path = format!(
+            "/_matrix/client/v3/rooms/{}/redact/{}/{}",
+            urlencoding::encode("!roomid:example.org"),
+            urlencoding::encode("$bridged"),
+            txn_id
+        );
+
+        let accepted = server
+            .mock("PUT", path.as_str())
+            .match_body(mockito::Matcher::Json(serde_json::json!({
+                "reason": "Removed by admin",
+            })))
+            .with_status(200)
+            .with_body(r#"{"event_id":"$redaction"}"#)
+            .create();
+        let result = client
+            .redact_event("!roomid:example.org", "$bridged", "Removed by admin")
+            .await;
+        accepted.assert();
+        assert!(result.is_ok());
+
+        let refused = server
+            .mock("PUT", mockito::Matcher::Any)
+            .with_status(403)
+            .with_body(r#"{"errcode":"M_FORBIDDEN"}"#)
+            .create();
+        let err = client
+            .redact_event("!roomid:example.org", "$bridged", "Removed by admin")
+            .await
+            .unwrap_err();
+        refused.assert();
+        assert!(err.to_string().contains("M_FORBIDDEN"));
+    }
+
+    #[test]
+    fn retry_after_delay_reads_header_then_body() {
+        let cap = Duration::from_secs(60);
+        let mut headers = HeaderMap::new();
+        assert_eq!(
+            retry_after_delay(&headers, r#"{"retry_after_ms": 2500}"#, cap),
+            Duration::from_millis(2500)
+        );
+        assert_eq!(retry_after_delay(&headers, "", cap), Duration::from_secs(1));
+
+        headers.insert(reqwest::header::RETRY_AFTER, "5".parse().unwrap());
+        assert_eq!(
+            retry_after_delay(&headers, r#"{"retry_after_ms": 2500}"#, cap),
+            Duration::from_secs(5)
+        );
+    }
+
+    #[test]
+    fn retry_after_delay_clamps_oversized_value_to_cap() {
+        let mut headers = HeaderMap::new();
+        headers.insert(reqwest::header::RETRY_AFTER, "7200".parse().unwrap());
+        assert_eq!(
+            retry_after_delay(&headers, "", Duration::from_secs(60)),
+            Duration::from_secs(60)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_send_formatted_message_as_retries_after_rate_limit() {
+        let mut server = mockito::Server::new_async().await;
+        let client = {
+            let mut cfg = dummy_cfg();
+            cfg.homeserver = server.url();
+            // The server asks for an hour; the cap keeps the test instant.
+            cfg.max_retry_after_secs = 0;
+            MatrixAppserviceClient::new(reqwest::Client::new(), cfg)
+        };
+        let txn_id = client.upcoming_txn_id();
+        let path = format!(
+            "/_matrix/client/v3/rooms/{}/send/m.room.message/{}",
+            urlencoding::encode("!roomid:example.org"),
+            txn_id
+        );
+
+        let limited = server
+            .mock("PUT", path.as_str())
+            .match_query(mockito::Matcher::Any)
+            .with_status(429)
+            .with_header("retry-after", "3600")
+            .expect(1)
+            .create();
+        let accepted = server
+            .mock("PUT", path.as_str())
+            .match_query(mockito::Matcher::Any)
+            .with_status(200)
+            .with_body(r#"{"event_id":"$hello"}"#)
+            .expect(1)
+            .create();
+
+        let result = client
+            .send_formatted_message_as(
+                "@test:example.org",
+                "!roomid:example.org",
+                "hello",
+                "hello",
+                None,
+            )
+            .await;
+
+        limited.assert();
+        accepted.assert();
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_send_reaction_as_retries_after_rate_limit() {
+        let mut server = mockito::Server::new_async().await;
+        let client = {
+            let mut cfg = dummy_cfg();
+            cfg.homeserver = server.url();
+            cfg.max_retry_after_secs = 0;
+            MatrixAppserviceClient::new(reqwest::Client::new(), cfg)
+        };
+        let txn_id = client.upcoming_txn_id();
+        let path = format!(
+            "/_matrix/client/v3/rooms/{}/send/m.reaction/{}",
+            urlencoding::encode("!roomid:example.org"),
+            txn_id
+        );
+
+        let limited = server
+            .mock("PUT", path.as_str())
+            .match_query(mockito::Matcher::Any)
+            .with_status(429)
+            .with_header("retry-after", "3600")
+            .expect(1)
+            .create();
+        let accepted = server
+            .mock("PUT", path.as_str())
+            .match_query(mockito::Matcher::Any)
+            .with_status(200)
+            .expect(1)
+            .create();
+
+        let result = client
+            .send_reaction_as("@test:example.org", "!roomid:example.org", "$orig", "🔁")
+            .await;
+
+        limited.assert();
+        accepted.assert();
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_send_formatted_message_as_requires_event_id() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock(
+                "PUT",
+                mockito::Matcher::Regex(r"/rooms/.+/send/m.room.message/".to_string()),
+            )
+            .match_query(mockito::Matcher::Any)
+            .with_status(200)
+            .with_body("{}")
+            .create();
+
+        let mut cfg = dummy_cfg();
+        cfg.homeserver = server.url();
+        let client = MatrixAppserviceClient::new(reqwest::Client::new(), cfg);
+        let err = client
+            .send_formatted_message_as(
+                "@test:example.org",
+                "!roomid:example.org",
+                "hello",
+                "hello",
+                None,
+            )
+            .await
+            .unwrap_err();
+
+        mock.assert();
+        assert!(err.to_string().contains("no event_id"));
+    }
+
+    #[tokio::test]
+    async fn test_send_formatted_message_as_gives_up_after_bounded_rate_limit_retries() {
+        let mut server = mockito::Server::new_async().await;
+        let client = {
+            let mut cfg = dummy_cfg();
+            cfg.homeserver = server.url();
+            cfg.max_retry_after_secs = 0;
+            cfg.max_rate_limit_retries = 2;
+            MatrixAppserviceClient::new(reqwest::Client::new(), cfg)
+        };
+        let txn_id = client.upcoming_txn_id();
+        let path = format!(
+            "/_matrix/client/v3/rooms/{}/send/m.room.message/{}",
+            urlencoding::encode("!roomid:example.org"),
+            txn_id
+        );
+
+        // The first attempt plus two retries, all with the same txn id.
+        let limited = server
+            .mock("PUT", path.as_str())
+            .match_query(mockito::Matcher::Any)
+            .with_status(429)
+            .with_header("retry-after", "1")
+            .expect(3)
+            .create();
+
+        let result = client
+            .send_formatted_message_as(
+                "@test:example.org",
+                "!roomid:example.org",
+                "hello",
+                "hello",
+                None,
+            )
+            .await;
+
+        limited.assert();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn send_limiter_allows_a_burst_then_paces() {
+        let limiter = SendLimiter::new(5.0);
+        let start = Instant::now();
+        for _ in 0..5 {
+            assert_eq!(limiter.reserve(start), Duration::ZERO);
+        }
+        // Reserved tokens queue up behind each other.
+        assert_eq!(limiter.reserve(start), Duration::from_millis(200));
+        assert_eq!(limiter.reserve(start), Duration::from_millis(400));
+
+        // Two seconds later the bucket has refilled, but only to the burst.
+        let later = start + Duration::from_secs(2);
+        for _ in 0..5 {
+            assert_eq!(limiter.reserve(later), Duration::ZERO);
+        }
+        assert!(limiter.reserve(later) > Duration::ZERO);
+    }
+
+    #[test]
+    fn send_limiter_is_disabled_by_zero_rate() {
+        let limiter = SendLimiter::new(0.0);
+        let now = Instant::now();
+        for _ in 0..100 {
+            assert_eq!(limiter.reserve(now), Duration::ZERO);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_notice_as_bot() {
+        let mut server = mockito::Server::new_async().await;
+        let room_id = "!logs:example.org";
+        let client = {
+            let mut cfg = dummy_cfg();
+            cfg.homeserver = server.url();
+            MatrixAppserviceClient::new(reqwest::Client::new(), cfg)
+        };
+        let txn_id = client.upcoming_txn_id();
+        let path = format!(
+            "/_matrix/client/v3/rooms/{}/send/m.room.message/{}",
+            urlencoding::encode(room_id),
+            txn_id
+        );
+
+        let mock = server
+            .mock("PUT", path.as_str())
+            .match_query(mockito::Matcher::Missing)
+            .match_header("authorization", "Bearer AS_TOKEN")
+            .match_body(mockito::Matcher::Json(serde_json::json!({
+                "msgtype": "m.notice",
+                "body": "[WARN] something happened",
+            })))
+            .with_status(200)
+            .create();
+
+        let result = client
+            .send_notice(room_id, NoticeLevel::Warn, "something happened")
+            .await;
+
+        mock.assert();
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_send_rich_notice_carries_severity_markup() {
+        let mut server = mockito::Server::new_async().await;
+        let client = {
+            let mut cfg = dummy_cfg();
+            cfg.homeserver = server.url();
+            cfg.rich_notices = true;
+            MatrixAppserviceClient::new(reqwest::Client::new(), cfg)
+        };
+        let mock = server
+            .mock(
+                "PUT",
+                mockito::Matcher::Regex(r"/_matrix/client/v3/rooms/.+/send/.+".to_string()),
+            )
+            .match_body(mockito::Matcher::Json(serde_json::json!({
+                "msgtype": "m.notice",
+                "body": "[ERROR] send to <room> failed",
+                "format": "org.matrix.custom.html",
+                "formatted_body": "<font data-mx-color=\"#c01c28\"><b>[ERROR]</b></font> send to &lt;room&gt; failed",
+            })))
+            .with_status(200)
+            .create();
+
+        let result = client
+            .send_notice(
+                "!logs:example.org",
+                NoticeLevel::Error,
+                "send to <room> failed",
+            )
+            .await;
+
+        mock.assert();
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn info_notices_have_no_severity_tag() {
+        assert_eq!(
+            format_notice(NoticeLevel::Info, "📍 TN moved\nnorth"),
+            (
+                "📍 TN moved\nnorth".to_string(),
+                "📍 TN moved<br>north".to_string()
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_send_notice_retries_after_rate_limit() {
+        let mut server = mockito::Server::new_async().await;
+        let room_id = "!logs:example.org";
+        let client = {
+            let mut cfg = dummy_cfg();
+            cfg.homeserver = server.url();
+            cfg.max_retry_after_secs = 0;
+            MatrixAppserviceClient::new(reqwest::Client::new(), cfg)
+        };
+        let txn_id = client.upcoming_txn_id();
+        let path = format!(
+            "/_matrix/client/v3/rooms/{}/send/m.room.message/{}",
+            urlencoding::encode(room_id),
+            txn_id
+        );
+
+        let limited = server
+            .mock("PUT", path.as_str())
+            .with_status(429)
+            .with_body(r#"{"errcode":"M_LIMIT_EXCEEDED","retry_after_ms":3600000}"#)
+            .expect(1)
+            .create();
+        let accepted = server
+            .mock("PUT", path.as_str())
+            .with_status(200)
+            .expect(1)
+            .create();
+
+        let result = client
+            .send_notice(room_id, NoticeLevel::Info, "📍 TN moved")
+            .await;
+
+        limited.assert();
+        accepted.assert();
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_redact_event_retries_after_rate_limit() {
+        let mut server = mockito::Server::new_async().await;
+        let client = {
+            let mut cfg = dummy_cfg();
+            cfg.homeserver = server.url();
+            cfg.max_retry_after_secs = 0;
+            MatrixAppserviceClient::new(reqwest::Client::new(), cfg)
+        };
+        let txn_id = client.upcoming_txn_id();
+        let path = format!(
+            "/_matrix/client/v3/rooms/{}/redact/{}/{}",
+            urlencoding::encode("!roomid:example.org"),
+            urlencoding::encode("$bridged"),
+            txn_id
+        );
+
+        let limited = server
+            .mock("PUT", path.as_str())
+            .with_status(429)
+            .with_header("retry-after", "3600")
+            .expect(1)
+            .create();
+        let accepted = server
+            .mock("PUT", path.as_str())
+            .with_status(200)
+            .expect(1)
+            .create();
+
+        let result = client
+            .redact_event("!roomid:example.org", "$bridged", "Removed by admin")
+            .await;
+
+        limited.assert();
+        accepted.assert();
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_send_notice_failure() {
+        let mut server = mockito::Server::new_async().await;
+        let client = {
+            let mut cfg = dummy_cfg();
+            cfg.homeserver = server.url();
+            MatrixAppserviceClient::new(reqwest::Client::new(), cfg)
+        };
+        let mock = server
+            .mock(
+                "PUT",
+                mockito::Matcher::Regex(r"/_matrix/client/v3/rooms/.+/send/.+".to_string()),
+            )
+            .with_status(403)
+            .create();
+
+        let result = client
+            .send_notice("!logs:example.org", NoticeLevel::Warn, "boom")
+            .await;
+
+        mock.assert();
+        assert!(result.is_err());
     }
 }
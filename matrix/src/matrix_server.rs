@@ -14,18 +14,206 @@
 
 use axum::{
     extract::{Path, Query, State},
-    http::{header::AUTHORIZATION, HeaderMap, StatusCode},
+    http::{
+        header::{AUTHORIZATION, CONTENT_TYPE},
+        HeaderMap, StatusCode,
+    },
     response::IntoResponse,
-    routing::put,
+    routing::{get, post, put},
     Json, Router,
 };
 use serde_json::Value;
+use std::collections::HashSet;
 use std::net::SocketAddr;
-use tracing::info;
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::sync::mpsc;
+use tracing::{debug, error, info, warn};
+
+use crate::metrics::Metrics;
+use crate::potatomesh::{self, PotatoClient};
+use crate::txns::ProcessedTxns;
 
 #[derive(Clone)]
 struct SynapseState {
     hs_token: String,
+    /// Transaction ids already handled, shared across requests.
+    txns: Arc<Mutex<ProcessedTxns>>,
+    /// Transaction ids a request is handling right now, so a retry that
+    /// arrives meanwhile is not acted on a second time.
+    in_flight: Arc<Mutex<HashSet<String>>>,
+    /// Where `txns` is persisted; `None` keeps the record in memory only.
+    txn_path: Option<String>,
+    /// Held while writing `txn_path`, so saves land in order.
+    saving: Arc<Mutex<()>>,
+    /// PotatoMesh client whose node cache the admin endpoints operate on.
+    potato: PotatoClient,
+    /// Poll loop counters for `/health` and `/metrics`.
+    metrics: Metrics,
+    /// Where `!redact` commands found in transactions are handed to the poll
+    /// loop; `None` ignores them.
+    commands: Option<mpsc::Sender<RedactCommand>>,
+    /// Rooms whose events are processed; events from any other room the
+    /// appservice sees are dropped. `None` processes every room.
+    rooms: Option<BridgedRooms>,
+}
+
+/// Room ids the bridge forwards into. Clones share the set, so the poll
+/// loop can keep it current across reloads and created rooms.
+#[derive(Clone, Debug, Default)]
+pub struct BridgedRooms(Arc<RwLock<HashSet<String>>>);
+
+impl BridgedRooms {
+    /// Make `rooms` the bridged rooms.
+    pub fn replace(&self, rooms: impl IntoIterator<Item = String>) {
+        let mut set = self.0.write().unwrap_or_else(|e| e.into_inner());
+        *set = rooms.into_iter().collect();
+    }
+
+    fn contains(&self, room_id: &str) -> bool {
+        self.0
+            .read()
+            .map(|set| set.contains(room_id))
+            .unwrap_or(false)
+    }
+}
+
+impl SynapseState {
+    fn new(
+        hs_token: String,
+        txns: ProcessedTxns,
+        txn_path: Option<String>,
+        potato: PotatoClient,
+        metrics: Metrics,
+    ) -> Self {
+        Self {
+            hs_token,
+            txns: Arc::new(Mutex::new(txns)),
+            in_flight: Arc::default(),
+            txn_path,
+            saving: Arc::default(),
+            potato,
+            metrics,
+            commands: None,
+            rooms: None,
+        }
+    }
+
+    /// Hand `!redact` commands to `commands` instead of ignoring them.
+    fn with_commands(mut self, commands: mpsc::Sender<RedactCommand>) -> Self {
+        self.commands = Some(commands);
+        self
+    }
+
+    /// Only process events from `rooms`.
+    fn with_rooms(mut self, rooms: BridgedRooms) -> Self {
+        self.rooms = Some(rooms);
+        self
+    }
+
+    /// Whether an event's room is one the bridge forwards into.
+    fn is_bridged(&self, event: &Value) -> bool {
+        match &self.rooms {
+            None => true,
+            Some(rooms) => event
+                .get("room_id")
+                .and_then(Value::as_str)
+                .is_some_and(|room_id| rooms.contains(room_id)),
+        }
+    }
+
+    /// Check the request carries the homeserver token, preferring headers
+    /// over the legacy `access_token` query parameter.
+    fn is_authorized(&self, headers: &HeaderMap, auth: &AuthQuery) -> bool {
+        if let Some(token) = extract_access_token(headers) {
+            constant_time_eq(&token, &self.hs_token)
+        } else {
+            auth.access_token
+                .as_deref()
+                .is_some_and(|token| constant_time_eq(token, &self.hs_token))
+        }
+    }
+
+    /// Whether `txn_id` has already been processed.
+    fn is_processed(&self, txn_id: &str) -> bool {
+        self.txns
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .contains(txn_id)
+    }
+
+    /// Claim `txn_id` for handling, unless it was already processed or
+    /// another delivery of it is being handled. Both are checked and the
+    /// claim taken under one lock, so of two concurrent deliveries only one
+    /// gets to act on the events.
+    fn claim(&self, txn_id: &str) -> Result<TxnClaim, Unclaimed> {
+        let mut in_flight = self.in_flight.lock().unwrap_or_else(|e| e.into_inner());
+        if self.is_processed(txn_id) {
+            return Err(Unclaimed::Processed);
+        }
+        if !in_flight.insert(txn_id.to_string()) {
+            return Err(Unclaimed::InFlight);
+        }
+        Ok(TxnClaim {
+            in_flight: self.in_flight.clone(),
+            txn_id: txn_id.to_string(),
+        })
+    }
+
+    /// Record `txn_id` as processed, returning `false` when it already was.
+    ///
+    /// The file is written on a blocking thread, from a snapshot taken once
+    /// earlier saves are done, so the newest record is the one that lands.
+    async fn mark_processed(&self, txn_id: &str) -> bool {
+        {
+            let mut txns = self.txns.lock().unwrap_or_else(|e| e.into_inner());
+            if txns.contains(txn_id) {
+                return false;
+            }
+            txns.record(txn_id);
+        }
+        if let Some(path) = self.txn_path.clone() {
+            let txns = self.txns.clone();
+            let saving = self.saving.clone();
+            let saved = tokio::task::spawn_blocking(move || {
+                let _saving = saving.lock().unwrap_or_else(|e| e.into_inner());
+                let data = txns.lock().unwrap_or_else(|e| e.into_inner()).to_json()?;
+                std::fs::write(&path, data)?;
+                anyhow::Ok(())
+            })
+            .await;
+            match saved {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => error!("Error saving processed transactions: {:?}", e),
+                Err(e) => error!("Error saving processed transactions: {:?}", e),
+            }
+        }
+        true
+    }
+}
+
+/// Why [`SynapseState::claim`] did not hand out a transaction.
+#[derive(Debug, PartialEq, Eq)]
+enum Unclaimed {
+    /// It was already processed.
+    Processed,
+    /// Another delivery of it is being handled.
+    InFlight,
+}
+
+/// A transaction being handled; dropping it releases the claim.
+#[derive(Debug)]
+struct TxnClaim {
+    in_flight: Arc<Mutex<HashSet<String>>>,
+    txn_id: String,
+}
+
+impl Drop for TxnClaim {
+    fn drop(&mut self) {
+        self.in_flight
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&self.txn_id);
+    }
 }
 
 #[derive(serde::Deserialize)]
@@ -69,6 +257,50 @@ fn constant_time_eq(a: &str, b: &str) -> bool {
     diff == 0
 }
 
+/// `!redact [<source>] <mesh_id>` posted in a room the appservice watches,
+/// asking for the Matrix event bridged from that mesh message to be
+/// redacted. Whether `sender` may do so is checked against
+/// `bridge.admin_users` by the poll loop, which owns the record of bridged
+/// events.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RedactCommand {
+    /// Matrix user who posted the command.
+    pub sender: String,
+    /// Room the command was posted in. Only a message bridged into this
+    /// room is redacted.
+    pub room_id: String,
+    /// `label` of the source the message came from, telling apart sources
+    /// that bridged the same mesh id into one room.
+    pub source: Option<String>,
+    /// Mesh id of the message to redact.
+    pub mesh_id: u64,
+}
+
+/// Parse a `!redact [<source>] <mesh_id>` command out of a transaction
+/// event. Anything else, including a command with a malformed id, yields
+/// `None`.
+fn parse_redact_command(event: &Value) -> Option<RedactCommand> {
+    if event.get("type")?.as_str()? != "m.room.message" {
+        return None;
+    }
+    let body = event.get("content")?.get("body")?.as_str()?;
+    let mut words = body.split_whitespace();
+    if words.next()? != "!redact" {
+        return None;
+    }
+    let (source, mesh_id) = match (words.next()?, words.next(), words.next()) {
+        (mesh_id, None, None) => (None, mesh_id),
+        (source, Some(mesh_id), None) => (Some(source.to_string()), mesh_id),
+        _ => return None,
+    };
+    Some(RedactCommand {
+        sender: event.get("sender")?.as_str()?.to_string(),
+        room_id: event.get("room_id")?.as_str()?.to_string(),
+        source,
+        mesh_id: mesh_id.parse().ok()?,
+    })
+}
+
 /// Captures inbound Synapse transaction payloads for logging.
 #[derive(Debug)]
 struct SynapseResponse {
@@ -83,6 +315,13 @@ fn build_router(state: SynapseState) -> Router {
             "/_matrix/appservice/v1/transactions/:txn_id",
             put(handle_transaction),
         )
+        .route("/admin/prime-nodes", post(handle_prime_nodes))
+        .route(
+            "/admin/invalidate-node/:node_id",
+            post(handle_invalidate_node),
+        )
+        .route("/health", get(handle_health))
+        .route("/metrics", get(handle_metrics))
         .with_state(state)
 }
 
@@ -94,17 +333,84 @@ async fn handle_transaction(
     headers: HeaderMap,
     Json(payload): Json<Value>,
 ) -> impl IntoResponse {
-    let header_token = extract_access_token(&headers);
-    let token_matches = if let Some(token) = header_token.as_deref() {
-        constant_time_eq(token, &state.hs_token)
-    } else {
-        auth.access_token
-            .as_deref()
-            .is_some_and(|token| constant_time_eq(token, &state.hs_token))
-    };
-    if !token_matches {
+    if !state.is_authorized(&headers, &auth) {
         return (StatusCode::UNAUTHORIZED, Json(serde_json::json!({})));
     }
+    // Held until the transaction is recorded as processed.
+    let _claim = match state.claim(&txn_id) {
+        Ok(claim) => claim,
+        Err(Unclaimed::Processed) => {
+            info!("Ignoring already-processed Synapse transaction {}", txn_id);
+            return (StatusCode::OK, Json(serde_json::json!({})));
+        }
+        Err(Unclaimed::InFlight) => {
+            // Not acknowledged: should the delivery being handled fail,
+            // Synapse's next retry is still there to act on the events.
+            info!(
+                "Synapse transaction {} is already being handled; asking for a retry",
+                txn_id
+            );
+            return (
+                StatusCode::SERVICE_UNAVAILABLE,
+                Json(serde_json::json!({
+                    "errcode": "M_UNKNOWN",
+                    "error": "Transaction is already being handled",
+                })),
+            );
+        }
+    };
+    let events: Vec<&Value> = payload
+        .get("events")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .collect();
+    let bridged: Vec<&Value> = events
+        .iter()
+        .copied()
+        .filter(|event| state.is_bridged(event))
+        .collect();
+    if bridged.len() < events.len() {
+        debug!(
+            "Ignoring {} events of transaction {} from rooms that are not bridged",
+            events.len() - bridged.len(),
+            txn_id
+        );
+    }
+    if let Some(commands) = &state.commands {
+        let parsed: Vec<RedactCommand> = bridged
+            .into_iter()
+            .filter_map(parse_redact_command)
+            .collect();
+        // Room for every command is reserved before any is queued, so the
+        // transaction is handed over whole or not at all.
+        let permits: Result<Vec<_>, _> = parsed.iter().map(|_| commands.try_reserve()).collect();
+        match permits {
+            Ok(permits) => {
+                for (permit, command) in permits.into_iter().zip(parsed) {
+                    permit.send(command);
+                }
+            }
+            Err(e) => {
+                // Left unrecorded and unacknowledged, so Synapse delivers
+                // the transaction again.
+                warn!(
+                    "Cannot queue the redact commands of transaction {}: {}",
+                    txn_id, e
+                );
+                return (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    Json(serde_json::json!({
+                        "errcode": "M_UNKNOWN",
+                        "error": "Cannot queue the transaction's commands",
+                    })),
+                );
+            }
+        }
+    }
+    // Recorded only once its commands are queued for the poll loop, so a
+    // crash before this point leaves the retry to queue them.
+    state.mark_processed(&txn_id).await;
     let response = SynapseResponse { txn_id, payload };
     info!(
         "Status response: SynapseResponse {{ txn_id: {}, payload: {:?} }}",
@@ -113,9 +419,95 @@ async fn handle_transaction(
     (StatusCode::OK, Json(serde_json::json!({})))
 }
 
+/// Reload the whole node cache from `/api/nodes` on operator request.
+///
+/// Authenticated with the same `hs_token` as Synapse callbacks.
+async fn handle_prime_nodes(
+    State(state): State<SynapseState>,
+    Query(auth): Query<AuthQuery>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if !state.is_authorized(&headers, &auth) {
+        return (StatusCode::UNAUTHORIZED, Json(serde_json::json!({})));
+    }
+    match state.potato.fetch_all_nodes().await {
+        Ok(loaded) => {
+            info!("Primed node cache with {} nodes", loaded);
+            (
+                StatusCode::OK,
+                Json(serde_json::json!({ "loaded": loaded })),
+            )
+        }
+        Err(e) => {
+            error!("Node cache prime failed: {:?}", e);
+            (
+                StatusCode::BAD_GATEWAY,
+                Json(serde_json::json!({ "error": "node fetch failed" })),
+            )
+        }
+    }
+}
+
+/// Drop one node from the cache so its next message refetches the node,
+/// e.g. after it was renamed.
+///
+/// Authenticated with the same `hs_token` as Synapse callbacks.
+async fn handle_invalidate_node(
+    Path(node_id): Path<String>,
+    State(state): State<SynapseState>,
+    Query(auth): Query<AuthQuery>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if !state.is_authorized(&headers, &auth) {
+        return (StatusCode::UNAUTHORIZED, Json(serde_json::json!({})));
+    }
+    let invalidated = state.potato.invalidate_node(&node_id).await;
+    info!("Invalidated cached node {}: {}", node_id, invalidated);
+    (
+        StatusCode::OK,
+        Json(serde_json::json!({ "invalidated": invalidated })),
+    )
+}
+
+/// Liveness probe reporting how long ago the poll loop last ran.
+async fn handle_health(State(state): State<SynapseState>) -> impl IntoResponse {
+    let last_poll_secs_ago = state.metrics.last_poll_secs_ago(potatomesh::now_secs());
+    Json(serde_json::json!({
+        "status": "ok",
+        "last_poll_secs_ago": last_poll_secs_ago,
+    }))
+}
+
+/// Bridge counters in the Prometheus text format.
+async fn handle_metrics(State(state): State<SynapseState>) -> impl IntoResponse {
+    (
+        [(CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.metrics.render_prometheus(),
+    )
+}
+
 /// Listen for Synapse callbacks on the configured address.
-pub async fn run_synapse_listener(addr: SocketAddr, hs_token: String) -> anyhow::Result<()> {
-    let app = build_router(SynapseState { hs_token });
+///
+/// Processed transaction ids are loaded from and persisted to `txn_path`;
+/// `!redact` commands posted in one of `rooms` are sent to `commands`.
+pub async fn run_synapse_listener(
+    addr: SocketAddr,
+    hs_token: String,
+    txn_path: String,
+    potato: PotatoClient,
+    metrics: Metrics,
+    commands: mpsc::Sender<RedactCommand>,
+    rooms: BridgedRooms,
+) -> anyhow::Result<()> {
+    let txns = ProcessedTxns::load(&txn_path).unwrap_or_else(|e| {
+        warn!("Ignoring unreadable transaction file {}: {:?}", txn_path, e);
+        ProcessedTxns::default()
+    });
+    let app = build_router(
+        SynapseState::new(hs_token, txns, Some(txn_path), potato, metrics)
+            .with_commands(commands)
+            .with_rooms(rooms),
+    );
     let listener = tokio::net::TcpListener::bind(addr).await?;
     info!("Synapse listener bound on {}", addr);
     axum::serve(listener, app).await?;
@@ -125,16 +517,47 @@ pub async fn run_synapse_listener(addr: SocketAddr, hs_token: String) -> anyhow:
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::PotatomeshConfig;
     use axum::body::Body;
     use axum::http::Request;
     use tokio::time::{sleep, Duration};
     use tower::ServiceExt;
 
+    fn potato_client(base_url: &str) -> PotatoClient {
+        PotatoClient::new(reqwest::Client::new(), PotatomeshConfig::for_test(base_url))
+    }
+
+    fn test_state() -> SynapseState {
+        SynapseState::new(
+            "HS_TOKEN".to_string(),
+            ProcessedTxns::default(),
+            None,
+            potato_client("http://localhost:8080"),
+            Metrics::default(),
+        )
+    }
+
+    fn prime_request(token: Option<&str>) -> Request<Body> {
+        let mut builder = Request::builder().method("POST").uri("/admin/prime-nodes");
+        if let Some(token) = token {
+            builder = builder.header("authorization", format!("Bearer {token}"));
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    fn transaction_request(txn_id: &str) -> Request<Body> {
+        Request::builder()
+            .method("PUT")
+            .uri(format!("/_matrix/appservice/v1/transactions/{txn_id}"))
+            .header("authorization", "Bearer HS_TOKEN")
+            .header("content-type", "application/json")
+            .body(Body::from(r#"{"events": []}"#))
+            .unwrap()
+    }
+
     #[tokio::test]
     async fn transactions_endpoint_accepts_payloads() {
-        let app = build_router(SynapseState {
-            hs_token: "HS_TOKEN".to_string(),
-        });
+        let app = build_router(test_state());
         let payload = serde_json::json!({
             "events": [],
             "txn_id": "123"
@@ -162,9 +585,7 @@ mod tests {
 
     #[tokio::test]
     async fn transactions_endpoint_rejects_missing_token() {
-        let app = build_router(SynapseState {
-            hs_token: "HS_TOKEN".to_string(),
-        });
+        let app = build_router(test_state());
         let payload = serde_json::json!({
             "events": [],
             "txn_id": "123"
@@ -191,9 +612,7 @@ mod tests {
 
     #[tokio::test]
     async fn transactions_endpoint_rejects_wrong_token() {
-        let app = build_router(SynapseState {
-            hs_token: "HS_TOKEN".to_string(),
-        });
+        let app = build_router(test_state());
         let payload = serde_json::json!({
             "events": [],
             "txn_id": "123"
@@ -221,9 +640,7 @@ mod tests {
 
     #[tokio::test]
     async fn transactions_endpoint_accepts_legacy_query_token() {
-        let app = build_router(SynapseState {
-            hs_token: "HS_TOKEN".to_string(),
-        });
+        let app = build_router(test_state());
         let payload = serde_json::json!({
             "events": [],
             "txn_id": "125"
@@ -246,9 +663,7 @@ mod tests {
 
     #[tokio::test]
     async fn transactions_endpoint_accepts_x_access_token_header() {
-        let app = build_router(SynapseState {
-            hs_token: "HS_TOKEN".to_string(),
-        });
+        let app = build_router(test_state());
         let payload = serde_json::json!({
             "events": [],
             "txn_id": "126"
@@ -270,11 +685,482 @@ mod tests {
         assert_eq!(response.status(), StatusCode::OK);
     }
 
+    #[tokio::test]
+    async fn transactions_endpoint_acknowledges_duplicate_txn_without_reprocessing() {
+        let state = test_state();
+        let app = build_router(state.clone());
+
+        let first = app
+            .clone()
+            .oneshot(transaction_request("dup"))
+            .await
+            .unwrap();
+        assert_eq!(first.status(), StatusCode::OK);
+        assert!(state.txns.lock().unwrap().contains("dup"));
+
+        let retry = app.oneshot(transaction_request("dup")).await.unwrap();
+        assert_eq!(retry.status(), StatusCode::OK);
+        assert!(!state.mark_processed("dup").await);
+    }
+
+    fn message_event(sender: &str, body: &str) -> Value {
+        serde_json::json!({
+            "type": "m.room.message",
+            "sender": sender,
+            "room_id": "!room:example.org",
+            "content": { "msgtype": "m.text", "body": body },
+        })
+    }
+
+    #[test]
+    fn parse_redact_command_reads_mesh_id() {
+        assert_eq!(
+            parse_redact_command(&message_event("@admin:example.org", " !redact 4242 ")),
+            Some(RedactCommand {
+                sender: "@admin:example.org".to_string(),
+                room_id: "!room:example.org".to_string(),
+                source: None,
+                mesh_id: 4242,
+            })
+        );
+        assert_eq!(
+            parse_redact_command(&message_event("@a:b", "!redact berlin 7"))
+                .and_then(|cmd| cmd.source),
+            Some("berlin".to_string())
+        );
+        for body in [
+            "!redact",
+            "!redact abc",
+            "!redact berlin abc",
+            "!redact a 1 2",
+            "!redacted 1",
+            "hi",
+        ] {
+            assert!(parse_redact_command(&message_event("@a:b", body)).is_none());
+        }
+        let mut reaction = message_event("@a:b", "!redact 1");
+        reaction["type"] = "m.reaction".into();
+        assert!(parse_redact_command(&reaction).is_none());
+    }
+
+    #[tokio::test]
+    async fn transactions_endpoint_forwards_redact_commands() {
+        let (tx, mut rx) = mpsc::channel(4);
+        let app = build_router(test_state().with_commands(tx));
+        let payload = serde_json::json!({
+            "events": [
+                message_event("@admin:example.org", "!redact 7"),
+                message_event("@admin:example.org", "hello"),
+            ]
+        });
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method("PUT")
+                    .uri("/_matrix/appservice/v1/transactions/cmd")
+                    .header("authorization", "Bearer HS_TOKEN")
+                    .header("content-type", "application/json")
+                    .body(Body::from(payload.to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(rx.try_recv().unwrap().mesh_id, 7);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn transactions_endpoint_acts_on_a_retried_txn_once() {
+        let (tx, mut rx) = mpsc::channel(4);
+        let app = build_router(test_state().with_commands(tx));
+        let payload = serde_json::json!({
+            "events": [message_event("@admin:example.org", "!redact 7")]
+        });
+        let request = || {
+            Request::builder()
+                .method("PUT")
+                .uri("/_matrix/appservice/v1/transactions/retried")
+                .header("authorization", "Bearer HS_TOKEN")
+                .header("content-type", "application/json")
+                .body(Body::from(payload.to_string()))
+                .unwrap()
+        };
+
+        for _ in 0..2 {
+            let response = app.clone().oneshot(request()).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            assert_eq!(body.as_ref(), b"{}");
+        }
+
+        assert_eq!(rx.try_recv().unwrap().mesh_id, 7);
+        assert!(rx.try_recv().is_err());
+    }
+
+    fn redact_request(txn_id: &str, mesh_id: u64) -> Request<Body> {
+        let payload = serde_json::json!({
+            "events": [message_event("@admin:example.org", &format!("!redact {mesh_id}"))]
+        });
+        Request::builder()
+            .method("PUT")
+            .uri(format!("/_matrix/appservice/v1/transactions/{txn_id}"))
+            .header("authorization", "Bearer HS_TOKEN")
+            .header("content-type", "application/json")
+            .body(Body::from(payload.to_string()))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn transactions_endpoint_acts_on_concurrent_deliveries_once() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("txns.json");
+        let (tx, mut rx) = mpsc::channel(4);
+        let state = SynapseState::new(
+            "HS_TOKEN".to_string(),
+            ProcessedTxns::default(),
+            Some(path.to_str().unwrap().to_string()),
+            potato_client("http://localhost:8080"),
+            Metrics::default(),
+        )
+        .with_commands(tx);
+        let app = build_router(state.clone());
+
+        let (first, second) = tokio::join!(
+            app.clone().oneshot(redact_request("racing", 7)),
+            app.clone().oneshot(redact_request("racing", 7)),
+        );
+
+        let statuses = [first.unwrap().status(), second.unwrap().status()];
+        assert!(statuses.contains(&StatusCode::OK));
+        assert!(statuses
+            .iter()
+            .all(|status| [StatusCode::OK, StatusCode::SERVICE_UNAVAILABLE].contains(status)));
+        assert_eq!(rx.try_recv().unwrap().mesh_id, 7);
+        assert!(rx.try_recv().is_err());
+        assert!(state.in_flight.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn transactions_endpoint_defers_a_delivery_of_a_txn_in_flight() {
+        let (tx, mut rx) = mpsc::channel(4);
+        let state = test_state().with_commands(tx);
+        let app = build_router(state.clone());
+
+        let claim = state.claim("slow").unwrap();
+        assert_eq!(state.claim("slow").unwrap_err(), Unclaimed::InFlight);
+        let retry = app
+            .clone()
+            .oneshot(redact_request("slow", 7))
+            .await
+            .unwrap();
+        assert_eq!(retry.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(rx.try_recv().is_err());
+        assert!(!state.is_processed("slow"));
+
+        // Once the first delivery lets go, Synapse's next retry is handled.
+        drop(claim);
+        let retry = app.oneshot(redact_request("slow", 7)).await.unwrap();
+        assert_eq!(retry.status(), StatusCode::OK);
+        assert_eq!(rx.try_recv().unwrap().mesh_id, 7);
+        assert_eq!(state.claim("slow").unwrap_err(), Unclaimed::Processed);
+    }
+
+    #[tokio::test]
+    async fn transactions_endpoint_asks_for_a_retry_when_commands_cannot_be_queued() {
+        let (tx, mut rx) = mpsc::channel(1);
+        let state = test_state().with_commands(tx);
+        let app = build_router(state.clone());
+        let payload = serde_json::json!({
+            "events": [
+                message_event("@admin:example.org", "!redact 7"),
+                message_event("@admin:example.org", "!redact 8"),
+            ]
+        });
+        let request = || {
+            Request::builder()
+                .method("PUT")
+                .uri("/_matrix/appservice/v1/transactions/full")
+                .header("authorization", "Bearer HS_TOKEN")
+                .header("content-type", "application/json")
+                .body(Body::from(payload.to_string()))
+                .unwrap()
+        };
+
+        // Two commands do not fit a queue of one: neither is queued.
+        let response = app.clone().oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(rx.try_recv().is_err());
+        assert!(!state.is_processed("full"));
+
+        // Nor does anything fit once the poll loop has gone away.
+        drop(rx);
+        let response = app.oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(!state.is_processed("full"));
+    }
+
+    #[tokio::test]
+    async fn transactions_endpoint_drops_events_from_rooms_not_bridged() {
+        let (tx, mut rx) = mpsc::channel(4);
+        let rooms = BridgedRooms::default();
+        rooms.replace(["!room:example.org".to_string()]);
+        let app = build_router(test_state().with_commands(tx).with_rooms(rooms));
+        let mut elsewhere = message_event("@admin:example.org", "!redact 8");
+        elsewhere["room_id"] = "!other:example.org".into();
+        let mut no_room = message_event("@admin:example.org", "!redact 9");
+        no_room.as_object_mut().unwrap().remove("room_id");
+        let payload = serde_json::json!({
+            "events": [
+                elsewhere,
+                message_event("@admin:example.org", "!redact 7"),
+                no_room,
+            ]
+        });
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method("PUT")
+                    .uri("/_matrix/appservice/v1/transactions/mixed")
+                    .header("authorization", "Bearer HS_TOKEN")
+                    .header("content-type", "application/json")
+                    .body(Body::from(payload.to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let command = rx.try_recv().unwrap();
+        assert_eq!(command.mesh_id, 7);
+        assert_eq!(command.room_id, "!room:example.org");
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn transactions_endpoint_recognizes_persisted_txn_after_reload() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("txns.json");
+        let path_str = path.to_str().unwrap().to_string();
+
+        let before_restart = SynapseState::new(
+            "HS_TOKEN".to_string(),
+            ProcessedTxns::default(),
+            Some(path_str.clone()),
+            potato_client("http://localhost:8080"),
+            Metrics::default(),
+        );
+        let response = build_router(before_restart)
+            .oneshot(transaction_request("crash-1"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let after_restart = SynapseState::new(
+            "HS_TOKEN".to_string(),
+            ProcessedTxns::load(&path_str).unwrap(),
+            Some(path_str),
+            potato_client("http://localhost:8080"),
+            Metrics::default(),
+        );
+        assert!(!after_restart.mark_processed("crash-1").await);
+        assert!(after_restart.mark_processed("crash-2").await);
+    }
+
+    #[tokio::test]
+    async fn rejected_transactions_are_not_recorded() {
+        let state = test_state();
+        let response = build_router(state.clone())
+            .oneshot(
+                Request::builder()
+                    .method("PUT")
+                    .uri("/_matrix/appservice/v1/transactions/unauth")
+                    .header("content-type", "application/json")
+                    .body(Body::from(r#"{"events": []}"#))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert!(!state.txns.lock().unwrap().contains("unauth"));
+    }
+
+    #[tokio::test]
+    async fn prime_nodes_endpoint_reports_loaded_count() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("GET", "/api/nodes")
+            .match_query(mockito::Matcher::UrlEncoded("limit".into(), "1000".into()))
+            .with_status(200)
+            .with_body(
+                r#"[
+                    {"node_id": "!AAAA0001", "short_name": "A1", "long_name": "Alpha"},
+                    {"node_id": "!bbbb0002", "short_name": "B2", "long_name": "Bravo"}
+                ]"#,
+            )
+            .create();
+        let state = SynapseState::new(
+            "HS_TOKEN".to_string(),
+            ProcessedTxns::default(),
+            None,
+            potato_client(&server.url()),
+            Metrics::default(),
+        );
+
+        let response = build_router(state.clone())
+            .oneshot(prime_request(Some("HS_TOKEN")))
+            .await
+            .unwrap();
+
+        mock.assert();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body, serde_json::json!({ "loaded": 2 }));
+        assert!(state.potato.get_node("!aaaa0001").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn prime_nodes_endpoint_rejects_wrong_token() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server.mock("GET", "/api/nodes").expect(0).create();
+        let state = SynapseState::new(
+            "HS_TOKEN".to_string(),
+            ProcessedTxns::default(),
+            None,
+            potato_client(&server.url()),
+            Metrics::default(),
+        );
+
+        let response = build_router(state)
+            .oneshot(prime_request(Some("NOPE")))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn invalidate_node_endpoint_forces_refetch() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("GET", "/api/nodes/aaaa0001")
+            .with_status(200)
+            .with_body(r#"{"node_id": "!aaaa0001", "long_name": "Alpha"}"#)
+            .expect(2)
+            .create();
+        let state = SynapseState::new(
+            "HS_TOKEN".to_string(),
+            ProcessedTxns::default(),
+            None,
+            potato_client(&server.url()),
+            Metrics::default(),
+        );
+        state.potato.get_node("!aaaa0001").await.unwrap();
+
+        let invalidate = || {
+            Request::post("/admin/invalidate-node/!AAAA0001")
+                .header("authorization", "Bearer HS_TOKEN")
+                .body(Body::empty())
+                .unwrap()
+        };
+        for expected in [true, false] {
+            let response = build_router(state.clone())
+                .oneshot(invalidate())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let body: Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(body, serde_json::json!({ "invalidated": expected }));
+        }
+
+        state.potato.get_node("!aaaa0001").await.unwrap();
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn health_endpoint_reports_time_since_last_poll() {
+        let state = test_state();
+        let get_health = || Request::get("/health").body(Body::empty()).unwrap();
+
+        let response = build_router(state.clone())
+            .oneshot(get_health())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({ "status": "ok", "last_poll_secs_ago": null })
+        );
+
+        state.metrics.record_poll(potatomesh::now_secs());
+        let response = build_router(state).oneshot(get_health()).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert!(body["last_poll_secs_ago"].as_u64().unwrap() <= 1);
+    }
+
+    #[tokio::test]
+    async fn metrics_endpoint_serves_prometheus_text() {
+        let state = test_state();
+        state.metrics.record_forwarded();
+        state.metrics.set_last_message_id(None, 1, 7);
+
+        let response = build_router(state)
+            .oneshot(Request::get("/metrics").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers()[CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .starts_with("text/plain"));
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("bridge_messages_forwarded_total 1\n"));
+        assert!(body.contains("bridge_fetch_errors_total 0\n"));
+        assert!(body.contains("bridge_last_message_id{channel=\"1\"} 7\n"));
+    }
+
     #[tokio::test]
     async fn run_synapse_listener_starts_and_can_abort() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let txn_path = tmp_dir.path().join("txns.json");
+        let txn_path = txn_path.to_str().unwrap().to_string();
         let addr = SocketAddr::from(([127, 0, 0, 1], 0));
-        let handle =
-            tokio::spawn(async move { run_synapse_listener(addr, "HS_TOKEN".to_string()).await });
+        let handle = tokio::spawn(async move {
+            run_synapse_listener(
+                addr,
+                "HS_TOKEN".to_string(),
+                txn_path,
+                potato_client("http://localhost:8080"),
+                Metrics::default(),
+                mpsc::channel(1).0,
+                BridgedRooms::default(),
+            )
+            .await
+        });
         sleep(Duration::from_millis(10)).await;
         handle.abort();
     }
@@ -283,7 +1169,18 @@ mod tests {
     async fn run_synapse_listener_returns_error_on_bind_failure() {
         let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
         let addr = listener.local_addr().unwrap();
-        let result = run_synapse_listener(addr, "HS_TOKEN".to_string()).await;
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let txn_path = tmp_dir.path().join("txns.json");
+        let result = run_synapse_listener(
+            addr,
+            "HS_TOKEN".to_string(),
+            txn_path.to_str().unwrap().to_string(),
+            potato_client("http://localhost:8080"),
+            Metrics::default(),
+            mpsc::channel(1).0,
+            BridgedRooms::default(),
+        )
+        .await;
         assert!(result.is_err());
     }
 }
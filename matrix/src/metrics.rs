@@ -0,0 +1,164 @@
+// Copyright © 2025-26 l5yth & contributors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Counters updated by the poll loop and read by the listener's `/health`
+//! and `/metrics` endpoints.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Bridge activity counters; clones share the same values.
+#[derive(Debug, Clone, Default)]
+pub struct Metrics(Arc<Counters>);
+
+#[derive(Debug, Default)]
+struct Counters {
+    messages_forwarded: AtomicU64,
+    fetch_errors: AtomicU64,
+    malformed_messages: AtomicU64,
+    /// Highest message id processed per source label (`None` for the main
+    /// source) and mesh channel.
+    last_message_ids: Mutex<BTreeMap<(Option<String>, u8), u64>>,
+    /// Unix timestamp of the last poll; 0 until the first one.
+    last_poll_at: AtomicU64,
+}
+
+impl Metrics {
+    /// Count a message posted to Matrix.
+    pub fn record_forwarded(&self) {
+        self.0.messages_forwarded.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a failed `/api/messages` fetch.
+    pub fn record_fetch_error(&self) {
+        self.0.fetch_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a fetched message skipped because it could not be parsed.
+    pub fn record_malformed_message(&self) {
+        self.0.malformed_messages.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Note that a poll started at `now`.
+    pub fn record_poll(&self, now: u64) {
+        self.0.last_poll_at.store(now, Ordering::Relaxed);
+    }
+
+    /// Update the highest message id processed on `channel` of `source`, a
+    /// further source's label or `None` for the main one.
+    pub fn set_last_message_id(&self, source: Option<&str>, channel: u8, id: u64) {
+        self.0
+            .last_message_ids
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert((source.map(str::to_string), channel), id);
+    }
+
+    /// Seconds since the last poll started; `None` before the first one.
+    pub fn last_poll_secs_ago(&self, now: u64) -> Option<u64> {
+        match self.0.last_poll_at.load(Ordering::Relaxed) {
+            0 => None,
+            at => Some(now.saturating_sub(at)),
+        }
+    }
+
+    /// Render the counters in the Prometheus text exposition format.
+    pub fn render_prometheus(&self) -> String {
+        let metrics = [
+            (
+                "bridge_messages_forwarded_total",
+                "counter",
+                "Messages posted to Matrix.",
+                &self.0.messages_forwarded,
+            ),
+            (
+                "bridge_fetch_errors_total",
+                "counter",
+                "Failed PotatoMesh message fetches.",
+                &self.0.fetch_errors,
+            ),
+            (
+                "bridge_malformed_messages_total",
+                "counter",
+                "Fetched messages skipped because they could not be parsed.",
+                &self.0.malformed_messages,
+            ),
+        ];
+        let mut out = String::new();
+        for (name, kind, help, value) in metrics {
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} {kind}");
+            let _ = writeln!(out, "{name} {}", value.load(Ordering::Relaxed));
+        }
+        let _ = writeln!(
+            out,
+            "# HELP bridge_last_message_id Highest mesh message id processed per source and channel."
+        );
+        let _ = writeln!(out, "# TYPE bridge_last_message_id gauge");
+        let ids = self
+            .0
+            .last_message_ids
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        for ((source, channel), id) in ids.iter() {
+            let _ = match source {
+                Some(source) => writeln!(
+                    out,
+                    "bridge_last_message_id{{source=\"{source}\",channel=\"{channel}\"}} {id}"
+                ),
+                None => writeln!(out, "bridge_last_message_id{{channel=\"{channel}\"}} {id}"),
+            };
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clones_share_counters() {
+        let metrics = Metrics::default();
+        let shared = metrics.clone();
+        shared.record_forwarded();
+        shared.record_forwarded();
+        shared.record_fetch_error();
+        shared.record_malformed_message();
+        shared.set_last_message_id(None, 0, 42);
+        shared.set_last_message_id(None, 2, 7);
+        shared.set_last_message_id(Some("south"), 0, 9);
+
+        let text = metrics.render_prometheus();
+        assert!(text.contains("# TYPE bridge_messages_forwarded_total counter\n"));
+        assert!(text.contains("\nbridge_messages_forwarded_total 2\n"));
+        assert!(text.contains("\nbridge_fetch_errors_total 1\n"));
+        assert!(text.contains("\nbridge_malformed_messages_total 1\n"));
+        assert!(text.contains("# TYPE bridge_last_message_id gauge\n"));
+        assert!(text.contains("\nbridge_last_message_id{channel=\"0\"} 42\n"));
+        assert!(text.contains("\nbridge_last_message_id{channel=\"2\"} 7\n"));
+        assert!(text.contains("\nbridge_last_message_id{source=\"south\",channel=\"0\"} 9\n"));
+    }
+
+    #[test]
+    fn last_poll_is_unknown_until_first_poll() {
+        let metrics = Metrics::default();
+        assert_eq!(metrics.last_poll_secs_ago(100), None);
+
+        metrics.record_poll(90);
+        assert_eq!(metrics.last_poll_secs_ago(100), Some(10));
+    }
+}
@@ -12,21 +12,36 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use serde::Deserialize;
-use std::collections::HashMap;
-use std::sync::Arc;
-use tokio::sync::RwLock;
+use futures::stream::{self, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::{hash_map::Entry, HashMap};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::{Notify, RwLock};
 
-use crate::config::PotatomeshConfig;
+use crate::config::{CheckpointTimeSource, PotatomeshConfig, SinceUnit, TEXT_MESSAGE_PORTNUM};
+use crate::metrics::Metrics;
+use crate::text::truncate_chars;
 
 #[allow(dead_code)]
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct PotatoMessage {
     pub id: u64,
     pub rx_time: u64,
     pub rx_iso: String,
-    pub from_id: String,
-    pub to_id: String,
+    /// Sending node. Null in some payloads; see [`PotatoMessage::sender_id`].
+    #[serde(default)]
+    pub from_id: Option<String>,
+    /// Destination node, or `^all` for broadcasts. Null in some payloads.
+    #[serde(default)]
+    pub to_id: Option<String>,
     pub channel: u8,
     #[serde(default)]
     pub portnum: Option<String>,
@@ -35,8 +50,16 @@ pub struct PotatoMessage {
     pub rssi: Option<i16>,
     #[serde(default)]
     pub hop_limit: Option<u8>,
+    /// Relays the message passed through before reception; 0 when heard
+    /// directly. Missing when the ingestor could not tell.
+    #[serde(default)]
+    pub hops: Option<i64>,
     pub lora_freq: u32,
+    /// Empty when the payload has null.
+    #[serde(default, deserialize_with = "null_as_empty")]
     pub modem_preset: String,
+    /// Empty when the payload has null.
+    #[serde(default, deserialize_with = "null_as_empty")]
     pub channel_name: String,
     #[serde(default)]
     pub snr: Option<f32>,
@@ -47,16 +70,63 @@ pub struct PotatoMessage {
     /// "meshcore". Optional because historical payloads predate the field.
     #[serde(default)]
     pub protocol: Option<String>,
+    /// Ids of the earlier messages `coalesce_secs` merged into this one, so
+    /// its delivery is journaled for each of them. Set by the bridge, never
+    /// by PotatoMesh.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub merged_ids: Vec<u64>,
+}
+
+impl PotatoMessage {
+    /// Id of the sending node: `from_id`, falling back to `node_id` when the
+    /// payload left it null, and to `"unknown"` when both are missing.
+    pub fn sender_id(&self) -> &str {
+        match self.from_id.as_deref() {
+            Some(id) if !id.is_empty() => id,
+            _ if !self.node_id.is_empty() => &self.node_id,
+            _ => "unknown",
+        }
+    }
+
+    /// Whether the message went to everyone rather than one node: `to_id` is
+    /// `^all` or the broadcast address (`!ffffffff`, `4294967295`). A
+    /// missing `to_id` counts as broadcast.
+    pub fn is_broadcast(&self) -> bool {
+        match self.to_id.as_deref().map(str::trim) {
+            None | Some("") => true,
+            Some(to) => {
+                to == "^all"
+                    || to.eq_ignore_ascii_case("!ffffffff")
+                    || to == BROADCAST_NODE_NUM.to_string()
+            }
+        }
+    }
+}
+
+/// Meshtastic node number messages to every node are addressed to.
+const BROADCAST_NODE_NUM: u32 = u32::MAX;
+
+/// Read a string that the API may send as null as an empty string.
+fn null_as_empty<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Ok(Option::<String>::deserialize(deserializer)?.unwrap_or_default())
 }
 
 #[derive(Debug, Default, Clone)]
 pub struct FetchParams {
     pub limit: Option<u32>,
     pub since: Option<u64>,
+    /// Inclusive upper bound on `rx_time`, for paging back through results.
+    pub before: Option<u64>,
+    /// Only messages with a higher id; needs a server with id cursors
+    /// (see [`PotatoClient::detect_id_cursor`]).
+    pub after_id: Option<u64>,
 }
 
 #[allow(dead_code)]
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct PotatoNode {
     pub node_id: String,
     #[serde(default)]
@@ -78,12 +148,238 @@ pub struct PotatoNode {
     pub altitude: Option<f64>,
 }
 
+/// `/api/messages` response. Normally a bare array, but some proxies wrap it
+/// as `{"messages": [...], "server_time": ...}`; both shapes are accepted.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum MessagesBody<T> {
+    Bare(Vec<T>),
+    Wrapped { messages: Vec<T> },
+}
+
+impl<T> MessagesBody<T> {
+    fn into_messages(self) -> Vec<T> {
+        match self {
+            MessagesBody::Bare(messages) | MessagesBody::Wrapped { messages } => messages,
+        }
+    }
+}
+
+/// Longest node name accepted from the API; Meshtastic itself allows 39
+/// bytes, so anything past this is a buggy or hostile node and is cut.
+pub const MAX_NODE_NAME_CHARS: usize = 100;
+
+/// Page size requested when priming the node cache; the API's maximum.
+const NODES_PRIME_LIMIT: u32 = 1000;
+
+/// Node lookups [`PotatoClient::get_nodes`] keeps in flight at once.
+const NODE_LOOKUP_CONCURRENCY: usize = 8;
+
+/// Node metadata cached alongside the time it was fetched from the API.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct CachedNode {
+    node: PotatoNode,
+    /// Unix timestamp (seconds) of the `/api/nodes/{id}` lookup.
+    fetched_at: u64,
+}
+
 #[derive(Clone)]
 pub struct PotatoClient {
     http: reqwest::Client,
-    cfg: PotatomeshConfig,
-    // simple in-memory cache for node metadata
-    nodes_cache: Arc<RwLock<HashMap<String, PotatoNode>>>,
+    pub cfg: PotatomeshConfig,
+    // simple in-memory cache for node metadata, keyed by hex id without `!`
+    nodes_cache: Arc<RwLock<HashMap<String, CachedNode>>>,
+    /// Counts messages skipped because they could not be parsed.
+    metrics: Metrics,
+    /// Whether `/api/messages` accepts the `after_id` cursor, as detected by
+    /// [`Self::detect_id_cursor`].
+    id_cursor: Arc<AtomicBool>,
+    /// Whether other sources bridge into the same rooms, so this one's
+    /// label goes into each message's metadata.
+    shares_rooms: bool,
+}
+
+/// Entry of the `/version` `capabilities` list announcing `after_id` support.
+const ID_CURSOR_CAPABILITY: &str = "after_id";
+
+/// Messages requested per page by [`PotatoClient::fetch_all_since`].
+const MESSAGE_PAGE_SIZE: u32 = 200;
+
+/// Longest wait between two fetch attempts.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// First wait before reconnecting a failed live update stream; doubled per
+/// failure up to [`MAX_RETRY_DELAY`].
+const LIVE_UPDATE_RETRY_BASE_MS: u64 = 1_000;
+
+/// Silence after which the live update stream counts as dead. PotatoMesh
+/// sends a keepalive comment well within this.
+const LIVE_UPDATE_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Cap on one live update connection, replacing the client-wide request
+/// timeout that would otherwise cut the stream short.
+const LIVE_UPDATE_MAX_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// A live update stream closed sooner than this counts as failed, so a
+/// server that keeps closing it straight away is backed off from.
+const LIVE_UPDATE_MIN_LIFETIME: Duration = Duration::from_secs(30);
+
+/// `/api/events` answered with something other than a server-sent event
+/// stream, e.g. a proxy's page or an older PotatoMesh's fallback route.
+#[derive(Debug)]
+struct NotEventStream(String);
+
+impl fmt::Display for NotEventStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "expected text/event-stream, got {:?}", self.0)
+    }
+}
+
+impl std::error::Error for NotEventStream {}
+
+/// Whether a failed request is worth retrying: the connection failed or
+/// timed out, or the server answered 5xx.
+fn is_transient(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<reqwest::Error>()
+        .is_some_and(|e| match e.status() {
+            Some(status) => status.is_server_error(),
+            None => e.is_connect() || e.is_timeout(),
+        })
+}
+
+/// Wait before retry number `attempt` (1-based): `base_delay_ms` doubled per
+/// attempt, plus up to half of that again as jitter, capped at
+/// [`MAX_RETRY_DELAY`].
+fn backoff_delay(base_delay_ms: u64, attempt: u32) -> Duration {
+    let exp = base_delay_ms.saturating_mul(1 << (attempt - 1).min(20));
+    let jitter = random_u64() % (exp / 2 + 1);
+    Duration::from_millis(exp.saturating_add(jitter)).min(MAX_RETRY_DELAY)
+}
+
+/// Random number from the OS's randomness for jitter; without it, no
+/// jitter.
+fn random_u64() -> u64 {
+    getrandom::u64().unwrap_or(0)
+}
+
+/// Whether the server-sent event `frame` is a `change` event for the
+/// `messages` collection.
+fn is_message_change(frame: &str) -> bool {
+    #[derive(Deserialize)]
+    struct Change {
+        collection: String,
+    }
+
+    let mut event = None;
+    let mut data = String::new();
+    for line in frame.lines() {
+        if let Some(value) = line.strip_prefix("event:") {
+            event = Some(value.trim());
+        } else if let Some(value) = line.strip_prefix("data:") {
+            data.push_str(value.trim());
+        }
+    }
+    event == Some("change")
+        && serde_json::from_str::<Change>(&data).is_ok_and(|change| change.collection == "messages")
+}
+
+/// Keep [`PotatoClient::stream_message_changes`] running in the background,
+/// reconnecting with backoff when the stream drops or closes early, and
+/// after a short pause when it closes normally. Stops for good when the
+/// server has no live updates (404, or a response that is not an event
+/// stream), leaving the bridge to its regular polls.
+pub fn spawn_live_updates(
+    potato: PotatoClient,
+    changed: Arc<Notify>,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut attempt = 1;
+        loop {
+            let opened = tokio::time::Instant::now();
+            match potato.stream_message_changes(&changed).await {
+                Ok(()) if opened.elapsed() >= LIVE_UPDATE_MIN_LIFETIME => {
+                    attempt = 1;
+                    let delay = backoff_delay(LIVE_UPDATE_RETRY_BASE_MS, attempt);
+                    tracing::debug!(
+                        "PotatoMesh live update stream closed; reconnecting in {:?}",
+                        delay
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+                Ok(()) => {
+                    let delay = backoff_delay(LIVE_UPDATE_RETRY_BASE_MS, attempt);
+                    tracing::warn!(
+                        "PotatoMesh live update stream closed after {:?}, reconnecting in {:?}",
+                        opened.elapsed(),
+                        delay
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt = attempt.saturating_add(1);
+                }
+                Err(e) if is_not_found(&e) || e.is::<NotEventStream>() => {
+                    tracing::info!(
+                        "PotatoMesh has no live updates ({}); polling every {}s",
+                        e,
+                        potato.cfg.poll_interval_secs
+                    );
+                    return;
+                }
+                Err(e) => {
+                    let delay = backoff_delay(LIVE_UPDATE_RETRY_BASE_MS, attempt);
+                    tracing::warn!(
+                        "PotatoMesh live update stream failed, reconnecting in {:?}: {}",
+                        delay,
+                        e
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt = attempt.saturating_add(1);
+                }
+            }
+        }
+    })
+}
+
+/// Truncate node names past [`MAX_NODE_NAME_CHARS`] so they cannot bloat display names.
+fn cap_node_names(mut node: PotatoNode) -> PotatoNode {
+    let names = std::iter::once(&mut node.long_name).chain(node.short_name.as_mut());
+    for name in names {
+        if let Cow::Owned(capped) = truncate_chars(name, MAX_NODE_NAME_CHARS) {
+            tracing::warn!(
+                "Node {} has a {}-character name; truncating it",
+                node.node_id,
+                name.chars().count()
+            );
+            *name = capped;
+        }
+    }
+    node
+}
+
+/// Reduce a node id like `"!67FC83CB"` to its canonical lowercase hex form
+/// (`"67fc83cb"`), so differently-cased ids map to one node.
+pub fn normalize_node_hex(node_id: &str) -> String {
+    node_id.trim_start_matches('!').to_ascii_lowercase()
+}
+
+/// Whether `id` is a `^`-prefixed pseudo-destination such as `^all` rather
+/// than a node id. These have no `/api/nodes` entry.
+pub fn is_pseudo_destination(id: &str) -> bool {
+    id.starts_with('^')
+}
+
+/// Whether `err` is the API reporting an unknown node (HTTP 404).
+pub fn is_not_found(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<reqwest::Error>()
+        .and_then(reqwest::Error::status)
+        == Some(reqwest::StatusCode::NOT_FOUND)
+}
+
+/// Current Unix time in seconds.
+pub(crate) fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
 }
 
 impl PotatoClient {
@@ -92,9 +388,71 @@ impl PotatoClient {
             http,
             cfg,
             nodes_cache: Arc::new(RwLock::new(HashMap::new())),
+            metrics: Metrics::default(),
+            id_cursor: Arc::new(AtomicBool::new(false)),
+            shares_rooms: false,
+        }
+    }
+
+    /// A client using `cfg` that shares this one's HTTP client, node cache,
+    /// metrics and detected capabilities.
+    pub fn with_config(&self, cfg: PotatomeshConfig) -> Self {
+        Self {
+            http: self.http.clone(),
+            cfg,
+            nodes_cache: self.nodes_cache.clone(),
+            metrics: self.metrics.clone(),
+            id_cursor: self.id_cursor.clone(),
+            shares_rooms: self.shares_rooms,
         }
     }
 
+    /// Report skipped messages to `metrics` from now on.
+    pub fn set_metrics(&mut self, metrics: Metrics) {
+        self.metrics = metrics;
+    }
+
+    /// Configured name of this PotatoMesh source, if any.
+    pub fn label(&self) -> Option<&str> {
+        self.cfg.label.as_deref()
+    }
+
+    /// Mark this source as one of several bridging into the same rooms.
+    pub fn set_shares_rooms(&mut self, shares_rooms: bool) {
+        self.shares_rooms = shares_rooms;
+    }
+
+    /// Label to add to the metadata of this source's messages when the
+    /// `metadata_template` leaves out `{source}`: set only while other
+    /// sources share its rooms.
+    pub fn source_tag(&self) -> Option<&str> {
+        self.label().filter(|_| self.shares_rooms)
+    }
+
+    /// Unit the API expects for the `since` query parameter.
+    pub fn since_unit(&self) -> SinceUnit {
+        self.cfg.since_unit
+    }
+
+    /// Clock the `since` checkpoint is taken from.
+    pub fn checkpoint_time_source(&self) -> CheckpointTimeSource {
+        self.cfg.checkpoint_time_source
+    }
+
+    /// Whether messages on `portnum` are forwarded, per `forward_portnums`.
+    /// A message without a port counts as text; an empty list forwards
+    /// every port.
+    pub fn forwards_portnum(&self, portnum: Option<&str>) -> bool {
+        let portnum = portnum.unwrap_or(TEXT_MESSAGE_PORTNUM);
+        self.cfg.forward_portnums.is_empty()
+            || self.cfg.forward_portnums.iter().any(|port| port == portnum)
+    }
+
+    /// Configured tries per `/api/messages` fetch.
+    pub fn max_fetch_attempts(&self) -> u32 {
+        self.cfg.retry.max_attempts
+    }
+
     /// Build the API root; accept either a bare domain or one already ending in `/api`.
     fn api_base(&self) -> String {
         let trimmed = self.cfg.base_url.trim_end_matches('/');
@@ -109,19 +467,31 @@ impl PotatoClient {
         format!("{}/messages", self.api_base())
     }
 
+    fn nodes_url(&self) -> String {
+        format!("{}/nodes", self.api_base())
+    }
+
     fn node_url(&self, hex_id: &str) -> String {
         // e.g. https://potatomesh.net/api/nodes/67fc83cb
         format!("{}/nodes/{}", self.api_base(), hex_id)
     }
 
-    /// Basic liveness check against the PotatoMesh API.
-    pub async fn health_check(&self) -> anyhow::Result<()> {
+    fn events_url(&self) -> String {
+        format!("{}/events", self.api_base())
+    }
+
+    fn version_url(&self) -> String {
         let base = self
             .cfg
             .base_url
             .trim_end_matches('/')
             .trim_end_matches("/api");
-        let url = format!("{}/version", base);
+        format!("{}/version", base)
+    }
+
+    /// Basic liveness check against the PotatoMesh API.
+    pub async fn health_check(&self) -> anyhow::Result<()> {
+        let url = self.version_url();
         let resp = self.http.get(&url).send().await?;
         if resp.status().is_success() {
             tracing::info!("PotatoMesh API healthy at {}", self.cfg.base_url);
@@ -134,6 +504,174 @@ impl PotatoClient {
         }
     }
 
+    /// [`Self::fetch_messages`], retried on connection errors and 5xx
+    /// responses with exponential backoff and jitter, up to `max_attempts`
+    /// tries in total. Other failures are returned at once.
+    pub async fn fetch_messages_with_retry(
+        &self,
+        params: FetchParams,
+        max_attempts: u32,
+    ) -> anyhow::Result<Vec<PotatoMessage>> {
+        let mut attempt = 1;
+        loop {
+            match self.fetch_messages(params.clone()).await {
+                Err(e) if attempt < max_attempts && is_transient(&e) => {
+                    let delay = backoff_delay(self.cfg.retry.base_delay_ms, attempt);
+                    tracing::warn!(
+                        "Fetching messages failed (attempt {}/{}), retrying in {:?}: {}",
+                        attempt,
+                        max_attempts,
+                        delay,
+                        e
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Ask `/version` whether `/api/messages` takes an `after_id` cursor,
+    /// i.e. whether its `capabilities` list names it, and remember the
+    /// answer for [`Self::supports_id_cursor`]. A server that cannot be asked
+    /// is assumed not to, keeping the timestamp `since` cursor.
+    pub async fn detect_id_cursor(&self) -> bool {
+        let detected = match self.fetch_capabilities().await {
+            Ok(capabilities) => capabilities.iter().any(|c| c == ID_CURSOR_CAPABILITY),
+            Err(e) => {
+                tracing::warn!("Could not read PotatoMesh capabilities: {:?}", e);
+                false
+            }
+        };
+        self.id_cursor.store(detected, Ordering::Relaxed);
+        detected
+    }
+
+    /// The `capabilities` list of `/version`; empty for servers without one.
+    async fn fetch_capabilities(&self) -> anyhow::Result<Vec<String>> {
+        #[derive(Deserialize)]
+        struct Version {
+            #[serde(default)]
+            capabilities: Vec<String>,
+        }
+
+        let resp = self
+            .http
+            .get(self.version_url())
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(resp.json::<Version>().await?.capabilities)
+    }
+
+    /// Whether messages are fetched by id cursor rather than by `since`.
+    pub fn supports_id_cursor(&self) -> bool {
+        self.id_cursor.load(Ordering::Relaxed)
+    }
+
+    /// Fetch every message since `since`, however many pages that takes.
+    /// See [`Self::fetch_all`].
+    pub async fn fetch_all_since(&self, since: u64) -> anyhow::Result<Vec<PotatoMessage>> {
+        self.fetch_all(Some(since), None).await
+    }
+
+    /// Fetch every message with an id above `after_id`, however many pages
+    /// that takes. See [`Self::fetch_all`].
+    pub async fn fetch_all_after(&self, after_id: u64) -> anyhow::Result<Vec<PotatoMessage>> {
+        self.fetch_all(None, Some(after_id)).await
+    }
+
+    /// Fetch the `count` most recent messages, walking back a page at a
+    /// time with the `before` cursor. The result is sorted by id.
+    pub async fn fetch_recent(&self, count: usize) -> anyhow::Result<Vec<PotatoMessage>> {
+        let limit = u32::try_from(count).map_or(MESSAGE_PAGE_SIZE, |c| c.min(MESSAGE_PAGE_SIZE));
+        let mut messages: HashMap<u64, PotatoMessage> = HashMap::new();
+        let mut before = None;
+        while messages.len() < count {
+            let params = FetchParams {
+                limit: Some(limit),
+                before,
+                ..Default::default()
+            };
+            let page = self
+                .fetch_messages_with_retry(params, self.max_fetch_attempts())
+                .await?;
+            let full_page = page.len() >= limit as usize;
+            let oldest = page.iter().map(|m| m.rx_time).min();
+            let mut new = 0;
+            for msg in page {
+                if let Entry::Vacant(slot) = messages.entry(msg.id) {
+                    slot.insert(msg);
+                    new += 1;
+                }
+            }
+            if !full_page || new == 0 || oldest == before {
+                break;
+            }
+            before = oldest;
+        }
+
+        let mut messages: Vec<PotatoMessage> = messages.into_values().collect();
+        messages.sort_by_key(|m| m.id);
+        let excess = messages.len().saturating_sub(count);
+        messages.drain(..excess);
+        Ok(messages)
+    }
+
+    /// Fetch every message past the `since`/`after_id` lower bound.
+    ///
+    /// The API returns the newest `limit` messages first, so a backlog larger
+    /// than one page is walked backwards with the `before` cursor until a
+    /// short page arrives. Pages that bring nothing new (e.g. more than a
+    /// page of messages sharing one `rx_time`) end the walk instead of
+    /// looping. The result is sorted by id.
+    async fn fetch_all(
+        &self,
+        since: Option<u64>,
+        after_id: Option<u64>,
+    ) -> anyhow::Result<Vec<PotatoMessage>> {
+        let mut messages: HashMap<u64, PotatoMessage> = HashMap::new();
+        let mut before = None;
+        loop {
+            let params = FetchParams {
+                limit: Some(MESSAGE_PAGE_SIZE),
+                since,
+                before,
+                after_id,
+            };
+            let page = self
+                .fetch_messages_with_retry(params, self.max_fetch_attempts())
+                .await?;
+            let full_page = page.len() >= MESSAGE_PAGE_SIZE as usize;
+            let oldest = page.iter().map(|m| m.rx_time).min();
+            let mut new = 0;
+            for msg in page {
+                if let Entry::Vacant(slot) = messages.entry(msg.id) {
+                    slot.insert(msg);
+                    new += 1;
+                }
+            }
+
+            if !full_page {
+                break;
+            }
+            if new == 0 || oldest == before {
+                tracing::warn!(
+                    "Message pagination stopped making progress at rx_time {:?}; \
+                     older messages in this backlog may be missed",
+                    oldest
+                );
+                break;
+            }
+            before = oldest;
+        }
+
+        let mut messages: Vec<PotatoMessage> = messages.into_values().collect();
+        messages.sort_by_key(|m| m.id);
+        Ok(messages)
+    }
+
     pub async fn fetch_messages(&self, params: FetchParams) -> anyhow::Result<Vec<PotatoMessage>> {
         let mut req = self.http.get(self.messages_url());
         if let Some(limit) = params.limit {
@@ -142,40 +680,271 @@ impl PotatoClient {
         if let Some(since) = params.since {
             req = req.query(&[("since", since)]);
         }
+        if let Some(before) = params.before {
+            req = req.query(&[("before", before)]);
+        }
+        if let Some(after_id) = params.after_id {
+            req = req.query(&[("after_id", after_id)]);
+        }
 
         let resp = req.send().await?.error_for_status()?;
 
-        let msgs: Vec<PotatoMessage> = resp.json().await?;
-        Ok(msgs)
+        if self.cfg.strict_message_parsing {
+            let body: MessagesBody<PotatoMessage> = resp.json().await?;
+            return Ok(body.into_messages());
+        }
+        let body: MessagesBody<serde_json::Value> = resp.json().await?;
+        Ok(self.parse_messages(body.into_messages()))
+    }
+
+    /// Parse each message on its own, so one that does not fit
+    /// [`PotatoMessage`] is logged and skipped instead of failing the page.
+    fn parse_messages(&self, values: Vec<serde_json::Value>) -> Vec<PotatoMessage> {
+        values
+            .into_iter()
+            .filter_map(|value| {
+                let id = value.get("id").cloned();
+                match serde_json::from_value(value) {
+                    Ok(msg) => Some(msg),
+                    Err(e) => {
+                        self.metrics.record_malformed_message();
+                        tracing::warn!(
+                            "Skipping message {} that could not be parsed: {}",
+                            id.unwrap_or_default(),
+                            e
+                        );
+                        None
+                    }
+                }
+            })
+            .collect()
     }
 
     pub async fn get_node(&self, node_id_with_bang: &str) -> anyhow::Result<PotatoNode> {
-        // node_id is like "!67fc83cb" → we need "67fc83cb"
-        let hex = node_id_with_bang.trim_start_matches('!').to_string();
+        // node_id is like "!67FC83CB" → we need "67fc83cb"
+        let hex = normalize_node_hex(node_id_with_bang);
+
+        {
+            let cache = self.nodes_cache.read().await;
+            if let Some(node) = self.fresh_entry(&cache, &hex) {
+                return Ok(node);
+            }
+        }
+
+        self.refresh_node(node_id_with_bang).await
+    }
+
+    /// The cached node for `hex`, unless it is older than `cache_ttl_secs`.
+    fn fresh_entry(&self, cache: &HashMap<String, CachedNode>, hex: &str) -> Option<PotatoNode> {
+        cache
+            .get(hex)
+            .filter(|entry| now_secs().saturating_sub(entry.fetched_at) <= self.cfg.cache_ttl_secs)
+            .map(|entry| entry.node.clone())
+    }
+
+    /// Drop a node from the cache so its next lookup is fetched fresh.
+    ///
+    /// Returns whether the node was cached.
+    pub async fn invalidate_node(&self, node_id: &str) -> bool {
+        let hex = normalize_node_hex(node_id);
+        self.nodes_cache.write().await.remove(&hex).is_some()
+    }
 
+    /// Look up several nodes at once, keyed by the ids as given.
+    ///
+    /// Cached nodes are reused; the rest are fetched concurrently, at most
+    /// [`NODE_LOOKUP_CONCURRENCY`] at a time, and cached for later
+    /// [`Self::get_node`] calls. Nodes that cannot be fetched are left out.
+    pub async fn get_nodes(&self, ids: &[String]) -> HashMap<String, PotatoNode> {
+        let mut by_hex: HashMap<String, PotatoNode> = HashMap::new();
+        let mut missing: Vec<&String> = Vec::new();
         {
             let cache = self.nodes_cache.read().await;
-            if let Some(n) = cache.get(&hex) {
-                return Ok(n.clone());
+            for id in ids {
+                let hex = normalize_node_hex(id);
+                if by_hex.contains_key(&hex)
+                    || is_pseudo_destination(id)
+                    || missing.iter().any(|other| normalize_node_hex(other) == hex)
+                {
+                    continue;
+                }
+                match self.fresh_entry(&cache, &hex) {
+                    Some(node) => {
+                        by_hex.insert(hex, node);
+                    }
+                    None => missing.push(id),
+                }
+            }
+        }
+
+        // Owned ids keep the lookups `Send` for the tasks polling sources.
+        let mut fetches = stream::iter(missing.into_iter().cloned())
+            .map(|id| async move {
+                let result = self.refresh_node(&id).await;
+                (id, result)
+            })
+            .buffer_unordered(NODE_LOOKUP_CONCURRENCY);
+        while let Some((id, result)) = fetches.next().await {
+            match result {
+                Ok(node) => {
+                    by_hex.insert(normalize_node_hex(&id), node);
+                }
+                Err(e) => tracing::debug!("Could not look up node {}: {}", id, e),
             }
         }
 
+        ids.iter()
+            .filter_map(|id| {
+                let node = by_hex.get(&normalize_node_hex(id))?;
+                Some((id.clone(), node.clone()))
+            })
+            .collect()
+    }
+
+    /// Fetch a node from the API, bypassing and then updating the cache.
+    ///
+    /// Used where cached metadata is too stale, e.g. for a node's position.
+    pub async fn refresh_node(&self, node_id_with_bang: &str) -> anyhow::Result<PotatoNode> {
+        if is_pseudo_destination(node_id_with_bang) {
+            anyhow::bail!("{} is a pseudo-destination, not a node", node_id_with_bang);
+        }
+        let hex = normalize_node_hex(node_id_with_bang);
         let url = self.node_url(&hex);
         let resp = self.http.get(url).send().await?.error_for_status()?;
-        let node: PotatoNode = resp.json().await?;
+        let node = cap_node_names(resp.json().await?);
 
         {
             let mut cache = self.nodes_cache.write().await;
-            cache.insert(hex, node.clone());
+            cache.insert(
+                hex,
+                CachedNode {
+                    node: node.clone(),
+                    fetched_at: now_secs(),
+                },
+            );
         }
 
         Ok(node)
     }
+
+    /// Prime the node cache from the full `/api/nodes` listing.
+    ///
+    /// Returns the number of nodes loaded.
+    pub async fn fetch_all_nodes(&self) -> anyhow::Result<usize> {
+        Ok(self.list_nodes().await?.len())
+    }
+
+    /// Fetch the full `/api/nodes` listing, refreshing the node cache with it.
+    pub async fn list_nodes(&self) -> anyhow::Result<Vec<PotatoNode>> {
+        let resp = self
+            .http
+            .get(self.nodes_url())
+            .query(&[("limit", NODES_PRIME_LIMIT)])
+            .send()
+            .await?
+            .error_for_status()?;
+        let nodes: Vec<PotatoNode> = resp.json().await?;
+        let nodes: Vec<PotatoNode> = nodes.into_iter().map(cap_node_names).collect();
+
+        let fetched_at = now_secs();
+        let mut cache = self.nodes_cache.write().await;
+        for node in &nodes {
+            cache.insert(
+                normalize_node_hex(&node.node_id),
+                CachedNode {
+                    node: node.clone(),
+                    fetched_at,
+                },
+            );
+        }
+        Ok(nodes)
+    }
+
+    /// Follow the `/api/events` live-update stream, notifying `changed`
+    /// each time PotatoMesh reports new messages. The events carry no
+    /// messages themselves; they only tell the poll loop to fetch now.
+    ///
+    /// Returns `Ok` when the server closes the stream (it caps each
+    /// connection's lifetime) and an error when it cannot be opened, is not
+    /// a `text/event-stream`, or goes silent for
+    /// [`LIVE_UPDATE_IDLE_TIMEOUT`].
+    pub async fn stream_message_changes(&self, changed: &Notify) -> anyhow::Result<()> {
+        let mut resp = self
+            .http
+            .get(self.events_url())
+            .header(reqwest::header::ACCEPT, "text/event-stream")
+            .timeout(LIVE_UPDATE_MAX_LIFETIME)
+            .send()
+            .await?
+            .error_for_status()?;
+        let content_type = resp
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        if !content_type.starts_with("text/event-stream") {
+            return Err(NotEventStream(content_type.to_string()).into());
+        }
+        let mut buf: Vec<u8> = Vec::new();
+        loop {
+            let chunk = tokio::time::timeout(LIVE_UPDATE_IDLE_TIMEOUT, resp.chunk())
+                .await
+                .map_err(|_| anyhow::anyhow!("no live update or keepalive received"))??;
+            let Some(chunk) = chunk else {
+                return Ok(());
+            };
+            buf.extend_from_slice(&chunk);
+            while let Some(end) = buf.windows(2).position(|w| w == b"\n\n") {
+                let frame: Vec<u8> = buf.drain(..end + 2).collect();
+                if is_message_change(&String::from_utf8_lossy(&frame)) {
+                    changed.notify_one();
+                }
+            }
+        }
+    }
+
+    /// Write the node cache to `path` so a restart starts with it warm.
+    ///
+    /// Returns the number of entries written.
+    pub async fn save_nodes_cache(&self, path: &str) -> anyhow::Result<usize> {
+        let cache = self.nodes_cache.read().await;
+        let data = serde_json::to_string(&*cache)?;
+        fs::write(path, data)?;
+        Ok(cache.len())
+    }
+
+    /// Populate the node cache from a file written by [`Self::save_nodes_cache`].
+    ///
+    /// Entries fetched more than `cache_ttl_secs` ago are dropped, as a lookup
+    /// would not use them anyway. A missing or empty file is treated as an
+    /// empty cache. Returns the number of entries loaded.
+    pub async fn load_nodes_cache(&self, path: &str) -> anyhow::Result<usize> {
+        if !Path::new(path).exists() {
+            return Ok(0);
+        }
+        let data = fs::read_to_string(path)?;
+        if data.trim().is_empty() {
+            return Ok(0);
+        }
+        let entries: HashMap<String, CachedNode> = serde_json::from_str(&data)?;
+        let now = now_secs();
+        let mut cache = self.nodes_cache.write().await;
+        let mut loaded = 0;
+        for (hex, entry) in entries {
+            if now.saturating_sub(entry.fetched_at) > self.cfg.cache_ttl_secs {
+                continue;
+            }
+            cache.insert(hex, entry);
+            loaded += 1;
+        }
+        Ok(loaded)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::RetryConfig;
 
     #[test]
     fn deserialize_sample_message_array() {
@@ -205,7 +974,7 @@ mod tests {
         assert_eq!(msgs.len(), 1);
         let m = &msgs[0];
         assert_eq!(m.id, 2947676906);
-        assert_eq!(m.from_id, "!da6556d4");
+        assert_eq!(m.from_id.as_deref(), Some("!da6556d4"));
         assert_eq!(m.node_id, "!06871773");
         assert_eq!(m.portnum.as_deref(), Some("TEXT_MESSAGE_APP"));
         assert_eq!(m.lora_freq, 868);
@@ -242,6 +1011,76 @@ mod tests {
         assert!(m.protocol.is_none());
     }
 
+    #[test]
+    fn deserialize_message_with_null_sender_fields() {
+        let json = r#"
+        [
+          {
+            "id": 29,
+            "rx_time": 0,
+            "rx_iso": "2025-11-27T11:03:56Z",
+            "from_id": null,
+            "to_id": null,
+            "channel": 0,
+            "text": "Ping",
+            "lora_freq": 868,
+            "modem_preset": null,
+            "channel_name": null,
+            "node_id": "!abcd1234"
+          },
+          {
+            "id": 30,
+            "rx_time": 0,
+            "rx_iso": "2025-11-27T11:03:57Z",
+            "from_id": "!0badc0de",
+            "to_id": "^all",
+            "channel": 0,
+            "text": "Pong",
+            "lora_freq": 868,
+            "modem_preset": "MediumFast",
+            "channel_name": "TEST",
+            "node_id": "!0badc0de"
+          }
+        ]
+        "#;
+
+        let msgs: Vec<PotatoMessage> = serde_json::from_str(json).expect("valid message json");
+        assert_eq!(msgs.len(), 2);
+        let m = &msgs[0];
+        assert!(m.from_id.is_none());
+        assert!(m.to_id.is_none());
+        assert_eq!(m.modem_preset, "");
+        assert_eq!(m.channel_name, "");
+        assert_eq!(m.sender_id(), "!abcd1234");
+        assert_eq!(msgs[1].sender_id(), "!0badc0de");
+
+        let anonymous = PotatoMessage {
+            node_id: String::new(),
+            ..m.clone()
+        };
+        assert_eq!(anonymous.sender_id(), "unknown");
+    }
+
+    #[test]
+    fn is_broadcast_recognizes_broadcast_sentinels() {
+        let base: PotatoMessage = serde_json::from_value(serde_json::json!({
+            "id": 1, "rx_time": 0, "rx_iso": "2025-11-27T11:03:56Z",
+            "channel": 0, "text": "Ping", "lora_freq": 868, "node_id": "!abcd1234"
+        }))
+        .unwrap();
+        let to = |to_id: Option<&str>| PotatoMessage {
+            to_id: to_id.map(str::to_string),
+            ..base.clone()
+        };
+
+        for to_id in [None, Some("^all"), Some("!FFFFFFFF"), Some("4294967295")] {
+            assert!(to(to_id).is_broadcast(), "{to_id:?}");
+        }
+        for to_id in ["!0badc0de", "195936478"] {
+            assert!(!to(Some(to_id)).is_broadcast(), "{to_id}");
+        }
+    }
+
     #[test]
     fn deserialize_message_with_meshcore_protocol() {
         let json = r#"
@@ -293,6 +1132,60 @@ mod tests {
         assert!(node.latitude.is_none());
     }
 
+    #[test]
+    fn normalize_node_hex_lowercases_and_strips_bang() {
+        assert_eq!(normalize_node_hex("!67FC83CB"), "67fc83cb");
+        assert_eq!(normalize_node_hex("!67fc83cb"), "67fc83cb");
+        assert_eq!(normalize_node_hex("67Fc83cB"), "67fc83cb");
+    }
+
+    #[tokio::test]
+    async fn get_node_does_not_look_up_pseudo_destinations() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock(
+                "GET",
+                mockito::Matcher::Regex(r"^/api/nodes/.*".to_string()),
+            )
+            .expect(0)
+            .create();
+        let client = PotatoClient::new(
+            reqwest::Client::new(),
+            PotatomeshConfig::for_test(&server.url()),
+        );
+
+        for id in ["^all", "^local"] {
+            assert!(is_pseudo_destination(id));
+            let err = client.get_node(id).await.unwrap_err();
+            assert!(err.to_string().contains("pseudo-destination"), "id={id}");
+        }
+        assert!(!is_pseudo_destination("!abcd1234"));
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn get_node_shares_cache_entry_across_id_casing() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("GET", "/api/nodes/abcd1234")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"node_id": "!abcd1234", "long_name": "Test Node"}"#)
+            .expect(1)
+            .create();
+
+        let client = PotatoClient::new(
+            reqwest::Client::new(),
+            PotatomeshConfig::for_test(&server.url()),
+        );
+
+        client.get_node("!ABCD1234").await.unwrap();
+        client.get_node("!abcd1234").await.unwrap();
+
+        mock.assert();
+        assert!(client.nodes_cache.read().await.contains_key("abcd1234"));
+    }
+
     #[test]
     fn node_hex_id_is_stripped_correctly() {
         let with_bang = "!deadbeef";
@@ -307,22 +1200,17 @@ mod tests {
     #[test]
     fn test_new_potato_client() {
         let http_client = reqwest::Client::new();
-        let config = PotatomeshConfig {
-            base_url: "http://localhost:8080".to_string(),
-            poll_interval_secs: 60,
-        };
+        let config = PotatomeshConfig::for_test("http://localhost:8080");
         let client = PotatoClient::new(http_client, config);
         assert_eq!(client.cfg.base_url, "http://localhost:8080");
         assert_eq!(client.cfg.poll_interval_secs, 60);
+        assert_eq!(client.since_unit(), SinceUnit::Secs);
     }
 
     #[test]
     fn test_messages_url() {
         let http_client = reqwest::Client::new();
-        let config = PotatomeshConfig {
-            base_url: "http://localhost:8080".to_string(),
-            poll_interval_secs: 60,
-        };
+        let config = PotatomeshConfig::for_test("http://localhost:8080");
         let client = PotatoClient::new(http_client, config);
         assert_eq!(client.messages_url(), "http://localhost:8080/api/messages");
     }
@@ -330,10 +1218,7 @@ mod tests {
     #[test]
     fn test_messages_url_with_trailing_slash() {
         let http_client = reqwest::Client::new();
-        let config = PotatomeshConfig {
-            base_url: "http://localhost:8080/".to_string(),
-            poll_interval_secs: 60,
-        };
+        let config = PotatomeshConfig::for_test("http://localhost:8080/");
         let client = PotatoClient::new(http_client, config);
         assert_eq!(client.messages_url(), "http://localhost:8080/api/messages");
     }
@@ -341,10 +1226,7 @@ mod tests {
     #[test]
     fn test_messages_url_with_existing_api_suffix() {
         let http_client = reqwest::Client::new();
-        let config = PotatomeshConfig {
-            base_url: "http://localhost:8080/api/".to_string(),
-            poll_interval_secs: 60,
-        };
+        let config = PotatomeshConfig::for_test("http://localhost:8080/api/");
         let client = PotatoClient::new(http_client, config);
         assert_eq!(client.messages_url(), "http://localhost:8080/api/messages");
     }
@@ -352,10 +1234,7 @@ mod tests {
     #[test]
     fn test_node_url() {
         let http_client = reqwest::Client::new();
-        let config = PotatomeshConfig {
-            base_url: "http://localhost:8080".to_string(),
-            poll_interval_secs: 60,
-        };
+        let config = PotatomeshConfig::for_test("http://localhost:8080");
         let client = PotatoClient::new(http_client, config);
         assert_eq!(
             client.node_url("!1234"),
@@ -386,10 +1265,7 @@ mod tests {
             .create();
 
         let http_client = reqwest::Client::new();
-        let config = PotatomeshConfig {
-            base_url: server.url(),
-            poll_interval_secs: 60,
-        };
+        let config = PotatomeshConfig::for_test(&server.url());
         let client = PotatoClient::new(http_client, config);
         let result = client.fetch_messages(FetchParams::default()).await;
 
@@ -400,16 +1276,408 @@ mod tests {
         assert_eq!(messages[0].id, 2947676906);
     }
 
-    #[tokio::test]
+    async fn fetch_with_malformed_message(
+        strict: bool,
+    ) -> (anyhow::Result<Vec<PotatoMessage>>, Metrics) {
+        let mut server = mockito::Server::new_async().await;
+        let mut page: Vec<serde_json::Value> =
+            serde_json::from_str(&message_page([3, 2, 1].into_iter(), |id| 100 + id)).unwrap();
+        page[1]["channel"] = "not a number".into();
+        let _mock = server
+            .mock("GET", "/api/messages")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(serde_json::Value::from(page).to_string())
+            .create();
+
+        let mut client = PotatoClient::new(
+            reqwest::Client::new(),
+            PotatomeshConfig {
+                strict_message_parsing: strict,
+                ..PotatomeshConfig::for_test(&server.url())
+            },
+        );
+        let metrics = Metrics::default();
+        client.set_metrics(metrics.clone());
+        (client.fetch_messages(FetchParams::default()).await, metrics)
+    }
+
+    #[tokio::test]
+    async fn test_fetch_messages_skips_malformed_message() {
+        let (result, metrics) = fetch_with_malformed_message(false).await;
+
+        let ids: Vec<u64> = result.unwrap().iter().map(|m| m.id).collect();
+        assert_eq!(ids, vec![3, 1]);
+        assert!(metrics
+            .render_prometheus()
+            .contains("\nbridge_malformed_messages_total 1\n"));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_messages_strict_parsing_rejects_page() {
+        let (result, metrics) = fetch_with_malformed_message(true).await;
+
+        assert!(result.is_err());
+        assert!(metrics
+            .render_prometheus()
+            .contains("\nbridge_malformed_messages_total 0\n"));
+    }
+
+    fn parse_messages_body(json: &str) -> Vec<PotatoMessage> {
+        serde_json::from_str::<MessagesBody<PotatoMessage>>(json)
+            .expect("valid messages body")
+            .into_messages()
+    }
+
+    #[test]
+    fn messages_body_accepts_bare_and_wrapped_arrays() {
+        let page = message_page([2, 1].into_iter(), |id| 100 + id);
+        let wrapped = format!(r#"{{"messages": {page}, "server_time": 1764241436}}"#);
+
+        let bare = parse_messages_body(&page);
+        let unwrapped = parse_messages_body(&wrapped);
+        assert_eq!(bare.len(), 2);
+        let ids = |msgs: &[PotatoMessage]| msgs.iter().map(|m| m.id).collect::<Vec<_>>();
+        assert_eq!(ids(&bare), ids(&unwrapped));
+
+        assert!(parse_messages_body("[]").is_empty());
+        assert!(parse_messages_body(r#"{"messages": [], "server_time": 1}"#).is_empty());
+        assert!(serde_json::from_str::<MessagesBody<PotatoMessage>>(r#"{"items": []}"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_fetch_messages_accepts_wrapped_response() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("GET", "/api/messages")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(format!(
+                r#"{{"messages": {}, "server_time": 1764241436}}"#,
+                message_page([7].into_iter(), |_| 1764241436)
+            ))
+            .create();
+
+        let config = PotatomeshConfig::for_test(&server.url());
+        let client = PotatoClient::new(reqwest::Client::new(), config);
+        let messages = client.fetch_messages(FetchParams::default()).await.unwrap();
+
+        mock.assert();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].id, 7);
+    }
+
+    /// `/api/messages` page holding messages `ids`, each received at
+    /// `rx_time(id)`, newest first like the API.
+    fn message_page(ids: impl Iterator<Item = u64>, rx_time: impl Fn(u64) -> u64) -> String {
+        let mut page: Vec<serde_json::Value> = ids
+            .map(|id| {
+                serde_json::json!({
+                    "id": id, "rx_time": rx_time(id), "rx_iso": "2025-11-27T11:03:56Z",
+                    "from_id": "!da6556d4", "to_id": "^all", "channel": 1,
+                    "text": "Ping", "lora_freq": 868, "modem_preset": "MediumFast",
+                    "channel_name": "TEST", "node_id": "!da6556d4"
+                })
+            })
+            .collect();
+        page.sort_by_key(|m| std::cmp::Reverse(m["rx_time"].as_u64()));
+        serde_json::Value::from(page).to_string()
+    }
+
+    #[tokio::test]
+    async fn test_fetch_all_since_walks_back_through_pages() {
+        let mut server = mockito::Server::new_async().await;
+        let first = server
+            .mock("GET", "/api/messages")
+            .match_query(mockito::Matcher::AllOf(vec![
+                mockito::Matcher::UrlEncoded("limit".into(), "200".into()),
+                mockito::Matcher::UrlEncoded("since".into(), "100".into()),
+            ]))
+            .with_status(200)
+            .with_body(message_page(51..=250, |id| 1000 + id))
+            .expect(1)
+            .create();
+        // `before` is inclusive, so the oldest message of the first page
+        // comes back again and must not be duplicated.
+        let second = server
+            .mock("GET", "/api/messages")
+            .match_query(mockito::Matcher::AllOf(vec![
+                mockito::Matcher::UrlEncoded("since".into(), "100".into()),
+                mockito::Matcher::UrlEncoded("before".into(), "1051".into()),
+            ]))
+            .with_status(200)
+            .with_body(message_page(1..=51, |id| 1000 + id))
+            .expect(1)
+            .create();
+
+        let client = retrying_client(&server);
+        let messages = client.fetch_all_since(100).await.unwrap();
+
+        first.assert();
+        second.assert();
+        let ids: Vec<u64> = messages.iter().map(|m| m.id).collect();
+        assert_eq!(ids, (1..=250).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn test_fetch_all_since_stops_when_pages_repeat() {
+        let mut server = mockito::Server::new_async().await;
+        // A full page of messages sharing one rx_time: `before` cannot move
+        // past them, so every page is the same.
+        let mock = server
+            .mock("GET", "/api/messages")
+            .match_query(mockito::Matcher::Any)
+            .with_status(200)
+            .with_body(message_page(1..=200, |_| 1000))
+            .expect(2)
+            .create();
+
+        let client = retrying_client(&server);
+        let messages = client.fetch_all_since(100).await.unwrap();
+
+        mock.assert();
+        assert_eq!(messages.len(), 200);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_recent_pages_back_to_count() {
+        let mut server = mockito::Server::new_async().await;
+        let first = server
+            .mock("GET", "/api/messages")
+            .match_query(mockito::Matcher::UrlEncoded("limit".into(), "200".into()))
+            .with_status(200)
+            .with_body(message_page(51..=250, |id| 1000 + id))
+            .expect(1)
+            .create();
+        let second = server
+            .mock("GET", "/api/messages")
+            .match_query(mockito::Matcher::AllOf(vec![
+                mockito::Matcher::UrlEncoded("limit".into(), "200".into()),
+                mockito::Matcher::UrlEncoded("before".into(), "1051".into()),
+            ]))
+            .with_status(200)
+            .with_body(message_page(1..=51, |id| 1000 + id))
+            .expect(1)
+            .create();
+
+        let client = retrying_client(&server);
+        let messages = client.fetch_recent(230).await.unwrap();
+
+        first.assert();
+        second.assert();
+        let ids: Vec<u64> = messages.iter().map(|m| m.id).collect();
+        assert_eq!(ids, (21..=250).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn test_fetch_recent_asks_for_small_counts_only() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("GET", "/api/messages")
+            .match_query(mockito::Matcher::UrlEncoded("limit".into(), "3".into()))
+            .with_status(200)
+            .with_body(message_page(8..=10, |id| 1000 + id))
+            .expect(1)
+            .create();
+
+        let client = retrying_client(&server);
+        let messages = client.fetch_recent(3).await.unwrap();
+
+        mock.assert();
+        let ids: Vec<u64> = messages.iter().map(|m| m.id).collect();
+        assert_eq!(ids, vec![8, 9, 10]);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_all_after_sends_id_cursor() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("GET", "/api/messages")
+            .match_query(mockito::Matcher::AllOf(vec![
+                mockito::Matcher::UrlEncoded("limit".into(), "200".into()),
+                mockito::Matcher::UrlEncoded("after_id".into(), "41".into()),
+            ]))
+            .with_status(200)
+            .with_body(message_page(42..=44, |id| 1000 - id))
+            .expect(1)
+            .create();
+
+        let client = retrying_client(&server);
+        let messages = client.fetch_all_after(41).await.unwrap();
+
+        mock.assert();
+        let ids: Vec<u64> = messages.iter().map(|m| m.id).collect();
+        assert_eq!(ids, vec![42, 43, 44]);
+    }
+
+    #[tokio::test]
+    async fn test_detect_id_cursor_reads_version_capabilities() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("GET", "/version")
+            .with_status(200)
+            .with_body(r#"{"name":"Mesh","version":"0.7.0","capabilities":["after_id"]}"#)
+            .create();
+
+        let client = retrying_client(&server);
+        assert!(!client.supports_id_cursor());
+        assert!(client.detect_id_cursor().await);
+
+        mock.assert();
+        assert!(client.supports_id_cursor());
+        // Reloaded clients keep what was detected.
+        let reloaded = client.with_config(client.cfg.clone());
+        assert!(reloaded.supports_id_cursor());
+    }
+
+    #[tokio::test]
+    async fn test_detect_id_cursor_falls_back_to_since() {
+        let mut server = mockito::Server::new_async().await;
+        let without = server
+            .mock("GET", "/version")
+            .with_status(200)
+            .with_body(r#"{"name":"Mesh","version":"0.5.0"}"#)
+            .expect(1)
+            .create();
+        let client = retrying_client(&server);
+        assert!(!client.detect_id_cursor().await);
+        without.assert();
+
+        let failing = server
+            .mock("GET", "/version")
+            .with_status(500)
+            .expect(1)
+            .create();
+        assert!(!client.detect_id_cursor().await);
+        failing.assert();
+        assert!(!client.supports_id_cursor());
+    }
+
+    #[test]
+    fn is_message_change_matches_message_change_events_only() {
+        assert!(is_message_change(
+            "event: change\ndata: {\"collection\":\"messages\"}\n\n"
+        ));
+        assert!(is_message_change(
+            "event:change\ndata:{\"collection\":\"messages\",\"hint\":7}\n\n"
+        ));
+        assert!(!is_message_change(
+            "event: change\ndata: {\"collection\":\"nodes\"}\n\n"
+        ));
+        assert!(!is_message_change(": keepalive\n\n"));
+        assert!(!is_message_change(
+            "data: {\"collection\":\"messages\"}\n\n"
+        ));
+    }
+
+    #[tokio::test]
+    async fn stream_message_changes_notifies_on_message_events() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("GET", "/api/events")
+            .with_status(200)
+            .with_header("content-type", "text/event-stream")
+            .with_body(concat!(
+                ": connected\n\n",
+                "event: change\ndata: {\"collection\":\"nodes\"}\n\n",
+                "event: change\ndata: {\"collection\":\"messages\"}\n\n",
+            ))
+            .create();
+        let client = retrying_client(&server);
+        let changed = Notify::new();
+
+        client.stream_message_changes(&changed).await.unwrap();
+
+        mock.assert();
+        tokio::time::timeout(Duration::from_secs(1), changed.notified())
+            .await
+            .expect("a message change should have been reported");
+    }
+
+    #[tokio::test]
+    async fn stream_message_changes_reports_missing_endpoint() {
+        let mut server = mockito::Server::new_async().await;
+        server.mock("GET", "/api/events").with_status(404).create();
+        let client = retrying_client(&server);
+
+        let err = client
+            .stream_message_changes(&Notify::new())
+            .await
+            .unwrap_err();
+        assert!(is_not_found(&err));
+    }
+
+    #[tokio::test]
+    async fn spawn_live_updates_falls_back_to_polling_without_endpoint() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("GET", "/api/events")
+            .with_status(404)
+            .expect(1)
+            .create();
+        let client = retrying_client(&server);
+
+        let task = spawn_live_updates(client, Arc::new(Notify::new()));
+
+        tokio::time::timeout(Duration::from_secs(5), task)
+            .await
+            .expect("the task should stop on 404")
+            .unwrap();
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn spawn_live_updates_falls_back_to_polling_without_an_event_stream() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("GET", "/api/events")
+            .with_status(200)
+            .with_header("content-type", "text/html")
+            .with_body("<html>PotatoMesh</html>")
+            .expect(2)
+            .create();
+        let client = retrying_client(&server);
+
+        let err = client
+            .stream_message_changes(&Notify::new())
+            .await
+            .unwrap_err();
+        assert!(err.is::<NotEventStream>());
+        let task = spawn_live_updates(client, Arc::new(Notify::new()));
+
+        tokio::time::timeout(Duration::from_secs(5), task)
+            .await
+            .expect("the task should stop on a response that is not an event stream")
+            .unwrap();
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn spawn_live_updates_pauses_before_reconnecting_a_closed_stream() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("GET", "/api/events")
+            .with_status(200)
+            .with_header("content-type", "text/event-stream")
+            .with_body(": connected\n\n")
+            .expect(1)
+            .create();
+        let client = retrying_client(&server);
+
+        let task = spawn_live_updates(client, Arc::new(Notify::new()));
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        task.abort();
+
+        mock.assert();
+    }
+
+    #[tokio::test]
     async fn test_health_check_success() {
         let mut server = mockito::Server::new_async().await;
         let mock = server.mock("GET", "/version").with_status(200).create();
 
         let http_client = reqwest::Client::new();
-        let config = PotatomeshConfig {
-            base_url: server.url(),
-            poll_interval_secs: 60,
-        };
+        let config = PotatomeshConfig::for_test(&server.url());
         let client = PotatoClient::new(http_client, config);
         let result = client.health_check().await;
 
@@ -425,10 +1693,7 @@ mod tests {
         let http_client = reqwest::Client::new();
         let mut base = server.url();
         base.push_str("/api");
-        let config = PotatomeshConfig {
-            base_url: base,
-            poll_interval_secs: 60,
-        };
+        let config = PotatomeshConfig::for_test(&base);
         let client = PotatoClient::new(http_client, config);
         let result = client.health_check().await;
 
@@ -442,10 +1707,7 @@ mod tests {
         let mock = server.mock("GET", "/version").with_status(500).create();
 
         let http_client = reqwest::Client::new();
-        let config = PotatomeshConfig {
-            base_url: server.url(),
-            poll_interval_secs: 60,
-        };
+        let config = PotatomeshConfig::for_test(&server.url());
         let client = PotatoClient::new(http_client, config);
         let result = client.health_check().await;
 
@@ -462,10 +1724,7 @@ mod tests {
             .create();
 
         let http_client = reqwest::Client::new();
-        let config = PotatomeshConfig {
-            base_url: server.url(),
-            poll_interval_secs: 60,
-        };
+        let config = PotatomeshConfig::for_test(&server.url());
         let client = PotatoClient::new(http_client, config);
         let result = client.fetch_messages(FetchParams::default()).await;
 
@@ -473,6 +1732,140 @@ mod tests {
         assert!(result.is_err());
     }
 
+    fn retrying_client(server: &mockito::ServerGuard) -> PotatoClient {
+        let config = PotatomeshConfig {
+            retry: RetryConfig {
+                max_attempts: 3,
+                base_delay_ms: 1,
+            },
+            ..PotatomeshConfig::for_test(&server.url())
+        };
+        PotatoClient::new(reqwest::Client::new(), config)
+    }
+
+    fn client_forwarding(ports: &[&str]) -> PotatoClient {
+        let config = PotatomeshConfig {
+            forward_portnums: ports.iter().map(|port| port.to_string()).collect(),
+            ..PotatomeshConfig::for_test("http://localhost")
+        };
+        PotatoClient::new(reqwest::Client::new(), config)
+    }
+
+    #[test]
+    fn forwards_portnum_checks_allowlist() {
+        let client = client_forwarding(&["TEXT_MESSAGE_APP", "WAYPOINT_APP"]);
+        assert!(client.forwards_portnum(Some("WAYPOINT_APP")));
+        assert!(!client.forwards_portnum(Some("POSITION_APP")));
+        // A message without a port counts as text.
+        assert!(client.forwards_portnum(None));
+        assert!(!client_forwarding(&["WAYPOINT_APP"]).forwards_portnum(None));
+    }
+
+    #[test]
+    fn forwards_portnum_accepts_everything_when_empty() {
+        let client = client_forwarding(&[]);
+        assert!(client.forwards_portnum(Some("DETECTION_SENSOR_APP")));
+        assert!(client.forwards_portnum(None));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_messages_with_retry_recovers_from_5xx() {
+        let mut server = mockito::Server::new_async().await;
+        let failing = server
+            .mock("GET", "/api/messages")
+            .with_status(503)
+            .expect(2)
+            .create();
+        let ok = server
+            .mock("GET", "/api/messages")
+            .with_status(200)
+            .with_body("[]")
+            .expect(1)
+            .create();
+
+        let client = retrying_client(&server);
+        let result = client
+            .fetch_messages_with_retry(FetchParams::default(), 3)
+            .await;
+
+        failing.assert();
+        ok.assert();
+        assert!(result.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_fetch_messages_with_retry_gives_up_after_max_attempts() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("GET", "/api/messages")
+            .with_status(502)
+            .expect(3)
+            .create();
+
+        let client = retrying_client(&server);
+        let result = client
+            .fetch_messages_with_retry(FetchParams::default(), 3)
+            .await;
+
+        mock.assert();
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_fetch_messages_with_retry_does_not_retry_4xx() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("GET", "/api/messages")
+            .with_status(404)
+            .expect(1)
+            .create();
+
+        let client = retrying_client(&server);
+        let result = client
+            .fetch_messages_with_retry(FetchParams::default(), 3)
+            .await;
+
+        mock.assert();
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_fetch_messages_times_out_as_transient_error() {
+        let mut server = mockito::Server::new_async().await;
+        let _mock = server
+            .mock("GET", "/api/messages")
+            .with_status(200)
+            .with_chunked_body(|w| {
+                std::thread::sleep(Duration::from_millis(500));
+                w.write_all(b"[]")
+            })
+            .create();
+
+        let mut client = retrying_client(&server);
+        client.http = reqwest::Client::builder()
+            .timeout(Duration::from_millis(50))
+            .build()
+            .unwrap();
+        let err = client
+            .fetch_messages(FetchParams::default())
+            .await
+            .unwrap_err();
+
+        assert!(is_transient(&err));
+    }
+
+    #[test]
+    fn backoff_delay_doubles_with_bounded_jitter_and_cap() {
+        for _ in 0..100 {
+            let first = backoff_delay(500, 1);
+            assert!(first >= Duration::from_millis(500) && first <= Duration::from_millis(750));
+            let third = backoff_delay(500, 3);
+            assert!(third >= Duration::from_millis(2000) && third <= Duration::from_millis(3000));
+        }
+        assert_eq!(backoff_delay(500, 10), MAX_RETRY_DELAY);
+        assert_eq!(backoff_delay(500, 64), MAX_RETRY_DELAY);
+    }
+
     #[tokio::test]
     async fn test_fetch_messages_with_limit_and_since() {
         let mut server = mockito::Server::new_async().await;
@@ -488,14 +1881,13 @@ mod tests {
             .create();
 
         let http_client = reqwest::Client::new();
-        let config = PotatomeshConfig {
-            base_url: server.url(),
-            poll_interval_secs: 60,
-        };
+        let config = PotatomeshConfig::for_test(&server.url());
         let client = PotatoClient::new(http_client, config);
         let params = FetchParams {
             limit: Some(10),
             since: Some(123),
+            before: None,
+            after_id: None,
         };
         let result = client.fetch_messages(params).await;
 
@@ -507,10 +1899,7 @@ mod tests {
     #[tokio::test]
     async fn test_get_node_cache_hit() {
         let http_client = reqwest::Client::new();
-        let config = PotatomeshConfig {
-            base_url: "http://localhost:8080".to_string(),
-            poll_interval_secs: 60,
-        };
+        let config = PotatomeshConfig::for_test("http://localhost:8080");
         let client = PotatoClient::new(http_client, config);
         let node = PotatoNode {
             node_id: "!1234".to_string(),
@@ -524,11 +1913,13 @@ mod tests {
             longitude: None,
             altitude: None,
         };
-        client
-            .nodes_cache
-            .write()
-            .await
-            .insert("1234".to_string(), node.clone());
+        client.nodes_cache.write().await.insert(
+            "1234".to_string(),
+            CachedNode {
+                node: node.clone(),
+                fetched_at: now_secs(),
+            },
+        );
         let result = client.get_node("!1234").await;
         assert!(result.is_ok());
         let got = result.unwrap();
@@ -556,10 +1947,7 @@ mod tests {
             .create();
 
         let http_client = reqwest::Client::new();
-        let config = PotatomeshConfig {
-            base_url: server.url(),
-            poll_interval_secs: 60,
-        };
+        let config = PotatomeshConfig::for_test(&server.url());
         let client = PotatoClient::new(http_client, config);
 
         // first call, should miss cache and hit the server
@@ -573,6 +1961,34 @@ mod tests {
         // mockito would panic here if we made a second request
     }
 
+    #[tokio::test]
+    async fn get_node_truncates_absurdly_long_names() {
+        let mut server = mockito::Server::new_async().await;
+        let long_name = "x".repeat(5000);
+        let _mock = server
+            .mock("GET", "/api/nodes/1234")
+            .match_query(mockito::Matcher::Any)
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(
+                serde_json::json!({
+                    "node_id": "!1234",
+                    "short_name": long_name,
+                    "long_name": long_name,
+                })
+                .to_string(),
+            )
+            .create();
+
+        let config = PotatomeshConfig::for_test(&server.url());
+        let client = PotatoClient::new(reqwest::Client::new(), config);
+        let node = client.get_node("!1234").await.unwrap();
+
+        assert_eq!(node.long_name.chars().count(), MAX_NODE_NAME_CHARS);
+        assert!(node.long_name.ends_with('…'));
+        assert_eq!(node.short_name, Some(node.long_name.clone()));
+    }
+
     #[tokio::test]
     async fn test_get_node_error() {
         let mut server = mockito::Server::new_async().await;
@@ -582,13 +1998,212 @@ mod tests {
             .create();
 
         let http_client = reqwest::Client::new();
-        let config = PotatomeshConfig {
-            base_url: server.url(),
-            poll_interval_secs: 60,
-        };
+        let config = PotatomeshConfig::for_test(&server.url());
         let client = PotatoClient::new(http_client, config);
         let result = client.get_node("!1234").await;
         mock.assert();
         assert!(result.is_err());
     }
+
+    fn sample_cached_node(node_id: &str, fetched_at: u64) -> CachedNode {
+        CachedNode {
+            node: PotatoNode {
+                node_id: node_id.to_string(),
+                short_name: Some("test".to_string()),
+                long_name: "test node".to_string(),
+                role: None,
+                hw_model: None,
+                last_heard: None,
+                first_heard: None,
+                latitude: None,
+                longitude: None,
+                altitude: None,
+            },
+            fetched_at,
+        }
+    }
+
+    fn offline_client() -> PotatoClient {
+        PotatoClient::new(
+            reqwest::Client::new(),
+            PotatomeshConfig::for_test("http://localhost:8080"),
+        )
+    }
+
+    #[tokio::test]
+    async fn fetch_all_nodes_primes_cache() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("GET", "/api/nodes")
+            .match_query(mockito::Matcher::UrlEncoded("limit".into(), "1000".into()))
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(
+                r#"[
+                  {"node_id": "!ABCD1234", "long_name": "Node A"},
+                  {"node_id": "!deadbeef", "short_name": "DB", "long_name": "Node B"}
+                ]"#,
+            )
+            .create();
+        let client = PotatoClient::new(
+            reqwest::Client::new(),
+            PotatomeshConfig::for_test(&server.url()),
+        );
+
+        assert_eq!(client.fetch_all_nodes().await.unwrap(), 2);
+
+        mock.assert();
+        let cache = client.nodes_cache.read().await;
+        assert!(cache.contains_key("abcd1234"));
+        assert_eq!(cache["deadbeef"].node.long_name, "Node B");
+    }
+
+    #[tokio::test]
+    async fn get_nodes_fetches_each_uncached_node_once() {
+        let mut server = mockito::Server::new_async().await;
+        let found = server
+            .mock("GET", "/api/nodes/abcd1234")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"node_id": "!abcd1234", "long_name": "Node A"}"#)
+            .expect(1)
+            .create();
+        let missing = server
+            .mock("GET", "/api/nodes/0badc0de")
+            .with_status(404)
+            .expect(1)
+            .create();
+        let client = retrying_client(&server);
+        client
+            .nodes_cache
+            .write()
+            .await
+            .insert("1234".to_string(), sample_cached_node("!1234", now_secs()));
+
+        let ids = [
+            "!abcd1234",
+            "!ABCD1234",
+            "!abcd1234",
+            "!1234",
+            "!0badc0de",
+            "^all",
+        ]
+        .map(String::from);
+        let nodes = client.get_nodes(&ids).await;
+
+        found.assert();
+        missing.assert();
+        assert_eq!(nodes.len(), 3);
+        assert_eq!(nodes["!ABCD1234"].long_name, "Node A");
+        assert_eq!(nodes["!1234"].node_id, "!1234");
+        // Later single lookups are served from the warmed cache.
+        assert_eq!(
+            client.get_node("!abcd1234").await.unwrap().long_name,
+            "Node A"
+        );
+        found.assert();
+    }
+
+    #[tokio::test]
+    async fn get_node_refetches_entries_older_than_ttl() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("GET", "/api/nodes/1234")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"node_id": "!1234", "long_name": "Renamed"}"#)
+            .expect(1)
+            .create();
+        let client = retrying_client(&server);
+        let stale = now_secs() - 3601;
+        client
+            .nodes_cache
+            .write()
+            .await
+            .insert("1234".to_string(), sample_cached_node("!1234", stale));
+
+        assert_eq!(client.get_node("!1234").await.unwrap().long_name, "Renamed");
+        // The refetched entry is fresh again.
+        assert_eq!(client.get_node("!1234").await.unwrap().long_name, "Renamed");
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn fetch_all_nodes_error() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("GET", "/api/nodes")
+            .match_query(mockito::Matcher::Any)
+            .with_status(503)
+            .create();
+        let client = PotatoClient::new(
+            reqwest::Client::new(),
+            PotatomeshConfig::for_test(&server.url()),
+        );
+
+        assert!(client.fetch_all_nodes().await.is_err());
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn nodes_cache_save_load_roundtrip() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("nodes_cache.json");
+        let path_str = path.to_str().unwrap();
+
+        let client = offline_client();
+        client
+            .nodes_cache
+            .write()
+            .await
+            .insert("1234".to_string(), sample_cached_node("!1234", now_secs()));
+        assert_eq!(client.save_nodes_cache(path_str).await.unwrap(), 1);
+
+        let restored = offline_client();
+        assert_eq!(restored.load_nodes_cache(path_str).await.unwrap(), 1);
+        // Served from the restored cache; no server is listening on localhost:8080.
+        let node = restored.get_node("!1234").await.unwrap();
+        assert_eq!(node.long_name, "test node");
+    }
+
+    #[tokio::test]
+    async fn nodes_cache_load_drops_stale_entries() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("nodes_cache.json");
+        let path_str = path.to_str().unwrap();
+
+        let client = offline_client();
+        {
+            let mut cache = client.nodes_cache.write().await;
+            cache.insert(
+                "fresh".to_string(),
+                sample_cached_node("!fresh", now_secs()),
+            );
+            cache.insert(
+                "stale".to_string(),
+                sample_cached_node("!stale", now_secs() - 7200),
+            );
+        }
+        client.save_nodes_cache(path_str).await.unwrap();
+
+        let restored = offline_client();
+        assert_eq!(restored.load_nodes_cache(path_str).await.unwrap(), 1);
+        let cache = restored.nodes_cache.read().await;
+        assert!(cache.contains_key("fresh"));
+        assert!(!cache.contains_key("stale"));
+    }
+
+    #[tokio::test]
+    async fn nodes_cache_load_missing_or_empty_file() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("nodes_cache.json");
+        let path_str = path.to_str().unwrap();
+
+        let client = offline_client();
+        assert_eq!(client.load_nodes_cache(path_str).await.unwrap(), 0);
+
+        fs::write(&path, "  ").unwrap();
+        assert_eq!(client.load_nodes_cache(path_str).await.unwrap(), 0);
+        assert!(client.nodes_cache.read().await.is_empty());
+    }
 }
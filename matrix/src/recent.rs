@@ -0,0 +1,297 @@
+// Copyright © 2025-26 l5yth & contributors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Bounded map of recently bridged mesh messages, used to resolve what a
+//! mesh reply (`reply_id`) refers to and which Matrix event to thread it under.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+
+use serde::{Deserialize, Serialize, Serializer};
+
+/// Number of bridged messages remembered; the oldest are evicted first.
+pub const MAX_RECENT_MESSAGES: usize = 5000;
+
+/// A mesh message the bridge has forwarded to Matrix.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RecentMessage {
+    /// Mesh message id.
+    pub id: u64,
+    /// Node that sent the message, e.g. `"!abcd1234"`.
+    pub node_id: String,
+    /// Message text as bridged, used to quote the parent of a reply under
+    /// `reply_cold_start = "quote"`; not kept otherwise.
+    #[serde(default)]
+    pub text: Option<String>,
+    /// Matrix event the message was sent as. Missing when the homeserver
+    /// reported none, or for entries persisted before replies were threaded.
+    #[serde(default)]
+    pub event_id: Option<String>,
+    /// Room the event was sent to. Missing before the message is sent, or
+    /// for entries persisted before the room was kept.
+    #[serde(default)]
+    pub room_id: Option<String>,
+}
+
+/// Saved as the list of bridged messages, oldest first.
+#[derive(Default, Deserialize)]
+#[serde(from = "Vec<RecentMessage>")]
+pub struct RecentMessages {
+    /// Bridged messages by mesh id, each with the stamp of its latest record.
+    entries: HashMap<u64, (u64, RecentMessage)>,
+    /// Ids and stamps in record order, oldest first. Recording an id again
+    /// leaves its earlier stamp behind, which eviction skips.
+    order: VecDeque<(u64, u64)>,
+    next_stamp: u64,
+    /// Kept in a table of its own by a SQLite store, so left out of the
+    /// state's serialized fields.
+    in_table: bool,
+}
+
+impl fmt::Debug for RecentMessages {
+    // The full list would flood every state log line; the size is enough.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "RecentMessages({} entries)", self.entries.len())
+    }
+}
+
+impl From<Vec<RecentMessage>> for RecentMessages {
+    fn from(messages: Vec<RecentMessage>) -> Self {
+        let mut recent = Self::default();
+        for message in messages {
+            recent.record(message);
+        }
+        recent
+    }
+}
+
+impl Serialize for RecentMessages {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.iter())
+    }
+}
+
+impl RecentMessages {
+    /// Remember a bridged message, replacing any earlier entry with the same id.
+    pub fn record(&mut self, message: RecentMessage) {
+        let stamp = self.next_stamp;
+        self.next_stamp += 1;
+        self.order.push_back((message.id, stamp));
+        self.entries.insert(message.id, (stamp, message));
+        while self.entries.len() > MAX_RECENT_MESSAGES {
+            if let Some((id, stamp)) = self.order.pop_front() {
+                if self.is_current(id, stamp) {
+                    self.entries.remove(&id);
+                }
+            }
+        }
+        // Drop the stamps left behind by re-recorded ids, so the queue stays
+        // bounded too.
+        if self.order.len() > 2 * MAX_RECENT_MESSAGES {
+            let entries = &self.entries;
+            self.order
+                .retain(|&(id, stamp)| entries.get(&id).is_some_and(|(s, _)| *s == stamp));
+        }
+    }
+
+    /// Look up a bridged message by its mesh id.
+    pub fn get(&self, id: u64) -> Option<&RecentMessage> {
+        self.entries.get(&id).map(|(_, message)| message)
+    }
+
+    /// The bridged messages, oldest first.
+    pub fn iter(&self) -> impl Iterator<Item = &RecentMessage> {
+        self.iter_stamped().map(|(_, message)| message)
+    }
+
+    /// Rebuild a map kept in a SQLite store from its `(stamp, message)`
+    /// entries, oldest first.
+    pub fn restore(entries: Vec<(u64, RecentMessage)>) -> Self {
+        let mut recent = Self {
+            in_table: true,
+            ..Self::default()
+        };
+        for (stamp, message) in entries {
+            recent.order.push_back((message.id, stamp));
+            recent.entries.insert(message.id, (stamp, message));
+            recent.next_stamp = stamp + 1;
+        }
+        recent
+    }
+
+    /// Stamp of the oldest message kept; every evicted one had a lower one.
+    pub fn oldest_stamp(&self) -> Option<u64> {
+        self.iter_stamped().next().map(|(stamp, _)| stamp)
+    }
+
+    /// Stamp the next recorded message gets.
+    pub fn next_stamp(&self) -> u64 {
+        self.next_stamp
+    }
+
+    /// The messages recorded from `stamp` on, with their stamps.
+    pub fn recorded_since(&self, stamp: u64) -> impl Iterator<Item = (u64, &RecentMessage)> {
+        let start = self.order.partition_point(|&(_, s)| s < stamp);
+        self.order
+            .range(start..)
+            .filter_map(|&(id, stamp)| self.current(id, stamp).map(|message| (stamp, message)))
+    }
+
+    /// Whether a SQLite store keeps the messages in a table of their own.
+    pub fn in_table(&self) -> bool {
+        self.in_table
+    }
+
+    pub fn set_in_table(&mut self, in_table: bool) {
+        self.in_table = in_table;
+    }
+
+    fn iter_stamped(&self) -> impl Iterator<Item = (u64, &RecentMessage)> {
+        self.recorded_since(0)
+    }
+
+    fn current(&self, id: u64, stamp: u64) -> Option<&RecentMessage> {
+        match self.entries.get(&id) {
+            Some((current, message)) if *current == stamp => Some(message),
+            _ => None,
+        }
+    }
+
+    fn is_current(&self, id: u64, stamp: u64) -> bool {
+        self.current(id, stamp).is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: u64, node_id: &str) -> RecentMessage {
+        RecentMessage {
+            id,
+            node_id: node_id.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn recent_messages_record_and_get() {
+        let mut recent = RecentMessages::default();
+        recent.record(RecentMessage {
+            text: Some("first".to_string()),
+            ..message(1, "!aaaa0001")
+        });
+        recent.record(RecentMessage {
+            text: Some("second".to_string()),
+            event_id: Some("$second".to_string()),
+            ..message(1, "!bbbb0002")
+        });
+
+        assert_eq!(recent.entries.len(), 1);
+        assert_eq!(recent.get(1).unwrap().node_id, "!bbbb0002");
+        assert_eq!(recent.get(1).unwrap().text.as_deref(), Some("second"));
+        assert_eq!(recent.get(1).unwrap().event_id.as_deref(), Some("$second"));
+        assert!(recent.get(2).is_none());
+    }
+
+    #[test]
+    fn recent_messages_evicts_oldest_past_bound() {
+        let mut recent = RecentMessages::default();
+        for id in 0..=MAX_RECENT_MESSAGES as u64 {
+            recent.record(message(id, "!abcd1234"));
+        }
+
+        assert_eq!(recent.entries.len(), MAX_RECENT_MESSAGES);
+        assert!(recent.get(0).is_none());
+        assert!(recent.get(MAX_RECENT_MESSAGES as u64).is_some());
+    }
+
+    #[test]
+    fn recent_messages_recorded_again_are_evicted_last() {
+        let mut recent = RecentMessages::default();
+        for id in 0..MAX_RECENT_MESSAGES as u64 {
+            recent.record(message(id, "!abcd1234"));
+        }
+        recent.record(message(0, "!abcd1234"));
+        recent.record(message(MAX_RECENT_MESSAGES as u64, "!abcd1234"));
+
+        assert!(recent.get(0).is_some());
+        assert!(recent.get(1).is_none());
+        assert_eq!(recent.iter().next().unwrap().id, 2);
+        assert_eq!(recent.iter().last().unwrap().id, MAX_RECENT_MESSAGES as u64);
+    }
+
+    #[test]
+    fn recent_messages_drop_stale_stamps_of_re_recorded_ids() {
+        let mut recent = RecentMessages::default();
+        for _ in 0..=2 * MAX_RECENT_MESSAGES {
+            recent.record(message(1, "!abcd1234"));
+        }
+
+        assert_eq!(recent.order.len(), 1);
+        assert_eq!(recent.iter().count(), 1);
+    }
+
+    #[test]
+    fn recent_messages_restore_keeps_stamps() {
+        let mut recent = RecentMessages::default();
+        for id in [3, 1, 2, 3] {
+            recent.record(message(id, "!abcd1234"));
+        }
+        let since: Vec<(u64, u64)> = recent
+            .recorded_since(2)
+            .map(|(stamp, message)| (stamp, message.id))
+            .collect();
+        assert_eq!(since, vec![(2, 2), (3, 3)]);
+
+        let stamped = recent
+            .recorded_since(0)
+            .map(|(stamp, message)| (stamp, message.clone()))
+            .collect();
+        let mut restored = RecentMessages::restore(stamped);
+        assert!(restored.in_table());
+        assert_eq!(restored.oldest_stamp(), Some(1));
+        restored.record(message(4, "!abcd1234"));
+        assert_eq!(restored.next_stamp(), 5);
+        assert_eq!(restored.get(1).unwrap().id, 1);
+    }
+
+    #[test]
+    fn recent_messages_round_trip_in_record_order() {
+        let mut recent = RecentMessages::default();
+        for id in [3, 1, 2, 3] {
+            recent.record(message(id, "!abcd1234"));
+        }
+
+        let json = serde_json::to_string(&recent).unwrap();
+        let loaded: RecentMessages = serde_json::from_str(&json).unwrap();
+        let ids: Vec<u64> = loaded.iter().map(|message| message.id).collect();
+        assert_eq!(ids, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn recent_messages_load_entries_without_text() {
+        let recent: RecentMessages =
+            serde_json::from_str(r#"[{"id": 3, "node_id": "!abcd1234"}]"#).unwrap();
+        assert!(recent.get(3).unwrap().text.is_none());
+        assert!(recent.get(3).unwrap().event_id.is_none());
+    }
+
+    #[test]
+    fn recent_messages_debug_reports_size_only() {
+        let mut recent = RecentMessages::default();
+        recent.record(message(7, "!abcd1234"));
+        assert_eq!(format!("{recent:?}"), "RecentMessages(1 entries)");
+    }
+}
@@ -0,0 +1,148 @@
+// Copyright © 2025-26 l5yth & contributors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The `gen-registration` subcommand: the appservice registration file the
+//! homeserver needs, built from the bridge's own config so the two agree.
+
+use crate::config::RegistrationSettings;
+
+/// Appservice id written to the registration file.
+const REGISTRATION_ID: &str = "potatomesh-bridge";
+
+/// A registration file and the tokens it holds that the config lacks.
+pub struct Registration {
+    /// The registration file as YAML.
+    pub yaml: String,
+    /// Config keys whose value was generated, so `Config.toml` must be
+    /// updated to match.
+    pub generated: Vec<&'static str>,
+}
+
+/// Build the registration for `settings`, generating any missing token.
+pub fn generate(
+    settings: &RegistrationSettings,
+    url: &str,
+    sender_localpart: &str,
+) -> anyhow::Result<Registration> {
+    let mut generated = Vec::new();
+    let mut token = |configured: &Option<String>, key| match configured {
+        Some(token) => Ok(token.clone()),
+        None => {
+            generated.push(key);
+            random_token()
+        }
+    };
+    let as_token = token(&settings.as_token, "matrix.as_token")?;
+    let hs_token = token(&settings.hs_token, "matrix.hs_token")?;
+
+    // Node puppets and channel bots both start with the prefix.
+    let user_regex = format!(
+        "@{}.*:{}",
+        regex_escape(&settings.puppet_prefix),
+        regex_escape(&settings.server_name)
+    );
+
+    let yaml = format!(
+        "id: {}\nurl: {}\nas_token: {}\nhs_token: {}\nsender_localpart: {}\nrate_limited: false\nnamespaces:\n  users:\n    - exclusive: true\n      regex: {}\n  rooms: []\n  aliases: []\n",
+        quote(REGISTRATION_ID),
+        quote(url),
+        quote(&as_token),
+        quote(&hs_token),
+        quote(sender_localpart),
+        quote(&user_regex),
+    );
+    Ok(Registration { yaml, generated })
+}
+
+/// 64 hex characters from the OS's randomness.
+fn random_token() -> anyhow::Result<String> {
+    let mut bytes = [0u8; 32];
+    getrandom::fill(&mut bytes)
+        .map_err(|e| anyhow::anyhow!("Could not generate a random token: {}", e))?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// `value` as a double-quoted YAML scalar; JSON string escapes are valid
+/// YAML.
+fn quote(value: &str) -> String {
+    serde_json::Value::from(value).to_string()
+}
+
+/// Escape the regex metacharacters in `value`, e.g. the dots of a server
+/// name.
+fn regex_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if "\\.+*?()|[]{}^$".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> RegistrationSettings {
+        RegistrationSettings {
+            server_name: "example.org".to_string(),
+            as_token: Some("AS_TOKEN".to_string()),
+            hs_token: Some("HS_TOKEN".to_string()),
+            puppet_prefix: "potato_".to_string(),
+        }
+    }
+
+    #[test]
+    fn generate_uses_configured_tokens_and_puppet_namespace() {
+        let registration =
+            generate(&settings(), "http://bridge:41448", "potatomesh-bridge").unwrap();
+
+        assert!(registration.generated.is_empty());
+        assert_eq!(
+            registration.yaml,
+            "id: \"potatomesh-bridge\"\n\
+             url: \"http://bridge:41448\"\n\
+             as_token: \"AS_TOKEN\"\n\
+             hs_token: \"HS_TOKEN\"\n\
+             sender_localpart: \"potatomesh-bridge\"\n\
+             rate_limited: false\n\
+             namespaces:\n  \
+             users:\n    \
+             - exclusive: true\n      \
+             regex: \"@potato_.*:example\\\\.org\"\n  \
+             rooms: []\n  \
+             aliases: []\n"
+        );
+    }
+
+    #[test]
+    fn generate_fills_in_missing_tokens() {
+        let settings = RegistrationSettings {
+            hs_token: None,
+            ..settings()
+        };
+
+        let registration = generate(&settings, "http://bridge:41448", "bot").unwrap();
+
+        assert_eq!(registration.generated, vec!["matrix.hs_token"]);
+        let hs_line = registration
+            .yaml
+            .lines()
+            .find(|line| line.starts_with("hs_token: "))
+            .unwrap();
+        assert_eq!(hs_line.len(), "hs_token: \"\"".len() + 64);
+    }
+}
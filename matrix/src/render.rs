@@ -0,0 +1,474 @@
+// Copyright © 2025-26 l5yth & contributors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Text the bridge renders: message bodies and metadata templates, reply
+//! fallbacks, display names and the room topic.
+
+use std::borrow::Cow;
+
+use tracing::warn;
+
+use crate::config::{BridgeConfig, ReplyColdStart};
+use crate::potatomesh::{self, PotatoClient, PotatoNode};
+use crate::recent::RecentMessage;
+use crate::text;
+
+/// Hard cap on a node's short name wherever it is shown, e.g. the `(short)`
+/// in display names. Meshtastic short names are at most four characters.
+const MAX_SHORT_NAME_CHARS: usize = 8;
+
+/// Room topic summarizing the mesh, e.g.
+/// `PotatoMesh — 42 nodes seen, 7 active in last hour, last msg 3m ago`.
+pub fn mesh_topic(nodes: &[PotatoNode], last_rx_time: Option<u64>, now: u64) -> String {
+    let active = nodes
+        .iter()
+        .filter(|node| {
+            node.last_heard
+                .is_some_and(|heard| now.saturating_sub(heard) <= 3600)
+        })
+        .count();
+    let noun = if nodes.len() == 1 { "node" } else { "nodes" };
+    let mut topic = format!(
+        "PotatoMesh — {} {} seen, {} active in last hour",
+        nodes.len(),
+        noun,
+        active
+    );
+    if let Some(rx_time) = last_rx_time {
+        topic.push_str(&format!(
+            ", last msg {}",
+            format_age(now.saturating_sub(rx_time))
+        ));
+    }
+    topic
+}
+
+/// Coarse age like `3m ago`, so the topic does not change on every poll.
+fn format_age(secs: u64) -> String {
+    match secs {
+        0..60 => "just now".to_string(),
+        60..3600 => format!("{}m ago", secs / 60),
+        3600..86400 => format!("{}h ago", secs / 3600),
+        _ => format!("{}d ago", secs / 86400),
+    }
+}
+
+/// Build the plain-text reply context prepended to a mesh reply's body.
+///
+/// When the parent cannot be threaded (its Matrix event is unknown),
+/// `reply_cold_start = "quote"` quotes the cached parent text Matrix-fallback
+/// style (`> <sender> text`); otherwise, or when the parent text is not
+/// cached, `reply_fallback_prefix` adds `> in reply to <sender>`.
+/// `parent` is the bridged message replied to; `None` when the message is
+/// not a reply, or the parent was never bridged (or has aged out of the
+/// recent-message map). Returns `None` then, or when neither option applies.
+pub async fn reply_fallback(
+    potato: &PotatoClient,
+    bridge_cfg: &BridgeConfig,
+    parent: Option<&RecentMessage>,
+) -> Option<String> {
+    if bridge_cfg.reply_cold_start != ReplyColdStart::Quote && !bridge_cfg.reply_fallback_prefix {
+        return None;
+    }
+    let parent = parent?;
+    let quote = bridge_cfg.reply_cold_start == ReplyColdStart::Quote && parent.event_id.is_none();
+    if !quote && !bridge_cfg.reply_fallback_prefix {
+        return None;
+    }
+    let name = reply_parent_name(potato, bridge_cfg, parent).await;
+    match parent.text.as_deref() {
+        Some(text) if quote => Some(quote_reply_parent(&name, text)),
+        _ if bridge_cfg.reply_fallback_prefix => Some(format!("> in reply to {}", name)),
+        _ => None,
+    }
+}
+
+/// Quote a parent message line by line, naming its sender on the first line.
+fn quote_reply_parent(name: &str, text: &str) -> String {
+    let mut lines = text.lines();
+    let mut quoted = format!("> <{}> {}", name, lines.next().unwrap_or_default());
+    for line in lines {
+        quoted.push_str("\n> ");
+        quoted.push_str(line);
+    }
+    quoted
+}
+
+/// Resolve the short name of the sender of a bridged parent message, falling
+/// back to its node id when the metadata cannot be fetched.
+async fn reply_parent_name(
+    potato: &PotatoClient,
+    bridge_cfg: &BridgeConfig,
+    parent: &RecentMessage,
+) -> String {
+    match potato.get_node(&parent.node_id).await {
+        Ok(node) => short_or_long_name(&node),
+        Err(e) => {
+            warn!("Failed to resolve reply parent {}: {:?}", parent.node_id, e);
+            unknown_node_name(
+                bridge_cfg.unknown_node_name_template.as_deref(),
+                &parent.node_id,
+            )
+        }
+    }
+}
+
+/// Name shown for a node without metadata: `template` with `{hex}` filled
+/// in, or the raw node id when no template is configured. A message that
+/// carries no node id at all is attributed to `unknown`.
+pub fn unknown_node_name(template: Option<&str>, node_id: &str) -> String {
+    if node_id.is_empty() {
+        return "unknown".to_string();
+    }
+    match template {
+        Some(template) => template.replace("{hex}", &potatomesh::normalize_node_hex(node_id)),
+        None => node_id.to_string(),
+    }
+}
+
+/// Node altitude for the `{altitude}` placeholder, e.g. `"312 m"`; empty
+/// when unknown.
+pub fn format_altitude(altitude: Option<f64>) -> String {
+    match altitude {
+        Some(meters) if meters.is_finite() => format!("{} m", meters.round() as i64),
+        _ => String::new(),
+    }
+}
+
+/// Remove the parts of a metadata template that show one of the `unknown`
+/// placeholders: a whole `[...]` segment, or just the comma-separated field
+/// inside it when the segment holds others. Placeholders outside brackets
+/// are removed on their own.
+pub fn drop_unknown_fields(template: &str, unknown: &[&str]) -> String {
+    let shows_unknown = |part: &str| {
+        unknown
+            .iter()
+            .any(|name| part.contains(&format!("{{{name}}}")))
+    };
+    let mut kept = String::new();
+    let mut rest = template;
+    while let Some(open) = rest.find('[') {
+        let Some(close) = rest[open..].find(']').map(|close| open + close) else {
+            break;
+        };
+        kept.push_str(&rest[..open]);
+        let fields: Vec<&str> = rest[open + 1..close]
+            .split(',')
+            .filter(|field| !shows_unknown(field))
+            .map(str::trim)
+            .collect();
+        if !fields.is_empty() {
+            kept.push('[');
+            kept.push_str(&fields.join(", "));
+            kept.push(']');
+        }
+        rest = &rest[close + 1..];
+    }
+    kept.push_str(rest);
+    text::fill_placeholders(
+        &kept,
+        &unknown.iter().map(|name| (*name, "")).collect::<Vec<_>>(),
+    )
+}
+
+/// SNR changes smaller than this (dB) count as steady.
+const SNR_TREND_DEADBAND_DB: f32 = 1.0;
+
+/// Arrow describing the change from `previous` to `current` SNR.
+pub fn snr_trend_arrow(previous: f32, current: f32) -> &'static str {
+    let delta = current - previous;
+    if delta >= SNR_TREND_DEADBAND_DB {
+        "↑"
+    } else if delta <= -SNR_TREND_DEADBAND_DB {
+        "↓"
+    } else {
+        "→"
+    }
+}
+
+/// A node's short name, or its long name when the short one is missing or blank.
+pub fn short_or_long_name(node: &PotatoNode) -> String {
+    short_name(node)
+        .map(Cow::into_owned)
+        .unwrap_or_else(|| node.long_name.clone())
+}
+
+/// A node's trimmed short name capped at [`MAX_SHORT_NAME_CHARS`], or `None`
+/// when missing or blank.
+pub fn short_name(node: &PotatoNode) -> Option<Cow<'_, str>> {
+    node.short_name
+        .as_deref()
+        .map(str::trim)
+        .filter(|short| !short.is_empty())
+        .map(|short| text::truncate_chars(short, MAX_SHORT_NAME_CHARS))
+}
+
+/// Fill a `position_beacon_template`; coordinates use four decimals (~10 m).
+pub fn render_position_beacon(template: &str, name: &str, lat: f64, lon: f64) -> String {
+    template
+        .replace("{name}", name)
+        .replace("{lat}", &format!("{lat:.4}"))
+        .replace("{lon}", &format!("{lon:.4}"))
+}
+
+/// Short tag prepended to the message prefix so readers can tell the source
+/// mesh protocol apart at a glance. `"[MT]"` identifies Meshtastic (also the
+/// default when the protocol field is missing, since the full stack treats a
+/// missing protocol as Meshtastic) and `"[MC]"` identifies MeshCore. Any other
+/// value renders as `"[??]"` so unknown protocols surface visibly instead of
+/// being silently relabeled as Meshtastic.
+pub fn protocol_tag(protocol: Option<&str>) -> &'static str {
+    match protocol {
+        Some("meshcore") => "[MC]",
+        Some("meshtastic") | None => "[MT]",
+        Some(_) => "[??]",
+    }
+}
+
+/// Build plain text + HTML message bodies with inline-code metadata and,
+/// when given, the name of the node the message is from.
+pub fn format_message_bodies(prefix: &str, sender: Option<&str>, text: &str) -> (String, String) {
+    match sender {
+        None => (
+            format!("`{}` {}", prefix, text),
+            format!(
+                "<code>{}</code> {}",
+                text::escape_html(prefix),
+                text::escape_html(text)
+            ),
+        ),
+        Some(sender) => (
+            format!("`{}` {}: {}", prefix, sender, text),
+            format!(
+                "<code>{}</code> <strong>{}</strong>: {}",
+                text::escape_html(prefix),
+                text::escape_html(sender),
+                text::escape_html(text)
+            ),
+        ),
+    }
+}
+
+/// HTML body under `html_layout = "signal"`: `sender` in bold, then `text`
+/// with its line breaks kept, then the `signal` fields that are known on a
+/// `<small>` line of their own.
+pub fn format_signal_html(sender: &str, text: &str, signal: &[&str]) -> String {
+    let mut html = format!(
+        "<strong>{}</strong> {}",
+        text::escape_html(sender),
+        text::escape_html(text).replace('\n', "<br>")
+    );
+    if !signal.is_empty() {
+        html.push_str(&format!(
+            "<br><small>{}</small>",
+            text::escape_html(&signal.join(" · "))
+        ));
+    }
+    html
+}
+
+/// Plain and HTML bodies for a `message_template`. Values are escaped for
+/// the HTML body, where line breaks become `<br>`.
+pub fn render_message_template(template: &str, values: &[(&str, &str)]) -> (String, String) {
+    let escaped: Vec<(&str, String)> = values
+        .iter()
+        .map(|(name, value)| (*name, text::escape_html(value)))
+        .collect();
+    let escaped: Vec<(&str, &str)> = escaped
+        .iter()
+        .map(|(name, value)| (*name, value.as_str()))
+        .collect();
+    (
+        text::fill_placeholders(template, values),
+        text::fill_placeholders(&text::escape_html(template), &escaped).replace('\n', "<br>"),
+    )
+}
+
+/// Build the Matrix display name from a node's long/short names, cutting the
+/// long name so the whole fits in `max_chars` when set.
+pub fn display_name_for_node(node: &PotatoNode, max_chars: Option<usize>) -> String {
+    let suffix = short_name(node)
+        .filter(|_| node.short_name.as_deref().map(str::trim) != Some(&node.long_name))
+        .map(|short| format!(" ({})", short))
+        .unwrap_or_default();
+    let long_name = match max_chars {
+        Some(max) => text::truncate_chars(
+            &node.long_name,
+            max.saturating_sub(suffix.chars().count()).max(1),
+        ),
+        None => Cow::Borrowed(node.long_name.as_str()),
+    };
+    format!("{}{}", long_name, suffix)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::potatomesh::PotatoNode;
+
+    fn sample_node(short_name: Option<&str>, long_name: &str) -> PotatoNode {
+        PotatoNode {
+            node_id: "!abcd1234".to_string(),
+            short_name: short_name.map(str::to_string),
+            long_name: long_name.to_string(),
+            role: None,
+            hw_model: None,
+            last_heard: None,
+            first_heard: None,
+            latitude: None,
+            longitude: None,
+            altitude: None,
+        }
+    }
+
+    #[test]
+    fn format_message_bodies_escape_html() {
+        let (body, formatted) = format_message_bodies("[868][LF]", None, "Hello <&>");
+        assert_eq!(body, "`[868][LF]` Hello <&>");
+        assert_eq!(formatted, "<code>[868][LF]</code> Hello &lt;&amp;&gt;");
+    }
+
+    #[test]
+    fn format_signal_html_bolds_sender_and_escapes_text() {
+        let html = format_signal_html("T<N", "Hello <&>", &["RSSI -100 dBm", "TEST/MF"]);
+        assert_eq!(
+            html,
+            "<strong>T&lt;N</strong> Hello &lt;&amp;&gt;<br><small>RSSI -100 dBm · TEST/MF</small>"
+        );
+        assert_eq!(
+            format_signal_html("TN", "Hi", &[]),
+            "<strong>TN</strong> Hi"
+        );
+        assert_eq!(
+            format_signal_html("TN", "one\ntwo", &["TEST/MF"]),
+            "<strong>TN</strong> one<br>two<br><small>TEST/MF</small>"
+        );
+    }
+
+    #[test]
+    fn render_message_template_escapes_values_for_html() {
+        let (body, formatted) = render_message_template(
+            "[{short}] {text}\n({rssi})",
+            &[("short", "TN"), ("text", "a < b {rssi}"), ("rssi", "n/a")],
+        );
+        assert_eq!(body, "[TN] a < b {rssi}\n(n/a)");
+        assert_eq!(formatted, "[TN] a &lt; b {rssi}<br>(n/a)");
+    }
+
+    #[test]
+    fn protocol_tag_returns_expected_label() {
+        assert_eq!(protocol_tag(Some("meshcore")), "[MC]");
+        assert_eq!(protocol_tag(Some("meshtastic")), "[MT]");
+        // Missing protocol keeps the Meshtastic default for legacy payloads.
+        assert_eq!(protocol_tag(None), "[MT]");
+        // Unknown protocols surface as "[??]" rather than silently claiming Meshtastic.
+        assert_eq!(protocol_tag(Some("reticulum")), "[??]");
+        assert_eq!(protocol_tag(Some("")), "[??]");
+    }
+
+    #[test]
+    fn display_name_for_node_includes_short_when_present() {
+        let node = sample_node(Some("TN"), "Test Node");
+        assert_eq!(display_name_for_node(&node, None), "Test Node (TN)");
+    }
+
+    #[test]
+    fn display_name_for_node_ignores_empty_or_duplicate_short() {
+        let empty_short = sample_node(Some(""), "Test Node");
+        assert_eq!(display_name_for_node(&empty_short, None), "Test Node");
+
+        let duplicate_short = sample_node(Some("Test Node"), "Test Node");
+        assert_eq!(display_name_for_node(&duplicate_short, None), "Test Node");
+    }
+
+    #[test]
+    fn display_name_for_node_caps_long_and_short_names() {
+        let long = "L".repeat(500);
+        let node = sample_node(Some(&"S".repeat(500)), &long);
+        let name = display_name_for_node(&node, Some(32));
+
+        assert_eq!(name.chars().count(), 32);
+        assert_eq!(name, format!("{}… (SSSSSSS…)", "L".repeat(20)));
+        assert_eq!(short_or_long_name(&node), "SSSSSSS…");
+    }
+
+    #[test]
+    fn display_name_for_node_is_uncapped_by_default() {
+        let node = sample_node(Some("TN"), &"L".repeat(500));
+        assert_eq!(
+            display_name_for_node(&node, None),
+            format!("{} (TN)", "L".repeat(500))
+        );
+    }
+
+    #[test]
+    fn snr_trend_arrow_uses_deadband() {
+        assert_eq!(snr_trend_arrow(-5.0, 2.5), "↑");
+        assert_eq!(snr_trend_arrow(4.0, -3.0), "↓");
+        assert_eq!(snr_trend_arrow(4.0, 4.5), "→");
+    }
+
+    #[test]
+    fn format_altitude_rounds_to_whole_meters() {
+        assert_eq!(format_altitude(Some(312.4)), "312 m");
+        assert_eq!(format_altitude(Some(99.5)), "100 m");
+        assert_eq!(format_altitude(Some(-3.2)), "-3 m");
+        assert_eq!(format_altitude(Some(f64::NAN)), "");
+        assert_eq!(format_altitude(None), "");
+    }
+
+    #[test]
+    fn drop_unknown_fields_collapses_separators() {
+        assert_eq!(
+            drop_unknown_fields("{tag}[RSSI {rssi}, SNR {snr}][{channel}]", &["rssi"]),
+            "{tag}[SNR {snr}][{channel}]"
+        );
+        assert_eq!(
+            drop_unknown_fields("{tag}[{altitude}] {rssi}", &["altitude", "rssi"]),
+            "{tag} "
+        );
+        assert_eq!(
+            drop_unknown_fields("{tag}[{channel}", &["rssi"]),
+            "{tag}[{channel}"
+        );
+    }
+
+    #[test]
+    fn quote_reply_parent_quotes_every_line() {
+        assert_eq!(quote_reply_parent("TN", "Hello"), "> <TN> Hello");
+        assert_eq!(
+            quote_reply_parent("TN", "line one\nline two"),
+            "> <TN> line one\n> line two"
+        );
+    }
+
+    #[test]
+    fn format_age_rounds_down_to_the_largest_unit() {
+        assert_eq!(format_age(59), "just now");
+        assert_eq!(format_age(60), "1m ago");
+        assert_eq!(format_age(3599), "59m ago");
+        assert_eq!(format_age(7200), "2h ago");
+        assert_eq!(format_age(3 * 86400 + 5), "3d ago");
+    }
+
+    #[test]
+    fn unknown_node_name_fills_template_or_keeps_id() {
+        assert_eq!(
+            unknown_node_name(Some("🥔 {hex}"), "!ABCD1234"),
+            "🥔 abcd1234"
+        );
+        assert_eq!(unknown_node_name(None, "!abcd1234"), "!abcd1234");
+        assert_eq!(unknown_node_name(Some("Node {hex}"), ""), "unknown");
+    }
+}
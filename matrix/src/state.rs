@@ -0,0 +1,1416 @@
+// Copyright © 2025-26 l5yth & contributors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The bridge's persistent state: per-channel checkpoints, the caches that
+//! survive a restart, and the state file every source saves to.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fs,
+    path::Path,
+    sync::Arc,
+};
+
+use anyhow::Result;
+use tracing::{debug, error, info};
+
+use crate::config::{CheckpointTimeSource, NodeCooldown, SinceUnit};
+use crate::dedup::SeenContent;
+use crate::matrix::{PuppetCache, TxnCounter};
+use crate::metrics::Metrics;
+use crate::potatomesh::{self, FetchParams, PotatoMessage};
+#[cfg(test)]
+use crate::recent::RecentMessage;
+use crate::recent::RecentMessages;
+use crate::render::snr_trend_arrow;
+use crate::state_db;
+
+#[derive(Debug, serde::Serialize, serde::Deserialize, Default)]
+pub struct BridgeState {
+    /// Highest message id processed by the bridge, per mesh channel.
+    #[serde(default, deserialize_with = "deserialize_channel_checkpoints")]
+    pub last_message_id: HashMap<u8, u64>,
+    /// The single id older versions kept for every channel, standing in for
+    /// the checkpoint of each channel that has none of its own yet.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub legacy_message_id: Option<u64>,
+    /// Highest rx_time observed; used to build incremental fetch queries.
+    #[serde(default)]
+    pub last_rx_time: Option<u64>,
+    /// Message ids seen at the current last_rx_time for de-duplication.
+    #[serde(default)]
+    pub last_rx_time_ids: Vec<u64>,
+    /// Bridge clock at the start of the last poll whose batch was fully
+    /// processed; the `since` checkpoint under
+    /// `checkpoint_time_source = "local"`.
+    #[serde(default)]
+    pub last_polled_at: Option<u64>,
+    /// Legacy checkpoint timestamp used before last_rx_time was added.
+    #[serde(default, skip_serializing)]
+    pub last_checked_at: Option<u64>,
+    /// Recently bridged messages, used to resolve the parent of mesh replies:
+    /// their events, and under `reply_cold_start = "quote"` the text of
+    /// those that passed filtering, sent or not.
+    #[serde(default, skip_serializing_if = "RecentMessages::in_table")]
+    pub recent_messages: RecentMessages,
+    /// Content hashes of recently processed messages, so a message whose id
+    /// PotatoMesh reused is not mistaken for one already bridged.
+    #[serde(default, skip_serializing_if = "SeenContent::in_table")]
+    pub seen_content: SeenContent,
+    /// Last position announced per node (normalized hex id), so repeated
+    /// beacons from a stationary node are not re-posted.
+    #[serde(default)]
+    pub last_positions: HashMap<String, (f64, f64)>,
+    /// Last message posted to each room (by room id), for collapsing
+    /// repeats of it. In-memory only; a restart simply posts the next
+    /// message normally.
+    #[serde(skip)]
+    pub last_sent: HashMap<String, LastSent>,
+    /// SNR of the last bridged message per node (normalized hex id), for
+    /// `snr_trend`.
+    #[serde(default)]
+    pub last_snr: HashMap<String, f32>,
+    /// Puppets already registered and their display names, shared with the
+    /// Matrix client so a restart does not repeat those calls for every puppet.
+    #[serde(default)]
+    pub puppets: PuppetCache,
+    /// Transaction counter of the Matrix client, so ids keep increasing
+    /// across restarts even if the clock goes backwards.
+    #[serde(default)]
+    pub txn_counter: TxnCounter,
+    /// Avatar uploaded for each puppet (normalized hex id), so it is only
+    /// regenerated when the node's hardware model changes.
+    #[serde(default)]
+    pub avatars: HashMap<String, PuppetAvatar>,
+    /// Presence last reported per node (normalized hex id), for
+    /// `node_presence`.
+    #[serde(default)]
+    pub last_seen: HashMap<String, NodeSeen>,
+    /// Messages fetched during a maintenance window, forwarded in order once
+    /// it ends. Persisted so a restart inside the window loses nothing.
+    #[serde(default)]
+    pub held_messages: Vec<PotatoMessage>,
+    /// Room made by `auto_create_room`, used on later runs instead of the
+    /// configured room it replaced.
+    #[serde(default)]
+    pub created_room: Option<CreatedRoom>,
+    /// Messages parked while their node was in a `node_cooldown`, retried
+    /// once it ends.
+    #[serde(default)]
+    pub cooldown_messages: Vec<PotatoMessage>,
+    /// Messages that failed under `ordering = "relaxed"`, retried on later
+    /// polls after the checkpoint has moved past them.
+    #[serde(default)]
+    pub retry_messages: Vec<RetryMessage>,
+    /// Ids of messages `concurrency` sent after one that failed and stopped
+    /// the batch. The checkpoint stays before the failed one, so they are
+    /// fetched again and skipped rather than sent twice.
+    #[serde(default)]
+    pub delivered_ahead: BTreeSet<u64>,
+    /// Consecutive forward failures per node (normalized hex id), for
+    /// `node_cooldown`. In-memory only, like the poison-message tracking.
+    #[serde(skip)]
+    pub node_failures: HashMap<String, NodeFailures>,
+    /// Id of the message currently blocking the batch, and how many consecutive
+    /// polls it has failed to forward. In-memory only (never persisted — a
+    /// restart is itself a fresh attempt); used to skip a poison message after
+    /// [`MAX_FORWARD_ATTEMPTS`](crate::MAX_FORWARD_ATTEMPTS) instead of
+    /// retrying it forever.
+    #[serde(skip)]
+    pub failing_msg_id: Option<u64>,
+    #[serde(skip)]
+    pub failing_msg_attempts: u32,
+    /// End of the `startup_grace_secs` period, as a Unix timestamp.
+    /// In-memory only; every start gets its own grace period.
+    #[serde(skip)]
+    pub startup_grace_until: Option<u64>,
+    /// Counters served by the listener's `/metrics` endpoint.
+    #[serde(skip)]
+    pub metrics: Metrics,
+    /// Set by `dry_run_hold_checkpoint`: [`Self::save`] writes nothing, so
+    /// the next run starts from the checkpoint this one started from.
+    #[serde(skip)]
+    pub hold_checkpoint: bool,
+    /// The server takes `after_id`: fetch and de-duplicate by message id
+    /// alone, since `rx_time` order can disagree with id order.
+    #[serde(skip)]
+    pub id_cursor: bool,
+    /// States of the further `[[potatomesh]]` sources by label, as loaded;
+    /// each source takes its own with [`Self::take_source`].
+    #[serde(default, skip_serializing)]
+    pub sources: BTreeMap<String, BridgeState>,
+    /// Label of the further source this state belongs to; `None` for the
+    /// main source.
+    #[serde(skip)]
+    pub source: Option<String>,
+    /// The state file this state is saved to along with every other source's.
+    #[serde(skip)]
+    pub file: StateFile,
+}
+
+/// Read `last_message_id` as the per-channel map; `null` reads as empty.
+/// The single id of older state files is moved aside by
+/// [`JsonStateFile::load`] first.
+fn deserialize_channel_checkpoints<'de, D>(deserializer: D) -> Result<HashMap<u8, u64>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    struct CheckpointsVisitor;
+
+    impl<'de> serde::de::Visitor<'de> for CheckpointsVisitor {
+        type Value = HashMap<u8, u64>;
+
+        fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            f.write_str("a map of channel to message id")
+        }
+
+        fn visit_unit<E: serde::de::Error>(self) -> Result<Self::Value, E> {
+            Ok(HashMap::new())
+        }
+
+        fn visit_map<A: serde::de::MapAccess<'de>>(
+            self,
+            mut map: A,
+        ) -> Result<Self::Value, A::Error> {
+            let mut ids = HashMap::new();
+            while let Some((channel, id)) = map.next_entry()? {
+                ids.insert(channel, id);
+            }
+            Ok(ids)
+        }
+    }
+
+    deserializer.deserialize_any(CheckpointsVisitor)
+}
+
+/// Failure streak of one node and, once it starts one, the end of its
+/// cooldown.
+#[derive(Debug, Default, Clone)]
+pub struct NodeFailures {
+    pub count: u32,
+    pub cooling_until: Option<u64>,
+}
+
+/// A message left behind by `ordering = "relaxed"` and its failed tries.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct RetryMessage {
+    pub msg: PotatoMessage,
+    pub attempts: u32,
+}
+
+/// Identicon set as a puppet's avatar and the hardware model it was drawn for.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct PuppetAvatar {
+    pub mxc_uri: String,
+    #[serde(default)]
+    pub hw_model: Option<String>,
+}
+
+/// What `node_presence` last made of a node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct NodeSeen {
+    pub online: bool,
+    /// Unix timestamp of the last notice about the node.
+    #[serde(default)]
+    pub notified_at: Option<u64>,
+}
+
+/// Room created by the bridge in place of a missing configured room.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct CreatedRoom {
+    /// Configured `room_id` that did not exist.
+    pub replaces: String,
+    pub room_id: String,
+}
+
+/// Text and event of the most recent message the bridge posted to a room.
+#[derive(Debug, Clone)]
+pub struct LastSent {
+    pub text: String,
+    pub event_id: String,
+    /// Unix timestamp (seconds) of the send.
+    pub sent_at: u64,
+}
+
+/// Fields of a further source's state kept only in the main source's part
+/// of the state file, since every source shares them.
+const MAIN_STATE_FIELDS: [&str; 3] = ["puppets", "txn_counter", "created_room"];
+
+/// Where the bridge state is kept: every source's state, each saved on its
+/// own as it changes.
+pub trait StateStore: Send {
+    /// The main source's state, with the further sources' under `sources`
+    /// by label; `None` when nothing was saved yet.
+    fn load(&mut self) -> Result<Option<BridgeState>>;
+
+    /// Save `state` as its source's part of the store.
+    fn save(&mut self, state: &BridgeState) -> Result<()>;
+}
+
+/// The fields `state` saves as its source's part of a store.
+pub(crate) fn source_fields(
+    state: &BridgeState,
+) -> Result<serde_json::Map<String, serde_json::Value>> {
+    let serde_json::Value::Object(mut fields) = serde_json::to_value(state)? else {
+        anyhow::bail!("Bridge state must serialize to a JSON object");
+    };
+    if state.source.is_some() {
+        for field in MAIN_STATE_FIELDS {
+            fields.remove(field);
+        }
+    }
+    Ok(fields)
+}
+
+/// Open the store at `path`: a SQLite database when it ends in `.db`, a
+/// JSON file otherwise.
+fn open_store(path: &str) -> Result<Box<dyn StateStore>> {
+    if state_db::is_db_path(path) {
+        Ok(Box::new(state_db::SqliteBridgeState::open(path)?))
+    } else {
+        Ok(Box::new(JsonStateFile::new(path)))
+    }
+}
+
+/// A JSON state file: the main source's state, with each further source's
+/// under `sources` by label. Every save rewrites the whole file.
+struct JsonStateFile {
+    path: String,
+    main: serde_json::Map<String, serde_json::Value>,
+    sources: BTreeMap<String, serde_json::Value>,
+}
+
+impl JsonStateFile {
+    fn new(path: &str) -> Self {
+        Self {
+            path: path.to_string(),
+            main: serde_json::Map::new(),
+            sources: BTreeMap::new(),
+        }
+    }
+
+    fn record(&mut self, state: &BridgeState) -> Result<()> {
+        let fields = source_fields(state)?;
+        match &state.source {
+            None => self.main = fields,
+            Some(label) => {
+                self.sources.insert(label.clone(), fields.into());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl StateStore for JsonStateFile {
+    /// Missing and empty files read as nothing saved. The parts read are
+    /// kept, so a save before every source has taken its own keeps them.
+    fn load(&mut self) -> Result<Option<BridgeState>> {
+        if !Path::new(&self.path).exists() {
+            return Ok(None);
+        }
+        let data = fs::read_to_string(&self.path)?;
+        // Treat empty/whitespace-only files as a fresh state.
+        if data.trim().is_empty() {
+            return Ok(None);
+        }
+        let mut value: serde_json::Value = serde_json::from_str(&data)?;
+        // Older versions kept one id for all channels; it holds for every
+        // channel until that channel has a checkpoint of its own.
+        let legacy_id = value
+            .get_mut("last_message_id")
+            .filter(|id| id.is_u64())
+            .map(serde_json::Value::take)
+            .and_then(|id| id.as_u64());
+        let mut s: BridgeState = serde_json::from_value(value)?;
+        s.legacy_message_id = s.legacy_message_id.or(legacy_id);
+        if s.last_rx_time.is_none() {
+            s.last_rx_time = s.last_checked_at;
+        }
+        s.last_checked_at = None;
+        for (label, parked) in &mut s.sources {
+            parked.source = Some(label.clone());
+            self.record(parked)?;
+        }
+        self.record(&s)?;
+        Ok(Some(s))
+    }
+
+    fn save(&mut self, state: &BridgeState) -> Result<()> {
+        self.record(state)?;
+        let mut fields = self.main.clone();
+        if !self.sources.is_empty() {
+            fields.insert("sources".to_string(), serde_json::to_value(&self.sources)?);
+        }
+        fs::write(&self.path, serde_json::to_string_pretty(&fields)?)?;
+        Ok(())
+    }
+}
+
+/// The store every source saves to. Clones share it, so each source's
+/// saves go to the same store, one at a time.
+#[derive(Clone, Default)]
+pub struct StateFile(Arc<std::sync::Mutex<Option<OpenStore>>>);
+
+impl std::fmt::Debug for StateFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("StateFile").finish_non_exhaustive()
+    }
+}
+
+struct OpenStore {
+    path: String,
+    store: Box<dyn StateStore>,
+}
+
+impl StateFile {
+    fn new(path: &str, store: Box<dyn StateStore>) -> Self {
+        Self(Arc::new(std::sync::Mutex::new(Some(OpenStore {
+            path: path.to_string(),
+            store,
+        }))))
+    }
+
+    /// Save `state` to the store at `path`, opening it first if this file
+    /// has none open there. Saves happen under the lock, so an older
+    /// snapshot never overwrites a newer one.
+    fn save(&self, path: &str, state: &BridgeState) -> Result<()> {
+        let mut open = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        if open.as_ref().is_none_or(|open| open.path != path) {
+            *open = Some(OpenStore {
+                path: path.to_string(),
+                store: open_store(path)?,
+            });
+        }
+        match open.as_mut() {
+            Some(open) => open.store.save(state),
+            None => unreachable!("store opened above"),
+        }
+    }
+}
+
+impl BridgeState {
+    /// Event id of the last message posted to `room_id` when `text` repeats
+    /// it within `window_secs`.
+    pub fn duplicate_of(
+        &self,
+        room_id: &str,
+        text: &str,
+        window_secs: u64,
+        now: u64,
+    ) -> Option<&str> {
+        self.last_sent
+            .get(room_id)
+            .filter(|last| last.text == text && now.saturating_sub(last.sent_at) <= window_secs)
+            .map(|last| last.event_id.as_str())
+    }
+
+    /// Trend of `snr` against the node's previously bridged SNR; `None` for
+    /// a node's first message or when either reading is missing.
+    pub fn snr_trend(&self, node_id: &str, snr: Option<f32>) -> Option<&'static str> {
+        let previous = self
+            .last_snr
+            .get(&potatomesh::normalize_node_hex(node_id))?;
+        Some(snr_trend_arrow(*previous, snr?))
+    }
+
+    pub fn record_snr(&mut self, node_id: &str, snr: Option<f32>) {
+        if let Some(snr) = snr {
+            self.last_snr
+                .insert(potatomesh::normalize_node_hex(node_id), snr);
+        }
+    }
+
+    /// Load the state from `path`: a SQLite database when it ends in `.db`,
+    /// JSON otherwise.
+    pub fn load(path: &str) -> Result<Self> {
+        let mut store = open_store(path)?;
+        let mut state = match store.load()? {
+            Some(state) => state,
+            None if state_db::is_db_path(path) => Self::import_json_state(path, &mut *store)?,
+            None => Self::default(),
+        };
+        if state_db::is_db_path(path) {
+            state.set_in_table();
+        }
+        for (label, parked) in &mut state.sources {
+            parked.source = Some(label.clone());
+        }
+        state.file = StateFile::new(path, store);
+        state.start_content_record();
+        Ok(state)
+    }
+
+    /// Leave the recent messages and content hashes, which a SQLite store
+    /// keeps in tables of their own, out of the serialized fields.
+    fn set_in_table(&mut self) {
+        self.recent_messages.set_in_table(true);
+        self.seen_content.set_in_table(true);
+        for parked in self.sources.values_mut() {
+            parked.set_in_table();
+        }
+    }
+
+    /// Have a content record saved empty, e.g. by a version that kept
+    /// none, start after the messages already processed: they were never
+    /// recorded, so their content must not count as new. They were
+    /// received up to the `since` checkpoint, or, without one, before now.
+    fn start_content_record(&mut self) {
+        if self.has_message_checkpoint() || self.last_rx_time.is_some() {
+            let processed_until = self.last_rx_time.unwrap_or_else(potatomesh::now_secs);
+            self.seen_content.start_after(processed_until);
+        }
+        for parked in self.sources.values_mut() {
+            parked.start_content_record();
+        }
+    }
+
+    /// Seed the new state database `store` at `db_path` from the JSON state
+    /// file next to it (same name, `.json` extension), so switching backends
+    /// keeps the checkpoint. The JSON file is left in place.
+    fn import_json_state(db_path: &str, store: &mut dyn StateStore) -> Result<Self> {
+        let json_path = Path::new(db_path).with_extension("json");
+        let Some(json_path) = json_path.to_str() else {
+            return Ok(Self::default());
+        };
+        let Some(state) = JsonStateFile::new(json_path).load()? else {
+            return Ok(Self::default());
+        };
+        store.save(&state)?;
+        for parked in state.sources.values() {
+            store.save(parked)?;
+        }
+        info!(
+            "Imported state from {} into {}; the JSON file is no longer used",
+            json_path, db_path
+        );
+        Ok(state)
+    }
+
+    /// Like [`Self::load`], but when `recover` is set an unparseable file is
+    /// renamed to `<path>.corrupt.<unix ts>` and a fresh state is returned,
+    /// so the bridge restarts from recent messages instead of not at all.
+    pub fn load_or_recover(path: &str, recover: bool) -> Result<Self> {
+        let state = match Self::load(path) {
+            Err(e) if recover && (e.is::<serde_json::Error>() || state_db::is_corrupt(&e)) => {
+                let backup = format!("{}.corrupt.{}", path, potatomesh::now_secs());
+                fs::rename(path, &backup)?;
+                error!(
+                    "State file {} is corrupt ({}); moved it to {} and starting from a fresh state",
+                    path, e, backup
+                );
+                let mut state = Self::default();
+                if state_db::is_db_path(path) {
+                    state.set_in_table();
+                }
+                state
+            }
+            result => result?,
+        };
+        Ok(state)
+    }
+
+    /// The state of the further source `label`, fresh when the file has
+    /// none, saved to the same file and sharing the puppets, transaction
+    /// counter, metrics and run settings of this, the main source's state.
+    pub fn take_source(&mut self, label: &str) -> Self {
+        let mut state = self.sources.remove(label).unwrap_or_default();
+        state.source = Some(label.to_string());
+        state.file = self.file.clone();
+        state.puppets = self.puppets.clone();
+        state.txn_counter = self.txn_counter.clone();
+        state.metrics = self.metrics.clone();
+        state.hold_checkpoint = self.hold_checkpoint;
+        if self.recent_messages.in_table() {
+            state.set_in_table();
+        }
+        state
+    }
+
+    pub fn save(&self, path: &str) -> Result<()> {
+        if self.hold_checkpoint {
+            debug!("Dry run: not saving state to {}", path);
+            return Ok(());
+        }
+        self.file.save(path, self)
+    }
+
+    /// Whether `msg` is past the checkpoint. With a `since` checkpoint that
+    /// is its receive time: ids are not in receive order (Meshtastic's are
+    /// random), so only messages at the checkpoint's timestamp are told
+    /// apart by id. Otherwise it is its channel's id checkpoint, checked on
+    /// its own so a channel running ahead never hides another's messages.
+    pub fn should_forward(&self, msg: &PotatoMessage) -> bool {
+        // An id that looks processed may have been reused by PotatoMesh for
+        // a different message, so unseen content still goes through.
+        match self.last_rx_time {
+            Some(last_ts) if !self.id_cursor => match msg.rx_time.cmp(&last_ts) {
+                std::cmp::Ordering::Greater => true,
+                std::cmp::Ordering::Less => false,
+                std::cmp::Ordering::Equal => {
+                    !self.last_rx_time_ids.contains(&msg.id) || self.seen_content.is_new(msg)
+                }
+            },
+            _ => match self.last_message_id(msg.channel) {
+                None => true,
+                Some(last_id) => msg.id > last_id || self.seen_content.is_new(msg),
+            },
+        }
+    }
+
+    /// Whether `now` falls in the startup grace period.
+    pub fn in_startup_grace(&self, now: u64) -> bool {
+        self.startup_grace_until.is_some_and(|until| now < until)
+    }
+
+    /// Whether `node_id` is in a `node_cooldown` at `now`. An expired
+    /// cooldown is cleared, starting a fresh failure streak.
+    pub fn node_cooling_down(&mut self, node_id: &str, now: u64) -> bool {
+        let hex = potatomesh::normalize_node_hex(node_id);
+        match self.node_failures.get(&hex).and_then(|f| f.cooling_until) {
+            Some(until) if now < until => true,
+            Some(_) => {
+                info!("Cooldown of node {} is over", node_id);
+                self.node_failures.remove(&hex);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Count a failed forward from `node_id`. Returns `true` when this
+    /// failure starts a cooldown.
+    pub fn record_node_failure(
+        &mut self,
+        node_id: &str,
+        cooldown: &NodeCooldown,
+        now: u64,
+    ) -> bool {
+        let failures = self
+            .node_failures
+            .entry(potatomesh::normalize_node_hex(node_id))
+            .or_default();
+        failures.count += 1;
+        if failures.count < cooldown.failures {
+            return false;
+        }
+        failures.cooling_until = Some(now.saturating_add(cooldown.secs));
+        true
+    }
+
+    /// Highest message id processed on `channel`.
+    pub fn last_message_id(&self, channel: u8) -> Option<u64> {
+        self.last_message_id
+            .get(&channel)
+            .copied()
+            .or(self.legacy_message_id)
+    }
+
+    /// Whether any message has been processed.
+    pub fn has_message_checkpoint(&self) -> bool {
+        !self.last_message_id.is_empty() || self.legacy_message_id.is_some()
+    }
+
+    /// Id checkpoint of the channel furthest behind.
+    pub fn lowest_message_id(&self) -> Option<u64> {
+        self.last_message_id
+            .values()
+            .copied()
+            .chain(self.legacy_message_id)
+            .min()
+    }
+
+    /// Report each channel's checkpoint to `metrics`.
+    pub fn publish_last_message_id(&self) {
+        for (&channel, &id) in &self.last_message_id {
+            self.metrics
+                .set_last_message_id(self.source.as_deref(), channel, id);
+        }
+    }
+
+    pub fn update_with(&mut self, msg: &PotatoMessage) {
+        let start = self.legacy_message_id.unwrap_or(msg.id);
+        let last_id = self.last_message_id.entry(msg.channel).or_insert(start);
+        *last_id = (*last_id).max(msg.id);
+        self.publish_last_message_id();
+        self.seen_content.record(msg);
+        if self.last_rx_time.is_none() || Some(msg.rx_time) > self.last_rx_time {
+            self.last_rx_time = Some(msg.rx_time);
+            self.last_rx_time_ids = vec![msg.id];
+        } else if Some(msg.rx_time) == self.last_rx_time && !self.last_rx_time_ids.contains(&msg.id)
+        {
+            self.last_rx_time_ids.push(msg.id);
+        }
+    }
+
+    /// Move every channel checkpoint behind `id` up to it, once all
+    /// messages up to `id` are accounted for, so a quiet channel does not
+    /// hold the `after_id` cursor back.
+    pub fn catch_up_channels(&mut self, id: u64) {
+        for last_id in self.last_message_id.values_mut() {
+            *last_id = (*last_id).max(id);
+        }
+        if let Some(legacy_id) = &mut self.legacy_message_id {
+            *legacy_id = (*legacy_id).max(id);
+        }
+        self.publish_last_message_id();
+    }
+}
+
+/// Build the next `/api/messages` query from the checkpoint: an `after_id`
+/// cursor when the server supports one, else `since` in the unit the API
+/// expects. The cursor starts after the channel furthest behind, so none
+/// of its messages are skipped; the others filter out what they have.
+pub fn build_fetch_params(
+    state: &BridgeState,
+    since_unit: SinceUnit,
+    time_source: CheckpointTimeSource,
+) -> FetchParams {
+    let checkpoint = match time_source {
+        CheckpointTimeSource::MaxRxTime => state.last_rx_time,
+        CheckpointTimeSource::Local => state.last_polled_at.or(state.last_rx_time),
+    };
+    if !state.has_message_checkpoint() {
+        FetchParams::default()
+    } else if state.id_cursor {
+        FetchParams {
+            after_id: state.lowest_message_id(),
+            ..Default::default()
+        }
+    } else if let Some(ts) = checkpoint {
+        FetchParams {
+            since: Some(since_unit.scale_secs(ts)),
+            ..Default::default()
+        }
+    } else {
+        FetchParams {
+            limit: Some(10),
+            ..Default::default()
+        }
+    }
+}
+
+/// Persist the bridge state and log any write errors.
+pub fn persist_state(state: &BridgeState, state_path: &str) {
+    if let Err(e) = state.save(state_path) {
+        error!("Error saving state: {:?}", e);
+    }
+}
+
+/// Emit a debug log of the checkpoint a state update left. The queues and
+/// caches are left out; logged after every message, they would flood the log.
+pub fn log_state_update(state: &BridgeState) {
+    debug!(
+        source = state.source.as_deref().unwrap_or_default(),
+        "Updated state: last_message_id={:?} last_rx_time={:?} last_polled_at={:?}",
+        state.last_message_id,
+        state.last_rx_time,
+        state.last_polled_at
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_msg(id: u64) -> PotatoMessage {
+        PotatoMessage {
+            id,
+            rx_time: 0,
+            rx_iso: "2025-11-27T00:00:00Z".to_string(),
+            from_id: Some("!abcd1234".to_string()),
+            to_id: Some("^all".to_string()),
+            channel: 1,
+            portnum: Some("TEXT_MESSAGE_APP".to_string()),
+            text: "Ping".to_string(),
+            rssi: Some(-100),
+            hop_limit: Some(1),
+            hops: None,
+            lora_freq: 868,
+            modem_preset: "MediumFast".to_string(),
+            channel_name: "TEST".to_string(),
+            snr: Some(0.0),
+            reply_id: None,
+            node_id: "!abcd1234".to_string(),
+            protocol: Some("meshtastic".to_string()),
+            merged_ids: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn bridge_state_initially_forwards_all() {
+        let state = BridgeState::default();
+        let msg = sample_msg(42);
+
+        assert!(state.should_forward(&msg));
+    }
+
+    #[test]
+    fn bridge_state_tracks_latest_rx_time_and_skips_older() {
+        let mut state = BridgeState::default();
+        let m1 = sample_msg(10);
+        let m2 = sample_msg(20);
+        let m3 = sample_msg(15);
+        let m1 = PotatoMessage { rx_time: 10, ..m1 };
+        let m2 = PotatoMessage { rx_time: 20, ..m2 };
+        let m3 = PotatoMessage { rx_time: 15, ..m3 };
+
+        // First message, should forward
+        assert!(state.should_forward(&m1));
+        state.update_with(&m1);
+        assert_eq!(state.last_message_id(1), Some(10));
+        assert_eq!(state.last_rx_time, Some(10));
+
+        // Second message, higher id, should forward
+        assert!(state.should_forward(&m2));
+        state.update_with(&m2);
+        assert_eq!(state.last_message_id(1), Some(20));
+        assert_eq!(state.last_rx_time, Some(20));
+
+        // Third message, lower than last, should NOT forward
+        assert!(!state.should_forward(&m3));
+        // state remains unchanged
+        assert_eq!(state.last_message_id(1), Some(20));
+        assert_eq!(state.last_rx_time, Some(20));
+    }
+
+    #[test]
+    fn bridge_state_uses_legacy_id_filter_when_rx_time_missing() {
+        let state = BridgeState {
+            last_message_id: HashMap::from([(1, 10)]),
+            last_rx_time: None,
+            last_rx_time_ids: vec![],
+            last_checked_at: None,
+            ..Default::default()
+        };
+        let older = sample_msg(9);
+        let newer = sample_msg(11);
+
+        assert!(!state.should_forward(&older));
+        assert!(state.should_forward(&newer));
+    }
+
+    #[test]
+    fn bridge_state_with_id_cursor_ignores_rx_time_order() {
+        let mut state = BridgeState {
+            id_cursor: true,
+            ..Default::default()
+        };
+        let first = PotatoMessage {
+            rx_time: 200,
+            ..sample_msg(10)
+        };
+        // Arrived with an earlier rx_time but a later id.
+        let late = PotatoMessage {
+            rx_time: 100,
+            ..sample_msg(11)
+        };
+
+        state.update_with(&first);
+        assert!(state.should_forward(&late));
+        state.update_with(&late);
+        assert!(!state.should_forward(&late));
+        assert!(!state.should_forward(&first));
+    }
+
+    #[test]
+    fn bridge_state_checkpoints_each_channel_separately() {
+        let mut state = BridgeState {
+            last_message_id: HashMap::from([(0, 50)]),
+            ..Default::default()
+        };
+        let on_channel = |id, channel| PotatoMessage {
+            channel,
+            ..sample_msg(id)
+        };
+
+        // A lower id on another channel is not behind that channel's checkpoint.
+        assert!(state.should_forward(&on_channel(20, 2)));
+        assert!(!state.should_forward(&on_channel(40, 0)));
+
+        state.update_with(&on_channel(20, 2));
+        state.update_with(&on_channel(40, 0));
+        assert_eq!(state.last_message_id(0), Some(50));
+        assert_eq!(state.last_message_id(2), Some(20));
+    }
+
+    #[test]
+    fn bridge_state_with_rx_time_ignores_id_order() {
+        let mut state = BridgeState::default();
+        // Meshtastic packet ids are random, so a later message can carry a
+        // far lower id.
+        let first = PotatoMessage {
+            rx_time: 100,
+            ..sample_msg(2_947_676_906)
+        };
+        let next = PotatoMessage {
+            rx_time: 101,
+            ..sample_msg(17)
+        };
+
+        state.update_with(&first);
+        assert!(state.should_forward(&next));
+        state.update_with(&next);
+        assert!(!state.should_forward(&next));
+        assert!(!state.should_forward(&first));
+    }
+
+    #[test]
+    fn bridge_state_dedupes_same_timestamp() {
+        let mut state = BridgeState::default();
+        let m1 = PotatoMessage {
+            rx_time: 100,
+            ..sample_msg(10)
+        };
+        let m2 = PotatoMessage {
+            rx_time: 100,
+            text: "Pong".to_string(),
+            ..sample_msg(9)
+        };
+        let dup = PotatoMessage {
+            rx_time: 100,
+            ..sample_msg(10)
+        };
+
+        assert!(state.should_forward(&m1));
+        state.update_with(&m1);
+        assert!(state.should_forward(&m2));
+        state.update_with(&m2);
+        assert!(!state.should_forward(&dup));
+        assert_eq!(state.last_rx_time, Some(100));
+        assert_eq!(state.last_rx_time_ids, vec![10, 9]);
+    }
+
+    #[test]
+    fn bridge_state_forwards_reused_id_with_new_content() {
+        let mut state = BridgeState::default();
+        let first = PotatoMessage {
+            rx_time: 100,
+            ..sample_msg(10)
+        };
+        state.update_with(&first);
+
+        // Same id and timestamp, but PotatoMesh reused the id for new text.
+        let reused = PotatoMessage {
+            text: "Something else".to_string(),
+            ..first.clone()
+        };
+        assert!(!state.should_forward(&first));
+        assert!(state.should_forward(&reused));
+
+        state.update_with(&reused);
+        assert!(!state.should_forward(&reused));
+    }
+
+    #[test]
+    fn bridge_state_forwards_reused_id_with_new_content_in_legacy_mode() {
+        let mut state = BridgeState::default();
+        state.update_with(&sample_msg(10));
+        state.last_rx_time = None;
+
+        let reused = PotatoMessage {
+            text: "Something else".to_string(),
+            ..sample_msg(9)
+        };
+        assert!(!state.should_forward(&sample_msg(9)));
+        assert!(state.should_forward(&reused));
+    }
+
+    #[test]
+    fn bridge_state_from_before_content_hashes_does_not_replay_on_refetch() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let file_path = tmp_dir.path().join("state.json");
+        let path_str = file_path.to_str().unwrap();
+        fs::write(
+            path_str,
+            r#"{"last_message_id":{"1":42},"last_rx_time":100,"last_rx_time_ids":[42]}"#,
+        )
+        .unwrap();
+        let mut state = BridgeState::load(path_str).unwrap();
+
+        // The first message after the upgrade starts the content record...
+        let next = PotatoMessage {
+            rx_time: 101,
+            ..sample_msg(43)
+        };
+        assert!(state.should_forward(&next));
+        state.update_with(&next);
+
+        // ...which says nothing about the messages bridged before it.
+        for id in 30..=42 {
+            let refetched = PotatoMessage {
+                rx_time: 90,
+                text: format!("Message {id}"),
+                ..sample_msg(id)
+            };
+            assert!(!state.should_forward(&refetched));
+        }
+        let reused = PotatoMessage {
+            text: "Something else".to_string(),
+            ..next
+        };
+        assert!(state.should_forward(&reused));
+    }
+
+    #[test]
+    fn bridge_state_forwards_reused_low_ids_after_restart_and_evictions() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let file_path = tmp_dir.path().join("state.json");
+        let path_str = file_path.to_str().unwrap();
+        let mut state = BridgeState {
+            id_cursor: true,
+            ..Default::default()
+        };
+        state.seen_content.set_capacity(2);
+        for (id, rx_time) in [(1000, 100), (1001, 101), (1002, 102)] {
+            state.update_with(&PotatoMessage {
+                rx_time,
+                text: format!("Message {id}"),
+                ..sample_msg(id)
+            });
+        }
+        state.save(path_str).unwrap();
+
+        let mut state = BridgeState {
+            id_cursor: true,
+            ..BridgeState::load(path_str).unwrap()
+        };
+        state.seen_content.set_capacity(2);
+        // PotatoMesh restarted its ids.
+        let reused = PotatoMessage {
+            rx_time: 200,
+            text: "Fresh".to_string(),
+            ..sample_msg(1)
+        };
+        assert!(state.should_forward(&reused));
+        state.update_with(&reused);
+        assert!(!state.should_forward(&reused));
+
+        // The evicted message, fetched again, is still judged by its id.
+        let evicted = PotatoMessage {
+            rx_time: 100,
+            text: "Message 1000".to_string(),
+            ..sample_msg(1000)
+        };
+        assert!(!state.should_forward(&evicted));
+    }
+
+    #[test]
+    fn bridge_state_persists_seen_content() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("state.json");
+        let path = path.to_str().unwrap();
+
+        let mut state = BridgeState::default();
+        state.update_with(&PotatoMessage {
+            rx_time: 100,
+            ..sample_msg(10)
+        });
+        state.save(path).unwrap();
+
+        let loaded = BridgeState::load(path).unwrap();
+        let reused = PotatoMessage {
+            rx_time: 100,
+            ..sample_msg(10)
+        };
+        assert!(!loaded.should_forward(&reused));
+    }
+
+    #[test]
+    fn bridge_state_load_save_roundtrip() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let file_path = tmp_dir.path().join("state.json");
+        let path_str = file_path.to_str().unwrap();
+
+        let state = BridgeState {
+            last_message_id: HashMap::from([(1, 12345)]),
+            last_rx_time: Some(99),
+            last_rx_time_ids: vec![123],
+            last_checked_at: Some(77),
+            ..Default::default()
+        };
+        state.save(path_str).unwrap();
+
+        let loaded_state = BridgeState::load(path_str).unwrap();
+        assert_eq!(loaded_state.last_message_id(1), Some(12345));
+        assert_eq!(loaded_state.last_rx_time, Some(99));
+        assert_eq!(loaded_state.last_rx_time_ids, vec![123]);
+        assert_eq!(loaded_state.last_checked_at, None);
+    }
+
+    #[test]
+    fn bridge_state_load_nonexistent() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let file_path = tmp_dir.path().join("nonexistent.json");
+        let path_str = file_path.to_str().unwrap();
+
+        let state = BridgeState::load(path_str).unwrap();
+        assert_eq!(state.last_message_id(1), None);
+        assert_eq!(state.last_rx_time, None);
+        assert!(state.last_rx_time_ids.is_empty());
+    }
+
+    #[test]
+    fn bridge_state_load_empty_file() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let file_path = tmp_dir.path().join("empty.json");
+        let path_str = file_path.to_str().unwrap();
+
+        fs::write(path_str, "").unwrap();
+
+        let state = BridgeState::load(path_str).unwrap();
+        assert_eq!(state.last_message_id(1), None);
+        assert_eq!(state.last_rx_time, None);
+        assert!(state.last_rx_time_ids.is_empty());
+        assert_eq!(state.last_checked_at, None);
+    }
+
+    #[test]
+    fn bridge_state_recovers_from_corrupt_file() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let file_path = tmp_dir.path().join("state.json");
+        let path_str = file_path.to_str().unwrap();
+        fs::write(path_str, r#"{"last_message_id": 4"#).unwrap();
+
+        let state = BridgeState::load_or_recover(path_str, true).unwrap();
+
+        assert_eq!(state.last_message_id(1), None);
+        assert!(!file_path.exists());
+        let backups: Vec<_> = fs::read_dir(tmp_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        assert_eq!(backups.len(), 1);
+        assert!(backups[0].starts_with("state.json.corrupt."));
+        assert_eq!(
+            fs::read_to_string(tmp_dir.path().join(&backups[0])).unwrap(),
+            r#"{"last_message_id": 4"#
+        );
+    }
+
+    #[test]
+    fn bridge_state_corrupt_file_is_fatal_without_recovery() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let file_path = tmp_dir.path().join("state.json");
+        let path_str = file_path.to_str().unwrap();
+        fs::write(path_str, "not json").unwrap();
+
+        assert!(BridgeState::load_or_recover(path_str, false).is_err());
+        assert!(file_path.exists());
+    }
+
+    #[test]
+    fn bridge_state_round_trips_through_database() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let file_path = tmp_dir.path().join("state.db");
+        let path_str = file_path.to_str().unwrap();
+        let mut state = BridgeState {
+            last_message_id: HashMap::from([(1, 12345)]),
+            last_rx_time: Some(99),
+            last_rx_time_ids: vec![123],
+            ..Default::default()
+        };
+        state.recent_messages.record(RecentMessage {
+            id: 12345,
+            node_id: "!abcd1234".to_string(),
+            event_id: Some("$sent".to_string()),
+            ..Default::default()
+        });
+
+        state.save(path_str).unwrap();
+        state.update_with(&sample_msg(12346));
+        state.save(path_str).unwrap();
+
+        let loaded = BridgeState::load(path_str).unwrap();
+        assert_eq!(loaded.last_message_id(1), Some(12346));
+        assert_eq!(loaded.last_rx_time, state.last_rx_time);
+        assert_eq!(loaded.last_rx_time_ids, state.last_rx_time_ids);
+        assert_eq!(
+            loaded
+                .recent_messages
+                .get(12345)
+                .unwrap()
+                .event_id
+                .as_deref(),
+            Some("$sent")
+        );
+    }
+
+    #[test]
+    fn bridge_state_keeps_each_source_under_its_label() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        for file in ["state.json", "state.db"] {
+            let file_path = tmp_dir.path().join(file);
+            let path_str = file_path.to_str().unwrap();
+            let mut state = BridgeState::load_or_recover(path_str, false).unwrap();
+            let mut south = state.take_source("south");
+
+            south.update_with(&sample_msg(50));
+            south.save(path_str).unwrap();
+            state.update_with(&sample_msg(30));
+            state.save(path_str).unwrap();
+
+            let mut loaded = BridgeState::load_or_recover(path_str, false).unwrap();
+            assert_eq!(loaded.last_message_id(1), Some(30));
+            let south = loaded.take_source("south");
+            assert_eq!(south.last_message_id(1), Some(50));
+            assert_eq!(south.source.as_deref(), Some("south"));
+        }
+
+        // Bridge-wide fields are only kept in the main source's part.
+        let saved = fs::read_to_string(tmp_dir.path().join("state.json")).unwrap();
+        let saved: serde_json::Value = serde_json::from_str(&saved).unwrap();
+        assert!(saved.get("puppets").is_some());
+        assert!(saved["sources"]["south"].get("puppets").is_none());
+    }
+
+    #[test]
+    fn bridge_state_keeps_sources_it_has_not_handed_out() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let file_path = tmp_dir.path().join("state.json");
+        let path_str = file_path.to_str().unwrap();
+        let mut state = BridgeState::load_or_recover(path_str, false).unwrap();
+        let mut south = state.take_source("south");
+        south.update_with(&sample_msg(50));
+        south.save(path_str).unwrap();
+
+        // A run without the south source still saves its state.
+        let mut state = BridgeState::load_or_recover(path_str, false).unwrap();
+        state.update_with(&sample_msg(30));
+        state.save(path_str).unwrap();
+
+        let mut loaded = BridgeState::load_or_recover(path_str, false).unwrap();
+        assert_eq!(loaded.take_source("south").last_message_id(1), Some(50));
+    }
+
+    #[test]
+    fn bridge_state_database_imports_json_state_on_first_run() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let json_path = tmp_dir.path().join("bridge_state.json");
+        fs::write(
+            &json_path,
+            r#"{"last_message_id":42,"last_checked_at":1710000000}"#,
+        )
+        .unwrap();
+        let db_path = tmp_dir.path().join("bridge_state.db");
+        let db_str = db_path.to_str().unwrap();
+
+        let state = BridgeState::load(db_str).unwrap();
+        assert_eq!(state.last_message_id(0), Some(42));
+        assert_eq!(state.last_rx_time, Some(1_710_000_000));
+
+        // Later runs read the database, not the JSON file.
+        fs::write(&json_path, r#"{"last_message_id":1}"#).unwrap();
+        let state = BridgeState::load(db_str).unwrap();
+        assert_eq!(state.last_message_id(0), Some(42));
+    }
+
+    #[test]
+    fn bridge_state_recovers_from_corrupt_database() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let file_path = tmp_dir.path().join("state.db");
+        let path_str = file_path.to_str().unwrap();
+        fs::write(path_str, "this is not a database, just some text").unwrap();
+
+        let state = BridgeState::load_or_recover(path_str, true).unwrap();
+
+        assert_eq!(state.last_message_id(1), None);
+        assert!(!file_path.exists());
+    }
+
+    #[test]
+    fn bridge_state_migrates_legacy_checkpoint() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let file_path = tmp_dir.path().join("legacy_state.json");
+        let path_str = file_path.to_str().unwrap();
+
+        fs::write(
+            path_str,
+            r#"{"last_message_id":42,"last_checked_at":1710000000}"#,
+        )
+        .unwrap();
+
+        let state = BridgeState::load(path_str).unwrap();
+        assert_eq!(state.last_message_id(0), Some(42));
+        assert_eq!(state.last_message_id(1), Some(42));
+        assert_eq!(state.last_rx_time, Some(1_710_000_000));
+        assert!(state.last_rx_time_ids.is_empty());
+    }
+
+    #[test]
+    fn bridge_state_legacy_checkpoint_holds_for_every_channel() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let file_path = tmp_dir.path().join("legacy_state.json");
+        let path_str = file_path.to_str().unwrap();
+        fs::write(path_str, r#"{"last_message_id":42}"#).unwrap();
+        let mut state = BridgeState {
+            id_cursor: true,
+            ..BridgeState::load(path_str).unwrap()
+        };
+        let on_channel = |id, channel| PotatoMessage {
+            channel,
+            ..sample_msg(id)
+        };
+
+        assert!(!state.should_forward(&on_channel(42, 2)));
+        state.update_with(&on_channel(43, 2));
+        assert_eq!(state.last_message_id(2), Some(43));
+        assert!(!state.should_forward(&on_channel(42, 3)));
+
+        // Saved back, the old id stays apart from the channels' own.
+        state.save(path_str).unwrap();
+        let loaded = BridgeState::load(path_str).unwrap();
+        assert_eq!(loaded.last_message_id(2), Some(43));
+        assert_eq!(loaded.last_message_id(3), Some(42));
+        let loaded = BridgeState {
+            id_cursor: true,
+            ..loaded
+        };
+        let params = build_fetch_params(&loaded, SinceUnit::Secs, CheckpointTimeSource::MaxRxTime);
+        assert_eq!(params.after_id, Some(42));
+    }
+
+    #[test]
+    fn bridge_state_reads_null_checkpoint_as_empty() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let file_path = tmp_dir.path().join("state.json");
+        let path_str = file_path.to_str().unwrap();
+        fs::write(path_str, r#"{"last_message_id":null}"#).unwrap();
+
+        let state = BridgeState::load(path_str).unwrap();
+
+        assert!(state.last_message_id.is_empty());
+    }
+
+    #[test]
+    fn fetch_params_respects_missing_last_message_id() {
+        let state = BridgeState {
+            last_message_id: HashMap::new(),
+            last_rx_time: Some(123),
+            last_rx_time_ids: vec![],
+            last_checked_at: None,
+            ..Default::default()
+        };
+
+        let params = build_fetch_params(&state, SinceUnit::Secs, CheckpointTimeSource::MaxRxTime);
+        assert_eq!(params.limit, None);
+        assert_eq!(params.since, None);
+    }
+
+    #[test]
+    fn fetch_params_uses_since_when_safe() {
+        let state = BridgeState {
+            last_message_id: HashMap::from([(1, 1)]),
+            last_rx_time: Some(123),
+            last_rx_time_ids: vec![],
+            last_checked_at: None,
+            ..Default::default()
+        };
+
+        let params = build_fetch_params(&state, SinceUnit::Secs, CheckpointTimeSource::MaxRxTime);
+        assert_eq!(params.limit, None);
+        assert_eq!(params.since, Some(123));
+    }
+
+    #[test]
+    fn fetch_params_scales_since_to_millis() {
+        let state = BridgeState {
+            last_message_id: HashMap::from([(1, 1)]),
+            last_rx_time: Some(123),
+            ..Default::default()
+        };
+
+        let params = build_fetch_params(&state, SinceUnit::Millis, CheckpointTimeSource::MaxRxTime);
+        assert_eq!(params.limit, None);
+        assert_eq!(params.since, Some(123_000));
+    }
+
+    #[test]
+    fn fetch_params_prefers_id_cursor_when_supported() {
+        let state = BridgeState {
+            last_message_id: HashMap::from([(0, 7), (1, 12)]),
+            last_rx_time: Some(123),
+            id_cursor: true,
+            ..Default::default()
+        };
+
+        let params = build_fetch_params(&state, SinceUnit::Secs, CheckpointTimeSource::MaxRxTime);
+        assert_eq!(params.after_id, Some(7));
+        assert_eq!(params.since, None);
+        assert_eq!(params.limit, None);
+    }
+
+    #[test]
+    fn fetch_params_defaults_to_small_window() {
+        let state = BridgeState {
+            last_message_id: HashMap::from([(1, 1)]),
+            last_rx_time: None,
+            last_rx_time_ids: vec![],
+            last_checked_at: None,
+            ..Default::default()
+        };
+
+        let params = build_fetch_params(&state, SinceUnit::Secs, CheckpointTimeSource::MaxRxTime);
+        assert_eq!(params.limit, Some(10));
+        assert_eq!(params.since, None);
+    }
+
+    #[test]
+    fn log_state_update_logs_the_checkpoint() {
+        let state = BridgeState::default();
+        log_state_update(&state);
+    }
+
+    #[test]
+    fn persist_state_writes_file() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let file_path = tmp_dir.path().join("state.json");
+        let path_str = file_path.to_str().unwrap();
+
+        let state = BridgeState {
+            last_message_id: HashMap::from([(1, 42)]),
+            last_rx_time: Some(123),
+            last_rx_time_ids: vec![42],
+            last_checked_at: None,
+            ..Default::default()
+        };
+
+        persist_state(&state, path_str);
+
+        let loaded = BridgeState::load(path_str).unwrap();
+        assert_eq!(loaded.last_message_id(1), Some(42));
+    }
+
+    #[test]
+    fn persist_state_logs_on_error() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let dir_path = tmp_dir.path().to_str().unwrap();
+        let state = BridgeState::default();
+
+        // Writing to a directory path should trigger the error branch.
+        persist_state(&state, dir_path);
+    }
+
+    #[test]
+    fn snr_trend_needs_previous_and_current_readings() {
+        let mut state = BridgeState::default();
+        // First message from the node: nothing to compare against.
+        assert_eq!(state.snr_trend("!abcd1234", Some(3.0)), None);
+
+        state.record_snr("!ABCD1234", Some(3.0));
+        state.record_snr("!abcd1234", None);
+        assert_eq!(state.snr_trend("!abcd1234", Some(6.0)), Some("↑"));
+        assert_eq!(state.snr_trend("!abcd1234", Some(-1.0)), Some("↓"));
+        assert_eq!(state.snr_trend("!abcd1234", None), None);
+    }
+}
@@ -0,0 +1,507 @@
+// Copyright © 2025-26 l5yth & contributors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! SQLite storage for the bridge state, used when `state_file` ends in `.db`.
+//!
+//! Every source's state is kept under its label (`""` for the main source)
+//! in a few tables: the per-channel checkpoints, the recent-message map and
+//! the content-dedup ring each have their own, with one row per entry, and
+//! the remaining fields are one JSON value per row. A save writes only the
+//! rows that changed since the last one, over a connection kept open.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::Result;
+use rusqlite::{params, Connection, ErrorCode};
+use serde_json::{Map, Value};
+
+use crate::dedup::SeenContent;
+use crate::recent::{RecentMessage, RecentMessages};
+use crate::state::{source_fields, BridgeState, StateStore};
+
+/// Whether `path` selects the SQLite backend.
+pub fn is_db_path(path: &str) -> bool {
+    Path::new(path).extension().is_some_and(|ext| ext == "db")
+}
+
+/// Whether `err` means the file is not a usable SQLite database, as opposed
+/// to, say, a locked one.
+pub fn is_corrupt(err: &anyhow::Error) -> bool {
+    matches!(
+        err.downcast_ref::<rusqlite::Error>()
+            .and_then(rusqlite::Error::sqlite_error_code),
+        Some(ErrorCode::NotADatabase | ErrorCode::DatabaseCorrupt)
+    )
+}
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS state_fields (
+        source TEXT NOT NULL,
+        key TEXT NOT NULL,
+        value TEXT NOT NULL,
+        PRIMARY KEY (source, key)
+    );
+    CREATE TABLE IF NOT EXISTS checkpoints (
+        source TEXT NOT NULL,
+        channel INTEGER NOT NULL,
+        message_id INTEGER NOT NULL,
+        PRIMARY KEY (source, channel)
+    );
+    CREATE TABLE IF NOT EXISTS recent_messages (
+        source TEXT NOT NULL,
+        id INTEGER NOT NULL,
+        stamp INTEGER NOT NULL,
+        node_id TEXT NOT NULL,
+        text TEXT,
+        event_id TEXT,
+        room_id TEXT,
+        PRIMARY KEY (source, id)
+    );
+    CREATE TABLE IF NOT EXISTS seen_content (
+        source TEXT NOT NULL,
+        seq INTEGER NOT NULL,
+        rx_time INTEGER NOT NULL,
+        hash INTEGER NOT NULL,
+        PRIMARY KEY (source, seq)
+    );
+";
+
+/// Fields kept in tables of their own rather than as JSON values.
+const TABLE_FIELDS: [&str; 3] = ["last_message_id", "recent_messages", "seen_content"];
+
+/// SQLite integers are signed; ids and hashes are stored bit for bit.
+fn to_sql(value: u64) -> i64 {
+    value as i64
+}
+
+fn from_sql(value: i64) -> u64 {
+    value as u64
+}
+
+/// A bridge state database, holding the state of every source.
+pub struct SqliteBridgeState {
+    conn: Connection,
+    /// What each source (by label) last had saved, to tell what changed.
+    saved: HashMap<String, Saved>,
+}
+
+/// The parts of one source's state as last saved or loaded.
+#[derive(Default)]
+struct Saved {
+    fields: Map<String, Value>,
+    checkpoints: HashMap<u8, u64>,
+    /// Stamp of the next recent message not yet written.
+    recent_stamp: u64,
+    recent_oldest: Option<u64>,
+    /// Sequence number of the next content hash not yet written.
+    seen_seq: u64,
+    seen_first: u64,
+}
+
+impl SqliteBridgeState {
+    /// Open (or create) the database at `path`.
+    pub fn open(path: &str) -> Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(Self {
+            conn,
+            saved: HashMap::new(),
+        })
+    }
+
+    /// Labels of the sources with a saved state, the main source's (`""`)
+    /// first.
+    fn sources(&self) -> Result<Vec<String>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT DISTINCT source FROM state_fields ORDER BY source")?;
+        let labels = stmt
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(labels)
+    }
+
+    fn load_source(&mut self, label: &str) -> Result<BridgeState> {
+        let mut saved = Saved::default();
+        let mut stmt = self
+            .conn
+            .prepare("SELECT key, value FROM state_fields WHERE source = ?1")?;
+        let rows = stmt.query_map(params![label], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?;
+        for row in rows {
+            let (key, value) = row?;
+            saved.fields.insert(key, serde_json::from_str(&value)?);
+        }
+        let mut state: BridgeState = serde_json::from_value(Value::Object(saved.fields.clone()))?;
+
+        let mut stmt = self
+            .conn
+            .prepare("SELECT channel, message_id FROM checkpoints WHERE source = ?1")?;
+        let rows = stmt.query_map(params![label], |row| {
+            Ok((row.get::<_, u8>(0)?, from_sql(row.get(1)?)))
+        })?;
+        state.last_message_id = rows.collect::<rusqlite::Result<_>>()?;
+        saved.checkpoints = state.last_message_id.clone();
+
+        let mut stmt = self.conn.prepare(
+            "SELECT stamp, id, node_id, text, event_id, room_id FROM recent_messages
+             WHERE source = ?1 ORDER BY stamp",
+        )?;
+        let rows = stmt.query_map(params![label], |row| {
+            Ok((
+                from_sql(row.get(0)?),
+                RecentMessage {
+                    id: from_sql(row.get(1)?),
+                    node_id: row.get(2)?,
+                    text: row.get(3)?,
+                    event_id: row.get(4)?,
+                    room_id: row.get(5)?,
+                },
+            ))
+        })?;
+        state.recent_messages = RecentMessages::restore(rows.collect::<rusqlite::Result<_>>()?);
+        saved.recent_stamp = state.recent_messages.next_stamp();
+        saved.recent_oldest = state.recent_messages.oldest_stamp();
+
+        let mut stmt = self.conn.prepare(
+            "SELECT seq, rx_time, hash FROM seen_content WHERE source = ?1 ORDER BY seq",
+        )?;
+        let rows = stmt.query_map(params![label], |row| {
+            Ok((
+                from_sql(row.get(0)?),
+                from_sql(row.get(1)?),
+                from_sql(row.get(2)?),
+            ))
+        })?;
+        let floor = state.seen_content.floor();
+        state.seen_content = SeenContent::restore(floor, rows.collect::<rusqlite::Result<_>>()?);
+        saved.seen_seq = state.seen_content.next_seq();
+        saved.seen_first = state.seen_content.first_seq();
+
+        self.saved.insert(label.to_string(), saved);
+        Ok(state)
+    }
+}
+
+impl StateStore for SqliteBridgeState {
+    fn load(&mut self) -> Result<Option<BridgeState>> {
+        let labels = self.sources()?;
+        if labels.is_empty() {
+            return Ok(None);
+        }
+        let mut state = BridgeState::default();
+        for label in labels {
+            let source = self.load_source(&label)?;
+            if label.is_empty() {
+                state = BridgeState {
+                    sources: std::mem::take(&mut state.sources),
+                    ..source
+                };
+            } else {
+                state.sources.insert(label, source);
+            }
+        }
+        Ok(Some(state))
+    }
+
+    fn save(&mut self, state: &BridgeState) -> Result<()> {
+        let label = state.source.clone().unwrap_or_default();
+        let mut fields = source_fields(state)?;
+        for field in TABLE_FIELDS {
+            fields.remove(field);
+        }
+        // The entries have a table; the floor is kept with the other fields.
+        fields.insert(
+            "seen_content".to_string(),
+            serde_json::json!({ "floor": state.seen_content.floor() }),
+        );
+
+        let saved = self.saved.entry(label.clone()).or_default();
+        let tx = self.conn.transaction()?;
+        {
+            let mut upsert = tx.prepare_cached(
+                "INSERT INTO state_fields (source, key, value) VALUES (?1, ?2, ?3)
+                 ON CONFLICT(source, key) DO UPDATE SET value = excluded.value",
+            )?;
+            for (key, value) in fields.iter() {
+                if saved.fields.get(key) != Some(value) {
+                    upsert.execute(params![label, key, value.to_string()])?;
+                }
+            }
+            for key in saved.fields.keys().filter(|key| !fields.contains_key(*key)) {
+                tx.execute(
+                    "DELETE FROM state_fields WHERE source = ?1 AND key = ?2",
+                    params![label, key],
+                )?;
+            }
+
+            let mut upsert = tx.prepare_cached(
+                "INSERT INTO checkpoints (source, channel, message_id) VALUES (?1, ?2, ?3)
+                 ON CONFLICT(source, channel) DO UPDATE SET message_id = excluded.message_id",
+            )?;
+            for (&channel, &id) in &state.last_message_id {
+                if saved.checkpoints.get(&channel) != Some(&id) {
+                    upsert.execute(params![label, channel, to_sql(id)])?;
+                }
+            }
+            for channel in saved
+                .checkpoints
+                .keys()
+                .filter(|channel| !state.last_message_id.contains_key(*channel))
+            {
+                tx.execute(
+                    "DELETE FROM checkpoints WHERE source = ?1 AND channel = ?2",
+                    params![label, channel],
+                )?;
+            }
+
+            let recent = &state.recent_messages;
+            let mut upsert = tx.prepare_cached(
+                "INSERT INTO recent_messages (source, id, stamp, node_id, text, event_id, room_id)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+                 ON CONFLICT(source, id) DO UPDATE SET stamp = excluded.stamp,
+                    node_id = excluded.node_id, text = excluded.text,
+                    event_id = excluded.event_id, room_id = excluded.room_id",
+            )?;
+            for (stamp, message) in recent.recorded_since(saved.recent_stamp) {
+                upsert.execute(params![
+                    label,
+                    to_sql(message.id),
+                    to_sql(stamp),
+                    message.node_id,
+                    message.text,
+                    message.event_id,
+                    message.room_id,
+                ])?;
+            }
+            // Every evicted message has a lower stamp than those kept.
+            if recent.oldest_stamp() != saved.recent_oldest {
+                tx.execute(
+                    "DELETE FROM recent_messages WHERE source = ?1 AND stamp < ?2",
+                    params![
+                        label,
+                        to_sql(recent.oldest_stamp().unwrap_or(recent.next_stamp()))
+                    ],
+                )?;
+            }
+
+            let seen = &state.seen_content;
+            let mut insert = tx.prepare_cached(
+                "INSERT OR REPLACE INTO seen_content (source, seq, rx_time, hash)
+                 VALUES (?1, ?2, ?3, ?4)",
+            )?;
+            for (seq, rx_time, hash) in seen.recorded_since(saved.seen_seq) {
+                insert.execute(params![label, to_sql(seq), to_sql(rx_time), to_sql(hash)])?;
+            }
+            if seen.first_seq() != saved.seen_first {
+                tx.execute(
+                    "DELETE FROM seen_content WHERE source = ?1 AND seq < ?2",
+                    params![label, to_sql(seen.first_seq())],
+                )?;
+            }
+        }
+        tx.commit()?;
+
+        saved.fields = fields;
+        saved.checkpoints = state.last_message_id.clone();
+        saved.recent_stamp = state.recent_messages.next_stamp();
+        saved.recent_oldest = state.recent_messages.oldest_stamp();
+        saved.seen_seq = state.seen_content.next_seq();
+        saved.seen_first = state.seen_content.first_seq();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::potatomesh::PotatoMessage;
+
+    fn db_path(dir: &tempfile::TempDir) -> String {
+        dir.path().join("state.db").to_str().unwrap().to_string()
+    }
+
+    fn message(id: u64, rx_time: u64) -> PotatoMessage {
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "rx_time": rx_time,
+            "rx_iso": "2025-11-27T00:00:00Z",
+            "from_id": "!abcd1234",
+            "to_id": "^all",
+            "channel": 0,
+            "text": format!("Ping {id}"),
+            "lora_freq": 868,
+            "modem_preset": "MediumFast",
+            "channel_name": "TEST",
+            "node_id": "!abcd1234",
+        }))
+        .unwrap()
+    }
+
+    fn count(store: &SqliteBridgeState, table: &str) -> u64 {
+        store
+            .conn
+            .query_row(&format!("SELECT COUNT(*) FROM {table}"), [], |row| {
+                row.get(0)
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn empty_database_loads_nothing() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut store = SqliteBridgeState::open(&db_path(&tmp_dir)).unwrap();
+
+        assert!(store.load().unwrap().is_none());
+    }
+
+    #[test]
+    fn save_and_load_round_trip() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = db_path(&tmp_dir);
+        let mut state = BridgeState::default();
+        state.last_message_id.insert(0, 42);
+        state.last_message_id.insert(1, u64::MAX);
+        state.last_rx_time = Some(1_700_000_000);
+        for (id, rx_time) in [(41, 99), (42, 100)] {
+            let msg = message(id, rx_time);
+            state.update_with(&msg);
+            state.recent_messages.record(RecentMessage {
+                id,
+                node_id: "!abcd1234".to_string(),
+                text: Some(msg.text.clone()),
+                event_id: Some(format!("${id}")),
+                room_id: Some("!room:example.org".to_string()),
+            });
+        }
+
+        let mut store = SqliteBridgeState::open(&path).unwrap();
+        store.save(&state).unwrap();
+        drop(store);
+
+        let loaded = SqliteBridgeState::open(&path)
+            .unwrap()
+            .load()
+            .unwrap()
+            .unwrap();
+        assert_eq!(loaded.last_message_id, state.last_message_id);
+        assert_eq!(loaded.last_rx_time, state.last_rx_time);
+        let recent = loaded.recent_messages.get(42).unwrap();
+        assert_eq!(recent.event_id.as_deref(), Some("$42"));
+        assert_eq!(recent.room_id.as_deref(), Some("!room:example.org"));
+        assert!(loaded.recent_messages.in_table());
+        assert!(!loaded.seen_content.is_new(&message(42, 100)));
+        assert!(loaded.seen_content.is_new(&message(43, 101)));
+    }
+
+    #[test]
+    fn saves_write_rows_as_they_change() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut store = SqliteBridgeState::open(&db_path(&tmp_dir)).unwrap();
+        let mut state = BridgeState::default();
+        state.seen_content.set_capacity(2);
+        for id in 1..=3 {
+            state.update_with(&message(id, 100 + id));
+            state.recent_messages.record(RecentMessage {
+                id,
+                ..Default::default()
+            });
+            store.save(&state).unwrap();
+        }
+        state.last_message_id.clear();
+        state.last_message_id.insert(1, 7);
+        store.save(&state).unwrap();
+
+        assert_eq!(count(&store, "recent_messages"), 3);
+        assert_eq!(count(&store, "seen_content"), 2);
+        assert_eq!(count(&store, "checkpoints"), 1);
+        let loaded = store.load().unwrap().unwrap();
+        assert_eq!(loaded.last_message_id, HashMap::from([(1, 7)]));
+        assert_eq!(loaded.seen_content.floor(), Some(101));
+        assert_eq!(loaded.seen_content.first_seq(), 1);
+    }
+
+    #[test]
+    fn evicted_recent_messages_are_deleted() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut store = SqliteBridgeState::open(&db_path(&tmp_dir)).unwrap();
+        let mut state = BridgeState::default();
+        for id in 0..=crate::recent::MAX_RECENT_MESSAGES as u64 {
+            state.recent_messages.record(RecentMessage {
+                id,
+                ..Default::default()
+            });
+        }
+        store.save(&state).unwrap();
+
+        assert_eq!(
+            count(&store, "recent_messages"),
+            crate::recent::MAX_RECENT_MESSAGES as u64
+        );
+        let loaded = store.load().unwrap().unwrap();
+        assert!(loaded.recent_messages.get(0).is_none());
+    }
+
+    #[test]
+    fn sources_are_kept_apart() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut store = SqliteBridgeState::open(&db_path(&tmp_dir)).unwrap();
+        let mut main = BridgeState::default();
+        main.last_message_id.insert(0, 1);
+        let mut other = BridgeState {
+            source: Some("other".to_string()),
+            ..Default::default()
+        };
+        other.last_message_id.insert(0, 2);
+        other.txn_counter = 5.into();
+        store.save(&main).unwrap();
+        store.save(&other).unwrap();
+
+        let loaded = store.load().unwrap().unwrap();
+        assert_eq!(loaded.last_message_id, HashMap::from([(0, 1)]));
+        let other = &loaded.sources["other"];
+        assert_eq!(other.last_message_id, HashMap::from([(0, 2)]));
+        let fields: Vec<String> = store
+            .conn
+            .prepare("SELECT key FROM state_fields WHERE source = 'other'")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert!(!fields.iter().any(|field| field == "txn_counter"));
+    }
+
+    #[test]
+    fn non_database_file_is_reported_as_corrupt() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = db_path(&tmp_dir);
+        std::fs::write(&path, "this is not a database, just some text").unwrap();
+
+        let err = SqliteBridgeState::open(&path)
+            .and_then(|mut store| store.load())
+            .unwrap_err();
+
+        assert!(is_corrupt(&err));
+    }
+
+    #[test]
+    fn is_db_path_checks_extension() {
+        assert!(is_db_path("bridge_state.db"));
+        assert!(!is_db_path("bridge_state.json"));
+        assert!(!is_db_path("db"));
+    }
+}
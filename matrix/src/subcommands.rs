@@ -0,0 +1,158 @@
+// Copyright © 2025-26 l5yth & contributors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The `check`, `audit`, `gen-registration` and `backfill` subcommands.
+
+use anyhow::Result;
+#[cfg(not(test))]
+use tracing::info;
+
+#[cfg(not(test))]
+use crate::config::{self, Config};
+use crate::config::{BridgeConfig, DeliveryJournal};
+use crate::filtered_out;
+use crate::journal;
+#[cfg(not(test))]
+use crate::matrix::MatrixAppserviceClient;
+use crate::potatomesh::{self, PotatoClient};
+#[cfg(not(test))]
+use crate::state::BridgeState;
+#[cfg(not(test))]
+use crate::{backfill, check, registration, restore_created_room};
+
+/// The `audit --missing` subcommand: print the ids of the `count` most
+/// recent messages that should have been bridged but were never journaled.
+#[cfg(not(test))]
+pub async fn run_audit(
+    bridge_cfg: &BridgeConfig,
+    potato: &PotatoClient,
+    count: usize,
+) -> Result<()> {
+    let Some(journal) = &bridge_cfg.delivery_journal else {
+        anyhow::bail!("bridge.delivery_journal is not configured; there is nothing to audit");
+    };
+    let (missing, expected) = missing_deliveries(potato, bridge_cfg, journal, count).await?;
+    for id in &missing {
+        println!("{}", id);
+    }
+    if !missing.is_empty() {
+        anyhow::bail!(
+            "{} of {} messages were never delivered",
+            missing.len(),
+            expected
+        );
+    }
+    info!("All {} messages were delivered", expected);
+    Ok(())
+}
+
+/// Ids of the `count` most recent messages that pass the bridge's filters
+/// but are not in `journal`, in id order, and how many passed.
+pub async fn missing_deliveries(
+    potato: &PotatoClient,
+    bridge_cfg: &BridgeConfig,
+    journal: &DeliveryJournal,
+    count: usize,
+) -> Result<(Vec<u64>, usize)> {
+    let delivered = journal::delivered_ids(journal)?;
+    let now = potatomesh::now_secs();
+    let mut expected = Vec::new();
+    for msg in potato.fetch_recent(count).await? {
+        if filtered_out(potato, bridge_cfg, &msg, now).await.is_none() {
+            expected.push(msg.id);
+        }
+    }
+    expected.sort_unstable();
+    let missing: Vec<u64> = expected
+        .iter()
+        .copied()
+        .filter(|id| !delivered.contains(id))
+        .collect();
+    Ok((missing, expected.len()))
+}
+
+/// The `check` subcommand: print the preflight checklist, failing if any
+/// step did. Sends for real even under `dry_run`.
+#[cfg(not(test))]
+pub async fn run_check(potato: &PotatoClient, matrix: &MatrixAppserviceClient) -> Result<()> {
+    let steps = check::run_checks(potato, matrix).await;
+    for step in &steps {
+        println!("{}", step);
+    }
+    let failed = steps.iter().filter(|step| step.error.is_some()).count();
+    if failed > 0 {
+        anyhow::bail!("{} of {} checks failed", failed, steps.len());
+    }
+    Ok(())
+}
+
+/// The `gen-registration` subcommand: print the registration file, and
+/// point out tokens that were generated rather than taken from the config.
+#[cfg(not(test))]
+pub fn run_gen_registration(
+    settings: &config::RegistrationSettings,
+    url: &str,
+    sender_localpart: &str,
+) -> Result<()> {
+    let registration = registration::generate(settings, url, sender_localpart)?;
+    print!("{}", registration.yaml);
+    // stderr, so the YAML on stdout can be redirected into a file as is.
+    for key in registration.generated {
+        eprintln!(
+            "Warning: {key} is not configured; the registration holds a new one. \
+             Set the same value as {key} in Config.toml, or the bridge and the \
+             homeserver will not accept each other."
+        );
+    }
+    Ok(())
+}
+
+/// The `backfill` subcommand: forward the `count` most recent messages and
+/// exit. The state file is only written with `commit`.
+#[cfg(not(test))]
+pub async fn run_backfill(
+    cfg: &Config,
+    potato: &PotatoClient,
+    matrix: &mut MatrixAppserviceClient,
+    count: usize,
+    commit: bool,
+) -> Result<()> {
+    let state_path = &cfg.state.state_file;
+    let mut state = BridgeState::load_or_recover(state_path, cfg.state.recover_corrupt_state)?;
+    state.hold_checkpoint = !commit || (cfg.bridge.dry_run && cfg.bridge.dry_run_hold_checkpoint);
+    state.id_cursor = potato.supports_id_cursor();
+    restore_created_room(&state, matrix);
+    state.txn_counter = matrix.resume_txn_counter(&state.txn_counter);
+    if !cfg.bridge.dry_run {
+        matrix.set_puppet_cache(state.puppets.clone());
+    }
+    matrix
+        .check_room_membership(&matrix.bridged_rooms())
+        .await?;
+    state
+        .seen_content
+        .set_capacity(cfg.bridge.content_dedup_size);
+
+    let fetched = backfill(potato, matrix, &cfg.bridge, &mut state, state_path, count).await?;
+    state.save(state_path)?;
+    if state.hold_checkpoint {
+        info!("Backfilled {} messages; state file left untouched", fetched);
+    } else {
+        info!(
+            "Backfilled {} messages; state saved to {}",
+            fetched, state_path
+        );
+    }
+    Ok(())
+}
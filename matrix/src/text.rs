@@ -0,0 +1,314 @@
+// Copyright © 2025-26 l5yth & contributors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Normalization helpers applied to mesh message text before it is
+//! formatted for Matrix.
+
+use std::borrow::Cow;
+
+/// Decode literal `\uXXXX` escape sequences left behind by upstream
+/// double-encoding (e.g. `Gr\u00fc\u00dfe` arriving as ten ASCII characters
+/// instead of `Grüße`).
+///
+/// The decoder is deliberately conservative so legitimately-backslashed text
+/// survives untouched:
+///
+/// * only `\u` followed by exactly four hex digits is considered;
+/// * only a `\u` ending an odd run of backslashes is an escape: in `\\u00e4`
+///   the backslash is itself escaped and the text is left as-is, while in
+///   `\\\u00e4` the escaped backslash is kept and `\u00e4` is decoded;
+/// * UTF-16 surrogate pairs (`\ud83e\udd54`) are combined, while lone
+///   surrogates are left as literal text;
+/// * sequences decoding to control characters are left as literal text so a
+///   stray `\u0000` cannot inject invisible bytes into the room.
+///
+/// Returns a borrowed value when nothing was decoded.
+pub fn unescape_unicode(input: &str) -> Cow<'_, str> {
+    if !input.contains("\\u") {
+        return Cow::Borrowed(input);
+    }
+
+    let chars: Vec<char> = input.chars().collect();
+    let mut out = String::with_capacity(input.len());
+    let mut changed = false;
+    let mut idx = 0;
+    while idx < chars.len() {
+        if chars[idx] != '\\' {
+            out.push(chars[idx]);
+            idx += 1;
+            continue;
+        }
+        // Backslashes pair up as escaped backslashes; only the odd one
+        // left over at the end of a run can start an escape.
+        let run = chars[idx..].iter().take_while(|&&c| c == '\\').count();
+        let last = idx + run - 1;
+        out.extend(&chars[idx..last]);
+        if run % 2 == 1 {
+            if let Some((decoded, consumed)) = decode_escape_at(&chars, last) {
+                out.push(decoded);
+                idx = last + consumed;
+                changed = true;
+                continue;
+            }
+        }
+        out.push('\\');
+        idx = last + 1;
+    }
+
+    if changed {
+        Cow::Owned(out)
+    } else {
+        Cow::Borrowed(input)
+    }
+}
+
+/// Cut `input` to at most `max_chars` characters, ending it with `…` when
+/// anything was removed. Borrowed when it already fits.
+pub fn truncate_chars(input: &str, max_chars: usize) -> Cow<'_, str> {
+    match input.char_indices().nth(max_chars) {
+        None => Cow::Borrowed(input),
+        Some(_) => {
+            let keep = max_chars.saturating_sub(1);
+            let mut out: String = input.chars().take(keep).collect();
+            out.push('…');
+            Cow::Owned(out)
+        }
+    }
+}
+
+/// Strip leading/trailing whitespace and the trailing NULs some firmware
+/// pads text with. Inner spacing and line breaks are kept.
+pub fn trim_padding(input: Cow<'_, str>) -> Cow<'_, str> {
+    let is_padding = |c: char| c.is_whitespace() || c == '\0';
+    match input {
+        Cow::Borrowed(text) => Cow::Borrowed(text.trim_matches(is_padding)),
+        Cow::Owned(text) => {
+            let trimmed = text.trim_matches(is_padding);
+            if trimmed.len() == text.len() {
+                Cow::Owned(text)
+            } else {
+                Cow::Owned(trimmed.to_string())
+            }
+        }
+    }
+}
+
+/// Names of the `{name}` placeholders in `template`, in order. Braces around
+/// anything but ASCII letters, digits and `_` are literal text.
+pub fn placeholders(template: &str) -> Vec<&str> {
+    let mut names = Vec::new();
+    let mut rest = template;
+    while let Some((_, name, after)) = next_placeholder(rest) {
+        names.push(name);
+        rest = after;
+    }
+    names
+}
+
+/// Replace each `{name}` placeholder in `template` with its value from
+/// `values` in a single pass, so a value that itself contains `{...}` is not
+/// expanded again. Placeholders without a value are kept as written.
+pub fn fill_placeholders(template: &str, values: &[(&str, &str)]) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some((before, name, after)) = next_placeholder(rest) {
+        out.push_str(before);
+        match values.iter().find(|(key, _)| *key == name) {
+            Some((_, value)) => out.push_str(value),
+            None => {
+                out.push('{');
+                out.push_str(name);
+                out.push('}');
+            }
+        }
+        rest = after;
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Split `input` around its first `{name}` placeholder into the text before
+/// it, the name, and the text after it.
+fn next_placeholder(input: &str) -> Option<(&str, &str, &str)> {
+    let mut search_from = 0;
+    loop {
+        let open = search_from + input[search_from..].find('{')?;
+        let close = open + input[open..].find('}')?;
+        let name = &input[open + 1..close];
+        if !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Some((&input[..open], name, &input[close + 1..]));
+        }
+        search_from = open + 1;
+    }
+}
+
+/// Minimal HTML escaping for Matrix formatted_body payloads.
+pub fn escape_html(input: &str) -> String {
+    let mut escaped = String::with_capacity(input.len());
+    for ch in input.chars() {
+        match ch {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(ch),
+        }
+    }
+    escaped
+}
+
+/// 64-bit FNV-1a hash, stable across platforms and releases.
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Parse the four hex digits of a `\uXXXX` sequence starting at `idx`.
+fn parse_escape_unit(chars: &[char], idx: usize) -> Option<u32> {
+    if chars.get(idx) != Some(&'\\') || chars.get(idx + 1) != Some(&'u') {
+        return None;
+    }
+    let digits = chars.get(idx + 2..idx + 6)?;
+    let hex: String = digits.iter().collect();
+    if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    u32::from_str_radix(&hex, 16).ok()
+}
+
+/// Decode the escape sequence at `idx`, returning the character and the
+/// number of source characters it consumed.
+fn decode_escape_at(chars: &[char], idx: usize) -> Option<(char, usize)> {
+    let unit = parse_escape_unit(chars, idx)?;
+    let (code, consumed) = match unit {
+        // High surrogate: only valid when immediately followed by a low one.
+        0xD800..=0xDBFF => {
+            let low = parse_escape_unit(chars, idx + 6)?;
+            if !(0xDC00..=0xDFFF).contains(&low) {
+                return None;
+            }
+            (0x10000 + ((unit - 0xD800) << 10) + (low - 0xDC00), 12)
+        }
+        // Lone low surrogate.
+        0xDC00..=0xDFFF => return None,
+        _ => (unit, 6),
+    };
+    let decoded = char::from_u32(code)?;
+    if decoded.is_control() {
+        return None;
+    }
+    Some((decoded, consumed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn placeholders_lists_names_and_skips_literal_braces() {
+        assert_eq!(
+            placeholders("[{short}] {text} {not a placeholder} {} {{snr}}"),
+            vec!["short", "text", "snr"]
+        );
+        assert!(placeholders("no placeholders {").is_empty());
+    }
+
+    #[test]
+    fn fill_placeholders_does_not_expand_values() {
+        assert_eq!(
+            fill_placeholders(
+                "[{short}] {text} {unknown}",
+                &[("short", "TN"), ("text", "say {short}")]
+            ),
+            "[TN] say {short} {unknown}"
+        );
+    }
+
+    #[test]
+    fn escape_html_escapes_quotes() {
+        assert_eq!(escape_html("a\"b'c"), "a&quot;b&#39;c");
+    }
+
+    #[test]
+    fn unescape_unicode_decodes_escaped_sequences() {
+        assert_eq!(unescape_unicode(r"Gr\u00fc\u00dfe"), "Grüße");
+        assert_eq!(unescape_unicode(r"\u00C4pfel"), "Äpfel");
+    }
+
+    #[test]
+    fn unescape_unicode_combines_surrogate_pairs() {
+        assert_eq!(unescape_unicode(r"potato \ud83e\udd54"), "potato 🥔");
+    }
+
+    #[test]
+    fn unescape_unicode_decodes_after_escaped_backslashes() {
+        assert_eq!(unescape_unicode(r"\\\u00e4"), r"\\ä");
+        assert_eq!(unescape_unicode(r"a\\\\\u00e4b"), r"a\\\\äb");
+        assert_eq!(unescape_unicode(r"\\\\u00e4"), r"\\\\u00e4");
+    }
+
+    #[test]
+    fn unescape_unicode_leaves_normal_text_untouched() {
+        let input = "Grüße aus Berlin";
+        assert!(matches!(unescape_unicode(input), Cow::Borrowed(_)));
+        assert_eq!(unescape_unicode(input), input);
+    }
+
+    #[test]
+    fn unescape_unicode_leaves_legitimate_backslashes_untouched() {
+        for input in [
+            r"C:\users\admin",
+            r"escaped \\u00e4 stays",
+            r"short \u00e",
+            r"not hex \u00zz",
+            r"lone \ud83e surrogate",
+            r"lone \udd54 low",
+            r"control \u0000 char",
+        ] {
+            assert_eq!(unescape_unicode(input), input, "input={input}");
+        }
+    }
+
+    #[test]
+    fn trim_padding_strips_outer_whitespace_and_nuls() {
+        assert_eq!(
+            trim_padding(Cow::Borrowed("  hello world \n")),
+            "hello world"
+        );
+        assert_eq!(trim_padding(Cow::Borrowed("ack\0\0")), "ack");
+        assert_eq!(
+            trim_padding(Cow::Owned(" line one\n  line two \0".to_string())),
+            "line one\n  line two"
+        );
+    }
+
+    #[test]
+    fn trim_padding_leaves_clean_text_untouched() {
+        let input = "already  clean";
+        assert!(matches!(trim_padding(Cow::Borrowed(input)), Cow::Borrowed(text) if text == input));
+    }
+
+    #[test]
+    fn truncate_chars_keeps_short_text_borrowed() {
+        assert!(matches!(truncate_chars("Node", 4), Cow::Borrowed("Node")));
+    }
+
+    #[test]
+    fn truncate_chars_cuts_on_char_boundaries_with_ellipsis() {
+        assert_eq!(truncate_chars("Grüße aus Wien", 6), "Grüße…");
+        assert_eq!(truncate_chars("🥔🥔🥔", 2), "🥔…");
+    }
+}
@@ -0,0 +1,130 @@
+// Copyright © 2025-26 l5yth & contributors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Bounded, persisted record of Synapse transaction ids the listener has
+//! already processed.
+//!
+//! Synapse re-delivers a transaction when it does not see a timely `200`, so
+//! the same id can arrive twice — including across a bridge restart after a
+//! crash mid-transaction. Persisting the recent ids lets the listener
+//! acknowledge such retries without acting on their events again.
+
+use std::collections::VecDeque;
+use std::{fs, path::Path};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+/// Number of transaction ids remembered; the oldest are evicted first.
+pub const MAX_PROCESSED_TXNS: usize = 1024;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ProcessedTxns {
+    /// Processed ids, oldest first.
+    #[serde(default)]
+    ids: VecDeque<String>,
+}
+
+impl ProcessedTxns {
+    /// Load processed ids from `path`, treating a missing or empty file as empty.
+    pub fn load(path: &str) -> Result<Self> {
+        if !Path::new(path).exists() {
+            return Ok(Self::default());
+        }
+        let data = fs::read_to_string(path)?;
+        if data.trim().is_empty() {
+            return Ok(Self::default());
+        }
+        let mut txns: Self = serde_json::from_str(&data)?;
+        txns.truncate();
+        Ok(txns)
+    }
+
+    /// The file contents [`ProcessedTxns::load`] reads back.
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string(self)?)
+    }
+
+    /// Whether `txn_id` has already been processed.
+    pub fn contains(&self, txn_id: &str) -> bool {
+        self.ids.iter().any(|id| id == txn_id)
+    }
+
+    /// Record `txn_id` as processed, evicting the oldest ids past the bound.
+    pub fn record(&mut self, txn_id: &str) {
+        if self.contains(txn_id) {
+            return;
+        }
+        self.ids.push_back(txn_id.to_string());
+        self.truncate();
+    }
+
+    fn truncate(&mut self) {
+        while self.ids.len() > MAX_PROCESSED_TXNS {
+            self.ids.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn processed_txns_records_and_detects_ids() {
+        let mut txns = ProcessedTxns::default();
+        assert!(!txns.contains("1"));
+        txns.record("1");
+        txns.record("1");
+        assert!(txns.contains("1"));
+        assert_eq!(txns.ids.len(), 1);
+    }
+
+    #[test]
+    fn processed_txns_evicts_oldest_past_bound() {
+        let mut txns = ProcessedTxns::default();
+        for idx in 0..=MAX_PROCESSED_TXNS {
+            txns.record(&idx.to_string());
+        }
+        assert_eq!(txns.ids.len(), MAX_PROCESSED_TXNS);
+        assert!(!txns.contains("0"));
+        assert!(txns.contains(&MAX_PROCESSED_TXNS.to_string()));
+    }
+
+    #[test]
+    fn processed_txns_survive_reload() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("txns.json");
+        let path_str = path.to_str().unwrap();
+
+        let mut txns = ProcessedTxns::default();
+        txns.record("txn-42");
+        fs::write(path_str, txns.to_json().unwrap()).unwrap();
+
+        let reloaded = ProcessedTxns::load(path_str).unwrap();
+        assert!(reloaded.contains("txn-42"));
+        assert!(!reloaded.contains("txn-43"));
+    }
+
+    #[test]
+    fn processed_txns_load_missing_or_empty_file() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("txns.json");
+        let path_str = path.to_str().unwrap();
+
+        assert!(ProcessedTxns::load(path_str).unwrap().ids.is_empty());
+        fs::write(&path, "\n").unwrap();
+        assert!(ProcessedTxns::load(path_str).unwrap().ids.is_empty());
+    }
+}